@@ -1,6 +1,6 @@
 use criterion::{Criterion, Throughput, criterion_group, criterion_main};
 use rand::RngCore;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 
 fn buf_reader_writer_write_only_throughput(c: &mut Criterion) {
@@ -217,7 +217,103 @@ fn in_mem_buf_writer_write_only_throughput(c: &mut Criterion) {
     group.finish();
 }
 
+fn random_patches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("RandomPatches");
+    let file_len = 64 * 1024 * 1024;
+    let num_patches = 10_000;
+    let patch = [0xAB_u8; 16];
 
+    let file = tempfile::tempfile().unwrap();
+    file.set_len(file_len).unwrap();
+    let mut rng = rand::rng();
+    let offsets = (0..num_patches)
+        .map(|_| rng.next_u64() % (file_len - patch.len() as u64))
+        .collect::<Vec<_>>();
+
+    group.throughput(Throughput::Elements(num_patches));
+    group.bench_function("BufReaderWriter", |b| {
+        b.iter(|| {
+            let mut output = bufrw::BufReaderWriter::new(file.try_clone().unwrap());
+            for offset in &offsets {
+                output.seek(SeekFrom::Start(*offset)).unwrap();
+                output.write_all(&patch).unwrap();
+            }
+            output.flush().unwrap();
+        })
+    });
+    #[cfg(feature = "mmap-write")]
+    group.bench_function("MmapFile", |b| {
+        b.iter(|| {
+            // SAFETY: the temporary file is only used by this benchmark
+            let mut output = unsafe { bufrw::MmapFile::open(file.try_clone().unwrap()) }.unwrap();
+            for offset in &offsets {
+                output.seek(SeekFrom::Start(*offset)).unwrap();
+                output.write_all(&patch).unwrap();
+            }
+            output.flush().unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn read_to_end(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ReadToEnd");
+    let file_len = 100 * 1024 * 1024;
+
+    let mut file = tempfile::tempfile().unwrap();
+    let mut bytes = vec![0; file_len];
+    rand::rng().fill_bytes(&mut bytes);
+    file.write_all(&bytes).unwrap();
+    drop(bytes);
+
+    group.throughput(Throughput::Bytes(file_len as u64));
+    group.bench_function("BufReaderWriter", |b| {
+        b.iter(|| {
+            let mut file = file.try_clone().unwrap();
+            file.rewind().unwrap();
+            let mut input = bufrw::BufReaderWriter::new(file);
+            let mut content = Vec::new();
+            input.read_to_end(&mut content).unwrap();
+            content
+        })
+    });
+    group.bench_function("BufReader", |b| {
+        b.iter(|| {
+            let mut file = file.try_clone().unwrap();
+            file.rewind().unwrap();
+            let mut input = std::io::BufReader::new(file);
+            let mut content = Vec::new();
+            input.read_to_end(&mut content).unwrap();
+            content
+        })
+    });
+    group.finish();
+}
+
+fn write_zeros(c: &mut Criterion) {
+    let mut group = c.benchmark_group("WriteZeros");
+    group.sample_size(10);
+    let len = 1024 * 1024 * 1024;
+    let file = tempfile::tempfile().unwrap();
+
+    group.throughput(Throughput::Bytes(len));
+    group.bench_function("BufReaderWriter", |b| {
+        b.iter(|| {
+            let mut output = bufrw::BufReaderWriter::new(file.try_clone().unwrap());
+            output.rewind().unwrap();
+            output.write_zeros(len).unwrap();
+            output.flush().unwrap();
+        })
+    });
+    group.bench_function("io::copy", |b| {
+        b.iter(|| {
+            let mut output = file.try_clone().unwrap();
+            output.rewind().unwrap();
+            std::io::copy(&mut std::io::repeat(0).take(len), &mut output).unwrap();
+        })
+    });
+    group.finish();
+}
 
 criterion_group!(
     benches,
@@ -229,5 +325,8 @@ criterion_group!(
     in_mem_buf_reader_read_only_throughput,
     in_mem_buf_reader_writer_write_only_throughput,
     in_mem_buf_writer_write_only_throughput,
+    random_patches,
+    read_to_end,
+    write_zeros,
 );
 criterion_main!(benches);
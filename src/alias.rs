@@ -0,0 +1,192 @@
+//! Debug facility detecting several adapters buffering writes to the same file
+//!
+//! Each adapter that opted in registers the identity of its file
+//! (device + inode on Unix, volume serial number + file index on Windows)
+//! in a process-global table, along with the range it currently holds as dirty.
+//!
+//! When an adapter's dirty range overlaps the dirty range of another adapter
+//! on the same file, one of them is bound to overwrite the other's writes
+//! with stale bytes when it flushes, so we panic.
+//!
+//! This is a heuristic: it only sees adapters that opted in, and only
+//! conflicts that happen while both are dirty.
+use std::fs::File;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static REGISTRY: Mutex<Vec<Group>> = Mutex::new(Vec::new());
+
+/// All the registered adapters on the same file
+struct Group {
+    id: u64,
+    handle: same_file::Handle,
+    members: Vec<Member>,
+}
+
+struct Member {
+    instance: u64,
+    dirty: Range<u64>,
+}
+
+fn registry() -> MutexGuard<'static, Vec<Group>> {
+    // A panic while holding the lock does not leave the table inconsistent
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    !a.is_empty() && !b.is_empty() && a.start < b.end && b.start < a.end
+}
+
+/// Registration of one adapter, removed from the table on drop
+pub(crate) struct AliasGuard {
+    group: u64,
+    instance: u64,
+}
+
+impl AliasGuard {
+    pub(crate) fn register(file: &File) -> std::io::Result<Self> {
+        let handle = same_file::Handle::from_file(file.try_clone()?)?;
+        let instance = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        let mut groups = registry();
+        let index = match groups.iter().position(|group| group.handle == handle) {
+            Some(index) => index,
+            None => {
+                groups.push(Group {
+                    id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                    handle,
+                    members: Vec::new(),
+                });
+                groups.len() - 1
+            }
+        };
+        let group = &mut groups[index];
+        group.members.push(Member {
+            instance,
+            dirty: 0..0,
+        });
+
+        Ok(Self {
+            group: group.id,
+            instance,
+        })
+    }
+
+    /// Records the range this adapter holds as dirty (empty when clean)
+    ///
+    /// # Panics
+    ///
+    /// If the range overlaps the dirty range of another adapter on the same file
+    pub(crate) fn update(&self, dirty: Range<u64>) {
+        let conflict = {
+            let mut groups = registry();
+            let Some(group) = groups.iter_mut().find(|group| group.id == self.group) else {
+                return;
+            };
+
+            let mut conflict = None;
+            for member in group.members.iter_mut() {
+                if member.instance == self.instance {
+                    member.dirty = dirty.clone();
+                } else if overlaps(&member.dirty, &dirty) {
+                    conflict = Some(member.dirty.clone());
+                }
+            }
+            conflict
+        };
+
+        // Panic after the lock is released
+        if let Some(other) = conflict {
+            panic!(
+                "bufrw: two BufReaderWriter instances on the same file have overlapping \
+                 dirty ranges ({dirty:?} and {other:?}), one of them will overwrite the \
+                 other's writes with stale data"
+            );
+        }
+    }
+}
+
+impl Drop for AliasGuard {
+    fn drop(&mut self) {
+        let mut groups = registry();
+        if let Some(index) = groups.iter().position(|group| group.id == self.group) {
+            let group = &mut groups[index];
+            group
+                .members
+                .retain(|member| member.instance != self.instance);
+            if group.members.is_empty() {
+                groups.swap_remove(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::panic::AssertUnwindSafe;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> (PathBuf, File) {
+        let path = std::env::temp_dir().join(format!(
+            "bufrw-alias-{name}-{}.bin",
+            std::process::id()
+        ));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[b'.'; 1024]).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn test_overlapping_dirty_ranges_are_detected() {
+        let (path, file) = temp_file("overlap");
+
+        let mut a = BufReaderWriter::new(file.try_clone().unwrap());
+        let mut b = BufReaderWriter::new(file);
+        a.enable_alias_detection().unwrap();
+        b.enable_alias_detection().unwrap();
+
+        a.write_all(b"aaaa").unwrap();
+        b.seek(SeekFrom::Start(2)).unwrap();
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| b.write_all(b"bbbb")));
+        let message = result.unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert!(message.contains("overlapping dirty ranges"), "{message}");
+
+        drop((a, b));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_disjoint_or_flushed_ranges_are_fine() {
+        let (path, file) = temp_file("disjoint");
+
+        let mut a = BufReaderWriter::new(file.try_clone().unwrap());
+        let mut b = BufReaderWriter::new(file);
+        a.enable_alias_detection().unwrap();
+        b.enable_alias_detection().unwrap();
+
+        a.write_all(b"aaaa").unwrap();
+        b.seek(SeekFrom::Start(100_000)).unwrap();
+        b.write_all(b"bbbb").unwrap();
+
+        // Once flushed, the range is no longer dirty
+        a.flush().unwrap();
+        b.seek(SeekFrom::Start(2)).unwrap();
+        b.write_all(b"bbbb").unwrap();
+
+        drop((a, b));
+        std::fs::remove_file(path).unwrap();
+    }
+}
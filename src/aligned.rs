@@ -0,0 +1,349 @@
+//! Buffers aligned in memory and in the stream, for files opened with `O_DIRECT`
+use crate::storage::Storage;
+use crate::{BufReaderWriter, Buffer};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Reads from a `T`, captured where `T: Read` is known
+pub(crate) type ReadFn<T> = fn(&mut T, &mut [u8]) -> std::io::Result<usize>;
+
+/// The alignment of the buffer, and how to fill it
+pub(crate) struct Alignment<T> {
+    size: usize,
+    // Writes need to read the start of their block
+    read: ReadFn<T>,
+}
+
+impl<T> Clone for Alignment<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Alignment<T> {}
+
+impl<T> Alignment<T> {
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Reads from a `T` through the captured function
+pub(crate) struct ReadWith<'a, T>(pub(crate) &'a mut T, pub(crate) ReadFn<T>);
+
+impl<T> Read for ReadWith<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (self.1)(self.0, buf)
+    }
+}
+
+impl<T> BufReaderWriter<T>
+where
+    T: Read + Write + Seek,
+{
+    /// Creates a new BufReaderWriter whose buffer is aligned to `alignment` bytes,
+    /// both in memory and in the stream, as files opened with `O_DIRECT` require
+    ///
+    /// The capacity is rounded up to a multiple of `alignment`.
+    /// Before the buffer is used at a position that is not a multiple of `alignment`,
+    /// it is filled from the start of the block containing it, so that the buffer dumps
+    /// and fills are issued at offsets and with lengths that are multiples of `alignment`.
+    /// Reads and writes larger than the capacity also go through the buffer,
+    /// unless [Self::set_bypass_threshold] is used.
+    ///
+    /// Before a buffer ending in the middle of a block is dumped, the rest of the block
+    /// is read, so the dumps cover whole blocks.
+    ///
+    /// The last block of the stream is the exception: when the stream length is not
+    /// a multiple of `alignment`, the buffer is dumped or filled up to the end of
+    /// the stream, with a length that is not a multiple of `alignment`,
+    /// and the reads finding the end of the stream are issued at its unaligned length.
+    /// With `O_DIRECT`, such a partial block is rejected by some file systems, the file
+    /// may have to be written up to a block boundary then truncated with [std::fs::File::set_len].
+    ///
+    /// # Errors
+    ///
+    /// If `capacity` is 0, as the accesses would not be aligned without a buffer,
+    /// or if `alignment` is not a power of two, with the kind
+    /// [std::io::ErrorKind::InvalidInput].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw =
+    ///     BufReaderWriter::with_aligned_capacity(Cursor::new(vec![0u8; 8192]), 6000, 4096)?;
+    /// assert_eq!(rw.capacity(), 8192);
+    /// rw.seek(SeekFrom::Start(5000))?;
+    /// // Reads the block starting at 4096 first
+    /// rw.write_all(b"data")?;
+    /// assert_eq!(&rw.into_inner()?.get_ref()[5000..5004], b"data");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_aligned_capacity(
+        inner: T,
+        capacity: usize,
+        alignment: usize,
+    ) -> std::io::Result<Self> {
+        if capacity == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "capacity must be non-zero",
+            ));
+        }
+        if !alignment.is_power_of_two() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "alignment must be a power of two",
+            ));
+        }
+        let capacity = capacity.next_multiple_of(alignment);
+        let mut buffer = Buffer::with_storage(Storage::new_aligned(capacity, alignment));
+        buffer.read_bypass = usize::MAX;
+        buffer.write_bypass = usize::MAX;
+        let mut rw = Self::from_internal_buffer(inner, buffer);
+        rw.alignment = Some(Alignment {
+            size: alignment,
+            read: |inner, buf| inner.read(buf),
+        });
+        Ok(rw)
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    /// Moves the start of a clean buffer to the start of the block containing the position,
+    /// filling it with the bytes before the position
+    ///
+    /// Does nothing when the buffer is not aligned or dirty, or if the stream ends before
+    /// the position, in which case the buffer stays unaligned.
+    pub(crate) fn align_buffer(&mut self) -> std::io::Result<()> {
+        let Some(alignment) = self.alignment else {
+            return Ok(());
+        };
+        let size = alignment.size as u64;
+        if self.buffering_paused
+            || self.buffer.is_dirty()
+            || self.start_position_in_source().is_multiple_of(size)
+        {
+            return Ok(());
+        }
+        let position = self.position();
+        let start = position - position % size;
+        self.buffer.clear();
+        self.n = 0;
+        self.pos = position;
+        if start == position {
+            return self.recover_inner_position();
+        }
+
+        self.inner_pos_lost = true;
+        self.seek_inner(SeekFrom::Start(start))?;
+        let filled = self
+            .buffer
+            .fill_all_from(ReadWith(&mut self.inner, alignment.read));
+        let before = (position - start) as usize;
+        match filled {
+            Ok(n) if n >= before => {
+                self.stats.fill(n);
+                self.buffer.pos = before;
+                self.pos = start + n as u64;
+                self.n = n;
+                self.inner_pos_lost = false;
+                Ok(())
+            }
+            // The stream ends before the position
+            Ok(_) => {
+                self.buffer.clear();
+                self.seek_back()
+            }
+            Err(e) => {
+                self.buffer.clear();
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the range of the buffer to write to dump the dirty data,
+    /// extended to whole blocks when the buffer is aligned
+    pub(crate) fn dump_range(&self) -> Range<usize> {
+        let dirty = self.buffer.dirty.clone();
+        let Some(alignment) = self.alignment else {
+            return dirty;
+        };
+        let size = alignment.size as u64;
+        let start = self.start_position_in_source();
+        let from = start + dirty.start as u64;
+        let to = start + dirty.end as u64;
+        let from = (from - from % size).max(start);
+        let to = to.next_multiple_of(size).min(start + self.buffer.filled as u64);
+        (from - start) as usize..(to - start) as usize
+    }
+
+    /// Reads the end of the last block of an aligned buffer, so that it is dumped whole
+    pub(crate) fn complete_last_block(&mut self) -> std::io::Result<()> {
+        let Some(alignment) = self.alignment else {
+            return Ok(());
+        };
+        let size = alignment.size;
+        let start = self.start_position_in_source();
+        let end = self.buffer.filled;
+        if !start.is_multiple_of(size as u64) || end.is_multiple_of(size) {
+            return Ok(());
+        }
+
+        // Reading the whole block, the reads must be aligned too
+        let block_start = end - end % size;
+        let mut block = Storage::new_aligned(size, size);
+        self.inner_pos_lost = true;
+        self.seek_inner(SeekFrom::Start(start + block_start as u64))?;
+        let mut reader = ReadWith(&mut self.inner, alignment.read);
+        let mut k = 0;
+        while k < size {
+            match reader.read(&mut block[k..]) {
+                Ok(0) => break,
+                Ok(m) if m > size - k => {
+                    return Err(std::io::Error::other(
+                        "inner stream reported reading more bytes than requested",
+                    ));
+                }
+                Ok(m) => k += m,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let filled = (block_start + k).max(end);
+        self.buffer.data[end..filled]
+            .copy_from_slice(&block[end - block_start..k.max(end - block_start)]);
+        self.stats.fill(k);
+        self.buffer.filled = filled;
+        self.pos = start + (block_start + k) as u64;
+        self.n = block_start + k;
+        self.inner_pos_lost = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use crate::counting::{CountingStream, Op};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_aligned_buffer_address() {
+        for alignment in [1, 64, 512, 4096] {
+            let rw =
+                BufReaderWriter::with_aligned_capacity(Cursor::new(Vec::new()), 1000, alignment)
+                    .unwrap();
+            assert_eq!(rw.capacity() % alignment, 0);
+            assert_eq!(rw.buffer.data.as_ptr() as usize % alignment, 0);
+            assert_eq!(rw.clone().buffer.data.as_ptr() as usize % alignment, 0);
+        }
+    }
+
+    #[test]
+    fn test_aligned_dumps_and_fills() {
+        let len = 10 * 512 + 100;
+        let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::with_aligned_capacity(
+            CountingStream::with_log(Cursor::new(data.clone())),
+            1024,
+            512,
+        )
+        .unwrap();
+        let mut expected = data.clone();
+        rw.seek(SeekFrom::Start(700)).unwrap();
+        rw.write_all(&[1; 2000]).unwrap();
+        expected[700..2700].fill(1);
+        rw.seek(SeekFrom::Start(3000)).unwrap();
+        let mut buf = [0; 1500];
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[3000..4500]);
+        rw.write_all(&[2; 10]).unwrap();
+        expected[4500..4510].fill(2);
+        rw.seek(SeekFrom::Start(len as u64 - 30)).unwrap();
+        rw.write_all(&[3; 50]).unwrap();
+        expected[len - 30..].fill(3);
+        expected.extend_from_slice(&[3; 20]);
+        rw.flush().unwrap();
+
+        // Replays the log to find the offset of each read and write,
+        // only the ones reaching the end of the stream can be partial blocks
+        let mut offset = 0;
+        let eof = data.len() as u64;
+        for &op in rw.inner().log() {
+            match op {
+                Op::Seek { position, .. } => offset = position,
+                Op::Read { read, .. } | Op::Write { written: read, .. } => {
+                    assert!(offset % 512 == 0 || offset >= eof, "{op:?} at {offset}");
+                    offset += read as u64;
+                    assert!(offset % 512 == 0 || offset >= eof, "{op:?} at {offset}");
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(offset, expected.len() as u64);
+        assert_eq!(rw.into_inner().unwrap().into_inner().into_inner(), expected);
+    }
+
+    #[test]
+    fn test_aligned_buffer_past_the_end() {
+        let mut rw =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(vec![1; 100]), 512, 512).unwrap();
+        rw.seek(SeekFrom::Start(200)).unwrap();
+        rw.write_all(b"end").unwrap();
+        let data = rw.into_inner().unwrap().into_inner();
+        assert_eq!(data.len(), 203);
+        assert_eq!(&data[200..], b"end");
+    }
+
+    #[test]
+    fn test_aligned_read_after_write() {
+        let data = (100..200u8).collect::<Vec<_>>();
+        let mut rw =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(data.clone()), 8, 8).unwrap();
+        rw.write_all(&[0; 11]).unwrap();
+        let mut buf = [0u8; 38];
+        let n = rw.read(&mut buf).unwrap();
+        // The rest of the block read to dump it is read first
+        assert_eq!(buf[..n], data[11..11 + n]);
+        assert_eq!(rw.position(), 11 + n as u64);
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[11 + n..49 + n]);
+
+        rw.seek(SeekFrom::Start(60)).unwrap();
+        rw.write_all(&[1; 3]).unwrap();
+        rw.read_exact(&mut buf[..20]).unwrap();
+        assert_eq!(buf[..20], data[63..83]);
+
+        let mut rw =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(data.clone()), 8, 8).unwrap();
+        rw.write_all(&[0; 11]).unwrap();
+        let mut offsets = Vec::new();
+        rw.rewrite_with(9, |offset, chunk| {
+            assert_eq!(chunk[0], data[offset as usize]);
+            offsets.push(offset);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(offsets[0], 11);
+    }
+
+    #[test]
+    fn test_aligned_invalid_sizes() {
+        for (capacity, alignment) in [(0, 512), (512, 0), (512, 500)] {
+            let err =
+                BufReaderWriter::with_aligned_capacity(Cursor::new(Vec::new()), capacity, alignment)
+                    .err()
+                    .unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+}
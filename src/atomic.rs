@@ -0,0 +1,284 @@
+//! Crash-safe saving of files, through a temporary file renamed over the original
+use crate::BufReaderWriter;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// A file whose modifications are only visible once [AtomicFile::finish] is called
+///
+/// Reads are done on the original file until the first write.
+/// On the first write, the original is copied into a temporary file
+/// in the same directory, and all subsequent reads and writes are done on it.
+/// [AtomicFile::finish] then syncs the temporary file and renames it over the original.
+///
+/// Dropping it without calling [AtomicFile::finish] deletes the temporary file,
+/// leaving the original untouched.
+///
+/// It is usually used through [BufReaderWriter::open_atomic].
+#[derive(Debug)]
+pub struct AtomicFile {
+    path: PathBuf,
+    // None when the file does not exist yet
+    original: Option<File>,
+    temp: Option<NamedTempFile>,
+}
+
+impl AtomicFile {
+    /// Opens the file at `path`
+    ///
+    /// The file does not have to exist, in that case it is read as empty
+    /// and is created by [AtomicFile::finish].
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let original = match File::open(&path) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            original,
+            temp: None,
+        })
+    }
+
+    /// Returns the path of the original file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the file was written to, that is, whether the temporary file exists
+    pub fn is_modified(&self) -> bool {
+        self.temp.is_some()
+    }
+
+    /// Syncs the temporary file and renames it over the original
+    ///
+    /// Does nothing if the file was never written to.
+    pub fn finish(self) -> std::io::Result<()> {
+        let Some(temp) = self.temp else {
+            return Ok(());
+        };
+        temp.as_file().sync_all()?;
+        temp.persist(&self.path).map_err(|e| e.error)?;
+
+        // Make the rename itself durable
+        #[cfg(unix)]
+        File::open(parent_dir(&self.path))?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Discards the modifications, deleting the temporary file
+    pub fn abort(self) {
+        drop(self);
+    }
+
+    /// Creates the temporary file with the content of the original file
+    fn start_modification(&mut self) -> std::io::Result<&mut NamedTempFile> {
+        if self.temp.is_none() {
+            let mut prefix = std::ffi::OsString::from(".");
+            if let Some(name) = self.path.file_name() {
+                prefix.push(name);
+            }
+            prefix.push(".");
+            let mut temp = tempfile::Builder::new()
+                .prefix(&prefix)
+                .suffix(".tmp")
+                .tempfile_in(parent_dir(&self.path))?;
+
+            if let Some(original) = self.original.as_mut() {
+                let position = original.stream_position()?;
+                original.seek(SeekFrom::Start(0))?;
+                std::io::copy(original, &mut temp)?;
+                temp.as_file()
+                    .set_permissions(original.metadata()?.permissions())?;
+                temp.seek(SeekFrom::Start(position))?;
+            }
+            self.temp = Some(temp);
+        }
+        Ok(self.temp.as_mut().unwrap())
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+impl Read for AtomicFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match (self.temp.as_mut(), self.original.as_mut()) {
+            (Some(temp), _) => temp.read(buf),
+            (None, Some(original)) => original.read(buf),
+            (None, None) => Ok(0),
+        }
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.start_modification()?.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.temp.as_mut() {
+            Some(temp) => temp.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match (self.temp.as_mut(), self.original.as_mut()) {
+            (Some(temp), _) => temp.seek(pos),
+            (None, Some(original)) => original.seek(pos),
+            (None, None) => {
+                // An empty file, only a non-negative offset from the start is valid
+                let offset = match pos {
+                    SeekFrom::Start(offset) => Some(offset),
+                    SeekFrom::End(offset) | SeekFrom::Current(offset) => u64::try_from(offset).ok(),
+                };
+                match offset {
+                    Some(0) => Ok(0),
+                    _ => {
+                        // Seeking past the end needs a file to remember the position
+                        let temp = self.start_modification()?;
+                        temp.seek(pos)
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl BufReaderWriter<AtomicFile> {
+    /// Opens the file at `path` for crash-safe modification
+    ///
+    /// Writes are done on a temporary file, that only replaces the original
+    /// when [BufReaderWriter::finish] is called, see [AtomicFile].
+    /// Dropping the adapter without calling [BufReaderWriter::finish]
+    /// discards the modifications.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = tempfile::tempdir()?;
+    /// let path = dir.path().join("config.ini");
+    /// std::fs::write(&path, "level=1\n")?;
+    ///
+    /// let mut rw = BufReaderWriter::open_atomic(&path)?;
+    /// rw.seek(SeekFrom::Start(6))?;
+    /// rw.write_all(b"2")?;
+    /// assert_eq!(std::fs::read(&path)?, b"level=1\n");
+    ///
+    /// rw.finish()?;
+    /// assert_eq!(std::fs::read(&path)?, b"level=2\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_atomic(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        AtomicFile::open(path).map(Self::new)
+    }
+
+    /// Writes the buffered data, then replaces the original file, see [AtomicFile::finish]
+    pub fn finish(self) -> std::io::Result<()> {
+        self.into_inner()?.finish()
+    }
+
+    /// Discards the buffered data and the modifications, leaving the original file untouched
+    pub fn abort(self) {
+        let (inner, _) = self.into_raw_parts();
+        inner.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut entries = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn test_atomic_drop_without_finish_keeps_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.txt");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let mut rw = BufReaderWriter::with_capacity(super::AtomicFile::open(&path).unwrap(), 4);
+        rw.seek(SeekFrom::Start(2)).unwrap();
+        rw.write_all(b"abcdefgh").unwrap();
+        rw.flush().unwrap();
+        assert!(rw.inner().is_modified());
+        assert_eq!(dir_entries(dir.path()).len(), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+
+        drop(rw);
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        assert_eq!(dir_entries(dir.path()), ["data.txt"]);
+
+        let mut rw = BufReaderWriter::open_atomic(&path).unwrap();
+        rw.write_all(b"abc").unwrap();
+        rw.abort();
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        assert_eq!(dir_entries(dir.path()), ["data.txt"]);
+    }
+
+    #[test]
+    fn test_atomic_finish_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.txt");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let mut rw = BufReaderWriter::with_capacity(super::AtomicFile::open(&path).unwrap(), 64);
+        let mut bytes = [0u8; 3];
+        rw.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"012");
+        assert!(!rw.inner().is_modified());
+
+        rw.write_all(b"abcdefghijkl").unwrap();
+        rw.seek(SeekFrom::Start(0)).unwrap();
+        let mut content = Vec::new();
+        rw.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"012abcdefghijkl");
+
+        rw.finish().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"012abcdefghijkl");
+        assert_eq!(dir_entries(dir.path()), ["data.txt"]);
+    }
+
+    #[test]
+    fn test_atomic_without_write_or_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.txt");
+
+        let mut rw = BufReaderWriter::open_atomic(&path).unwrap();
+        let mut content = Vec::new();
+        rw.read_to_end(&mut content).unwrap();
+        assert!(content.is_empty());
+        rw.finish().unwrap();
+        assert!(!path.exists());
+
+        let mut rw = BufReaderWriter::open_atomic(&path).unwrap();
+        rw.write_all(b"new file").unwrap();
+        rw.finish().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new file");
+    }
+}
@@ -0,0 +1,251 @@
+//! Configuring a BufReaderWriter before creating it
+use crate::{BufReaderWriter, RetryPolicy};
+use std::io::{Seek, Write};
+
+/// Builder of [BufReaderWriter]s, for when the constructors are not enough
+///
+/// Like [std::fs::OpenOptions], the options are set through `&mut self`,
+/// and the same builder can build several adapters.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::BufReaderWriterBuilder;
+/// use std::io::{Cursor, Write};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let mut rw = BufReaderWriterBuilder::new()
+///     .capacity(64 * 1024)
+///     .flush_inner_on_drop(false)
+///     .build(Cursor::new(Vec::new()))?;
+/// assert_eq!(rw.capacity(), 64 * 1024);
+/// rw.write_all(b"data")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct BufReaderWriterBuilder {
+    capacity: Option<usize>,
+    buffer: Option<Box<[u8]>>,
+    flush_on_drop: Option<bool>,
+    flush_inner_on_drop: Option<bool>,
+    retry: Option<RetryPolicy>,
+    write_through: Option<bool>,
+}
+
+impl BufReaderWriterBuilder {
+    /// Creates a builder with the default options, the ones of [BufReaderWriter::new]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the capacity of the internal buffer, the [crate::default_capacity] by default
+    pub fn capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Uses `buffer` as the internal buffer, like [BufReaderWriter::with_buffer]
+    ///
+    /// Only the first adapter built uses it, the following ones allocate
+    /// a buffer of the same capacity.
+    pub fn buffer(&mut self, buffer: Box<[u8]>) -> &mut Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Sets whether the dirty data is written when the adapter is dropped,
+    /// see [BufReaderWriter::set_flush_on_drop]
+    pub fn flush_on_drop(&mut self, flush: bool) -> &mut Self {
+        self.flush_on_drop = Some(flush);
+        self
+    }
+
+    /// Sets whether the inner stream is flushed when the adapter is dropped,
+    /// see [BufReaderWriter::set_flush_inner_on_drop]
+    pub fn flush_inner_on_drop(&mut self, flush: bool) -> &mut Self {
+        self.flush_inner_on_drop = Some(flush);
+        self
+    }
+
+    /// Sets the policy to retry transient errors, see [BufReaderWriter::set_retry_policy]
+    pub fn retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Sets whether the writes go to the inner stream immediately,
+    /// see [BufReaderWriter::set_write_through]
+    pub fn write_through(&mut self, enabled: bool) -> &mut Self {
+        self.write_through = Some(enabled);
+        self
+    }
+
+    /// Creates an adapter over `inner` with the options set
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if both a capacity
+    /// and a buffer were given, or if the capacity or the buffer is empty.
+    pub fn build<T: Write + Seek>(&mut self, inner: T) -> std::io::Result<BufReaderWriter<T>> {
+        let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        let mut rw = match (self.capacity, self.buffer.take()) {
+            (Some(_), Some(buffer)) => {
+                self.buffer = Some(buffer);
+                return Err(invalid("both a capacity and a buffer were given"));
+            }
+            (Some(0), None) => return Err(invalid("the capacity must be non-zero")),
+            (Some(capacity), None) => BufReaderWriter::with_capacity(inner, capacity),
+            (None, Some(buffer)) if buffer.is_empty() => {
+                self.buffer = Some(buffer);
+                return Err(invalid("the buffer must not be empty"));
+            }
+            (None, Some(buffer)) => {
+                // The next adapters get a buffer of the same capacity
+                self.capacity = Some(buffer.len());
+                BufReaderWriter::with_buffer(inner, buffer)
+            }
+            (None, None) => BufReaderWriter::new(inner),
+        };
+        if let Some(flush) = self.flush_on_drop {
+            rw.set_flush_on_drop(flush);
+        }
+        if let Some(flush) = self.flush_inner_on_drop {
+            rw.set_flush_inner_on_drop(flush);
+        }
+        rw.set_retry_policy(self.retry);
+        if let Some(enabled) = self.write_through {
+            rw.set_write_through(enabled)?;
+        }
+        Ok(rw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufReaderWriterBuilder;
+    use crate::RetryPolicy;
+    use crate::counting::CountingStream;
+    use std::io::{Cursor, ErrorKind, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn test_builder_capacity_and_buffer() {
+        let mut builder = BufReaderWriterBuilder::new();
+        builder.capacity(4);
+        for _ in 0..2 {
+            let mut rw = builder
+                .build(CountingStream::new(Cursor::new(Vec::new())))
+                .unwrap();
+            rw.write_all(b"abc").unwrap();
+            assert_eq!(rw.inner().writes(), 0);
+            rw.write_all(b"de").unwrap();
+            assert_eq!(rw.inner().writes(), 1);
+        }
+
+        let buffer = vec![0u8; 8].into_boxed_slice();
+        let address = buffer.as_ptr();
+        let mut builder = BufReaderWriterBuilder::new();
+        builder.buffer(buffer);
+        let rw = builder.build(Cursor::new(Vec::new())).unwrap();
+        assert_eq!(rw.into_parts().unwrap().1.as_ptr(), address);
+        let rw = builder.build(Cursor::new(Vec::new())).unwrap();
+        assert_eq!(rw.capacity(), 8);
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_combinations() {
+        let err = BufReaderWriterBuilder::new()
+            .capacity(16)
+            .buffer(vec![0u8; 16].into_boxed_slice())
+            .build(Cursor::new(Vec::new()))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let err = BufReaderWriterBuilder::new()
+            .capacity(0)
+            .build(Cursor::new(Vec::new()))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let mut builder = BufReaderWriterBuilder::new();
+        builder.buffer(Box::new([]));
+        for _ in 0..2 {
+            let err = builder.build(Cursor::new(Vec::new())).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_builder_drop_options() {
+        let mut data = Cursor::new(Vec::new());
+        let mut rw = BufReaderWriterBuilder::new()
+            .flush_on_drop(false)
+            .build(&mut data)
+            .unwrap();
+        rw.write_all(b"lost").unwrap();
+        drop(rw);
+        assert!(data.get_ref().is_empty());
+
+        let mut rw = BufReaderWriterBuilder::new()
+            .flush_inner_on_drop(false)
+            .build(CountingStream::new(&mut data))
+            .unwrap();
+        rw.write_all(b"kept").unwrap();
+        rw.flush().unwrap();
+        let flushes = rw.inner().flushes();
+        rw.write_all(b"!").unwrap();
+        let inner = rw.into_inner().unwrap();
+        assert_eq!(inner.flushes(), flushes);
+        assert_eq!(data.get_ref(), b"kept!");
+    }
+
+    #[test]
+    fn test_builder_retry_policy() {
+        struct Flaky(u32, Cursor<Vec<u8>>);
+
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0 != 0 {
+                    self.0 -= 1;
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                self.1.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl std::io::Seek for Flaky {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.1.seek(pos)
+            }
+        }
+
+        let mut rw = BufReaderWriterBuilder::new()
+            .retry_policy(RetryPolicy {
+                kinds: &[ErrorKind::TimedOut],
+                max_attempts: 3,
+                backoff: Duration::ZERO,
+                ..RetryPolicy::default()
+            })
+            .build(Flaky(2, Cursor::new(Vec::new())))
+            .unwrap();
+        rw.write_all(b"data").unwrap();
+        rw.flush().unwrap();
+        assert_eq!(rw.inner().1.get_ref(), b"data");
+    }
+
+    #[test]
+    fn test_builder_write_through() {
+        let mut rw = BufReaderWriterBuilder::new()
+            .write_through(true)
+            .build(Cursor::new(Vec::new()))
+            .unwrap();
+        assert!(rw.is_write_through());
+        rw.write_all(b"data").unwrap();
+        assert_eq!(rw.inner().get_ref(), b"data");
+    }
+}
@@ -0,0 +1,146 @@
+//! Streaming transformation of a source stream into a destination stream
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+
+/// Progress of a [BufCopyEditor]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyProgress {
+    /// Position in the source stream
+    pub source_position: u64,
+    /// Position in the destination stream
+    pub dest_position: u64,
+    /// Number of bytes copied from the source to the destination
+    pub bytes_copied: u64,
+    /// Number of bytes of the source that were skipped
+    pub bytes_skipped: u64,
+}
+
+/// Buffered reading of a source stream and writing of a destination stream,
+/// to transform one into the other
+///
+/// Both sides are buffered with the same capacity. Bytes can be read from the source
+/// to be transformed ([Self::read_record]), written to the destination ([Self::write_record]),
+/// passed through unchanged ([Self::copy]) or dropped ([Self::skip]).
+///
+/// [Self::finish] must be called to make sure the destination is complete,
+/// dropping the editor writes the buffered data but ignores errors.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::BufCopyEditor;
+/// use std::io::Cursor;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let source = Cursor::new(b"id=1;name=old;".to_vec());
+/// let mut editor = BufCopyEditor::new(source, Cursor::new(Vec::new()))?;
+/// editor.copy(10)?;
+/// editor.skip(3)?;
+/// editor.write_record(b"new")?;
+/// editor.copy(1)?;
+///
+/// let (_, dest) = editor.finish()?;
+/// assert_eq!(dest.into_inner(), b"id=1;name=new;");
+/// # Ok(())
+/// # }
+/// ```
+pub struct BufCopyEditor<R, W>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    source: BufReader<R>,
+    dest: BufWriter<W>,
+    progress: CopyProgress,
+}
+
+impl<R, W> BufCopyEditor<R, W>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    /// Creates a new editor, with buffers of the [default_capacity](crate::default_capacity)
+    ///
+    /// Both streams are used from their current position.
+    pub fn new(source: R, dest: W) -> std::io::Result<Self> {
+        Self::with_capacity(source, dest, crate::default_capacity())
+    }
+
+    /// Creates a new editor, with buffers of the given capacity
+    ///
+    /// Both streams are used from their current position.
+    pub fn with_capacity(mut source: R, mut dest: W, capacity: usize) -> std::io::Result<Self> {
+        let progress = CopyProgress {
+            source_position: source.stream_position()?,
+            dest_position: dest.stream_position()?,
+            ..Default::default()
+        };
+        Ok(Self {
+            source: BufReader::with_capacity(capacity, source),
+            dest: BufWriter::with_capacity(capacity, dest),
+            progress,
+        })
+    }
+
+    /// Returns the progress so far
+    pub fn progress(&self) -> CopyProgress {
+        self.progress
+    }
+
+    /// Reads exactly `buf.len()` bytes from the source
+    pub fn read_record(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.source.read_exact(buf)?;
+        self.progress.source_position += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Writes all of `buf` to the destination
+    pub fn write_record(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.dest.write_all(buf)?;
+        self.progress.dest_position += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Copies `len` bytes from the source to the destination
+    ///
+    /// Returns the number of bytes copied, which is less than `len`
+    /// only if the end of the source was reached.
+    pub fn copy(&mut self, len: u64) -> std::io::Result<u64> {
+        let mut copied = 0;
+        while copied < len {
+            let available = self.source.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let n = available.len().min((len - copied) as usize);
+            self.dest.write_all(&available[..n])?;
+            self.source.consume(n);
+            copied += n as u64;
+        }
+        self.progress.source_position += copied;
+        self.progress.dest_position += copied;
+        self.progress.bytes_copied += copied;
+        Ok(copied)
+    }
+
+    /// Skips `len` bytes of the source
+    ///
+    /// The buffered source data is kept when possible.
+    pub fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        let offset = i64::try_from(len).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "skip length too large")
+        })?;
+        self.source.seek_relative(offset)?;
+        self.progress.source_position += len;
+        self.progress.bytes_skipped += len;
+        Ok(())
+    }
+
+    /// Writes the buffered data to the destination, flushes it,
+    /// then returns the source and the destination streams
+    pub fn finish(self) -> std::io::Result<(R, W)> {
+        let source = self.source.into_inner();
+        let mut dest = self.dest.into_inner().map_err(|e| e.into_error())?;
+        dest.flush()?;
+        Ok((source, dest))
+    }
+}
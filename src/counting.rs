@@ -0,0 +1,243 @@
+//! Stream wrapper counting the calls made to it, to test the buffering behavior
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// A call made to a [CountingStream], as recorded in its log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// A read, with the size of the destination and the number of bytes read
+    Read { requested: usize, read: usize },
+    /// A write, with the size of the source and the number of bytes written
+    Write { requested: usize, written: usize },
+    /// A seek, with its argument and the resulting position
+    Seek { from: SeekFrom, position: u64 },
+    /// A flush
+    Flush,
+}
+
+/// Stream wrapper counting the calls made to it, and the bytes they transferred
+///
+/// Meant to turn the claims about the buffering (e.g. "a seek within the buffer
+/// does not reach the inner stream") into assertions.
+/// Failed calls are counted too, but transfer no bytes.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::{BufReaderWriter, CountingStream};
+/// use std::io::{Cursor, Read, Seek, SeekFrom};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let inner = CountingStream::new(Cursor::new(vec![0u8; 100]));
+/// let mut rw = BufReaderWriter::with_capacity(inner, 64);
+/// let mut bytes = [0u8; 10];
+/// rw.read_exact(&mut bytes)?;
+/// rw.seek(SeekFrom::Start(50))?;
+/// rw.read_exact(&mut bytes)?;
+///
+/// assert_eq!(rw.inner().reads(), 1);
+/// assert_eq!(rw.inner().seeks(), 0);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct CountingStream<T> {
+    inner: T,
+    reads: usize,
+    writes: usize,
+    seeks: usize,
+    flushes: usize,
+    bytes_read: u64,
+    bytes_written: u64,
+    log: Option<Vec<Op>>,
+}
+
+impl<T> CountingStream<T> {
+    /// Wraps `inner`, without logging the calls
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            reads: 0,
+            writes: 0,
+            seeks: 0,
+            flushes: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            log: None,
+        }
+    }
+
+    /// Wraps `inner`, logging each call with its arguments, see [CountingStream::log]
+    pub fn with_log(inner: T) -> Self {
+        Self {
+            log: Some(Vec::new()),
+            ..Self::new(inner)
+        }
+    }
+
+    /// Returns the number of calls to `read`
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// Returns the number of calls to `write`
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Returns the number of calls to `seek`
+    pub fn seeks(&self) -> usize {
+        self.seeks
+    }
+
+    /// Returns the number of calls to `flush`
+    pub fn flushes(&self) -> usize {
+        self.flushes
+    }
+
+    /// Returns the total number of bytes read
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Returns the total number of bytes written
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Returns the calls made since the creation or the last [CountingStream::reset],
+    /// empty if the stream was not created with [CountingStream::with_log]
+    pub fn log(&self) -> &[Op] {
+        self.log.as_deref().unwrap_or_default()
+    }
+
+    /// Sets all the counters back to 0 and clears the log
+    pub fn reset(&mut self) {
+        self.reads = 0;
+        self.writes = 0;
+        self.seeks = 0;
+        self.flushes = 0;
+        self.bytes_read = 0;
+        self.bytes_written = 0;
+        if let Some(log) = &mut self.log {
+            log.clear();
+        }
+    }
+
+    /// Gets a reference to the wrapped stream
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the wrapped stream, calls made through it are not counted
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwraps the stream
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, op: Op) {
+        if let Some(log) = &mut self.log {
+            log.push(op);
+        }
+    }
+}
+
+impl<T: Read> Read for CountingStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        let result = self.inner.read(buf);
+        let read = *result.as_ref().unwrap_or(&0);
+        self.bytes_read += read as u64;
+        self.record(Op::Read {
+            requested: buf.len(),
+            read,
+        });
+        result
+    }
+}
+
+impl<T: Write> Write for CountingStream<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes += 1;
+        let result = self.inner.write(buf);
+        let written = *result.as_ref().unwrap_or(&0);
+        self.bytes_written += written as u64;
+        self.record(Op::Write {
+            requested: buf.len(),
+            written,
+        });
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushes += 1;
+        self.record(Op::Flush);
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for CountingStream<T> {
+    fn seek(&mut self, from: SeekFrom) -> std::io::Result<u64> {
+        self.seeks += 1;
+        let result = self.inner.seek(from);
+        if let Ok(position) = result {
+            self.record(Op::Seek { from, position });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CountingStream, Op};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_counting_stream_counts_and_logs() {
+        let mut stream = CountingStream::with_log(Cursor::new(b"0123456789".to_vec()));
+        let mut bytes = [0u8; 4];
+        stream.read_exact(&mut bytes).unwrap();
+        stream.seek(SeekFrom::Start(8)).unwrap();
+        assert_eq!(stream.read(&mut bytes).unwrap(), 2);
+        stream.write_all(b"ab").unwrap();
+        stream.flush().unwrap();
+
+        assert_eq!(stream.reads(), 2);
+        assert_eq!(stream.bytes_read(), 6);
+        assert_eq!(stream.writes(), 1);
+        assert_eq!(stream.bytes_written(), 2);
+        assert_eq!(stream.seeks(), 1);
+        assert_eq!(stream.flushes(), 1);
+        assert_eq!(
+            stream.log(),
+            [
+                Op::Read {
+                    requested: 4,
+                    read: 4
+                },
+                Op::Seek {
+                    from: SeekFrom::Start(8),
+                    position: 8
+                },
+                Op::Read {
+                    requested: 4,
+                    read: 2
+                },
+                Op::Write {
+                    requested: 2,
+                    written: 2
+                },
+                Op::Flush,
+            ]
+        );
+
+        stream.reset();
+        assert_eq!(stream.reads(), 0);
+        assert_eq!(stream.bytes_written(), 0);
+        assert!(stream.log().is_empty());
+        assert_eq!(stream.into_inner().into_inner(), b"0123456789ab");
+    }
+}
@@ -0,0 +1,150 @@
+//! Comparison of the cached data with the inner stream, for debugging
+use crate::BufReaderWriter;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// A range of the stream where the cached data differs from the inner stream,
+/// as returned by [BufReaderWriter::diff_against_inner]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DivergentRange {
+    /// The positions in the stream
+    pub range: Range<u64>,
+    /// Whether the cached data holds writes not yet sent to the inner stream,
+    /// in which case the difference is expected
+    pub dirty: bool,
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Compares the cached data with what the inner stream holds at the same positions
+    ///
+    /// Returns the ranges where they differ, including the cached bytes that are
+    /// past the end of the inner stream. Ranges in dirty data are expected to differ
+    /// and are marked as such, any other range means that the inner stream was
+    /// modified behind the adapter's back.
+    ///
+    /// This is a diagnostic: the inner stream is read again,
+    /// but the position and the cached data are left untouched.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Read;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// let mut hello = [0u8; 5];
+    /// rw.read_exact(&mut hello)?;
+    /// assert!(rw.diff_against_inner()?.is_empty());
+    ///
+    /// rw.inner_mut().get_mut()[7] = b'0';
+    /// let diff = rw.diff_against_inner()?;
+    /// assert_eq!(diff[0].range, 7..8);
+    /// assert!(!diff[0].dirty);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff_against_inner(&mut self) -> std::io::Result<Vec<DivergentRange>> {
+        let start = self.start_position_in_source();
+        let dirty = self.buffer.is_dirty();
+        let len = self.buffer.num_valid_bytes();
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut on_inner = Vec::with_capacity(len);
+        self.recover_inner_position()?;
+        self.inner_pos_lost = true;
+        self.seek_inner(SeekFrom::Start(start))?;
+        let result = (&mut self.inner)
+            .take(len as u64)
+            .read_to_end(&mut on_inner);
+        self.stats.direct_read(on_inner.len());
+        self.seek_back()?;
+        result?;
+        let cached = &self.buffer.data[..len];
+
+        let mut ranges = Vec::new();
+        let mut divergence_start = None;
+        for (i, byte) in cached.iter().enumerate() {
+            let differs = on_inner.get(i) != Some(byte);
+            match (differs, divergence_start) {
+                (true, None) => divergence_start = Some(i),
+                (false, Some(s)) => {
+                    ranges.push(DivergentRange {
+                        range: start + s as u64..start + i as u64,
+                        dirty,
+                    });
+                    divergence_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = divergence_start {
+            ranges.push(DivergentRange {
+                range: start + s as u64..start + cached.len() as u64,
+                dirty,
+            });
+        }
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DivergentRange;
+    use crate::BufReaderWriter;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_diff_against_inner_reports_corruption() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data), 32);
+        buf.seek(SeekFrom::Start(40)).unwrap();
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert!(buf.diff_against_inner().unwrap().is_empty());
+
+        // Corrupt 50..53 and 80, only the first is in the cache (40..72)
+        buf.inner_mut().get_mut()[50..53].fill(0xFF);
+        buf.inner_mut().get_mut()[80] = 0xFF;
+        let stats = buf.stats();
+        assert_eq!(
+            buf.diff_against_inner().unwrap(),
+            [DivergentRange {
+                range: 50..53,
+                dirty: false
+            }]
+        );
+        assert_eq!(buf.stats().seeks, stats.seeks + 2);
+
+        // Nothing changed for the adapter
+        assert_eq!(buf.position(), 44);
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [44, 45, 46, 47]);
+        buf.seek(SeekFrom::Start(50)).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [50, 51, 52, 53]);
+    }
+
+    #[test]
+    fn test_diff_against_inner_reports_dirty_data() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 32);
+        buf.seek(SeekFrom::Start(8)).unwrap();
+        buf.write_all(b"abcd").unwrap();
+        assert!(buf.has_dirty_data());
+        assert_eq!(
+            buf.diff_against_inner().unwrap(),
+            [DivergentRange {
+                range: 8..12,
+                dirty: true
+            }]
+        );
+        assert!(buf.has_dirty_data());
+        assert_eq!(buf.position(), 12);
+        assert_eq!(buf.into_inner().unwrap().into_inner(), b"01234567abcd");
+    }
+}
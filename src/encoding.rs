@@ -0,0 +1,203 @@
+//! Decoding of text in legacy encodings, through the internal buffer
+use crate::BufReaderWriter;
+use encoding_rs::{CoderResult, Decoder, Encoding};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+/// Decodes `bytes` and appends them to `out`
+fn decode_to_string(decoder: &mut Decoder, mut bytes: &[u8], out: &mut String, last: bool) {
+    loop {
+        let needed = decoder
+            .max_utf8_buffer_length(bytes.len())
+            .unwrap_or(bytes.len());
+        out.reserve(needed);
+        let (result, read, _) = decoder.decode_to_string(bytes, out, last);
+        bytes = &bytes[read..];
+        if result == CoderResult::InputEmpty {
+            return;
+        }
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Reads bytes until a newline (the `0xA` byte) is reached,
+    /// decodes them with the `encoding` and appends them to `out`
+    ///
+    /// Malformed sequences are replaced with the replacement character.
+    ///
+    /// Returns the number of bytes read (not the number of bytes appended to `out`),
+    /// so that the position of the line in the stream is known, for it to be rewritten.
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if the encoding
+    /// is not ASCII compatible, as the newline could not be found byte-wise (e.g. UTF-16).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"caf\xE9\nna\xEFve\n".to_vec());
+    /// let mut line = String::new();
+    /// let n = rw.read_line_encoded(encoding_rs::WINDOWS_1252, &mut line)?;
+    /// assert_eq!((n, line.as_str()), (5, "café\n"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_line_encoded(
+        &mut self,
+        encoding: &'static Encoding,
+        out: &mut String,
+    ) -> std::io::Result<usize> {
+        if !encoding.is_ascii_compatible() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "lines can only be read with an ASCII compatible encoding",
+            ));
+        }
+
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut read = 0;
+        loop {
+            let available = self.fill_buf()?;
+            let (len, done) = match available.iter().position(|b| *b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (available.len(), available.is_empty()),
+            };
+            decode_to_string(&mut decoder, &available[..len], out, done);
+            self.consume(len);
+            read += len;
+            if done {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Decodes the `len` bytes at `position` with the `encoding`
+    ///
+    /// Malformed sequences are replaced with the replacement character.
+    /// Data written but not yet flushed is observed.
+    ///
+    /// The position is restored afterward.
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::UnexpectedEof] if the range
+    /// goes past the end of the stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"id=1;name=\x93\xfa\x96\x7b".to_vec());
+    /// assert_eq!(rw.decode_range(10, 4, encoding_rs::SHIFT_JIS)?, "日本");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn decode_range(
+        &mut self,
+        position: u64,
+        len: u64,
+        encoding: &'static Encoding,
+    ) -> std::io::Result<String> {
+        let saved_position = self.position();
+        self.seek(SeekFrom::Start(position))?;
+
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut out = String::new();
+        let mut remaining = len;
+        let result = loop {
+            if remaining == 0 {
+                decode_to_string(&mut decoder, &[], &mut out, true);
+                break Ok(out);
+            }
+            let available = match self.fill_buf() {
+                Ok(available) => available,
+                Err(e) => break Err(e),
+            };
+            if available.is_empty() {
+                break Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let n = available.len().min(remaining as usize);
+            decode_to_string(&mut decoder, &available[..n], &mut out, false);
+            self.consume(n);
+            remaining -= n as u64;
+        };
+
+        self.seek(SeekFrom::Start(saved_position))?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_read_line_with_sequence_split_at_buffer_boundary() {
+        // 日 is 0x93 0xFA, and is split by the end of the first buffer fill
+        let data = b"abcdefg\x93\xfa\x96\x7b\nxyz".to_vec();
+        let mut rw = BufReaderWriter::with_capacity(std::io::Cursor::new(data), 8);
+
+        let mut line = String::new();
+        let n = rw
+            .read_line_encoded(encoding_rs::SHIFT_JIS, &mut line)
+            .unwrap();
+        assert_eq!(line, "abcdefg日本\n");
+        assert_eq!(n, 12);
+        assert_eq!(rw.position(), 12);
+
+        // The last line has no newline
+        line.clear();
+        let n = rw
+            .read_line_encoded(encoding_rs::SHIFT_JIS, &mut line)
+            .unwrap();
+        assert_eq!((n, line.as_str()), (3, "xyz"));
+        line.clear();
+        let n = rw
+            .read_line_encoded(encoding_rs::SHIFT_JIS, &mut line)
+            .unwrap();
+        assert_eq!((n, line.as_str()), (0, ""));
+
+        // A truncated sequence at the end is malformed
+        let mut rw = BufReaderWriter::with_capacity(std::io::Cursor::new(b"ab\x93".to_vec()), 2);
+        line.clear();
+        rw.read_line_encoded(encoding_rs::SHIFT_JIS, &mut line)
+            .unwrap();
+        assert_eq!(line, "ab\u{FFFD}");
+
+        let err = rw
+            .read_line_encoded(encoding_rs::UTF_16LE, &mut line)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_decode_range() {
+        let mut rw = BufReaderWriter::with_capacity(std::io::Cursor::new(vec![b'_'; 32]), 8);
+        rw.seek(SeekFrom::Start(6)).unwrap();
+        // Not flushed, and split between two buffer fills
+        rw.write_all(b"\x80\x93\xfa\x96\x7b").unwrap();
+
+        assert_eq!(
+            rw.decode_range(6, 1, encoding_rs::WINDOWS_1252).unwrap(),
+            "€"
+        );
+        assert_eq!(
+            rw.decode_range(7, 4, encoding_rs::SHIFT_JIS).unwrap(),
+            "日本"
+        );
+        assert_eq!(
+            rw.decode_range(7, 3, encoding_rs::SHIFT_JIS).unwrap(),
+            "日\u{FFFD}"
+        );
+        assert_eq!(rw.position(), 11);
+
+        let err = rw.decode_range(30, 3, encoding_rs::SHIFT_JIS).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(rw.position(), 11);
+    }
+}
@@ -0,0 +1,96 @@
+//! Preferred buffer capacities of inner streams
+
+/// Trait for inner streams that know which buffer capacity suits them
+///
+/// Used by [BufReaderWriter::auto](crate::BufReaderWriter::auto).
+pub trait CapacityHint {
+    /// Returns the preferred capacity of a buffer over `self`,
+    /// `None` when there is no preference
+    fn preferred_buffer_capacity(&self) -> Option<usize>;
+}
+
+/// The block size of the filesystem, on Unix
+impl CapacityHint for std::fs::File {
+    fn preferred_buffer_capacity(&self) -> Option<usize> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let block_size = self.metadata().ok()?.blksize();
+            usize::try_from(block_size).ok().filter(|size| *size != 0)
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+}
+
+/// In-memory data does not benefit from large buffers
+impl<T> CapacityHint for std::io::Cursor<T> {
+    fn preferred_buffer_capacity(&self) -> Option<usize> {
+        Some(512)
+    }
+}
+
+impl<T> CapacityHint for &mut T
+where
+    T: CapacityHint + ?Sized,
+{
+    fn preferred_buffer_capacity(&self) -> Option<usize> {
+        (**self).preferred_buffer_capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CapacityHint;
+    use crate::BufReaderWriter;
+    use std::io::Cursor;
+
+    struct NoPreference(Cursor<Vec<u8>>);
+
+    impl CapacityHint for NoPreference {
+        fn preferred_buffer_capacity(&self) -> Option<usize> {
+            None
+        }
+    }
+
+    impl std::io::Write for NoPreference {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for NoPreference {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_chosen_capacities() {
+        assert_eq!(BufReaderWriter::auto(Cursor::new(vec![])).capacity(), 512);
+        let mut cursor = Cursor::new(vec![]);
+        assert_eq!(BufReaderWriter::auto(&mut cursor).capacity(), 512);
+
+        let rw = BufReaderWriter::auto(NoPreference(Cursor::new(vec![])));
+        assert_eq!(rw.capacity(), crate::default_capacity());
+
+        let path = std::env::temp_dir().join(format!("bufrw-hint-{}.bin", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let expected = file
+            .preferred_buffer_capacity()
+            .unwrap_or(crate::default_capacity());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(expected as u64, file.metadata().unwrap().blksize());
+        }
+        assert_eq!(BufReaderWriter::auto(file).capacity(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -8,11 +8,10 @@
 //!
 //! ```rust
 //! use bufrw::BufReaderWriter;
-//! use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+//! use std::io::{Read, Seek, SeekFrom, Write};
 //!
 //! # fn main() -> std::io::Result<()> {
-//! let inner = Cursor::new(String::from("Hello _____").into_bytes());
-//! let mut rw = BufReaderWriter::new(inner);
+//! let mut rw = BufReaderWriter::from_vec(b"Hello _____".to_vec());
 //!
 //! let mut s = String::new();
 //! rw.read_to_string(&mut s)?;
@@ -27,36 +26,201 @@
 //! rw.read_to_string(&mut s)?;
 //! assert_eq!(s, "Hello World");
 //!
-//! let inner = rw.into_inner()?;
-//! let underlying_bytes = inner.into_inner();
+//! let underlying_bytes = rw.into_vec()?;
 //! assert_eq!(underlying_bytes.as_slice(), "Hello World".as_bytes());
 //!
 //! # Ok::<_, std::io::Error>(())
 //! # }
 //! ```
-use std::io::{Read, Seek, SeekFrom, Write};
+//!
+//! # Cargo features
+//!
+//! * `alias-detection`: enables `BufReaderWriter::enable_alias_detection`,
+//!   a debug facility that panics when two adapters buffer overlapping writes to the same file.
+//! * `encoding`: enables `BufReaderWriter::read_line_encoded` and `BufReaderWriter::decode_range`,
+//!   decoding text in legacy encodings with [encoding_rs](https://docs.rs/encoding_rs).
+//! * `fs`: enables `BufReaderWriter::open_atomic` and `AtomicFile`,
+//!   crash-safe saving of files through a temporary file renamed over the original,
+//!   and `BufReaderWriter::spooled`, editing non-seekable inputs spooled to memory or a temporary file.
+//! * `test-util`: enables `CountingStream`, a stream wrapper counting the calls made to it,
+//!   to assert how often the inner stream is reached, and `BufReaderWriter::diff_against_inner`,
+//!   to check whether the cached data agrees with the inner stream, and `RecordingStream`
+//!   and `ReplayStream`, to record the calls made to a stream and replay them without it.
+//! * `notify`: enables `BufReaderWriter::watch_for_external_changes`, dropping the cached data
+//!   when the file is modified by another handle or process, using [notify](https://docs.rs/notify).
+//! * `nightly`: implements `Read::read_buf` and `Read::read_buf_exact`, reading into
+//!   uninitialized buffers without zeroing them first. Requires a nightly compiler.
+//! * `mmap-write`: enables `MmapFile`, reading and writing a file through a writable memory map
+//!   with [memmap2](https://docs.rs/memmap2).
+//! * `rayon`: enables `BufReaderWriter::par_process_chunks`, transforming chunks of the data
+//!   in parallel with [rayon](https://docs.rs/rayon).
+//! * `serde`: derives `Serialize` and `Deserialize` for [ResumeState] and [Stats].
+#![cfg_attr(feature = "nightly", feature(read_buf, core_io_borrowed_buf))]
+use std::io::{BufRead, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod aligned;
+#[cfg(feature = "alias-detection")]
+mod alias;
+#[cfg(feature = "fs")]
+mod atomic;
+mod builder;
+mod copy_editor;
+#[cfg(any(test, feature = "test-util"))]
+#[cfg_attr(not(feature = "test-util"), allow(dead_code))]
+mod counting;
+#[cfg(feature = "test-util")]
+mod diff;
+#[cfg(feature = "encoding")]
+mod encoding;
+mod hint;
+mod lines;
+#[cfg(feature = "mmap-write")]
+mod mmap;
+#[cfg(feature = "rayon")]
+mod par;
+mod records;
+#[cfg(feature = "test-util")]
+mod replay;
+mod retry;
+mod shared;
+#[cfg(feature = "fs")]
+mod spool;
+mod stats;
+mod storage;
+mod undo;
+#[cfg(feature = "notify")]
+mod watch;
+
+#[cfg(feature = "fs")]
+pub use atomic::AtomicFile;
+pub use builder::BufReaderWriterBuilder;
+pub use copy_editor::{BufCopyEditor, CopyProgress};
+#[cfg(feature = "test-util")]
+pub use counting::{CountingStream, Op};
+#[cfg(feature = "test-util")]
+pub use diff::DivergentRange;
+pub use hint::CapacityHint;
+pub use lines::LineIndex;
+#[cfg(feature = "mmap-write")]
+pub use mmap::MmapFile;
+pub use records::RevRecords;
+#[cfg(feature = "test-util")]
+pub use replay::{RecordingStream, ReplayStream};
+pub use retry::{RetriesExhausted, RetryPolicy};
+pub use shared::SharedReader;
+pub use stats::{Stats, StatsHandle};
+pub use undo::restore;
+#[cfg(feature = "notify")]
+pub use watch::{StaleCache, StalePolicy};
+
+/// Wraps the inner stream of `$rw` so that its reads and writes follow the retry policy
+macro_rules! retrying {
+    ($rw:ident) => {
+        retry::Retrying {
+            inner: &mut $rw.inner,
+            policy: $rw.retry.as_ref(),
+        }
+    };
+}
+
+static DEFAULT_CAPACITY: AtomicUsize = AtomicUsize::new(8192);
+
+/// Returns the capacity used by [BufReaderWriter::new]
+///
+/// It is `8KiB` (8192 bytes), unless changed with [set_default_capacity]
+pub fn default_capacity() -> usize {
+    DEFAULT_CAPACITY.load(Ordering::Relaxed)
+}
+
+/// Sets, for the whole process, the capacity used by [BufReaderWriter::new]
+///
+/// This only affects the instances constructed afterward. With a capacity of 0,
+/// they do not buffer anything, like [BufReaderWriter::with_capacity] documents.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::BufReaderWriter;
+/// use std::io::Cursor;
+///
+/// bufrw::set_default_capacity(65_536);
+/// let rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+/// assert_eq!(rw.capacity(), 65_536);
+/// ```
+pub fn set_default_capacity(capacity: usize) {
+    DEFAULT_CAPACITY.store(capacity, Ordering::Relaxed);
+}
 
 /// Struct that adds buffering to any `T` that supports `Read`, `Write` and `Seek`
 ///
 /// * Seeks do not invalidate the internal buffer if they don't need to
 /// * Large (>= internal buffer's capacity) read/writes will bypass the buffer
-pub struct BufReaderWriter<T: Write + Seek> {
+///
+/// `N` is the capacity of a buffer stored inline, see [InlineBufReaderWriter],
+/// the default 0 is for a heap-allocated buffer.
+pub struct BufReaderWriter<T: Write + Seek, const N: usize = 0> {
     inner: T,
     pos: u64,
     // The number of bytes we have read from the source into the buffer
     n: usize,
-    buffer: Buffer,
+    buffer: Buffer<N>,
+    // When true, all operations go directly to the inner stream
+    buffering_paused: bool,
+    flush_on_drop: bool,
+    flush_inner_on_drop: bool,
+    // Length of the inner stream when known, buffered data may extend past it
+    known_len: Option<u64>,
+    read_gaps_as_zero: bool,
+    // When true, writes go to the inner stream immediately and the buffer stays clean
+    write_through: bool,
+    // The dirty span at which the writes are written, usize::MAX for no limit
+    max_dirty: usize,
+    // How far past its capacity the buffer can grow to stage a write
+    overflow_limit: usize,
+    // The capacity to go back to once the staged writes are written
+    overflow_capacity: Option<usize>,
+    // The threshold of the whole file mode and how to read the stream,
+    // until the first access loads it
+    whole_file: Option<(u64, aligned::ReadFn<T>)>,
+    // The end of the furthest write
+    high_water: u64,
+    stats: std::sync::Arc<stats::Counters>,
+    // The bytes written to the inner stream when it was last flushed
+    flushed_bytes: u64,
+    undo: Option<undo::UndoLog<T>>,
+    retry: Option<RetryPolicy>,
+    #[cfg(feature = "alias-detection")]
+    alias_guard: Option<alias::AliasGuard>,
+    #[cfg(feature = "notify")]
+    watch: Option<watch::Watch>,
+    // The inner stream may not be at `pos`, after a failed seek or side trip
+    inner_pos_lost: bool,
+    // The position and length of the last `write_reserve`, until the next operation
+    reserved: Option<(u64, usize)>,
+    alignment: Option<aligned::Alignment<T>>,
+    // The position while the buffer holds a window prefetched away from it
+    detached_position: Option<u64>,
 }
 
+/// A [BufReaderWriter] whose buffer of `N` bytes is stored inline instead of
+/// being heap-allocated, created with [BufReaderWriter::new_inline]
+///
+/// Meant for small capacities, where the allocation and the indirection matter.
+/// The adapter is as large as its buffer, so moving it copies the buffer.
+pub type InlineBufReaderWriter<T, const N: usize> = BufReaderWriter<T, N>;
+
 impl<T> BufReaderWriter<T>
 where
     T: Write + Seek,
 {
-    const DEFAULT_CAPACITY: usize = 8192;
-
     /// Creates a new BufReaderWriter from the input
     ///
-    /// The buffer is allocated has the default capacity of `8KiB` (8192 bytes)
+    /// The buffer is allocated with the [default_capacity], `8KiB` (8192 bytes) unless changed
+    ///
+    /// The inner stream must be at its start, otherwise use [Self::new_at]
+    /// or [Self::at_current_position].
     ///
     /// # Example
     ///
@@ -74,11 +238,49 @@ where
     /// # }
     /// ```
     pub fn new(inner: T) -> Self {
-        Self::with_capacity(inner, Self::DEFAULT_CAPACITY)
+        Self::with_capacity(inner, default_capacity())
+    }
+
+    /// Creates a new BufReaderWriter over an inner stream that is at `position`
+    ///
+    /// `position` is trusted, it must be the current position of the inner stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut inner = Cursor::new(Vec::new());
+    /// inner.write_all(b"header")?;
+    /// let mut rw = BufReaderWriter::new_at(inner, 6);
+    /// rw.write_all(b"body")?;
+    /// assert_eq!(rw.position(), 10);
+    /// assert_eq!(rw.into_vec()?, b"headerbody");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_at(inner: T, position: u64) -> Self {
+        let mut this = Self::new(inner);
+        this.pos = position;
+        this
+    }
+
+    /// Creates a new BufReaderWriter starting at the current position of the inner stream,
+    /// which is queried
+    pub fn at_current_position(mut inner: T) -> std::io::Result<Self> {
+        let position = inner.stream_position()?;
+        Ok(Self::new_at(inner, position))
     }
 
     /// Creates a new BufReaderWriter with the given capacity for the internal buffer
     ///
+    /// With a capacity of 0, nothing is buffered: the reads, writes and seeks go directly
+    /// to the inner stream, and the position is tracked. Like for [std::io::BufReader],
+    /// [BufRead::fill_buf] then always returns an empty slice, so the [BufRead] methods
+    /// and the ones looking into the buffer, such as [Self::peek], see no data.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -95,1008 +297,8091 @@ where
     /// # }
     /// ```
     pub fn with_capacity(inner: T, capacity: usize) -> Self {
-        Self {
-            inner,
-            pos: 0,
-            n: 0,
-            buffer: Buffer::with_capacity(capacity),
-        }
+        Self::from_internal_buffer(inner, Buffer::with_capacity(capacity))
     }
 
-    /// Creates a new BufReaderWriter using the given buffer
-    pub fn with_buffer(inner: T, buffer: Box<[u8]>) -> Self {
-        Self {
-            inner,
-            pos: 0,
-            n: 0,
-            buffer: Buffer::with_buffer(buffer),
+    /// Creates a new BufReaderWriter whose fills read at most `read_capacity` bytes,
+    /// and whose writes are accumulated until they span `write_capacity` bytes
+    ///
+    /// This suits workloads reading large chunks but writing small patches, or the reverse.
+    /// The internal buffer is allocated with the larger of the two, which is
+    /// what [Self::capacity] returns. Reads of at least `read_capacity` bytes
+    /// and writes of at least `write_capacity` bytes bypass the buffer.
+    ///
+    /// If a capacity is 0, nothing is buffered, as with a capacity of 0 given to
+    /// [Self::with_capacity].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Cursor;
+    ///
+    /// let rw = BufReaderWriter::with_capacities(Cursor::new(Vec::new()), 65_536, 512);
+    /// assert_eq!(rw.capacity(), 65_536);
+    /// assert_eq!(rw.read_capacity(), 65_536);
+    /// assert_eq!(rw.write_capacity(), 512);
+    /// ```
+    pub fn with_capacities(inner: T, read_capacity: usize, write_capacity: usize) -> Self {
+        if read_capacity == 0 || write_capacity == 0 {
+            return Self::with_capacity(inner, 0);
         }
+        let mut buffer = Buffer::with_capacity(read_capacity.max(write_capacity));
+        buffer.read_capacity = read_capacity;
+        buffer.min_fill = read_capacity;
+        buffer.fill_size = read_capacity;
+        buffer.write_capacity = write_capacity;
+        buffer.read_bypass = read_capacity;
+        buffer.write_bypass = write_capacity;
+        Self::from_internal_buffer(inner, buffer)
     }
 
-    /// Returns the position in bytes in the data
-    pub fn position(&self) -> u64 {
-        self.start_position_in_source() + self.buffer.position() as u64
+    /// Creates a new BufReaderWriter with the given capacity for the internal buffer,
+    /// returning an error instead of aborting if the buffer cannot be allocated
+    ///
+    /// This allows to fall back to a smaller capacity when the capacity comes from
+    /// an untrusted source. On error, `inner` is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Cursor;
+    ///
+    /// let rw = BufReaderWriter::try_with_capacity(Cursor::new(Vec::new()), usize::MAX)
+    ///     .or_else(|_| BufReaderWriter::try_with_capacity(Cursor::new(Vec::new()), 4096))
+    ///     .unwrap();
+    /// assert_eq!(rw.capacity(), 4096);
+    /// ```
+    pub fn try_with_capacity(
+        inner: T,
+        capacity: usize,
+    ) -> Result<Self, std::collections::TryReserveError> {
+        Buffer::try_with_capacity(capacity).map(|buffer| Self::from_internal_buffer(inner, buffer))
     }
 
-    /// Returns the number of bytes the internal buffer can hold at once.
-    pub fn capacity(&self) -> usize {
-        self.buffer.capacity()
+    /// Creates a new BufReaderWriter with the capacity preferred by the inner stream
+    ///
+    /// Falls back to the [default_capacity] when the inner stream has no preference.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Cursor;
+    ///
+    /// let rw = BufReaderWriter::auto(Cursor::new(Vec::new()));
+    /// assert_eq!(rw.capacity(), 512);
+    /// ```
+    pub fn auto(inner: T) -> Self
+    where
+        T: CapacityHint,
+    {
+        let capacity = inner
+            .preferred_buffer_capacity()
+            .filter(|capacity| *capacity != 0)
+            .unwrap_or_else(default_capacity);
+        Self::with_capacity(inner, capacity)
     }
 
-    /// Returns a reference to the inner stream
-    pub fn inner(&self) -> &T {
-        &self.inner
+    /// Creates a new BufReaderWriter using the given buffer as the internal buffer
+    ///
+    /// The capacity is the length of `buffer`, whose content does not matter.
+    /// An empty `buffer` cannot buffer anything, so a buffer of the [default_capacity]
+    /// is allocated instead.
+    ///
+    /// Combined with [Self::into_parts], this allows to reuse one allocation
+    /// for several streams.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut buffer = vec![0u8; 4096].into_boxed_slice();
+    /// for name in ["a", "b"] {
+    ///     let mut rw = BufReaderWriter::with_buffer(Cursor::new(Vec::new()), buffer);
+    ///     rw.write_all(name.as_bytes())?;
+    ///     let (inner, b) = rw.into_parts()?;
+    ///     assert_eq!(inner.get_ref(), name.as_bytes());
+    ///     buffer = b;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_buffer(inner: T, buffer: Box<[u8]>) -> Self {
+        if buffer.is_empty() {
+            return Self::new(inner);
+        }
+        Self::from_internal_buffer(inner, Buffer::with_buffer(buffer))
     }
 
-    /// Returns a mutable reference to the inner stream
+    /// Creates a new BufReaderWriter from parts given by [Self::into_parts],
+    /// with the inner stream seeked to `position`
     ///
-    /// # Note
+    /// The buffer is used like with [Self::with_buffer], its content does not matter.
     ///
-    /// The buffer may need to be flushed with [Self::flush_buffer] before
+    /// # Errors
     ///
-    /// Doing modification (read, write, seek) in the returned inner stream
-    /// will cause problems unless carefully done.
-    pub fn inner_mut(&mut self) -> &mut T {
-        &mut self.inner
+    /// If the inner stream fails to seek to `position`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"one")?;
+    /// let position = rw.position();
+    /// let (inner, buffer) = rw.into_parts()?;
+    ///
+    /// let mut rw = BufReaderWriter::from_parts(inner, buffer, position)?;
+    /// rw.write_all(b"two")?;
+    /// assert_eq!(rw.into_vec()?, b"onetwo");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_parts(mut inner: T, buffer: Box<[u8]>, position: u64) -> std::io::Result<Self> {
+        if inner.seek(SeekFrom::Start(position))? != position {
+            return Err(std::io::Error::other(
+                "inner stream did not seek to the position",
+            ));
+        }
+        let mut this = Self::with_buffer(inner, buffer);
+        this.pos = position;
+        Ok(this)
     }
 
-    /// Unwraps the BufReaderWriter, returning the inner stream
+    /// Creates a new BufReaderWriter whose cache is primed with `data`,
+    /// bytes already read by the caller from the stream at `start_offset`
     ///
-    /// This may flush the buffer before which could result in an error
-    pub fn into_inner(self) -> std::io::Result<T> {
-       self.into_parts().map(|(inner, _)| inner)
+    /// The position is set to `position`, which must be within the primed range
+    /// or right at its end. Reading the primed range does not reach the inner stream,
+    /// which is only seeked once, to the end of the primed range.
+    ///
+    /// The capacity is the [default_capacity], or the length of `data` if larger.
+    ///
+    /// `data` is trusted: if it does not match what the stream holds at `start_offset`,
+    /// reads return it anyway, and writing in the primed range writes it back to the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [ErrorKind::InvalidInput](std::io::ErrorKind::InvalidInput)
+    /// if `position` is out of the primed range, or the error of the seek.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut inner = Cursor::new(b"#!magic header and body".to_vec());
+    /// let mut sniffed = [0u8; 8];
+    /// inner.read_exact(&mut sniffed)?;
+    ///
+    /// let mut rw = BufReaderWriter::with_initial_contents(inner, 0, &sniffed, 2)?;
+    /// let mut magic = [0u8; 5];
+    /// rw.read_exact(&mut magic)?;
+    /// assert_eq!(&magic, b"magic");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_initial_contents(
+        mut inner: T,
+        start_offset: u64,
+        data: &[u8],
+        position: u64,
+    ) -> std::io::Result<Self> {
+        let end = start_offset
+            .checked_add(data.len() as u64)
+            .filter(|end| (start_offset..=*end).contains(&position))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "position is out of the initial contents",
+                )
+            })?;
+        let pos = inner.seek(SeekFrom::Start(end))?;
+        let mut this = Self::with_capacity(inner, default_capacity().max(data.len()));
+        this.buffer.data[..data.len()].copy_from_slice(data);
+        this.buffer.filled = data.len();
+        this.buffer.pos = (position - start_offset) as usize;
+        this.pos = pos;
+        this.n = data.len();
+        Ok(this)
     }
 
+    /// Creates a new BufReaderWriter equivalent to the one the `state` was taken from
+    ///
+    /// The inner stream is seeked to the position saved in the `state`.
+    /// The capacities, alignment, fill sizes, bypass thresholds, limits and drop behavior
+    /// are restored, along with the statistics. The retry policy, the undo log,
+    /// the whole file mode and the watch of the file are not.
+    pub fn resume(mut inner: T, state: ResumeState) -> std::io::Result<Self>
+    where
+        T: Read,
+    {
+        let pos = inner.seek(SeekFrom::Start(state.position))?;
+        let mut this = match state.alignment {
+            Some(alignment) => Self::with_aligned_capacity(inner, state.capacity, alignment)?,
+            None => Self::with_capacity(inner, state.capacity),
+        };
+        this.pos = pos;
+        this.buffering_paused = state.buffering_paused;
+        this.read_gaps_as_zero = state.read_gaps_as_zero;
+        this.flush_on_drop = state.flush_on_drop;
+        this.flush_inner_on_drop = state.flush_inner_on_drop;
+        this.write_through = state.write_through;
+        this.max_dirty = state.max_dirty;
+        this.set_bypass_threshold(state.read_bypass, state.write_bypass);
+        this.buffer.read_capacity = state.read_capacity;
+        this.buffer.write_capacity = state.write_capacity;
+        this.buffer.min_fill = state.min_fill;
+        this.buffer.fill_size = state.min_fill;
+        this.overflow_limit = state.overflow_limit;
+        this.stats = std::sync::Arc::new(stats::Counters::from_stats(state.stats));
+        this.flushed_bytes = state.stats.bytes_written;
+        Ok(this)
+    }
 
-    pub fn into_parts(mut self) -> std::io::Result<(T, Box<[u8]>)> {
-        if self.buffer.is_dirty {
-            self.flush_buffer()?;
-        }
-
-        // Since `self` impl Drops we cannot simply deconstruct it
-        let this = std::mem::ManuallyDrop::new(self);
-
-        // SAFETY: double-drops are prevented by putting `this` in a ManuallyDrop that is never dropped
-
-        let inner = unsafe { std::ptr::read(&this.inner) };
-        let buffer = unsafe { std::ptr::read(&this.buffer.data) };
-
-        Ok((inner, buffer))
+    /// Detaches the internal buffer, to be reused by another adapter or
+    /// given back with [Self::put_buffer]
+    ///
+    /// The dirty data is written first, and the cached data is dropped.
+    /// Until a buffer is put back, the adapter has a capacity of 0: reads, writes and seeks
+    /// go directly to the inner stream, as with [Self::with_capacity] with 0.
+    /// The alignment of [Self::with_aligned_capacity] and the capacities
+    /// of [Self::with_capacities] are not kept.
+    ///
+    /// # Errors
+    ///
+    /// If writing the dirty data fails, in which case the buffer stays installed
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut pool = vec![vec![0u8; 4096].into_boxed_slice()];
+    ///
+    /// let mut rw = BufReaderWriter::with_buffer(Cursor::new(Vec::new()), pool.pop().unwrap());
+    /// rw.write_all(b"buffered")?;
+    /// pool.push(rw.take_buffer()?);
+    /// assert_eq!(rw.capacity(), 0);
+    /// assert_eq!(rw.inner().get_ref(), b"buffered");
+    ///
+    /// rw.put_buffer(pool.pop().unwrap())?;
+    /// rw.write_all(b" again")?;
+    /// assert_eq!(rw.into_inner()?.into_inner(), b"buffered again");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn take_buffer(&mut self) -> std::io::Result<Box<[u8]>> {
+        self.discard_buffer()?;
+        self.alignment = None;
+        self.overflow_capacity = None;
+        let buffer = std::mem::replace(&mut self.buffer, Buffer::with_capacity(0));
+        Ok(buffer.data.into_boxed_slice())
     }
 
-    /// Returns the current position in the source
-    fn start_position_in_source(&self) -> u64 {
-        self.pos - self.n as u64
+    /// Installs `buffer` as the internal buffer, like [Self::with_buffer]
+    ///
+    /// Usually called after [Self::take_buffer]. If a buffer is still installed,
+    /// its dirty data is written and it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// If writing the dirty data of the installed buffer fails, in which case it stays installed
+    pub fn put_buffer(&mut self, buffer: Box<[u8]>) -> std::io::Result<()> {
+        self.discard_buffer()?;
+        self.alignment = None;
+        self.overflow_capacity = None;
+        self.buffer = Buffer::with_buffer(buffer);
+        Ok(())
     }
 
-    /// Dump the buffer at the correct position
+    /// Reallocates the internal buffer with a capacity of `new_capacity`
     ///
-    /// Does not clear the buffer
-    pub fn flush_buffer(&mut self) -> std::io::Result<()> {
-        if self.n != 0 {
-            let p = self.inner.seek(SeekFrom::Current(-(self.n as i64)))?;
-            debug_assert_eq!(self.pos - self.n as u64, p);
-            self.pos = p;
+    /// Growing keeps all the buffered data. Shrinking keeps the cached data from
+    /// the position, as much as fits, so that the next reads do not need a refill,
+    /// and writes the dirty data first only if it does not fit.
+    /// The separate capacities of [Self::with_capacities] and the thresholds
+    /// of [Self::set_bypass_threshold] are reset to `new_capacity`.
+    /// With [Self::with_aligned_capacity], the new capacity is rounded up
+    /// to a multiple of the alignment.
+    ///
+    /// # Errors
+    ///
+    /// If writing the dirty data fails, in which case the buffer does not change
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![1u8; 100_000]), 64);
+    /// let mut header = [0u8; 16];
+    /// rw.read_exact(&mut header)?;
+    ///
+    /// // The record size is known from the header
+    /// rw.set_capacity(64 * 1024)?;
+    /// assert_eq!(rw.capacity(), 64 * 1024);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_capacity(&mut self, new_capacity: usize) -> std::io::Result<()> {
+        self.recover_inner_position()?;
+        self.reserved = None;
+        let new_capacity = match self.alignment {
+            Some(alignment) => new_capacity.next_multiple_of(alignment.size()),
+            None => new_capacity,
+        };
+
+        // Either everything fits, or the window from the position
+        let (keep_start, keep_end) = if new_capacity >= self.buffer.filled {
+            (0, self.buffer.filled)
+        } else {
+            let start = self.buffer.pos;
+            (start, self.buffer.filled.min(start + new_capacity))
+        };
+        let dirty = self.buffer.dirty.clone();
+        if self.buffer.is_dirty() && (dirty.start < keep_start || dirty.end > keep_end) {
+            self.dump_buffer()?;
         }
-        let n = self.buffer.dump(&mut self.inner)?;
-
-        // This would mean we wrote fewer bytes than what we originally read
-        debug_assert!(n >= self.n);
 
-        self.pos += n as u64;
-        self.n = n;
-        Ok(())
-    }
-}
+        let mut buffer = Buffer::with_storage(match self.alignment {
+            Some(alignment) => storage::Storage::new_aligned(new_capacity, alignment.size()),
+            None => storage::Storage::new_uninit(new_capacity),
+        });
+        let len = keep_end - keep_start;
+        buffer.data[..len].copy_from_slice(&self.buffer.data[keep_start..keep_end]);
+        buffer.filled = len;
+        buffer.pos = self.buffer.pos - keep_start;
+        if self.buffer.is_dirty() {
+            buffer.mark_dirty(self.buffer.dirty.start - keep_start..self.buffer.dirty.end - keep_start);
+        }
 
-impl<T> Read for BufReaderWriter<T>
-where
-    T: Read + Write + Seek,
-{
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self.buffer.get_read_command(buf) {
-            ReadCommand::Read(n) => self.buffer.read(&mut buf[..n]),
-            ReadCommand::FillRead { dump_before_fill } => {
-                if dump_before_fill {
-                    self.flush_buffer()?;
-                    self.buffer.clear();
-                    self.n = 0;
-                }
-                let n = self.buffer.fill_from(&mut self.inner)?;
-                self.pos += n as u64;
-                self.n = n;
-                self.buffer.read(buf)
-            }
-            ReadCommand::ReadDirect { dump_before } => {
-                if dump_before {
-                    self.flush_buffer()?;
-                    self.buffer.clear();
-                    self.n = 0;
-                }
-                let n = self.inner.read(buf)?;
-                self.pos += n as u64;
-                Ok(n)
-            }
+        let start = self.start_position_in_source() + keep_start as u64;
+        self.buffer = buffer;
+        self.overflow_capacity = None;
+        if self.n < keep_start || self.n > keep_end {
+            // The inner stream is put back at the end of the kept data by the next operation
+            self.pos = start + len as u64;
+            self.n = len;
+            self.inner_pos_lost = true;
+        } else {
+            self.n -= keep_start;
         }
+        Ok(())
     }
 
-    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
-        match self.buffer.get_read_exact_command(buf) {
-            ReadExactCommand::Read => {
-                self.buffer.read(buf)?;
-            }
-            ReadExactCommand::ReadFillRead { split, dump_before_fill } => {
-                let (first, second) = buf.split_at_mut(split);
-                self.buffer.read(first)?;
-                if dump_before_fill {
-                    self.flush_buffer()?;
-                    self.buffer.clear();
-                    self.n = 0;
-                }
-                let n = self.buffer.fill_from(&mut self.inner)?;
-                self.pos += n as u64;
-                self.n = n;
-                self.buffer.read(second)?;
-            }
-            ReadExactCommand::FillRead { dump_before_fill } => {
-                if dump_before_fill {
-                    self.flush_buffer()?;
-                    self.buffer.clear();
-                    self.n = 0;
-                }
-                let n = self.buffer.fill_from(&mut self.inner)?;
-                self.pos += n as u64;
-                self.buffer.read(buf)?;
-            }
-            ReadExactCommand::ReadDirect { dump_before } => {
-                if dump_before {
-                    self.flush_buffer()?;
-                    self.buffer.clear();
-                    self.n = 0;
-                }
-                let n = self.inner.read(buf)?;
-                self.pos += n as u64;
-            }
-            ReadExactCommand::ReadReadDirect { split, dump_before } => {
-                let (first, second) = buf.split_at_mut(split);
-                self.buffer.read(first)?;
-                if dump_before {
-                    self.flush_buffer()?;
-                    self.buffer.clear();
-                    self.n = 0;
-                }
-                let n= self.inner.read(second)?;
-                self.pos += n as u64;
-            }
+    /// Reallocates the internal buffer with a capacity of `target`, if it is larger,
+    /// to release the memory of an idle adapter
+    ///
+    /// Unlike [Self::set_capacity], the cached data is dropped, after the dirty data
+    /// is written, so nothing is copied. The separate capacities and the thresholds
+    /// are reset like with [Self::set_capacity].
+    ///
+    /// # Errors
+    ///
+    /// If writing the dirty data fails, in which case the buffer does not change
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 4 << 20);
+    /// rw.write_all(b"burst")?;
+    /// rw.shrink_to(4096)?;
+    /// assert_eq!(rw.capacity(), 4096);
+    /// assert_eq!(rw.inner().get_ref(), b"burst");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shrink_to(&mut self, target: usize) -> std::io::Result<()> {
+        let target = match self.alignment {
+            Some(alignment) => target.next_multiple_of(alignment.size()),
+            None => target,
+        };
+        if target >= self.capacity() {
+            return Ok(());
         }
+        self.discard_buffer()?;
+        self.reserved = None;
+        self.overflow_capacity = None;
+        self.buffer = Buffer::with_storage(match self.alignment {
+            Some(alignment) => storage::Storage::new_aligned(target, alignment.size()),
+            None => storage::Storage::new_uninit(target),
+        });
         Ok(())
     }
 }
 
-impl<T> Write for BufReaderWriter<T>
+impl<T, const N: usize> BufReaderWriter<T, N>
 where
     T: Write + Seek,
 {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self.buffer.get_write_exact_command(buf) {
-            WriteAllCommand::Write => self.buffer.write(buf),
-            WriteAllCommand::WriteDumpWrite(n) => {
-                let (first, second) = buf.split_at(n);
-                self.buffer.write(first)?;
-                self.flush_buffer()?;
-                self.buffer.clear();
-                self.n = 0;
-                self.buffer.write(second)?;
-                Ok(buf.len())
-            }
-            WriteAllCommand::DumpWriteDirect => {
-                self.flush_buffer()?;
-                self.buffer.clear();
-                self.n = 0;
-                self.inner.write(buf)
-            }
-            WriteAllCommand::WriteDirect => self.inner.write(buf),
-        }
-    }
-
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.flush_buffer()?;
-        self.buffer.clear();
+    /// Creates a new BufReaderWriter whose buffer of `N` bytes is stored inline,
+    /// without allocating
+    ///
+    /// The buffer cannot grow, see [BufReaderWriter::set_whole_file_threshold].
+    /// With `N` being 0, the adapter does not buffer, like [Self::with_capacity] with 0.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::{BufReaderWriter, InlineBufReaderWriter};
+    /// use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw: InlineBufReaderWriter<_, 512> = BufReaderWriter::new_inline(Cursor::new(Vec::new()));
+    /// assert_eq!(rw.capacity(), 512);
+    /// rw.write_all(b"no allocation")?;
+    /// rw.seek(SeekFrom::Start(3))?;
+    /// let mut buf = [0u8; 10];
+    /// rw.read_exact(&mut buf)?;
+    /// assert_eq!(&buf, b"allocation");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_inline(inner: T) -> Self {
+        Self::from_internal_buffer(inner, Buffer::with_storage(storage::Storage::new_inline()))
+    }
+
+    fn from_internal_buffer(inner: T, buffer: Buffer<N>) -> Self {
+        Self {
+            inner,
+            pos: 0,
+            n: 0,
+            buffer,
+            buffering_paused: false,
+            flush_on_drop: true,
+            flush_inner_on_drop: true,
+            known_len: None,
+            read_gaps_as_zero: false,
+            write_through: false,
+            max_dirty: usize::MAX,
+            overflow_limit: 0,
+            overflow_capacity: None,
+            whole_file: None,
+            high_water: 0,
+            stats: Default::default(),
+            flushed_bytes: 0,
+            undo: None,
+            retry: None,
+            #[cfg(feature = "alias-detection")]
+            alias_guard: None,
+            #[cfg(feature = "notify")]
+            watch: None,
+            inner_pos_lost: false,
+            reserved: None,
+            alignment: None,
+            detached_position: None,
+        }
+    }
+
+    /// Returns the position in bytes in the data
+    pub fn position(&self) -> u64 {
+        if let Some(position) = self.detached_position {
+            return position;
+        }
+        self.start_position_in_source() + self.buffer.position() as u64
+    }
+
+    /// Seeks relative to the current position, like [std::io::BufReader::seek_relative]
+    ///
+    /// When the target is within the cached data, including its end, only the position
+    /// in the buffer changes: the cached data is kept and the inner stream is not reached.
+    /// Otherwise the dirty data is written, the cached data is dropped and the inner stream
+    /// is seeked. `seek(SeekFrom::Current(offset))` behaves the same.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if the target is before
+    /// the start of the stream, in which case nothing changes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"Hello World".to_vec()));
+    /// let mut hello = [0u8; 5];
+    /// rw.read_exact(&mut hello)?;
+    /// rw.seek_relative(-5)?;
+    /// rw.read_exact(&mut hello)?;
+    /// assert_eq!(&hello, b"Hello");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn seek_relative(&mut self, offset: i64) -> std::io::Result<()> {
+        self.seek(SeekFrom::Current(offset)).map(|_| ())
+    }
+
+    /// Returns the number of bytes the internal buffer can hold at once.
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Returns the maximum number of bytes a fill of the buffer reads,
+    /// the capacity unless set with [Self::with_capacities]
+    pub fn read_capacity(&self) -> usize {
+        self.buffer.read_capacity
+    }
+
+    /// Returns the maximum span of the buffered writes, after which they are written
+    /// to the inner stream, the capacity unless set with [Self::with_capacities]
+    pub fn write_capacity(&self) -> usize {
+        self.buffer.write_capacity
+    }
+
+    /// Sets the sizes from which reads and writes bypass the buffer,
+    /// going directly to the inner stream
+    ///
+    /// By default, these are the read and write capacities. A threshold larger than
+    /// the capacity means never bypassing: large reads and writes then go through
+    /// the buffer in capacity-sized chunks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 8192);
+    /// // Two dumps rather than one large unaligned write
+    /// rw.set_bypass_threshold(4096, usize::MAX);
+    /// rw.write_all(&[0u8; 12 * 1024])?;
+    /// assert_eq!(rw.stats().direct_writes, 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_bypass_threshold(&mut self, read: usize, write: usize) {
+        self.buffer.read_bypass = read;
+        self.buffer.write_bypass = write;
+    }
+
+    /// Sets how much the fills of the buffer read, to limit the read amplification
+    /// of random accesses while keeping large reads for sequential scans
+    ///
+    /// The first fill after a seek outside of the cached data reads at most `min` bytes,
+    /// then each fill that continues a sequential scan reads twice as much as the
+    /// previous one, up to `max`. `max` is capped to the capacity, it is then
+    /// the [Self::read_capacity]. By default, both are the read capacity.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput]
+    /// if `min` is 0 or greater than `max`, in which case nothing changes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Seek, SeekFrom};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![0u8; 1 << 20]), 256 * 1024);
+    /// rw.set_fill_size(4096, 256 * 1024)?;
+    ///
+    /// rw.seek(SeekFrom::Start(500_000))?;
+    /// let mut record = [0u8; 100];
+    /// rw.read_exact(&mut record)?;
+    /// assert_eq!(rw.stats().bytes_read, 4096);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_fill_size(&mut self, min: usize, max: usize) -> std::io::Result<()> {
+        if min == 0 || min > max {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "fill sizes must be non-zero and ordered",
+            ));
+        }
+        let max = max.min(self.capacity());
+        self.buffer.read_capacity = max;
+        self.buffer.min_fill = min.min(max);
+        self.buffer.fill_size = min.min(max);
+        Ok(())
+    }
+
+    /// Sets the amount of dirty data from which it is written to the inner stream
+    /// right away, to bound how much an interruption can lose
+    ///
+    /// Once the writes span `bytes` bytes, see [Self::dirty_len], they are written
+    /// at the end of the write that reached it. The cached data is kept,
+    /// so the buffer can be large for the reads while few writes are pending.
+    /// If that write to the inner stream fails, the write still succeeds
+    /// as its bytes are buffered, and the next operation tries again.
+    ///
+    /// The writes into the buffer in place, like [Self::write_reserve] and [Self::commit],
+    /// are written when the next operation starts.
+    ///
+    /// By default, there is no limit other than the [Self::write_capacity].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 1 << 20);
+    /// rw.set_max_dirty(4096);
+    /// for _ in 0..16 {
+    ///     rw.write_all(&[0u8; 1000])?;
+    /// }
+    /// assert_eq!(rw.inner().get_ref().len(), 15_000);
+    /// assert_eq!(rw.dirty_len(), 1000);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_max_dirty(&mut self, bytes: usize) {
+        self.max_dirty = bytes;
+    }
+
+    /// Returns the amount of dirty data from which it is written right away,
+    /// see [Self::set_max_dirty]
+    pub fn max_dirty(&self) -> usize {
+        self.max_dirty
+    }
+
+    /// Sets how many bytes past its capacity the buffer can grow to stage a write
+    /// that does not fit in it and does not bypass it
+    ///
+    /// Without bypass, see [Self::set_bypass_threshold] and [Self::with_aligned_capacity],
+    /// a write larger than the space left is written in capacity-sized chunks
+    /// before returning. Instead, if the buffer has to grow by at most `bytes` to hold
+    /// the whole write, it grows and the write returns right away.
+    /// The staged data is cached like any write: it is read back from memory,
+    /// and written when the buffer is next written. The buffer then goes back
+    /// to its capacity at the start of the next operation.
+    ///
+    /// By default, the limit is 0 and the writes are never staged.
+    /// An inline buffer cannot grow.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_aligned_capacity(Cursor::new(Vec::new()), 4096, 512)?;
+    /// rw.set_overflow_limit(8192);
+    /// rw.write_all(&[1u8; 12_288])?;
+    /// assert_eq!(rw.stats().bytes_written, 0);
+    ///
+    /// rw.flush()?;
+    /// assert_eq!(rw.stats().bytes_written, 12_288);
+    /// rw.seek(SeekFrom::Start(0))?;
+    /// assert_eq!(rw.capacity(), 4096);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_overflow_limit(&mut self, bytes: usize) {
+        self.overflow_limit = bytes;
+    }
+
+    /// Grows the buffer so that a write of `len` bytes fits in it, if it does not bypass
+    /// the buffer and the growth is within the limit of [Self::set_overflow_limit]
+    fn stage_overflow(&mut self, len: usize) {
+        if N != 0 || len <= self.buffer.num_writable_bytes_left() || len >= self.buffer.write_bypass
+        {
+            return;
+        }
+        let capacity = self.regular_capacity();
+        let needed = self.buffer.pos + len;
+        if needed - capacity > self.overflow_limit {
+            return;
+        }
+        self.grow_buffer(needed);
+        self.overflow_capacity = Some(capacity);
+    }
+
+    /// Moves the buffered data to a larger allocation of at least `capacity` bytes,
+    /// keeping the alignment, capacities and thresholds of the buffer
+    ///
+    /// The buffer must not be inline.
+    fn grow_buffer(&mut self, capacity: usize) {
+        debug_assert!(N == 0 && capacity >= self.buffer.capacity());
+        let align = self.alignment.map_or(1, |alignment| alignment.size());
+        let mut data = storage::Storage::allocate(capacity.next_multiple_of(align), align);
+        let filled = self.buffer.filled;
+        data[..filled].copy_from_slice(&self.buffer.data[..filled]);
+        self.buffer.data = data;
+    }
+
+    /// Returns the capacity without the staged writes, the capacity of the buffer
+    /// at the start of the next operation if they are written by then
+    fn regular_capacity(&self) -> usize {
+        self.overflow_capacity.unwrap_or(self.buffer.capacity())
+    }
+
+    /// Goes back to the regular capacity once the staged writes are written,
+    /// keeping the cached data from the block of the position, as much as fits
+    fn release_overflow(&mut self) {
+        let Some(capacity) = self.overflow_capacity else {
+            return;
+        };
+        if self.buffer.is_dirty() || self.detached_position.is_some() {
+            return;
+        }
+        self.overflow_capacity = None;
+        let align = self.alignment.map_or(1, |alignment| alignment.size());
+        let from = self.buffer.pos - self.buffer.pos % align;
+        let keep = from..self.buffer.filled.min(from + capacity);
+        let mut data = storage::Storage::allocate(capacity, align);
+        data[..keep.len()].copy_from_slice(&self.buffer.data[keep.clone()]);
+
+        let start = self.start_position_in_source() + keep.start as u64;
+        self.buffer.data = data;
+        self.buffer.pos -= keep.start;
+        self.buffer.filled = keep.len();
+        if self.n != keep.end {
+            // The inner stream is put back at the end of the kept data by the next operation
+            self.pos = start + keep.len() as u64;
+            self.inner_pos_lost = true;
+        }
+        self.n = keep.len();
+    }
+
+    /// Returns the statistics of the operations done on the inner stream
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    /// Returns a handle to the statistics, that can be sampled from other threads
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Write;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::in_memory();
+    /// let handle = rw.stats_handle();
+    /// let monitor = std::thread::spawn(move || handle.snapshot());
+    ///
+    /// rw.write_all(b"data")?;
+    /// rw.flush()?;
+    /// assert_eq!(rw.stats().bytes_written, 4);
+    /// monitor.join().unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle(self.stats.clone())
+    }
+
+    /// Returns a reference to the inner stream
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner stream
+    ///
+    /// # Note
+    ///
+    /// The buffer may need to be flushed with [Self::flush_writes] before
+    ///
+    /// Doing modification (read, write, seek) in the returned inner stream
+    /// will cause problems unless carefully done.
+    pub fn inner_mut(&mut self) -> &mut T {
+        // The length may be changed through it
+        self.known_len = None;
+        &mut self.inner
+    }
+
+    /// Writes the dirty buffered data to the current inner stream, then replaces it
+    /// with `new_inner`, returning the old one
+    ///
+    /// The buffer allocation and the settings are kept, but the cached data is dropped,
+    /// and the position is the current position of `new_inner`.
+    /// The undo log, the alias detection and the file watch are stopped,
+    /// since they are about the old stream.
+    ///
+    /// # Errors
+    ///
+    /// If writing the buffered data or querying the position of `new_inner` fails,
+    /// in which case the inner stream is not replaced.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"first")?;
+    /// let first = rw.replace_inner(Cursor::new(Vec::new()))?;
+    /// assert_eq!(first.get_ref(), b"first");
+    /// rw.write_all(b"second")?;
+    /// assert_eq!(rw.into_inner()?.get_ref(), b"second");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn replace_inner(&mut self, mut new_inner: T) -> std::io::Result<T> {
+        self.recover_inner_position()?;
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        self.stats.seek();
+        let pos = new_inner.stream_position()?;
+
+        self.pos = pos;
         self.n = 0;
-        self.inner.flush()
+        self.buffer.clear();
+        self.known_len = None;
+        self.high_water = 0;
+        self.undo = None;
+        #[cfg(feature = "alias-detection")]
+        {
+            self.alias_guard = None;
+        }
+        #[cfg(feature = "notify")]
+        {
+            self.watch = None;
+        }
+        Ok(std::mem::replace(&mut self.inner, new_inner))
+    }
+
+    /// Writes the dirty buffered data, then wraps or converts the inner stream with `f`,
+    /// keeping the buffer allocation, the cached data, the position and the settings
+    ///
+    /// `f` must not move the stream, and the positions in the stream it returns
+    /// must be the same as in the old one, otherwise [BufReaderWriter::invalidate]
+    /// has to be called on the result.
+    /// The undo log, the alias detection and the file watch are stopped.
+    /// As the new stream may not be readable, the alignment of [Self::with_aligned_capacity]
+    /// is dropped, the buffer staying aligned in memory, and so is a whole file load
+    /// pending since [Self::set_whole_file_threshold].
+    ///
+    /// # Errors
+    ///
+    /// If writing the buffered data fails, in which case `f` is not called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"Hello World".to_vec()));
+    /// rw.write_all(b"J")?;
+    /// let mut rw = rw.map_inner(Box::new)?;
+    /// let mut s = String::new();
+    /// rw.read_to_string(&mut s)?;
+    /// assert_eq!(s, "ello World");
+    /// assert_eq!(rw.into_inner()?.get_ref(), b"Jello World");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn map_inner<U: Write + Seek>(
+        mut self,
+        f: impl FnOnce(T) -> U,
+    ) -> std::io::Result<BufReaderWriter<U, N>> {
+        self.recover_inner_position()?;
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        let stats = self.stats.clone();
+        let (pos, n, high_water, retry) = (self.pos, self.n, self.high_water, self.retry);
+        let (buffering_paused, read_gaps_as_zero) = (self.buffering_paused, self.read_gaps_as_zero);
+        let (flush_on_drop, flush_inner_on_drop) = (self.flush_on_drop, self.flush_inner_on_drop);
+        let (write_through, max_dirty) = (self.write_through, self.max_dirty);
+        let (overflow_limit, overflow_capacity) = (self.overflow_limit, self.overflow_capacity);
+        let (known_len, flushed_bytes) = (self.known_len, self.flushed_bytes);
+
+        let (inner, buffer) = self.into_raw_parts();
+        let mut mapped = BufReaderWriter::from_internal_buffer(f(inner), buffer);
+        mapped.pos = pos;
+        mapped.n = n;
+        mapped.high_water = high_water;
+        mapped.retry = retry;
+        mapped.stats = stats;
+        mapped.flushed_bytes = flushed_bytes;
+        mapped.buffering_paused = buffering_paused;
+        mapped.read_gaps_as_zero = read_gaps_as_zero;
+        mapped.flush_on_drop = flush_on_drop;
+        mapped.flush_inner_on_drop = flush_inner_on_drop;
+        mapped.write_through = write_through;
+        mapped.max_dirty = max_dirty;
+        mapped.overflow_limit = overflow_limit;
+        mapped.overflow_capacity = overflow_capacity;
+        mapped.known_len = known_len;
+        Ok(mapped)
+    }
+
+    /// Unwraps the BufReaderWriter without doing any IO, returning the inner stream
+    /// if no buffered write is pending, or `self` unchanged otherwise
+    ///
+    /// `self` is also given back when the inner stream is not where it should be,
+    /// after a failed operation. Otherwise the inner stream is at the end of the
+    /// cached data, like after [Self::into_inner], which may be past the position.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"pending")?;
+    /// let mut rw = rw.try_into_inner().unwrap_err();
+    /// rw.flush()?;
+    /// let inner = rw.try_into_inner().ok().unwrap();
+    /// assert_eq!(inner.get_ref(), b"pending");
+    /// # Ok(())
+    /// # }
+    /// ```
+    // Giving `self` back is the point
+    #[allow(clippy::result_large_err)]
+    pub fn try_into_inner(self) -> Result<T, Self> {
+        if self.buffer.is_dirty() || self.inner_pos_lost {
+            return Err(self);
+        }
+        Ok(self.into_raw_parts().0)
+    }
+
+    /// Unwraps the BufReaderWriter, returning the inner stream
+    ///
+    /// This may flush the buffer before which could result in an error
+    pub fn into_inner(self) -> std::io::Result<T> {
+       self.into_parts().map(|(inner, _)| inner)
+    }
+
+    /// Unwraps the BufReaderWriter, returning the inner stream and the internal buffer,
+    /// to be reused with [Self::with_buffer]
+    ///
+    /// The dirty buffered data is written before, which could result in an error.
+    /// The inner stream is not flushed.
+    pub fn into_parts(mut self) -> std::io::Result<(T, Box<[u8]>)> {
+        self.recover_inner_position()?;
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+
+        let (inner, buffer) = self.into_raw_parts();
+        Ok((inner, buffer.data.into_boxed_slice()))
+    }
+
+    /// Converts into a [std::io::BufReader] of the same capacity, for a read-only phase
+    ///
+    /// The dirty buffered data is written first, and the inner stream is seeked to the
+    /// current position, where the `BufReader` starts reading.
+    /// The cache does not survive: a `BufReader` cannot be given initial contents,
+    /// so the cached bytes that were not read yet are read again from the inner stream.
+    /// The inner stream is not flushed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{BufRead, Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"first\nsecond\n")?;
+    /// rw.seek(SeekFrom::Start(6))?;
+    ///
+    /// let reader = rw.into_reader()?;
+    /// let lines = reader.lines().collect::<Result<Vec<_>, _>>()?;
+    /// assert_eq!(lines, ["second"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_reader(mut self) -> std::io::Result<std::io::BufReader<T>>
+    where
+        T: Read,
+    {
+        self.discard_buffer()?;
+        let capacity = self.capacity();
+        let (inner, _) = self.into_raw_parts();
+        Ok(std::io::BufReader::with_capacity(capacity, inner))
+    }
+
+    /// Converts into a [std::io::BufWriter] of the same capacity, for a write-only phase
+    ///
+    /// The dirty buffered data is written first, and the inner stream is seeked to the
+    /// current position, even when it is behind the end of the cached data,
+    /// where the `BufWriter` starts writing.
+    /// The inner stream is not flushed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"header: ?\n".to_vec()));
+    /// rw.seek(SeekFrom::Start(8))?;
+    /// rw.write_all(b"1")?;
+    /// rw.seek(SeekFrom::End(0))?;
+    ///
+    /// let mut writer = rw.into_writer()?;
+    /// writer.write_all(b"appended\n")?;
+    /// let data = writer.into_inner()?.into_inner();
+    /// assert_eq!(data, b"header: 1\nappended\n");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_writer(mut self) -> std::io::Result<std::io::BufWriter<T>> {
+        self.discard_buffer()?;
+        let capacity = self.capacity();
+        let (inner, _) = self.into_raw_parts();
+        Ok(std::io::BufWriter::with_capacity(capacity, inner))
+    }
+
+    /// Deconstructs `self` without running its `Drop` impl, so without flushing
+    fn into_raw_parts(self) -> (T, Buffer<N>) {
+        // Since `self` impl Drops we cannot simply deconstruct it
+        let this = &mut std::mem::ManuallyDrop::new(self);
+
+        // SAFETY: double-drops are prevented by putting `this` in a ManuallyDrop that is never dropped,
+        // fields that are not moved out are dropped in place exactly once
+        unsafe {
+            let inner = std::ptr::read(&this.inner);
+            let buffer = std::ptr::read(&this.buffer);
+            std::ptr::drop_in_place(&mut this.stats);
+            std::ptr::drop_in_place(&mut this.undo);
+            #[cfg(feature = "alias-detection")]
+            std::ptr::drop_in_place(&mut this.alias_guard);
+            #[cfg(feature = "notify")]
+            std::ptr::drop_in_place(&mut this.watch);
+            (inner, buffer)
+        }
+    }
+
+    /// Returns the current position in the source
+    fn start_position_in_source(&self) -> u64 {
+        self.pos - self.n as u64
+    }
+
+    /// Seeks the inner stream, remembering that its position is unknown if it fails
+    ///
+    /// Like the reads and writes, the seeks failing with [std::io::ErrorKind::Interrupted]
+    /// are retried.
+    fn seek_inner(&mut self, from: SeekFrom) -> std::io::Result<u64> {
+        self.stats.seek();
+        loop {
+            match self.inner.seek(from) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                result => return result.inspect_err(|_| self.inner_pos_lost = true),
+            }
+        }
+    }
+
+    /// Puts the inner stream back at `pos`, after a side trip
+    fn seek_back(&mut self) -> std::io::Result<()> {
+        self.inner_pos_lost = true;
+        if self.seek_inner(SeekFrom::Start(self.pos))? != self.pos {
+            return Err(std::io::Error::other(
+                "inner stream did not seek back to its position",
+            ));
+        }
+        self.inner_pos_lost = false;
+        Ok(())
+    }
+
+    /// Puts the inner stream back at `pos` if a previous operation failed to
+    #[inline]
+    fn recover_inner_position(&mut self) -> std::io::Result<()> {
+        self.attach()?;
+        if self.inner_pos_lost {
+            self.seek_back()?;
+        }
+        Ok(())
+    }
+
+    /// Puts the position back in the buffer after a prefetch,
+    /// dropping the prefetched data if the position is not in it
+    ///
+    /// The prefetched data may have been written into in place, e.g. by
+    /// [Self::apply_patches], in which case it is written before being dropped.
+    /// On error, the position stays detached.
+    fn attach(&mut self) -> std::io::Result<()> {
+        let Some(position) = self.detached_position else {
+            return Ok(());
+        };
+        if let Some(offset) = self.offset_in_window(position) {
+            self.buffer.pos = offset;
+        } else {
+            if self.buffer.is_dirty() {
+                self.detached_position = None;
+                self.write_dirty()
+                    .inspect_err(|_| self.detached_position = Some(position))?;
+            }
+            self.buffer.clear();
+            self.pos = position;
+            self.n = 0;
+            self.inner_pos_lost = true;
+        }
+        self.detached_position = None;
+        Ok(())
+    }
+
+    /// Returns the offset in the buffer of `position`, if it is in the cached data or at its end
+    fn offset_in_window(&self, position: u64) -> Option<usize> {
+        let start = self.start_position_in_source();
+        (start..=start + self.buffer.filled as u64)
+            .contains(&position)
+            .then(|| (position - start) as usize)
+    }
+
+    /// Dump the dirty data of the buffer at the correct position
+    ///
+    /// Does not clear the buffer, and leaves the inner stream at the end of the cached data.
+    /// On error, the position does not change, and the buffer stays dirty
+    /// unless the inner stream failed to seek back after the write.
+    fn dump_buffer(&mut self) -> std::io::Result<()> {
+        self.write_dirty()?;
+        self.recover_inner_position()
+    }
+
+    /// Writes the dirty data of the buffer at the correct position,
+    /// for when the inner stream is seeked elsewhere right after
+    ///
+    /// Only the dirty range is written, clean cached data is never written back,
+    /// except to complete the blocks of an aligned buffer.
+    /// The inner stream is left at the end of the dirty data, to be put back
+    /// at the end of the cached data by the next [Self::recover_inner_position].
+    /// On error, the buffer stays dirty and the position does not change.
+    fn write_dirty(&mut self) -> std::io::Result<()> {
+        debug_assert!(self.buffer.is_dirty());
+        self.attach()?;
+        self.complete_last_block()?;
+        let range = self.dump_range();
+        let start = self.start_position_in_source();
+        // When the inner stream is not where expected, it goes to the dirty data directly
+        if self.inner_pos_lost || self.n != range.start {
+            let from = if self.inner_pos_lost {
+                SeekFrom::Start(start + range.start as u64)
+            } else {
+                SeekFrom::Current(range.start as i64 - self.n as i64)
+            };
+            let p = self.seek_inner(from)?;
+            if p != start + range.start as u64 {
+                self.inner_pos_lost = true;
+                return Err(std::io::Error::other(
+                    "inner stream did not seek back to the start of the dirty data",
+                ));
+            }
+            self.pos = p;
+            self.n = range.start;
+        }
+        // A failed write may have moved the inner stream by any amount
+        self.inner_pos_lost = true;
+        let n = self.buffer.dump(range.clone(), retrying!(self))?;
+        self.inner_pos_lost = false;
+        self.stats.dump(n);
+
+        self.pos += n as u64;
+        self.n = range.end;
+        if let Some(len) = &mut self.known_len {
+            *len = (*len).max(self.pos);
+        }
+        self.track_dirty_range(false);
+        // Clean cached data follows, the reads continue after it
+        if range.end != self.buffer.filled {
+            self.n = self.buffer.filled;
+            self.pos = start + self.n as u64;
+            self.inner_pos_lost = true;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty buffered data to the inner stream, without flushing the inner stream
+    ///
+    /// Unlike [Write::flush], this does not call `flush` on the inner stream,
+    /// which is useful when the inner stream is itself buffered or compressing and
+    /// flushing it has a cost (e.g. forcing a block boundary).
+    /// The cached data is kept and the position does not change.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"data")?;
+    /// assert!(rw.inner().get_ref().is_empty());
+    ///
+    /// rw.flush_writes()?;
+    /// assert_eq!(rw.inner().get_ref(), b"data");
+    /// assert_eq!(rw.position(), 4);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn flush_writes(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty buffered data to the inner stream, without flushing the inner stream
+    ///
+    /// Same as [flush_writes](Self::flush_writes): the flush does not cascade to the
+    /// inner stream, use [Write::flush] for that.
+    pub fn flush_data(&mut self) -> std::io::Result<()> {
+        self.flush_writes()
+    }
+
+    /// Writes the dirty buffered data that is in `pos..pos + len` to the inner stream,
+    /// without flushing the inner stream
+    ///
+    /// Returns the number of bytes written, 0 when no dirty data is in the range.
+    /// The dirty data spans from the first to the last byte written since the last flush.
+    /// The other dirty data stays buffered, the cached data is kept and
+    /// the position does not change.
+    ///
+    /// This allows to make some data durable before other data that was written
+    /// earlier, e.g. a record before the header pointing to it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(vec![0u8; 16]));
+    /// rw.write_all(b"header")?;
+    /// rw.write_all(b"record")?;
+    ///
+    /// assert_eq!(rw.flush_range(6, 6)?, 6);
+    /// assert_eq!(&rw.inner().get_ref()[..12], b"\0\0\0\0\0\0record");
+    /// rw.flush_writes()?;
+    /// assert_eq!(&rw.inner().get_ref()[..12], b"headerrecord");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn flush_range(&mut self, pos: u64, len: u64) -> std::io::Result<u64> {
+        if !self.buffer.is_dirty() {
+            return Ok(0);
+        }
+        self.recover_inner_position()?;
+        let start = self.start_position_in_source();
+        let dirty = self.buffer.dirty.clone();
+        let from = pos.max(start + dirty.start as u64);
+        let to = pos.saturating_add(len).min(start + dirty.end as u64);
+        if from >= to {
+            return Ok(0);
+        }
+        let range = (from - start) as usize..(to - start) as usize;
+
+        self.inner_pos_lost = true;
+        self.seek_inner(SeekFrom::Start(from))?;
+        let result = retrying!(self).write_all(&self.buffer.data[range.clone()]);
+        if result.is_ok() && range == dirty {
+            // Like after a dump, the reads continue after the cached data once it is clean
+            self.n = self.buffer.filled;
+            self.pos = start + self.n as u64;
+        }
+        self.seek_back()?;
+        result?;
+        self.stats.dump(range.len());
+
+        if let Some(len) = &mut self.known_len {
+            *len = (*len).max(to);
+        }
+        // What remains dirty, when the range is at one end of the dirty data
+        if range == dirty {
+            self.buffer.mark_clean();
+            self.track_dirty_range(false);
+        } else if range.start == dirty.start {
+            self.buffer.dirty = range.end..dirty.end;
+        } else if range.end == dirty.end {
+            self.buffer.dirty = dirty.start..range.start;
+        }
+        Ok(range.len() as u64)
+    }
+
+    /// Writes the dirty buffered data to the inner stream
+    #[deprecated(note = "use `flush_writes` instead")]
+    pub fn flush_buffer(&mut self) -> std::io::Result<()> {
+        self.flush_writes()
+    }
+
+    /// Writes the dirty buffered data, then drops the cached data
+    ///
+    /// Following reads are served by the inner stream again,
+    /// which is needed when the inner stream was modified by other means.
+    /// The position does not change.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Read;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello".to_vec());
+    /// let mut c = [0u8; 1];
+    /// rw.read_exact(&mut c)?;
+    ///
+    /// // Modified behind the back of the adapter
+    /// rw.inner_mut().get_mut()[1] = b'a';
+    /// rw.sync_cache()?;
+    ///
+    /// rw.read_exact(&mut c)?;
+    /// assert_eq!(&c, b"a");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sync_cache(&mut self) -> std::io::Result<()> {
+        self.discard_buffer()
+    }
+
+    /// Writes the dirty buffered data, drops the cached data, then takes
+    /// the position of the inner stream as the new position
+    ///
+    /// Unlike [Self::sync_cache], this is meant for when the inner stream was also
+    /// moved by other means, e.g. through [Self::inner_mut]: the next read fetches
+    /// the data from where the inner stream is.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// let mut c = [0u8; 1];
+    /// rw.read_exact(&mut c)?;
+    ///
+    /// let inner = rw.inner_mut();
+    /// inner.seek(SeekFrom::Start(6))?;
+    /// inner.write_all(b"w")?;
+    /// inner.seek(SeekFrom::Start(6))?;
+    /// rw.invalidate()?;
+    ///
+    /// assert_eq!(rw.position(), 6);
+    /// rw.read_exact(&mut c)?;
+    /// assert_eq!(&c, b"w");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn invalidate(&mut self) -> std::io::Result<()> {
+        let position = self.seek_inner(SeekFrom::Current(0))?;
+        if self.buffer.is_dirty() {
+            // The inner stream was not left where the adapter expects it
+            self.inner_pos_lost = true;
+            self.dump_buffer()?;
+            self.pos = self.seek_inner(SeekFrom::Start(position))?;
+        } else {
+            self.pos = position;
+        }
+        self.inner_pos_lost = false;
+        self.buffer.clear();
+        self.n = 0;
+        self.known_len = None;
+        Ok(())
+    }
+
+    /// Runs `f` on the inner stream, keeping the adapter consistent with whatever `f` does
+    ///
+    /// The dirty buffered data is written and the cached data is dropped before calling `f`,
+    /// then the inner stream is put back at the position, which does not change.
+    /// This makes operations like [std::fs::File::set_len] safe,
+    /// without the caveats of [Self::inner_mut].
+    ///
+    /// # Errors
+    ///
+    /// The error of `f`, or of writing the buffered data before, in which case `f` is not called,
+    /// or of seeking back after.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Read, Seek, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(tempfile::tempfile()?);
+    /// rw.write_all(b"Hello World")?;
+    /// rw.with_inner(|file| file.set_len(5))?;
+    ///
+    /// rw.rewind()?;
+    /// let mut s = String::new();
+    /// rw.read_to_string(&mut s)?;
+    /// assert_eq!(s, "Hello");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_inner<R>(
+        &mut self,
+        f: impl FnOnce(&mut T) -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        self.discard_buffer()?;
+        self.known_len = None;
+        self.inner_pos_lost = true;
+        let result = f(&mut self.inner);
+        self.seek_back()?;
+        result
+    }
+
+    /// Like [Self::invalidate], but refuses to write the dirty buffered data
+    ///
+    /// # Errors
+    ///
+    /// If some written data was not yet written to the inner stream,
+    /// in which case nothing changes.
+    pub fn discard_cache(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_dirty() {
+            return Err(std::io::Error::other(
+                "discarding the cache would lose buffered writes",
+            ));
+        }
+        self.invalidate()
+    }
+
+    /// Returns whether some written data was not yet written to the inner stream
+    ///
+    /// This is cheap and has no side effect.
+    pub fn has_dirty_data(&self) -> bool {
+        self.buffer.is_dirty()
+    }
+
+    /// Returns whether some written data was not yet written to the inner stream
+    ///
+    /// This is cheap and has no side effect, like [Self::has_dirty_data].
+    pub fn is_dirty(&self) -> bool {
+        self.buffer.is_dirty()
+    }
+
+    /// Returns the number of buffered bytes that were written since the buffer was last
+    /// written to the inner stream
+    ///
+    /// These are the bytes spanned by the writes, so writing twice at the same place
+    /// counts once. This is cheap and has no side effect, so it can drive a flush policy.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"pending")?;
+    /// assert_eq!(rw.dirty_len(), 7);
+    /// if rw.dirty_len() >= 4 {
+    ///     rw.flush()?;
+    /// }
+    /// assert_eq!(rw.dirty_len(), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dirty_len(&self) -> usize {
+        self.buffer.dirty.len()
+    }
+
+    /// Returns the number of cached bytes that can be read from the current position,
+    /// see [Self::read_buffered_only]
+    pub fn buffered_len(&self) -> usize {
+        self.buffer().len()
+    }
+
+    /// Returns the cached bytes that can be read from the current position,
+    /// like [std::io::BufReader::buffer]
+    ///
+    /// They include the written bytes that are not yet written to the inner stream.
+    /// The slice is empty when nothing is cached, no IO is performed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(b"Hello World".to_vec()), 8);
+    /// assert!(rw.buffer().is_empty());
+    /// rw.read_exact(&mut [0u8; 2])?;
+    /// rw.write_all(b"LL")?;
+    /// rw.seek(SeekFrom::Start(1))?;
+    /// assert_eq!(rw.buffer(), b"eLLo Wo");
+    /// assert_eq!(rw.buffer_start_offset(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn buffer(&self) -> &[u8] {
+        if self.detached_position.is_some() {
+            return &[];
+        }
+        &self.buffer.data[self.buffer.pos..self.buffer.filled]
+    }
+
+    /// Returns the offset in the stream of the first byte of [Self::buffer],
+    /// which is the position
+    pub fn buffer_start_offset(&self) -> u64 {
+        self.position()
+    }
+
+    /// Reads from the cached data only, never reaching the inner stream
+    ///
+    /// Copies up to `buf.len()` bytes, returning 0 when no cached bytes are left
+    /// instead of filling the buffer, even if the stream has more data.
+    /// Unlike [BufRead::fill_buf], the bytes are consumed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Read;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(std::io::Cursor::new(b"Hello World".to_vec()), 8);
+    /// let mut bytes = [0u8; 4];
+    /// rw.read_exact(&mut bytes)?;
+    /// assert_eq!(rw.buffered_len(), 4);
+    ///
+    /// let mut rest = [0u8; 16];
+    /// assert_eq!(rw.read_buffered_only(&mut rest), 4);
+    /// assert_eq!(&rest[..4], b"o Wo");
+    /// assert_eq!(rw.read_buffered_only(&mut rest), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_buffered_only(&mut self, buf: &mut [u8]) -> usize {
+        // Without IO, the prefetched data is only used if the position is in it
+        if let Some(position) = self.detached_position {
+            let Some(offset) = self.offset_in_window(position) else {
+                return 0;
+            };
+            self.buffer.pos = offset;
+            self.detached_position = None;
+        }
+        let n = self.buffer.num_readable_bytes_left().min(buf.len());
+        buf[..n].copy_from_slice(&self.buffer.data[self.buffer.pos..self.buffer.pos + n]);
+        self.buffer.pos += n;
+        n
+    }
+
+    /// Sets whether `self` writes its dirty data and flushes the inner stream when dropped
+    ///
+    /// When disabled, dropping `self` does not touch the inner stream,
+    /// so dirty data that was not explicitly written with [Self::flush_writes],
+    /// [Write::flush] or [Self::into_inner] is lost.
+    /// Combined with [Self::has_dirty_data], this allows to catch forgotten flushes.
+    ///
+    /// Defaults to `true`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Write;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::in_memory();
+    /// rw.set_flush_on_drop(false);
+    /// rw.write_all(b"data")?;
+    /// assert!(rw.has_dirty_data());
+    ///
+    /// // into_inner still writes the data
+    /// assert_eq!(rw.into_vec()?, b"data");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_flush_on_drop(&mut self, flush: bool) {
+        self.flush_on_drop = flush;
+    }
+
+    /// Sets whether the inner stream is flushed when `self` is dropped
+    ///
+    /// When dropped, dirty data is always written to the inner stream
+    /// (like [Self::flush_writes]), then the inner stream is flushed unless disabled here.
+    /// It is only flushed if something was written to it since it was last flushed
+    /// with [Write::flush], so dropping an adapter that only read does no IO.
+    ///
+    /// Defaults to `true`.
+    pub fn set_flush_inner_on_drop(&mut self, flush: bool) {
+        self.flush_inner_on_drop = flush;
+    }
+
+    /// Sets the policy to retry the reads and writes on the inner stream
+    /// that fail with transient errors, `None` to not retry them
+    ///
+    /// This covers the buffer fills and dumps, the reads and writes bypassing the buffer,
+    /// and the flushes of the inner stream.
+    /// Errors of kind [std::io::ErrorKind::Interrupted] are always retried, seeks included.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::{BufReaderWriter, RetryPolicy};
+    /// use std::io::ErrorKind;
+    /// use std::time::Duration;
+    ///
+    /// let mut rw = BufReaderWriter::in_memory();
+    /// rw.set_retry_policy(Some(RetryPolicy {
+    ///     kinds: &[ErrorKind::TimedOut],
+    ///     max_attempts: 3,
+    ///     backoff: Duration::from_millis(100),
+    ///     ..Default::default()
+    /// }));
+    /// assert_eq!(rw.retry_policy().unwrap().max_attempts, 3);
+    /// ```
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry = policy;
+    }
+
+    /// Returns the policy to retry the reads and writes on the inner stream, if any
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    /// Sets whether reading past the end of the inner stream, but before the end
+    /// of the furthest write, returns zeros instead of reaching the end of the stream
+    ///
+    /// This is what the gap left by a write past the end will contain once flushed.
+    ///
+    /// Defaults to `false`.
+    pub fn set_read_gaps_as_zero(&mut self, enabled: bool) {
+        self.read_gaps_as_zero = enabled;
+    }
+
+    /// Sets whether the writes go to the inner stream immediately,
+    /// while the cached data is kept for the reads
+    ///
+    /// In write-through mode, [Write::write] writes at the position directly to the
+    /// inner stream, and updates the cached bytes it overwrites so that the following
+    /// reads see them. The buffer is never dirty, so seeking never writes.
+    /// The dirty data is written when enabling it.
+    ///
+    /// The operations writing into the buffer in place, like [Self::write_reserve] and
+    /// [Self::commit] or [Self::swap_ranges], still buffer their data,
+    /// which is written when the next operation starts.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// # Errors
+    ///
+    /// If writing the dirty data fails, in which case the mode does not change.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"Hello World".to_vec()));
+    /// rw.set_write_through(true)?;
+    /// let mut hello = [0u8; 5];
+    /// rw.read_exact(&mut hello)?;
+    ///
+    /// rw.write_all(b"_")?;
+    /// assert_eq!(rw.inner().get_ref(), b"Hello_World");
+    ///
+    /// // The cached data has the write
+    /// rw.seek(SeekFrom::Start(0))?;
+    /// let mut data = String::new();
+    /// rw.read_to_string(&mut data)?;
+    /// assert_eq!(data, "Hello_World");
+    /// assert_eq!(rw.stats().fills, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_write_through(&mut self, enabled: bool) -> std::io::Result<()> {
+        if enabled {
+            self.flush_writes()?;
+        }
+        self.write_through = enabled;
+        Ok(())
+    }
+
+    /// Returns whether the writes go to the inner stream immediately,
+    /// see [Self::set_write_through]
+    pub fn is_write_through(&self) -> bool {
+        self.write_through
+    }
+
+    /// Flushes dirty data and returns the state needed to [resume](Self::resume)
+    /// the work later, possibly in another process
+    ///
+    /// The adapter stays usable after this call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![0u8; 100]), 64);
+    /// rw.seek(SeekFrom::Start(10))?;
+    /// rw.write_all(b"first half")?;
+    /// let state = rw.suspend()?;
+    /// let inner = rw.into_inner()?;
+    ///
+    /// // Later...
+    /// let mut rw = BufReaderWriter::resume(inner, state)?;
+    /// assert_eq!(rw.position(), 20);
+    /// assert_eq!(rw.capacity(), 64);
+    /// rw.write_all(b"second half")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn suspend(&mut self) -> std::io::Result<ResumeState> {
+        self.flush_writes()?;
+        Ok(ResumeState {
+            position: self.position(),
+            capacity: self.regular_capacity(),
+            buffering_paused: self.buffering_paused,
+            read_gaps_as_zero: self.read_gaps_as_zero,
+            flush_on_drop: self.flush_on_drop,
+            flush_inner_on_drop: self.flush_inner_on_drop,
+            write_through: self.write_through,
+            max_dirty: self.max_dirty,
+            read_bypass: self.buffer.read_bypass,
+            write_bypass: self.buffer.write_bypass,
+            read_capacity: self.read_capacity(),
+            write_capacity: self.write_capacity(),
+            min_fill: self.buffer.min_fill,
+            overflow_limit: self.overflow_limit,
+            alignment: self.alignment.map(|alignment| alignment.size()),
+            stats: self.stats(),
+        })
+    }
+
+    /// Temporarily disables buffering
+    ///
+    /// Dirty data is flushed and the cached data is dropped,
+    /// then all reads, writes and seeks go directly to the inner stream
+    /// until [Self::resume_buffering] is called.
+    ///
+    /// The position is still tracked, and the buffer allocation is kept.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"header")?;
+    ///
+    /// rw.pause_buffering()?;
+    /// // Goes straight to the cursor
+    /// rw.write_all(&[0u8; 4096])?;
+    /// assert_eq!(rw.inner().get_ref().len(), 4096 + 6);
+    /// rw.resume_buffering();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pause_buffering(&mut self) -> std::io::Result<()> {
+        if !self.buffering_paused {
+            self.discard_buffer()?;
+            self.buffering_paused = true;
+        }
+        Ok(())
+    }
+
+    /// Re-enables buffering after a call to [Self::pause_buffering]
+    pub fn resume_buffering(&mut self) {
+        self.buffering_paused = false;
+    }
+
+    /// Returns whether buffering is currently paused
+    pub fn is_buffering_paused(&self) -> bool {
+        self.buffering_paused
+    }
+
+    /// Reports the range currently held as dirty to the alias detection, if enabled
+    #[inline]
+    fn track_dirty_range(&self, _is_dirty: bool) {
+        #[cfg(feature = "alias-detection")]
+        if let Some(guard) = &self.alias_guard {
+            let start = self.start_position_in_source();
+            let end = if _is_dirty {
+                start + self.buffer.num_valid_bytes() as u64
+            } else {
+                start
+            };
+            guard.update(start..end);
+        }
+    }
+
+    /// Returns the length of the stream, including the buffered data not yet written
+    ///
+    /// The cached data and the position do not change. The length of the inner stream
+    /// is remembered once queried, as long as it is only modified through `self`,
+    /// otherwise the inner stream is seeked to its end then put back at its position.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"Hello".to_vec()));
+    /// rw.seek(SeekFrom::Start(3))?;
+    /// rw.write_all(b"p me")?;
+    /// assert_eq!(rw.stream_len()?, 7);
+    /// assert_eq!(rw.position(), 7);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream_len(&mut self) -> std::io::Result<u64> {
+        if let Some(len) = self.known_stream_len() {
+            return Ok(len);
+        }
+        self.inner_pos_lost = true;
+        let end = self.seek_inner(SeekFrom::End(0))?;
+        if end == self.pos {
+            self.inner_pos_lost = false;
+        } else {
+            self.seek_back()?;
+        }
+        self.known_len = Some(end);
+        Ok(end.max(self.cached_end()))
+    }
+
+    /// Returns the length of the stream if it is known without querying the inner stream
+    fn known_stream_len(&self) -> Option<u64> {
+        self.known_len.map(|len| len.max(self.cached_end()))
+    }
+
+    /// Returns the position in the source where the buffered data ends
+    ///
+    /// An empty buffer does not extend the stream, even when it sits past the end
+    /// after a seek or a [prefetch](Self::prefetch), so 0 is returned for it.
+    #[inline]
+    fn cached_end(&self) -> u64 {
+        match self.buffer.num_valid_bytes() {
+            0 => 0,
+            n => self.start_position_in_source() + n as u64,
+        }
+    }
+
+    /// Goes back to the regular capacity after staged writes, puts the inner stream back
+    /// after a previous failure, and handles the changes of the watched file, if any,
+    /// when an operation starts
+    #[inline]
+    fn check_inner_state(&mut self) -> std::io::Result<()> {
+        self.reserved = None;
+        self.release_overflow();
+        self.recover_inner_position()?;
+        // What was written in place since the last operation
+        if self.must_dump() {
+            self.dump_buffer()?;
+        }
+        if let Some((threshold, read)) = self.whole_file.take() {
+            // Tried again by the next operation if it fails
+            self.load_whole_file(threshold, read)
+                .inspect_err(|_| self.whole_file = Some((threshold, read)))?;
+        }
+        #[cfg(feature = "notify")]
+        if self.watch.as_ref().is_some_and(watch::Watch::take_stale) {
+            return self.on_external_change();
+        }
+        Ok(())
+    }
+
+    /// Returns whether the dirty data must be written without waiting for the buffer
+    /// to be full, see [Self::set_write_through] and [Self::set_max_dirty]
+    #[inline]
+    fn must_dump(&self) -> bool {
+        self.buffer.is_dirty()
+            && (self.write_through || self.buffer.dirty.len() >= self.max_dirty)
+    }
+
+    /// Loads the whole stream in the buffer on the first access after
+    /// [Self::set_whole_file_threshold], if its length is at most `threshold`
+    fn load_whole_file(&mut self, threshold: u64, read: aligned::ReadFn<T>) -> std::io::Result<()> {
+        if self.buffering_paused {
+            return Ok(());
+        }
+
+        let position = self.position();
+        let len = self.stream_len()?;
+        // An inline buffer cannot grow
+        if len > threshold || len > isize::MAX as u64 || (N != 0 && len > N as u64) {
+            return Ok(());
+        }
+
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        if self.pos != 0 {
+            self.seek_inner(SeekFrom::Start(0))?;
+            self.pos = 0;
+        }
+        self.buffer.clear();
+        self.n = 0;
+        if len as usize > self.buffer.capacity() {
+            let previous = self.buffer.capacity();
+            self.grow_buffer(len as usize);
+            self.buffer.follow_capacity(previous);
+            self.overflow_capacity = None;
+        }
+        // Limited to the length so that the end is not probed with another read
+        let mut source = aligned::ReadWith(&mut self.inner, read);
+        let source = retry::Retrying {
+            inner: &mut source,
+            policy: self.retry.as_ref(),
+        };
+        let n = self.buffer.fill_all_from(source.take(len))?;
+        self.stats.fill(n);
+        self.pos = n as u64;
+        self.n = n;
+        self.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+
+    /// Drops the data [BufRead::fill_buf] may have cached while buffering was paused
+    #[inline]
+    fn leave_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.num_valid_bytes() != 0 {
+            self.discard_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes dirty data and drops the cached data
+    ///
+    /// After this, the inner stream is positioned at the logical position
+    fn discard_buffer(&mut self) -> std::io::Result<()> {
+        self.recover_inner_position()?;
+        let position = self.position();
+        if self.buffer.is_dirty() {
+            self.write_dirty()?;
+        }
+        if self.pos != position || self.inner_pos_lost {
+            self.pos = self.seek_inner(SeekFrom::Start(position))?;
+            self.inner_pos_lost = false;
+        }
+        self.buffer.clear();
+        self.n = 0;
+        Ok(())
+    }
+
+    /// Writes `data` directly to the inner stream at the current position, bypassing the cache
+    ///
+    /// Meant for one-shot writes that must not be delayed or are not worth caching,
+    /// whatever their size.
+    /// The cached data is dropped, after being written if dirty, so that it cannot
+    /// become stale or overwrite `data` later.
+    ///
+    /// Like [Write::write], returns how many bytes the inner stream accepted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"header ")?;
+    /// assert_eq!(rw.write_uncached(b"payload")?, 7);
+    /// assert_eq!(rw.inner().get_ref(), b"header payload");
+    /// assert_eq!(rw.position(), 14);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_uncached(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.save_original(self.position(), data.len())?;
+        self.write_direct(data)
+    }
+
+    /// Returns `n` bytes of the buffer at the position, to write into them directly,
+    /// see [Self::commit]
+    ///
+    /// The dirty data is written first if there is not enough room after the position.
+    /// The content of the returned bytes is unspecified. Nothing is written until
+    /// [Self::commit] is called, which must be done before any other operation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput]
+    /// if `n` is larger than the capacity.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(Vec::new()));
+    /// rw.write_all(b"len:")?;
+    /// let space = rw.write_reserve(8)?;
+    /// space[..4].copy_from_slice(&42u32.to_le_bytes());
+    /// rw.commit(4)?;
+    /// assert_eq!(rw.into_vec()?, b"len:\x2a\0\0\0");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_reserve(&mut self, n: usize) -> std::io::Result<&mut [u8]> {
+        if n > self.regular_capacity() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "reservation is larger than the capacity",
+            ));
+        }
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        self.save_original(self.position(), n)?;
+        if self.buffering_paused {
+            self.leave_buffer()?;
+        } else if self.buffer.num_writable_bytes_left() < n {
+            self.discard_buffer()?;
+        } else if self.buffer.exceeds_write_capacity(n) {
+            self.dump_buffer()?;
+        }
+        self.reserved = Some((self.position(), n));
+        let from = self.buffer.pos;
+        Ok(&mut self.buffer.data[from..from + n])
+    }
+
+    /// Writes the first `written` bytes reserved by [Self::write_reserve],
+    /// moving the position after them
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput], and writes nothing,
+    /// if `written` is more than the bytes reserved by the last call to
+    /// [Self::write_reserve], or if another operation was made since.
+    pub fn commit(&mut self, written: usize) -> std::io::Result<()> {
+        let reserved = match self.reserved.take() {
+            Some((position, n)) if position == self.position() => n,
+            _ => 0,
+        };
+        if written > reserved {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("committing {written} bytes, but {reserved} bytes are reserved"),
+            ));
+        }
+        if written == 0 {
+            return Ok(());
+        }
+        // While buffering is paused, the data is written by the next operation
+        self.buffer.commit(written);
+        self.track_dirty_range(true);
+        self.high_water = self.high_water.max(self.position());
+        Ok(())
+    }
+
+    /// Writes `n` zero bytes, filling the internal buffer with them instead of
+    /// allocating a temporary
+    ///
+    /// The buffer is written to the inner stream each time it is full.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 16);
+    /// rw.write_all(b"slot")?;
+    /// rw.write_zeros(100)?;
+    /// assert_eq!(rw.position(), 104);
+    /// assert_eq!(rw.into_vec()?.iter().filter(|b| **b == 0).count(), 100);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_zeros(&mut self, mut n: u64) -> std::io::Result<()> {
+        if self.buffer.capacity() == 0 || self.write_through {
+            return std::io::copy(&mut std::io::repeat(0).take(n), self).map(|_| ());
+        }
+        while n != 0 {
+            let room = match self.buffer.num_writable_bytes_left() {
+                0 => self.regular_capacity(),
+                room => room.min(self.regular_capacity()),
+            };
+            let k = n.min(room as u64) as usize;
+            self.write_reserve(k)?.fill(0);
+            self.commit(k)?;
+            n -= k as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies up to `n` bytes from `src`, reading them directly into the internal buffer
+    ///
+    /// The buffer is written to the inner stream each time it is full,
+    /// so large copies go to the inner stream in capacity-sized writes.
+    ///
+    /// Returns the number of bytes copied, which is less than `n`
+    /// if the end of `src` was reached.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Seek, SeekFrom};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// rw.seek(SeekFrom::Start(6))?;
+    /// assert_eq!(rw.copy_from(&mut &b"Rust!!"[..], 5)?, 5);
+    /// assert_eq!(rw.copy_from(&mut &b""[..], 5)?, 0);
+    /// assert_eq!(rw.into_vec()?, b"Hello Rust!");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn copy_from<R: Read>(&mut self, src: &mut R, n: u64) -> std::io::Result<u64> {
+        if self.buffer.capacity() == 0 || self.write_through {
+            return std::io::copy(&mut src.take(n), self);
+        }
+        let mut copied = 0;
+        while copied < n {
+            let room = match self.buffer.num_writable_bytes_left() {
+                0 => self.regular_capacity(),
+                room => room.min(self.regular_capacity()),
+            };
+            let k = (n - copied).min(room as u64) as usize;
+            let num_read = match src.read(self.write_reserve(k)?) {
+                Ok(0) => break,
+                Ok(num_read) => num_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.commit(num_read)?;
+            copied += num_read as u64;
+        }
+        Ok(copied)
+    }
+
+    /// Writes a piece of formatted text, directly into the buffer when it fits
+    fn write_fragment(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if self.buffering_paused
+            || self.write_through
+            || self.undo.is_some()
+            || data.len() > self.buffer.num_writable_bytes_left()
+            || self.buffer.exceeds_write_capacity(data.len())
+        {
+            return self.write_all(data);
+        }
+        self.buffer.write(data)?;
+        self.track_dirty_range(true);
+        self.high_water = self.high_water.max(self.position());
+        if self.must_dump() {
+            // The bytes are buffered, so a failure shows at the next operation
+            let _ = self.dump_buffer();
+        }
+        Ok(())
+    }
+
+    fn write_direct(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.discard_buffer()?;
+        let n = retrying!(self).write(data)?;
+        self.stats.direct_write(n);
+        self.pos += n as u64;
+        if let Some(len) = &mut self.known_len {
+            *len = (*len).max(self.pos);
+        }
+        self.high_water = self.high_water.max(self.pos);
+        Ok(n)
+    }
+
+    /// Writes at the position directly to the inner stream, updating the cached bytes
+    /// it overwrites, see [Self::set_write_through]
+    fn write_through(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        debug_assert!(!self.buffer.is_dirty());
+        let position = self.position();
+        // A clean buffer leaves the inner stream at its end, not at the position
+        self.inner_pos_lost = true;
+        if self.pos != position && self.seek_inner(SeekFrom::Start(position))? != position {
+            return Err(std::io::Error::other(
+                "inner stream did not seek to the position",
+            ));
+        }
+        let n = retrying!(self).write(data)?;
+        self.stats.direct_write(n);
+
+        let (from, to) = (self.buffer.pos, self.buffer.pos + n);
+        if to <= self.buffer.capacity() {
+            let start = self.start_position_in_source();
+            self.buffer.data[from..to].copy_from_slice(&data[..n]);
+            self.buffer.pos = to;
+            // The written bytes extend the cached data
+            if to >= self.buffer.filled {
+                self.buffer.filled = to;
+                self.n = to;
+                self.pos = start + to as u64;
+            }
+        } else {
+            self.buffer.clear();
+            self.n = 0;
+            self.pos = position + n as u64;
+        }
+        // Otherwise, the inner stream goes back to the end of the buffer lazily
+        self.inner_pos_lost = self.pos != position + n as u64;
+        if let Some(len) = &mut self.known_len {
+            *len = (*len).max(position + n as u64);
+        }
+        Ok(n)
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Reads directly from the inner stream at the current position, bypassing the cache
+    ///
+    /// Meant for one-shot reads of data that will not be read again, whatever their size.
+    /// The cached data is dropped, after being written if dirty,
+    /// so that the read sees the data written through `self`.
+    ///
+    /// Like [Read::read], returns how many bytes the inner stream returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"Hello World".to_vec()));
+    /// rw.write_all(b"Jello")?;
+    /// rw.seek(SeekFrom::Start(0))?;
+    /// let mut blob = [0u8; 11];
+    /// assert_eq!(rw.read_uncached(&mut blob)?, 11);
+    /// assert_eq!(&blob, b"Jello World");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_uncached(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.discard_buffer()?;
+        let n = retrying!(self).read(buf)?;
+        self.stats.direct_read(n);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Puts back `data`, the bytes that were just read, so that the next reads return them again
+    ///
+    /// The position moves back by `data.len()`. When the bytes are still cached,
+    /// only the position in the buffer moves. When they start before the cached data,
+    /// the missing ones are staged in front of it if there is room in the buffer,
+    /// otherwise the inner stream is seeked back.
+    ///
+    /// `data` must be the bytes that are at that position in the stream,
+    /// which is only checked against the cached data in debug builds.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if the position
+    /// is less than `data.len()`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"123abc".to_vec()));
+    /// let mut bytes = [0u8; 4];
+    /// rw.read_exact(&mut bytes)?;
+    /// // One byte too many
+    /// rw.unread(&bytes[3..])?;
+    /// let mut rest = String::new();
+    /// rw.read_to_string(&mut rest)?;
+    /// assert_eq!(rest, "abc");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn unread(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.check_inner_state()?;
+        let len = data.len();
+        if len as u64 > self.position() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "unreading before the start of the stream",
+            ));
+        }
+        if len <= self.buffer.pos {
+            let from = self.buffer.pos - len;
+            debug_assert_eq!(
+                &self.buffer.data[from..self.buffer.pos],
+                data,
+                "unread bytes differ from the cached ones"
+            );
+            self.buffer.pos = from;
+            return Ok(());
+        }
+
+        let missing = len - self.buffer.pos;
+        let filled = self.buffer.filled;
+        if self.buffering_paused || missing + filled > self.buffer.capacity() {
+            return self.seek(SeekFrom::Current(-(len as i64))).map(|_| ());
+        }
+        debug_assert_eq!(
+            &self.buffer.data[..self.buffer.pos],
+            &data[missing..],
+            "unread bytes differ from the cached ones"
+        );
+        self.buffer.data.copy_within(0..filled, missing);
+        self.buffer.data[..missing].copy_from_slice(&data[..missing]);
+        self.buffer.filled += missing;
+        self.buffer.pos = 0;
+        if self.buffer.is_dirty() {
+            let dirty = &mut self.buffer.dirty;
+            *dirty = dirty.start + missing..dirty.end + missing;
+        }
+        // The cached data now starts earlier
+        self.n += missing;
+        self.track_dirty_range(self.buffer.is_dirty());
+        Ok(())
+    }
+
+    /// Skips the next `n` bytes, without copying them out
+    ///
+    /// The bytes are consumed from the cached data, filling the buffer again as needed,
+    /// so skipping small paddings never costs a seek of the inner stream.
+    /// When `n` is more than 4 times the capacity, the inner stream is seeked instead.
+    ///
+    /// # Errors
+    ///
+    /// Unlike a seek, returns an error of kind [std::io::ErrorKind::UnexpectedEof]
+    /// if the stream ends first, in which case the position is at the end of the stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"tag\0\0\0\0\0value".to_vec()));
+    /// rw.skip(8)?;
+    /// let mut value = String::new();
+    /// rw.read_to_string(&mut value)?;
+    /// assert_eq!(value, "value");
+    /// assert!(rw.skip(1).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn skip(&mut self, mut n: u64) -> std::io::Result<()> {
+        if n > 4 * self.buffer.capacity() as u64 {
+            let len = self.stream_len()?;
+            let target = self.position().saturating_add(n);
+            self.seek(SeekFrom::Start(target.min(len)))?;
+            if target > len {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(());
+        }
+        while n != 0 {
+            let available = self.fill_buf()?.len();
+            if available == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let k = n.min(available as u64) as usize;
+            self.consume(k);
+            n -= k as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies up to the next `n` bytes to `dst`, using the internal buffer as the staging area
+    ///
+    /// The cached bytes, including the ones written but not flushed yet, are copied first,
+    /// then the buffer is filled again as needed.
+    ///
+    /// Returns the number of bytes copied, which is less than `n`
+    /// if the end of the stream was reached.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// rw.write_all(b"J")?;
+    /// rw.rewind()?;
+    /// let mut chunk = Vec::new();
+    /// assert_eq!(rw.copy_to(&mut chunk, 5)?, 5);
+    /// assert_eq!(chunk, b"Jello");
+    /// assert_eq!(rw.copy_to(&mut chunk, 100)?, 6);
+    /// assert_eq!(chunk, b"Jello World");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn copy_to<W: Write>(&mut self, dst: &mut W, n: u64) -> std::io::Result<u64> {
+        if self.buffer.capacity() == 0 {
+            return std::io::copy(&mut self.take(n), dst);
+        }
+        let mut copied = 0;
+        while copied < n {
+            let available = self.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let k = (n - copied).min(available.len() as u64) as usize;
+            dst.write_all(&available[..k])?;
+            self.consume(k);
+            copied += k as u64;
+        }
+        Ok(copied)
+    }
+
+    /// Fills the buffer from the position ahead of the first read,
+    /// so that the latency of the inner stream is paid now
+    ///
+    /// Does nothing if cached data is left to read or if the buffer is dirty.
+    /// Returns the number of cached bytes that can be read from the position,
+    /// like [Self::buffered_len].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(vec![7u8; 100]));
+    /// assert_eq!(rw.preload()?, 100);
+    ///
+    /// let mut buf = [0u8; 10];
+    /// rw.read_exact(&mut buf)?;
+    /// assert_eq!(rw.stats().fills, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn preload(&mut self) -> std::io::Result<usize> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        if !self.buffering_paused
+            && !self.buffer.is_dirty()
+            && !self.buffer.has_readable_bytes_left()
+        {
+            self.refill()?;
+        }
+        Ok(self.buffered_len())
+    }
+
+    /// Fills the buffer with the data at `offset`, up to `len` bytes or the capacity,
+    /// ahead of a seek there, without changing the position
+    ///
+    /// Does nothing if the data is already cached. Otherwise, the dirty data is written,
+    /// and the cached data is replaced. A following seek into the prefetched data,
+    /// then reads from it, do not reach the inner stream.
+    /// Any other operation drops the prefetched data if the position is not in it,
+    /// and puts back the inner stream at the position.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Seek, SeekFrom};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let data = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(data), 4096);
+    /// rw.prefetch(50_000, 100)?;
+    /// assert_eq!(rw.position(), 0);
+    ///
+    /// // Later, once the index says so
+    /// let fills = rw.stats().fills;
+    /// rw.seek(SeekFrom::Start(50_000))?;
+    /// let mut record = [0u8; 100];
+    /// rw.read_exact(&mut record)?;
+    /// assert_eq!(rw.stats().fills, fills);
+    /// assert_eq!(record[0], (50_000 % 256) as u8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prefetch(&mut self, offset: u64, len: usize) -> std::io::Result<()> {
+        self.check_inner_state()?;
+        let len = len.min(self.capacity());
+        let start = self.start_position_in_source();
+        if self.buffering_paused
+            || len == 0
+            || (offset >= start
+                && offset.saturating_add(len as u64)
+                    <= start + self.buffer.num_valid_bytes() as u64)
+        {
+            return Ok(());
+        }
+
+        let position = self.position();
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        if let Err(e) = self.fill_window_at(offset) {
+            self.buffer.clear();
+            self.pos = position;
+            self.n = 0;
+            self.inner_pos_lost = true;
+            return Err(e);
+        }
+        self.detached_position = Some(position);
+        Ok(())
+    }
+
+    /// Copies the next bytes into `buf`, without consuming them
+    ///
+    /// The buffer is filled as needed, so fewer bytes than requested are returned only
+    /// at the end of the stream. At most the capacity is peeked,
+    /// larger `buf`s are only partially filled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"PK\x03\x04rest".to_vec()));
+    /// let mut magic = [0u8; 4];
+    /// assert_eq!(rw.peek(&mut magic)?, 4);
+    /// assert_eq!(&magic, b"PK\x03\x04");
+    /// assert_eq!(rw.position(), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.regular_capacity());
+        let available = self.fill_at_least(n)?;
+        let n = n.min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        Ok(n)
+    }
+
+    /// Copies the bytes at `offset` into `buf`, without changing the position
+    ///
+    /// The bytes are taken from the cached data when it holds them, dirty or not.
+    /// Otherwise they are read from the inner stream, which is then put back,
+    /// and the cached data that overlaps them replaces them, so that pending writes are seen.
+    /// Neither the cached data nor the position change.
+    ///
+    /// Returns the number of bytes copied, fewer than requested only at the end of the stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::new(Cursor::new(b"v1 header, data".to_vec()));
+    /// rw.seek(SeekFrom::Start(11))?;
+    /// rw.write_all(b"DATA")?;
+    ///
+    /// let mut bytes = [0u8; 6];
+    /// assert_eq!(rw.peek_at(9, &mut bytes)?, 6);
+    /// assert_eq!(&bytes, b", DATA");
+    /// assert_eq!(rw.position(), 15);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn peek_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        let start = self.start_position_in_source();
+        let end = start + self.buffer.num_valid_bytes() as u64;
+        let peek_end = offset.saturating_add(buf.len() as u64);
+        if start <= offset && peek_end <= end {
+            let from = (offset - start) as usize;
+            buf.copy_from_slice(&self.buffer.data[from..from + buf.len()]);
+            return Ok(buf.len());
+        }
+
+        self.inner_pos_lost = true;
+        self.seek_inner(SeekFrom::Start(offset))?;
+        let mut read_full = || -> std::io::Result<usize> {
+            let mut k = 0;
+            while k < buf.len() {
+                match retrying!(self).read(&mut buf[k..]) {
+                    Ok(0) => break,
+                    Ok(n) => k += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(k)
+        };
+        let result = read_full();
+        self.seek_back()?;
+        let mut k = result?;
+        self.stats.direct_read(k);
+
+        // Past the end of the inner stream, writing the cached data leaves zeros up to it
+        let zeros_end = peek_end.min(self.cached_end());
+        if offset + (k as u64) < zeros_end {
+            let zeros_end = (zeros_end - offset) as usize;
+            buf[k..zeros_end].fill(0);
+            k = zeros_end;
+        }
+        // The pending writes replace what the inner stream has
+        let (from, to) = (offset.max(start), peek_end.min(end));
+        if from < to {
+            let (from, to) = ((from - offset) as usize, (to - offset) as usize);
+            let cached = (offset + from as u64 - start) as usize;
+            buf[from..to].copy_from_slice(&self.buffer.data[cached..cached + to - from]);
+            k = k.max(to);
+        }
+        Ok(k)
+    }
+
+    /// Like [Self::fill_buf_min], but returns the cached data when the stream ends first
+    ///
+    /// The returned data is at least `n` bytes long, unless the end of the stream was reached.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [ErrorKind::InvalidInput](std::io::ErrorKind::InvalidInput)
+    /// if `n` is larger than the capacity.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{BufRead, Cursor};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+    /// let len = rw.fill_at_least(6)?.len();
+    /// rw.consume(len);
+    /// assert_eq!(rw.fill_at_least(4)?, b"89");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fill_at_least(&mut self, n: usize) -> std::io::Result<&[u8]> {
+        match self.fill_buf_min(n) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Ok(&self.buffer.data[self.buffer.pos..self.buffer.filled])
+            }
+            Err(e) => Err(e),
+            // Returning the slice of the Ok case conditionally does not pass the borrow checker
+            Ok(_) => Ok(&self.buffer.data[self.buffer.pos..self.buffer.filled]),
+        }
+    }
+
+    /// Like [BufRead::fill_buf], but the returned data is at least `n` bytes long
+    ///
+    /// When fewer than `n` bytes are cached past the position, they are moved
+    /// to the front of the buffer (after writing the dirty data), then the buffer
+    /// is topped up from the inner stream. Use [BufRead::consume] to consume the data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [ErrorKind::InvalidInput](std::io::ErrorKind::InvalidInput)
+    /// if `n` is larger than the capacity, and of kind
+    /// [ErrorKind::UnexpectedEof](std::io::ErrorKind::UnexpectedEof) if the stream ends first,
+    /// in which case the data read is still cached.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{BufRead, Cursor, Read};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+    /// let mut bytes = [0u8; 6];
+    /// rw.read_exact(&mut bytes)?;
+    /// // Only "67" is cached
+    /// let header = rw.fill_buf_min(4)?;
+    /// assert_eq!(&header[..4], b"6789");
+    /// rw.consume(4);
+    /// assert_eq!(rw.position(), 10);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fill_buf_min(&mut self, n: usize) -> std::io::Result<&[u8]> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        if n > self.buffer.capacity() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "lookahead is larger than the capacity",
+            ));
+        }
+        if self.buffer.num_readable_bytes_left() < n {
+            if self.buffer.is_dirty() {
+                self.dump_buffer()?;
+            }
+            let cache_end = self.start_position_in_source() + self.buffer.num_valid_bytes() as u64;
+            if self.pos != cache_end {
+                self.seek_inner(SeekFrom::Start(cache_end))?;
+                self.pos = cache_end;
+            }
+            let tail = self.buffer.pos..self.buffer.filled;
+            self.buffer.data.copy_within(tail.clone(), 0);
+            self.buffer.pos = 0;
+            self.buffer.filled = tail.len();
+            self.n = tail.len();
+
+            while self.buffer.filled < n {
+                let end = self.buffer.fill_end();
+                let k = retrying!(self).read(&mut self.buffer.data[self.buffer.filled..end])?;
+                if k > end - self.buffer.filled {
+                    return Err(std::io::Error::other(
+                        "inner stream reported reading more bytes than requested",
+                    ));
+                }
+                self.stats.fill(k);
+                self.buffer.filled += k;
+                self.n += k;
+                self.pos += k as u64;
+                if k == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        Ok(&self.buffer.data[self.buffer.pos..self.buffer.filled])
+    }
+
+    /// Reads `n` bytes without copying them, returning them from the internal buffer
+    ///
+    /// Like [Self::fill_buf_min], the bytes are made contiguous in the buffer,
+    /// then they are consumed. The returned slice borrows `self`,
+    /// so it cannot be kept across the next operation.
+    ///
+    /// # Errors
+    ///
+    /// Same as [Self::fill_buf_min]: if `n` is larger than the capacity, or of kind
+    /// [ErrorKind::UnexpectedEof](std::io::ErrorKind::UnexpectedEof) if the stream ends
+    /// first, in which case nothing is consumed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Cursor;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(b"aaaabbbbcccc".to_vec()), 6);
+    /// assert_eq!(rw.read_in_place(4)?, b"aaaa");
+    /// assert_eq!(rw.read_in_place(4)?, b"bbbb");
+    /// assert_eq!(rw.position(), 8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_in_place(&mut self, n: usize) -> std::io::Result<&[u8]> {
+        self.fill_buf_min(n)?;
+        let start = self.buffer.pos;
+        self.buffer.pos += n;
+        Ok(&self.buffer.data[start..start + n])
+    }
+
+    /// Exchanges the `len` bytes starting at `a` with the `len` bytes starting at `b`
+    ///
+    /// When both ranges are in the cached data, the swap is done in memory.
+    /// Otherwise, the ranges are streamed in chunks through the internal buffer,
+    /// each range using half of its capacity.
+    ///
+    /// The position is restored afterward.
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if the ranges overlap,
+    /// and of kind [std::io::ErrorKind::UnexpectedEof] if a range goes past the end of the stream.
+    /// If the inner stream fails while streaming, the ranges may be partially swapped
+    /// and the inner stream is put back on the next operation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"HelloWorld".to_vec());
+    /// rw.swap_ranges(0, 5, 5)?;
+    /// assert_eq!(rw.into_vec()?, b"WorldHello");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn swap_ranges(&mut self, a: u64, b: u64, len: u64) -> std::io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let (Some(a_end), Some(b_end)) = (a.checked_add(len), b.checked_add(len)) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "range end overflows",
+            ));
+        };
+        if a < b_end && b < a_end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot swap overlapping ranges",
+            ));
+        }
+        self.check_inner_state()?;
+
+        let start = self.start_position_in_source();
+        let end = start + self.buffer.num_valid_bytes() as u64;
+        if start <= a.min(b) && a_end.max(b_end) <= end {
+            self.save_original(a, len as usize)?;
+            self.save_original(b, len as usize)?;
+            let (low, high) = ((a.min(b) - start) as usize, (a.max(b) - start) as usize);
+            let (first, second) = self.buffer.data[..high + len as usize].split_at_mut(high);
+            first[low..low + len as usize].swap_with_slice(&mut second[..len as usize]);
+            self.buffer.mark_dirty(low..high + len as usize);
+            self.track_dirty_range(true);
+            return Ok(());
+        }
+
+        // Nothing is written unless both ranges are entirely in the stream
+        if a_end.max(b_end) > self.stream_len()? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "range goes past the end of the stream",
+            ));
+        }
+        self.save_original(a, len as usize)?;
+        self.save_original(b, len as usize)?;
+        self.discard_buffer()?;
+
+        self.inner_pos_lost = true;
+        let result = self.swap_chunks(a, b, len);
+
+        // Whatever happened, put the inner stream back where we expect it
+        self.seek_back()?;
+        result
+    }
+
+    /// Swaps the ranges in the inner stream chunk by chunk
+    ///
+    /// The buffer is empty, so it is used as staging area, each range using half of it.
+    fn swap_chunks(&mut self, a: u64, b: u64, len: u64) -> std::io::Result<()> {
+        let mut fallback = [0u8; 2];
+        let in_buffer = self.buffer.capacity() >= 2;
+        let half = if in_buffer { self.buffer.capacity() / 2 } else { 1 };
+
+        let mut done = 0;
+        while done < len {
+            let k = (len - done).min(half as u64) as usize;
+            for (from, to) in [(a + done, 0), (b + done, half)] {
+                self.seek_inner(SeekFrom::Start(from))?;
+                let staging = if in_buffer { &mut self.buffer.data[..] } else { &mut fallback[..] };
+                retrying!(self).read_exact(&mut staging[to..to + k])?;
+                self.stats.direct_read(k);
+            }
+            for (to, from) in [(b + done, 0), (a + done, half)] {
+                self.seek_inner(SeekFrom::Start(to))?;
+                let staging = if in_buffer { &self.buffer.data[..] } else { &fallback[..] };
+                retrying!(self).write_all(&staging[from..from + k])?;
+                self.stats.direct_write(k);
+            }
+            done += k as u64;
+        }
+        Ok(())
+    }
+
+    /// Loads the whole stream in memory if its length is at most `threshold`
+    ///
+    /// When it is loaded, reads, writes and seeks within the stream are served from memory,
+    /// and the data is written back in one write when flushed or dropped.
+    /// The buffer grows to the length of the stream if needed, except an inline buffer,
+    /// growing the stream beyond the buffer falls back to the normal operation.
+    ///
+    /// The stream is loaded by the next read, write or seek, if its length is
+    /// at most `threshold` then.
+    /// `None` disables the mode, a stream already loaded stays in the buffer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![0u8; 2048]), 512);
+    /// rw.set_whole_file_threshold(Some(4096));
+    /// assert_eq!(rw.capacity(), 512);
+    ///
+    /// rw.seek(SeekFrom::Start(1500))?;
+    /// assert_eq!(rw.capacity(), 2048);
+    /// rw.write_all(b"patched")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_whole_file_threshold(&mut self, threshold: Option<u64>) {
+        self.whole_file = threshold.map(|threshold| {
+            (threshold, (|inner, buf| inner.read(buf)) as aligned::ReadFn<T>)
+        });
+    }
+
+    /// When reading gaps as zeros, turns reaching the end of the inner stream
+    /// before the end of the furthest write into reading zeros
+    fn read_gap_if_eof(&mut self, buf: &mut [u8], n: usize) -> std::io::Result<usize> {
+        if n != 0 || buf.is_empty() || !self.read_gaps_as_zero {
+            return Ok(n);
+        }
+        let position = self.position();
+        if position >= self.high_water {
+            return Ok(0);
+        }
+        let k = (self.high_water - position).min(buf.len() as u64) as usize;
+        buf[..k].fill(0);
+        self.seek(SeekFrom::Start(position + k as u64))?;
+        Ok(k)
+    }
+
+    /// Returns an iterator over the records of `record_size` bytes of the stream,
+    /// starting from the last one
+    ///
+    /// The buffer is filled in windows that end with the next record to yield,
+    /// so that each fill serves `capacity / record_size` records,
+    /// instead of having one fill per record as seeking backward would.
+    ///
+    /// If `record_size` is 0, the iterator yields an error of kind
+    /// [std::io::ErrorKind::InvalidInput].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"aabbcc".to_vec());
+    /// let records = rw.rev_records(2).collect::<std::io::Result<Vec<_>>>()?;
+    /// assert_eq!(records, [b"cc", b"bb", b"aa"]);
+    /// assert_eq!(rw.position(), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rev_records(&mut self, record_size: usize) -> RevRecords<'_, T, N> {
+        RevRecords::new(self, record_size)
+    }
+
+    /// Writes each `(offset, bytes)` patch at its offset, in a single pass over the stream
+    ///
+    /// The patches are sorted by offset, then the patches that fall into the
+    /// same buffer window are applied in memory, so that each window is read
+    /// and written once, instead of once per patch.
+    ///
+    /// The position is restored afterward, the last window stays buffered.
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if patches overlap,
+    /// in which case nothing is written.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// rw.apply_patches(&mut [(6, b"Earth".to_vec()), (0, b"J".to_vec())])?;
+    /// assert_eq!(rw.into_vec()?, b"Jello Earth");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn apply_patches(&mut self, patches: &mut [(u64, Vec<u8>)]) -> std::io::Result<()> {
+        patches.sort_by_key(|(offset, _)| *offset);
+
+        let mut previous_end = 0;
+        for (offset, bytes) in patches.iter() {
+            if *offset < previous_end {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "cannot apply overlapping patches",
+                ));
+            }
+            previous_end = offset.checked_add(bytes.len() as u64).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "patch end overflows")
+            })?;
+        }
+
+        self.check_inner_state()?;
+        let position = self.position();
+        for (offset, bytes) in patches.iter() {
+            let (offset, len) = (*offset, bytes.len());
+            if len == 0 {
+                continue;
+            }
+            if self.buffering_paused || len >= self.buffer.capacity() {
+                self.seek(SeekFrom::Start(offset))?;
+                self.write_all(bytes)?;
+                continue;
+            }
+
+            self.save_original(offset, len)?;
+            let mut start = self.start_position_in_source();
+            if offset < start
+                || offset > start + self.buffer.num_valid_bytes() as u64
+                || offset + len as u64 > start + self.buffer.capacity() as u64
+            {
+                self.fill_window_at(offset)?;
+                start = offset;
+            }
+
+            let o = (offset - start) as usize;
+            self.buffer.data[o..o + len].copy_from_slice(bytes);
+            self.buffer.filled = self.buffer.filled.max(o + len);
+            self.buffer.mark_dirty(o..o + len);
+            self.track_dirty_range(true);
+        }
+        self.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+
+    /// Fills each `(offset, buf)` request with the bytes at its offset
+    ///
+    /// The requests are sorted by offset, then the requests that fall into the
+    /// same buffer window are served by a single fill.
+    /// Data written but not yet flushed is observed.
+    ///
+    /// The position is restored afterward, also when a request fails.
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::UnexpectedEof] if a request
+    /// goes past the end of the stream, and of kind [std::io::ErrorKind::InvalidInput]
+    /// if the end of a request overflows, in which case nothing is read.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// let (mut a, mut b) = ([0u8; 3], [0u8; 2]);
+    /// rw.read_scattered(&mut [(8, &mut a), (2, &mut b)])?;
+    /// assert_eq!((&a, &b), (b"rld", b"ll"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_scattered(&mut self, requests: &mut [(u64, &mut [u8])]) -> std::io::Result<()> {
+        requests.sort_by_key(|(offset, _)| *offset);
+        if requests
+            .iter()
+            .any(|(offset, buf)| offset.checked_add(buf.len() as u64).is_none())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "request end overflows",
+            ));
+        }
+
+        let position = self.position();
+        let result = self.read_sorted_requests(requests);
+        let restored = self.seek(SeekFrom::Start(position));
+        result?;
+        restored?;
+        Ok(())
+    }
+
+    /// Fills the requests of [Self::read_scattered], sorted by offset
+    fn read_sorted_requests(&mut self, requests: &mut [(u64, &mut [u8])]) -> std::io::Result<()> {
+        for (offset, buf) in requests.iter_mut() {
+            let offset = *offset;
+            if buf.is_empty() {
+                continue;
+            }
+            if self.buffering_paused || buf.len() >= self.buffer.capacity() {
+                self.seek(SeekFrom::Start(offset))?;
+                self.read_exact(buf)?;
+                continue;
+            }
+
+            let mut start = self.start_position_in_source();
+            let end = offset + buf.len() as u64;
+            if offset < start || end > start + self.buffer.num_valid_bytes() as u64 {
+                self.fill_window_at(offset)?;
+                start = offset;
+                if self.buffer.num_valid_bytes() < buf.len() {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+
+            let o = (offset - start) as usize;
+            buf.copy_from_slice(&self.buffer.data[o..o + buf.len()]);
+        }
+        Ok(())
+    }
+
+    /// Transforms in place the data from the current position to the end of the stream
+    ///
+    /// The data is handed to `f` in chunks of `chunk_size` bytes, along with their offset
+    /// (only the last chunk can be shorter).
+    /// Chunks are handed directly in the internal buffer when they fit in it,
+    /// and only the windows in which `f` modified a chunk are written back.
+    ///
+    /// Returns the number of bytes processed, after which the position is at the end of the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if `chunk_size` is 0
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// let n = rw.rewrite_with(4, |_, chunk| {
+    ///     chunk.make_ascii_uppercase();
+    ///     Ok(())
+    /// })?;
+    /// assert_eq!(n, 11);
+    /// assert_eq!(rw.into_vec()?, b"HELLO WORLD");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rewrite_with(
+        &mut self,
+        chunk_size: usize,
+        mut f: impl FnMut(u64, &mut [u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<u64> {
+        if chunk_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "chunk size must be non-zero",
+            ));
+        }
+        self.check_inner_state()?;
+        let start = self.position();
+        let mut position = start;
+        // To detect chunks left unmodified
+        let mut original = vec![0u8; chunk_size];
+
+        if self.buffering_paused || chunk_size > self.buffer.capacity() {
+            let mut chunk = vec![0u8; chunk_size];
+            loop {
+                let mut k = 0;
+                while k < chunk_size {
+                    match self.read(&mut chunk[k..]) {
+                        Ok(0) => break,
+                        Ok(n) => k += n,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if k == 0 {
+                    break;
+                }
+
+                original[..k].copy_from_slice(&chunk[..k]);
+                f(position, &mut chunk[..k])?;
+                if original[..k] != chunk[..k] {
+                    self.seek(SeekFrom::Start(position))?;
+                    self.write_all(&chunk[..k])?;
+                }
+                position += k as u64;
+                if k < chunk_size {
+                    break;
+                }
+            }
+            return Ok(position - start);
+        }
+
+        loop {
+            self.fill_window_at(position)?;
+            let filled = self.buffer.num_valid_bytes();
+            let reached_end = filled < self.buffer.capacity();
+
+            let mut o = 0;
+            let mut result = Ok(());
+            while o < filled {
+                let k = chunk_size.min(filled - o);
+                if k < chunk_size && !reached_end {
+                    // The chunk will be handed whole by the next window
+                    break;
+                }
+
+                let chunk = &mut self.buffer.data[o..o + k];
+                original[..k].copy_from_slice(chunk);
+                result = f(position + o as u64, chunk);
+                if original[..k] != *chunk {
+                    let saved = match self.undo.as_mut() {
+                        Some(undo) => undo.save(position + o as u64, &original[..k]),
+                        None => Ok(()),
+                    };
+                    match saved {
+                        Ok(()) => self.buffer.mark_dirty(o..o + k),
+                        Err(e) => {
+                            // Not overwriting what could not be saved
+                            self.buffer.data[o..o + k].copy_from_slice(&original[..k]);
+                            result = Err(e);
+                        }
+                    }
+                }
+                if result.is_err() {
+                    break;
+                }
+                o += k;
+            }
+            if self.buffer.is_dirty() {
+                self.track_dirty_range(true);
+            }
+            self.buffer.set_position(o as u64);
+            result?;
+
+            position += o as u64;
+            if reached_end {
+                return Ok(position - start);
+            }
+        }
+    }
+
+    /// Reads `buf.len()` bytes at `start`, then sets the position to `start`
+    ///
+    /// When the range is not cached, the buffer is filled with a window
+    /// ending with the range, aligned on `align` bytes.
+    pub(crate) fn read_window_backward(
+        &mut self,
+        start: u64,
+        buf: &mut [u8],
+        align: usize,
+    ) -> std::io::Result<()> {
+        let end = start + buf.len() as u64;
+        let cache_start = self.start_position_in_source();
+        let cache_end = cache_start + self.buffer.num_valid_bytes() as u64;
+
+        if !(cache_start <= start && end <= cache_end) {
+            if self.buffering_paused || buf.len() > self.buffer.capacity() {
+                self.seek(SeekFrom::Start(start))?;
+                self.read_exact(buf)?;
+                self.seek(SeekFrom::Start(start))?;
+                return Ok(());
+            }
+
+            let span = ((self.buffer.capacity() / align) * align).max(buf.len()) as u64;
+            let window_start = end - span.min(end);
+            self.fill_window_at(window_start)?;
+            if self.buffer.num_valid_bytes() < (end - window_start) as usize {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        let offset = (start - self.start_position_in_source()) as usize;
+        buf.copy_from_slice(&self.buffer.data[offset..offset + buf.len()]);
+        self.buffer.set_position(offset as u64);
+        Ok(())
+    }
+
+    /// Reads until `buf` is full, with as many calls to [Read::read] as needed
+    fn read_exact_by_reads(&mut self, mut buf: &mut [u8]) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => buf = &mut buf[n..],
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads directly from the inner stream until `buf` is full, like [Read::read_exact]
+    fn read_exact_uncached(&mut self, mut buf: &mut [u8]) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_uncached(buf) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => buf = &mut buf[n..],
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes dirty data, then fills the buffer with the data at the end of the cached data
+    ///
+    /// Meant to be called once all the cached data was read. The dump of an aligned buffer
+    /// can read the rest of its last block, which is then read next instead of filling the buffer.
+    fn refill(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        if self.buffer.has_readable_bytes_left() {
+            return Ok(());
+        }
+        self.buffer.clear();
+        self.n = 0;
+        let n = self.buffer.fill_from(retrying!(self))?;
+        self.stats.fill(n);
+        self.pos += n as u64;
+        self.n = n;
+        Ok(())
+    }
+
+    /// Flushes dirty data, then fills the buffer with the data starting at `start`
+    ///
+    /// The buffer is filled as much as possible, not with a single read.
+    fn fill_window_at(&mut self, start: u64) -> std::io::Result<()> {
+        self.recover_inner_position()?;
+        if self.buffer.is_dirty() {
+            self.dump_buffer()?;
+        }
+        if self.pos != start {
+            self.seek_inner(SeekFrom::Start(start))?;
+            self.pos = start;
+        }
+        self.buffer.clear();
+        self.n = 0;
+        let n = self.buffer.fill_all_from(retrying!(self))?;
+        self.stats.fill(n);
+        self.pos += n as u64;
+        self.n = n;
+        Ok(())
+    }
+}
+
+impl BufReaderWriter<std::io::Cursor<Vec<u8>>> {
+    /// Creates a new BufReaderWriter over an empty in-memory stream
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Write;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::in_memory();
+    /// rw.write_all(b"Hello")?;
+    /// assert_eq!(rw.into_vec()?, b"Hello");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn in_memory() -> Self {
+        Self::from_vec(Vec::new())
+    }
+
+    /// Creates a new BufReaderWriter over an in-memory stream holding `data`
+    ///
+    /// The position starts at the beginning of the data.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        Self::new(std::io::Cursor::new(data))
+    }
+
+    /// Unwraps the BufReaderWriter, returning the bytes of the in-memory stream
+    ///
+    /// This may flush the buffer before which could result in an error
+    pub fn into_vec(self) -> std::io::Result<Vec<u8>> {
+        self.into_inner().map(std::io::Cursor::into_inner)
+    }
+}
+
+#[cfg(feature = "alias-detection")]
+impl BufReaderWriter<std::fs::File> {
+    /// Registers the file in a process-global table to detect other adapters
+    /// buffering overlapping writes to the same file
+    ///
+    /// This is a debug facility meant to catch bugs where two adapters
+    /// (e.g. built from `try_clone`d handles) patch overlapping regions, in which
+    /// case one of them would flush stale cached bytes over the other's writes.
+    ///
+    /// The file identity is the device and inode on Unix, the volume serial number
+    /// and file index on Windows.
+    ///
+    /// # Panics
+    ///
+    /// Subsequent writes panic when the range this adapter holds as dirty overlaps the dirty range
+    /// of another adapter on the same file that also enabled the detection.
+    pub fn enable_alias_detection(&mut self) -> std::io::Result<()> {
+        if self.alias_guard.is_none() {
+            self.alias_guard = Some(alias::AliasGuard::register(&self.inner)?);
+            self.track_dirty_range(self.buffer.is_dirty());
+        }
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Read for BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Reads from the cached data, filling the buffer from the inner stream when needed
+    ///
+    /// If the inner stream fails, nothing is consumed, the dirty data stays buffered if it
+    /// could not be written, and the inner stream is put back where the adapter expects it
+    /// when the next operation starts.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        if self.buffering_paused {
+            self.leave_buffer()?;
+            let n = retrying!(self).read(buf)?;
+            self.stats.direct_read(n);
+            self.pos += n as u64;
+            return self.read_gap_if_eof(buf, n);
+        }
+
+        let n = match self.buffer.get_read_command(buf) {
+            ReadCommand::Read(n) => self.buffer.read(&mut buf[..n]),
+            ReadCommand::FillRead => {
+                self.refill()?;
+                self.buffer.read(buf)
+            }
+            ReadCommand::ReadDirect => self.read_uncached(buf),
+        }?;
+        self.read_gap_if_eof(buf, n)
+    }
+
+    /// Reads all the data until the end of the stream, appending it to `buf`
+    ///
+    /// The cached data is drained, then the dirty data is written and the cached data
+    /// dropped, and the rest is read directly from the inner stream into `buf`.
+    /// If the length of the stream is known, `buf` is reserved for it beforehand.
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        let start_len = buf.len();
+        buf.extend_from_slice(&self.buffer.data[self.buffer.pos..self.buffer.filled]);
+        self.buffer.pos = self.buffer.filled;
+        self.discard_buffer()?;
+
+        if let Some(len) = self.known_stream_len() {
+            buf.reserve(len.saturating_sub(self.pos).try_into().unwrap_or(usize::MAX));
+        }
+        let before = buf.len();
+        // A failed read_to_end is not idempotent, so it is only retried read by read
+        let result = if self.retry.is_none() {
+            self.inner.read_to_end(buf)
+        } else {
+            retrying!(self).read_to_end(buf)
+        };
+        // On error, the data read so far was appended too
+        let k = buf.len() - before;
+        self.stats.direct_read(k);
+        self.pos += k as u64;
+        result?;
+        self.known_len = Some(self.pos);
+
+        if self.read_gaps_as_zero && self.pos < self.high_water {
+            let gap = (self.high_water - self.pos) as usize;
+            buf.resize(buf.len() + gap, 0);
+            self.seek(SeekFrom::Start(self.high_water))?;
+        }
+        Ok(buf.len() - start_len)
+    }
+
+    /// Reads all the data until the end of the stream, appending it to `buf`
+    ///
+    /// Uses the same path as [Read::read_to_end], then only validates the appended bytes.
+    /// Like in std, if they are not valid UTF-8, `buf` is left unchanged
+    /// and an error of kind [std::io::ErrorKind::InvalidData] is returned.
+    fn read_to_string(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        let mut bytes = std::mem::take(buf).into_bytes();
+        let start_len = bytes.len();
+        let result = self.read_to_end(&mut bytes);
+        let result = if std::str::from_utf8(&bytes[start_len..]).is_ok() {
+            result
+        } else {
+            bytes.truncate(start_len);
+            result.and(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )))
+        };
+        // SAFETY: the bytes were a String, and the appended ones were validated
+        *buf = unsafe { String::from_utf8_unchecked(bytes) };
+        result
+    }
+
+    /// Reads the cached data into `cursor`, without initializing it first
+    ///
+    /// Follows the same rules as [Read::read]: when nothing is cached, the buffer is
+    /// filled first, unless `cursor` can take at least the capacity, in which case
+    /// the read goes directly to the inner stream.
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, mut cursor: std::io::BorrowedCursor<'_>) -> std::io::Result<()> {
+        if self.buffering_paused || self.read_gaps_as_zero {
+            let mut chunk = [0u8; 512];
+            let len = cursor.capacity().min(chunk.len());
+            let n = self.read(&mut chunk[..len])?;
+            cursor.append(&chunk[..n]);
+            return Ok(());
+        }
+        self.check_inner_state()?;
+        self.align_buffer()?;
+
+        if !self.buffer.has_readable_bytes_left() {
+            if cursor.capacity() >= self.buffer.read_bypass {
+                self.discard_buffer()?;
+                let before = cursor.written();
+                let result = retrying!(self).read_buf(cursor.reborrow());
+                let n = cursor.written() - before;
+                self.stats.direct_read(n);
+                self.pos += n as u64;
+                return result;
+            }
+            self.refill()?;
+        }
+
+        let n = self.buffer.num_readable_bytes_left().min(cursor.capacity());
+        cursor.append(&self.buffer.data[self.buffer.pos..self.buffer.pos + n]);
+        self.buffer.pos += n;
+        Ok(())
+    }
+
+    /// Fills `cursor` completely, without initializing it first
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::UnexpectedEof]
+    /// if the stream ends first, the data read until then is in `cursor`.
+    #[cfg(feature = "nightly")]
+    fn read_buf_exact(&mut self, mut cursor: std::io::BorrowedCursor<'_>) -> std::io::Result<()> {
+        while cursor.capacity() > 0 {
+            let before = cursor.written();
+            match self.read_buf(cursor.reborrow()) {
+                Ok(()) if cursor.written() == before => {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the cached data into several buffers in one call
+    ///
+    /// Like for [Read::read], when nothing is cached, the buffer is filled first,
+    /// unless the total length is at least the capacity, in which case the read goes
+    /// directly to the inner stream. Only the cached data is returned,
+    /// the buffer is not filled again to fill the rest of `bufs`.
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        if self.buffering_paused || self.read_gaps_as_zero {
+            let buf = bufs.iter_mut().find(|buf| !buf.is_empty());
+            return self.read(buf.map_or(&mut [], |buf| &mut **buf));
+        }
+        self.check_inner_state()?;
+        self.align_buffer()?;
+
+        if !self.buffer.has_readable_bytes_left() {
+            let total = bufs
+                .iter()
+                .fold(0usize, |total, buf| total.saturating_add(buf.len()));
+            if total >= self.buffer.read_bypass {
+                self.discard_buffer()?;
+                let n = retrying!(self).read_vectored(bufs)?;
+                self.stats.direct_read(n);
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            self.refill()?;
+        }
+
+        let mut n = 0;
+        for buf in bufs.iter_mut() {
+            if !self.buffer.has_readable_bytes_left() {
+                break;
+            }
+            n += self.buffer.read(buf)?;
+        }
+        Ok(n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        if self.buffering_paused
+            || self.read_gaps_as_zero
+            || (self.buffer.capacity()..self.buffer.read_bypass).contains(&buf.len())
+        {
+            return self.read_exact_by_reads(buf);
+        }
+
+        match self.buffer.get_read_exact_command(buf) {
+            ReadExactCommand::Read => {
+                self.buffer.read(buf)?;
+            }
+            ReadExactCommand::ReadFillRead { split } => {
+                // Dumping first, so that nothing is consumed if it fails
+                if self.buffer.is_dirty() {
+                    self.dump_buffer()?;
+                }
+                let (first, second) = buf.split_at_mut(split);
+                self.buffer.read(first)?;
+                self.refill()?;
+                let k = self.buffer.read(second)?;
+                // The fill may be smaller than the request
+                if k < second.len() {
+                    return self.read_exact_by_reads(&mut second[k..]);
+                }
+            }
+            ReadExactCommand::FillRead => {
+                self.refill()?;
+                let k = self.buffer.read(buf)?;
+                if k < buf.len() {
+                    return self.read_exact_by_reads(&mut buf[k..]);
+                }
+            }
+            ReadExactCommand::ReadDirect => {
+                self.read_exact_uncached(buf)?;
+            }
+            ReadExactCommand::ReadReadDirect { split } => {
+                let (first, second) = buf.split_at_mut(split);
+                self.buffer.read(first)?;
+                self.read_exact_uncached(second)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> BufRead for BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Returns the cached data that was not read yet, filling the buffer if there is none
+    ///
+    /// While buffering is paused, the buffer is still used, until the next read, write or seek.
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        if !self.buffer.has_readable_bytes_left() {
+            self.refill()?;
+        }
+        Ok(&self.buffer.data[self.buffer.pos..self.buffer.filled])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.buffer.pos = (self.buffer.pos + amount).min(self.buffer.filled);
+    }
+}
+
+impl<T, const N: usize> Write for BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    /// Writes to the buffer, writing the dirty data to the inner stream when it is full
+    ///
+    /// If the inner stream fails, either an error is returned and the position did not
+    /// change, or the bytes that were buffered are reported as a short write.
+    /// The dirty data stays buffered until it is written, and the inner stream is put back
+    /// where the adapter expects it when the next operation starts.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        self.save_original(self.position(), buf.len())?;
+        if self.buffering_paused {
+            self.leave_buffer()?;
+            self.known_len = None;
+            let n = retrying!(self).write(buf)?;
+            self.stats.direct_write(n);
+            self.pos += n as u64;
+            self.high_water = self.high_water.max(self.pos);
+            return Ok(n);
+        }
+        if self.write_through {
+            let n = self.write_through(buf)?;
+            self.high_water = self.high_water.max(self.position());
+            return Ok(n);
+        }
+        if self.buffer.exceeds_write_capacity(buf.len()) {
+            self.dump_buffer()?;
+        }
+        self.stage_overflow(buf.len());
+
+        let result: std::io::Result<usize> = match self.buffer.get_write_exact_command(buf) {
+            WriteAllCommand::Write => {
+                let n = self.buffer.write(buf)?;
+                self.track_dirty_range(true);
+                Ok(n)
+            }
+            WriteAllCommand::WriteDumpWrite(n) => {
+                let (first, second) = buf.split_at(n);
+                self.buffer.write(first)?;
+                self.track_dirty_range(true);
+                // A clean buffer is full of cached data, the inner stream is at its end
+                let dumped = if self.buffer.is_dirty() {
+                    self.dump_buffer()
+                } else {
+                    Ok(())
+                };
+                match dumped {
+                    // `first` is buffered, so this is a short write
+                    Err(_) if n != 0 => Ok(n),
+                    Err(e) => Err(e),
+                    Ok(()) => {
+                        self.buffer.clear();
+                        self.n = 0;
+                        // More than the capacity when not bypassing the buffer
+                        let m = self.buffer.write(second)?;
+                        self.track_dirty_range(true);
+                        Ok(n + m)
+                    }
+                }
+            }
+            WriteAllCommand::WriteDirect => self.write_direct(buf),
+        };
+        self.high_water = self.high_water.max(self.position());
+        if result.is_ok() && self.must_dump() {
+            // The bytes are buffered, so a failure shows at the next operation
+            let _ = self.dump_buffer();
+        }
+        result
+    }
+
+    /// Writes the dirty data, then flushes the inner stream
+    ///
+    /// The cached data is kept and the position does not change.
+    ///
+    /// To only make the buffered data visible to the inner stream without
+    /// flushing it, use [BufReaderWriter::flush_writes].
+    /// To also drop the cached data, use [BufReaderWriter::sync_cache].
+    ///
+    /// If writing fails, the data stays dirty and the position does not change,
+    /// so flushing again retries the whole write.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_writes()?;
+        let written = self.stats.bytes_written();
+        retrying!(self).flush()?;
+        self.flushed_bytes = written;
+        Ok(())
+    }
+
+    /// Formats directly into the buffer, the fragments that do not fit going through
+    /// [Write::write_all]
+    fn write_fmt(&mut self, args: std::fmt::Arguments<'_>) -> std::io::Result<()> {
+        struct Adapter<'a, T: Write + Seek, const N: usize> {
+            rw: &'a mut BufReaderWriter<T, N>,
+            error: std::io::Result<()>,
+        }
+
+        impl<T: Write + Seek, const N: usize> std::fmt::Write for Adapter<'_, T, N> {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.rw.write_fragment(s.as_bytes()).map_err(|e| {
+                    self.error = Err(e);
+                    std::fmt::Error
+                })
+            }
+        }
+
+        if let Some(s) = args.as_str() {
+            return self.write_all(s.as_bytes());
+        }
+        self.check_inner_state()?;
+        self.align_buffer()?;
+        let mut adapter = Adapter {
+            rw: self,
+            error: Ok(()),
+        };
+        match std::fmt::write(&mut adapter, args) {
+            Ok(()) => Ok(()),
+            // Either the inner stream or a formatting trait implementation failed
+            Err(_) => adapter
+                .error
+                .and(Err(std::io::Error::other("formatter error"))),
+        }
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> std::io::Result<()> {
+        // Buffered writes take everything at once, but direct writes
+        // return what the inner stream accepted
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => buf = &buf[n..],
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Seek for BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    /// Seek to an offset, in bytes,
+    ///
+    /// If the target position falls into the currently stored buffer,
+    /// no seek in the underlying reader will happen.
+    ///
+    /// If the inner stream fails to seek, the position and the cached data do not change,
+    /// except that the dirty data may have been written, and the inner stream is put
+    /// back where the adapter expects it when the next operation starts.
+    ///
+    /// Seeking before the start of the stream, from any [SeekFrom] variant, returns
+    /// an error of kind [std::io::ErrorKind::InvalidInput] and changes nothing.
+    fn seek(&mut self, mut seek_from: SeekFrom) -> std::io::Result<u64> {
+        // The prefetched window is used if the target is in it
+        if let Some(position) = self.detached_position {
+            let target = match seek_from {
+                SeekFrom::Start(target) => Some(target),
+                SeekFrom::Current(offset) => position.checked_add_signed(offset),
+                SeekFrom::End(_) => None,
+            };
+            if let Some(target) = target {
+                self.detached_position = Some(target);
+                seek_from = SeekFrom::Start(target);
+            }
+        }
+        self.check_inner_state()?;
+        let before_start =
+            || std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeking before start");
+        // Checked before anything changes, so that a failed seek keeps the cached data
+        if let SeekFrom::Current(offset) = seek_from
+            && offset < 0
+            && self.position().checked_add_signed(offset).is_none()
+        {
+            return Err(before_start());
+        }
+        if self.buffering_paused {
+            self.leave_buffer()?;
+            self.pos = self.seek_inner(seek_from)?;
+            return Ok(self.pos);
+        }
+
+        // When the length is known, no need to ask the inner stream
+        let seek_from = match seek_from {
+            SeekFrom::End(offset) if self.known_stream_len().is_none() => {
+                // The cached data is kept if this fails, and the inner stream
+                // goes back to the end of the cached data lazily when needed
+                self.inner_pos_lost = true;
+                let target = self.seek_inner(SeekFrom::End(offset)).map_err(|e| {
+                    if offset < 0 && e.kind() == std::io::ErrorKind::InvalidInput {
+                        before_start()
+                    } else {
+                        e
+                    }
+                })?;
+                self.known_len = u64::try_from(target as i128 - offset as i128).ok();
+                let cached_end = self.cached_end();
+                if self.known_len.is_none_or(|len| len < cached_end) {
+                    // The buffered data extends the stream
+                    match self.known_stream_len().and_then(|len| len.checked_add_signed(offset)) {
+                        Some(target) => SeekFrom::Start(target),
+                        None => return Err(before_start()),
+                    }
+                } else if target > cached_end && !self.buffer.is_dirty() {
+                    // Already there
+                    self.pos = target;
+                    self.inner_pos_lost = false;
+                    self.buffer.clear();
+                    self.n = 0;
+                    return Ok(target);
+                } else {
+                    self.inner_pos_lost = target != self.pos;
+                    SeekFrom::Start(target)
+                }
+            }
+            SeekFrom::End(offset) => match self
+                .known_stream_len()
+                .and_then(|len| len.checked_add_signed(offset))
+            {
+                Some(target) => SeekFrom::Start(target),
+                None => return Err(before_start()),
+            },
+            _ => seek_from,
+        };
+
+        match seek_from {
+            SeekFrom::Start(pos) if pos == self.position() => Ok(pos),
+            SeekFrom::Start(pos) => {
+                let in_mem_range = self.start_position_in_source()
+                    ..=self.start_position_in_source() + self.buffer.num_valid_bytes() as u64;
+                if in_mem_range.contains(&pos) {
+                    // We just need to adjust the position inside the buffer
+                    self.buffer
+                        .set_position(pos - self.start_position_in_source());
+                    Ok(self.position())
+                } else {
+                    if self.buffer.is_dirty() {
+                        self.write_dirty()?;
+                    }
+                    self.pos = self.seek_inner(SeekFrom::Start(pos))?;
+                    self.inner_pos_lost = false;
+                    self.buffer.clear();
+                    self.buffer.restart_fills();
+                    self.n = 0;
+                    Ok(self.position())
+                }
+            }
+            // Turned into a seek from the start above
+            SeekFrom::End(_) => Err(std::io::Error::other("the stream length is unknown")),
+            SeekFrom::Current(direction) => {
+                if direction == 0 {
+                    // Shortcut as doing SeekFrom::Current(0) is common to get
+                    // the position
+                    Ok(self.position())
+                } else if direction < 0 {
+                    // Seeking backward by (negating i64::MIN would overflow):
+                    let abs_d = direction.unsigned_abs();
+
+                    if abs_d > self.buffer.position() as u64 {
+                        // Trying to seek to a place that is before what the buffer contains
+                        if self.buffer.is_dirty() {
+                            self.dump_buffer()?;
+                        }
+
+                        self.pos = self.seek_inner(SeekFrom::Current(
+                            direction - (self.n as i64 - self.buffer.position() as i64),
+                        ))?;
+                        self.buffer.clear();
+                        self.n = 0;
+                        Ok(self.pos)
+                    } else {
+                        // Trying to seek to a place that is within the buffer
+                        self.buffer
+                            .set_position(self.buffer.position() as u64 - abs_d);
+                        Ok(self.position())
+                    }
+                } else {
+                    // Seeking forward
+                    let amount = direction as u64;
+
+                    if amount > self.buffer.num_readable_bytes_left() as u64 {
+                        // Trying to seek to a place that is past what the buffer contains
+                        if self.buffer.is_dirty() {
+                            self.dump_buffer()?;
+                        }
+
+                        // The inner stream is at the end of the cached data
+                        let offset = (self.buffer.position() as i64 - self.n as i64)
+                            .checked_add(direction)
+                            .ok_or_else(|| {
+                                std::io::Error::new(
+                                    std::io::ErrorKind::InvalidInput,
+                                    "seek position overflows",
+                                )
+                            })?;
+                        self.pos = self.seek_inner(SeekFrom::Current(offset))?;
+                        self.buffer.clear();
+                        self.n = 0;
+                        Ok(self.position())
+                    } else {
+                        // Trying to seek to a place that is within the buffer
+                        self.buffer
+                            .set_position(self.buffer.position() as u64 + amount);
+                        Ok(self.position())
+                    }
+                }
+            }
+        }
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.position())
+    }
+
+    /// Seeks to the start of the stream
+    ///
+    /// When the cached data starts at offset 0, only the position in the buffer is reset,
+    /// even if the data is dirty, so re-reading a header does not reach the inner stream.
+    fn rewind(&mut self) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(0)).map(|_| ())
+    }
+}
+
+impl<T, const N: usize> Drop for BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    fn drop(&mut self) {
+        if !self.flush_on_drop {
+            return;
+        }
+        if self.buffer.is_dirty() {
+            let _ = self.flush_writes();
+        }
+        // Dropping without having written does no IO
+        if self.flush_inner_on_drop && self.stats.bytes_written() != self.flushed_bytes {
+            let _ = retrying!(self).flush();
+        }
+    }
+}
+
+/// Shows the buffering state, but neither the cached data nor the inner stream
+impl<T, const N: usize> std::fmt::Debug for BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufReaderWriter")
+            .field("position", &self.position())
+            .field("capacity", &self.buffer.capacity())
+            .field("cache_start", &self.start_position_in_source())
+            .field("valid_bytes", &self.buffer.num_valid_bytes())
+            .field("unread_bytes", &self.buffer.num_readable_bytes_left())
+            .field("dirty", &self.buffer.is_dirty())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Clones the inner stream and the cached data, dirty or not,
+/// so that the clone carries on from the same position independently
+///
+/// Nothing is shared: the statistics are copied, and the undo log,
+/// the alias detection and the watch of the file are not carried over to the clone.
+impl<T, const N: usize> Clone for BufReaderWriter<T, N>
+where
+    T: Clone + Write + Seek,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            pos: self.pos,
+            n: self.n,
+            buffer: self.buffer.clone(),
+            buffering_paused: self.buffering_paused,
+            flush_on_drop: self.flush_on_drop,
+            flush_inner_on_drop: self.flush_inner_on_drop,
+            known_len: self.known_len,
+            read_gaps_as_zero: self.read_gaps_as_zero,
+            write_through: self.write_through,
+            max_dirty: self.max_dirty,
+            overflow_limit: self.overflow_limit,
+            overflow_capacity: self.overflow_capacity,
+            whole_file: self.whole_file,
+            high_water: self.high_water,
+            stats: std::sync::Arc::new(stats::Counters::from_stats(self.stats.snapshot())),
+            flushed_bytes: self.flushed_bytes,
+            undo: None,
+            retry: self.retry,
+            #[cfg(feature = "alias-detection")]
+            alias_guard: None,
+            #[cfg(feature = "notify")]
+            watch: None,
+            inner_pos_lost: self.inner_pos_lost,
+            reserved: self.reserved,
+            alignment: self.alignment,
+            detached_position: self.detached_position,
+        }
+    }
+}
+
+/// State of a [BufReaderWriter] saved by [BufReaderWriter::suspend]
+///
+/// With the `serde` feature, it can be serialized to be resumed by another process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResumeState {
+    position: u64,
+    capacity: usize,
+    buffering_paused: bool,
+    read_gaps_as_zero: bool,
+    flush_on_drop: bool,
+    flush_inner_on_drop: bool,
+    write_through: bool,
+    max_dirty: usize,
+    read_bypass: usize,
+    write_bypass: usize,
+    read_capacity: usize,
+    write_capacity: usize,
+    min_fill: usize,
+    overflow_limit: usize,
+    alignment: Option<usize>,
+    stats: Stats,
+}
+
+impl ResumeState {
+    /// Returns the logical position in the stream at the time of the suspension
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the capacity of the internal buffer
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the statistics at the time of the suspension
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+/// After executing a command, all the requested bytes should have been written
+/// unless an error occurred
+enum WriteAllCommand {
+    /// The buffer has enough capacity to store the data
+    ///
+    /// So, write to the buffer
+    Write,
+    /// The buffer does not have enough capacity to store the data
+    ///
+    /// Write to the buffer, then dump the buffer to the source
+    /// and finally, write again to the buffer
+    WriteDumpWrite(usize),
+    /// Write directly to the source, see [BufReaderWriter::write_uncached]
+    WriteDirect,
+}
+
+/// After executing a command, not all bytes may have been read
+enum ReadCommand {
+    /// Read `n` bytes from the buffer
+    Read(usize),
+    /// Fill the buffer, then read all the bytes from the original request
+    ///
+    /// The buffer may need to be dumped before being refilled
+    FillRead,
+    /// Read directly all the bytes from the original request from the source
+    /// (skip the buffer), see [BufReaderWriter::read_uncached]
+    ReadDirect,
+}
+
+/// After executing a command, all bytes will be read
+enum ReadExactCommand {
+    /// The whole output can be filled bu reading from the buffer
+    Read,
+    /// Read from the buffer, re-fill the buffer, then read all the bytes from the original request
+    ///
+    /// The buffer may need to be dumped before being refilled
+    ReadFillRead { split: usize },
+    FillRead,
+    /// Read directly all the bytes from the original request from the source
+    /// (skip the buffer), see [BufReaderWriter::read_uncached]
+    ReadDirect,
+    /// Read from buffer, then finish reading from the source
+    ReadReadDirect { split: usize },
+}
+
+#[derive(Clone)]
+struct Buffer<const N: usize = 0> {
+    data: storage::Storage<N>,
+    pos: usize,
+    filled: usize,
+    /// The range of the data written since the last dump, empty if clean
+    dirty: Range<usize>,
+    // The most a fill reads, and the most the dirty range spans
+    read_capacity: usize,
+    write_capacity: usize,
+    // The size of the next single-read fill, growing from `min_fill` to `read_capacity`
+    // while reading sequentially
+    min_fill: usize,
+    fill_size: usize,
+    // The sizes from which reads and writes go directly to the inner stream
+    read_bypass: usize,
+    write_bypass: usize,
+}
+
+impl Buffer {
+    fn with_capacity(capacity: usize) -> Self {
+        Self::with_storage(storage::Storage::new_uninit(capacity))
+    }
+
+    fn try_with_capacity(capacity: usize) -> Result<Self, std::collections::TryReserveError> {
+        storage::Storage::try_new_uninit(capacity).map(Self::with_storage)
+    }
+
+    fn with_buffer(buffer: Box<[u8]>) -> Self {
+        Self::with_storage(buffer.into())
+    }
+}
+
+impl<const N: usize> Buffer<N> {
+    fn with_storage(buffer: storage::Storage<N>) -> Self {
+        Self {
+            read_capacity: buffer.len(),
+            write_capacity: buffer.len(),
+            min_fill: buffer.len(),
+            fill_size: buffer.len(),
+            read_bypass: buffer.len(),
+            write_bypass: buffer.len(),
+            data: buffer,
+            pos: 0,
+            filled: 0,
+            dirty: 0..0,
+        }
+    }
+
+    #[inline]
+    fn has_readable_bytes_left(&self) -> bool {
+        self.pos != self.filled
+    }
+
+    #[inline]
+    fn num_readable_bytes_left(&self) -> usize {
+        self.filled - self.pos
+    }
+
+    #[inline]
+    fn num_writable_bytes_left(&self) -> usize {
+        self.capacity() - self.pos
+    }
+
+    #[inline]
+    fn num_valid_bytes(&self) -> usize {
+        self.filled
+    }
+
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The end of the next read of the inner stream appending to the valid bytes
+    #[inline]
+    fn fill_end(&self) -> usize {
+        (self.filled + self.read_capacity).min(self.capacity())
+    }
+
+    /// Makes the capacities and thresholds that were the `previous` capacity,
+    /// as they are by default, follow the new capacity
+    fn follow_capacity(&mut self, previous: usize) {
+        let capacity = self.capacity();
+        for setting in [
+            &mut self.read_capacity,
+            &mut self.write_capacity,
+            &mut self.min_fill,
+            &mut self.fill_size,
+            &mut self.read_bypass,
+            &mut self.write_bypass,
+        ] {
+            if *setting == previous {
+                *setting = capacity;
+            }
+        }
+    }
+
+    /// Returns whether writing `len` bytes at the position would make
+    /// the dirty data span more than the write capacity
+    ///
+    /// Writes that do not fit in the buffer are not concerned, the buffer is dumped anyway.
+    #[inline]
+    fn exceeds_write_capacity(&self, len: usize) -> bool {
+        self.is_dirty()
+            && len <= self.num_writable_bytes_left()
+            && self.dirty.end.max(self.pos + len) - self.dirty.start.min(self.pos)
+                > self.write_capacity
+    }
+
+    /// Fill the `self` from the `source`.
+    ///
+    /// This discards any data already present in `self`
+    fn fill_from(&mut self, mut source: impl Read) -> std::io::Result<usize> {
+        debug_assert!(!self.has_readable_bytes_left());
+        let len = self.fill_size;
+        let n = source.read(&mut self.data[..len])?;
+        self.fill_size = len.saturating_mul(2).min(self.read_capacity);
+        if n > len {
+            return Err(std::io::Error::other(
+                "inner stream reported reading more bytes than requested",
+            ));
+        }
+        self.filled = n;
+        self.pos = 0;
+        self.mark_clean();
+
+        Ok(n)
+    }
+
+    /// Fill the `self` from the `source`, until it is full or the source reaches its end
+    ///
+    /// This discards any data already present in `self`
+    fn fill_all_from(&mut self, mut source: impl Read) -> std::io::Result<usize> {
+        self.clear();
+        while self.filled < self.capacity() {
+            let end = self.fill_end();
+            match source.read(&mut self.data[self.filled..end]) {
+                Ok(0) => break,
+                Ok(n) if n > end - self.filled => {
+                    return Err(std::io::Error::other(
+                        "inner stream reported reading more bytes than requested",
+                    ));
+                }
+                Ok(n) => self.filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.filled)
+    }
+
+    #[inline]
+    fn set_position(&mut self, pos: u64) {
+        self.pos = pos.min(self.filled as u64) as usize;
+    }
+
+    #[inline]
+    fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Writes the bytes in `range` to `dst`, after which the buffer is no longer dirty
+    fn dump(&mut self, range: Range<usize>, mut dst: impl Write) -> std::io::Result<usize> {
+        dst.write_all(&self.data[range.clone()])?;
+        self.mark_clean();
+        Ok(range.len())
+    }
+
+    /// Makes the next fill the smallest, for a random access
+    #[inline]
+    fn restart_fills(&mut self) {
+        self.fill_size = self.min_fill;
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.pos = 0;
+        self.filled = 0;
+        self.mark_clean();
+    }
+
+    #[inline]
+    fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Adds `range` to the dirty data
+    #[inline]
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.dirty = if self.is_dirty() {
+            self.dirty.start.min(range.start)..self.dirty.end.max(range.end)
+        } else {
+            range
+        };
+    }
+
+    #[inline]
+    fn mark_clean(&mut self) {
+        self.dirty = 0..0;
+    }
+
+    #[inline]
+    fn get_read_command(&self, buf: &[u8]) -> ReadCommand {
+        if self.has_readable_bytes_left() {
+            ReadCommand::Read(buf.len().min(self.num_readable_bytes_left()))
+        } else if buf.len() >= self.read_bypass {
+            ReadCommand::ReadDirect
+        } else {
+            ReadCommand::FillRead
+        }
+    }
+
+    #[inline]
+    fn get_read_exact_command(&self, buf: &[u8]) -> ReadExactCommand {
+        // With a bypass threshold below the capacity, large reads can be in the cached data
+        if buf.len() >= self.read_bypass && self.num_readable_bytes_left() < buf.len() {
+            if self.has_readable_bytes_left() {
+                ReadExactCommand::ReadReadDirect {
+                    split: self.num_readable_bytes_left(),
+                }
+            } else {
+                ReadExactCommand::ReadDirect
+            }
+        } else if self.num_readable_bytes_left() >= buf.len() {
+            ReadExactCommand::Read
+        } else if self.num_readable_bytes_left() < buf.len() {
+            ReadExactCommand::ReadFillRead {
+                split: self.num_readable_bytes_left(),
+            }
+        } else {
+            debug_assert!(self.num_readable_bytes_left() == 0);
+            ReadExactCommand::FillRead
+        }
+    }
+
+    #[inline]
+    fn get_write_exact_command(&self, buf: &[u8]) -> WriteAllCommand {
+        if buf.len() >= self.write_bypass {
+            WriteAllCommand::WriteDirect
+        } else if self.num_writable_bytes_left() >= buf.len() {
+            WriteAllCommand::Write
+        } else {
+            WriteAllCommand::WriteDumpWrite(self.num_writable_bytes_left())
+        }
+    }
+
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.num_readable_bytes_left().min(buf.len());
+        let (Some(dst), Some(src)) = (buf.get_mut(..n), self.data.get(self.pos..self.pos + n))
+        else {
+            // Not allocating the error keeps this from panicking
+            return Err(std::io::ErrorKind::Other.into());
+        };
+        dst.copy_from_slice(src);
+        self.pos += n;
+        Ok(n)
+    }
+
+    /// Marks the `n` bytes after the position as written, moving the position after them
+    #[inline]
+    fn commit(&mut self, n: usize) {
+        debug_assert!(self.pos + n <= self.capacity());
+        self.mark_dirty(self.pos..self.pos + n);
+        self.pos += n;
+        self.filled = self.filled.max(self.pos);
+    }
+
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.num_writable_bytes_left().min(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let (Some(dst), Some(src)) = (self.data.get_mut(self.pos..self.pos + n), buf.get(..n))
+        else {
+            return Err(std::io::ErrorKind::Other.into());
+        };
+        dst.copy_from_slice(src);
+        if self.pos + n > self.filled {
+            self.filled = self.pos + n;
+        }
+        self.mark_dirty(self.pos..self.pos + n);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::bool_assert_comparison)]
+    use crate::BufReaderWriter;
+    use crate::counting::CountingStream;
+    use rand::Rng;
+    use rand::seq::SliceRandom;
+    use std::io::{Cursor, Read, Seek, Write};
+
+    #[test]
+    fn test_seek_end_then_write() {
+        let mut data = Cursor::new(vec![]);
+
+        data.write_all(b"Yoshi").unwrap();
+        data.set_position(0);
+
+        let mut buf = BufReaderWriter::new(data);
+
+        let n = buf.seek(std::io::SeekFrom::End(-3)).unwrap();
+        assert_eq!(n, 2);
+
+        buf.write_all(b"Yoshi").unwrap();
+        assert!(buf.buffer.is_dirty());
+        let n = buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        assert_eq!(n, 0);
+
+        let mut bytes = [0u8; 7];
+        buf.read_exact(bytes.as_mut_slice()).unwrap();
+        assert_eq!(&bytes, b"YoYoshi");
+    }
+
+    #[test]
+    fn test_seek_current_negative_too_far() {
+        let mut data = Cursor::new(vec![]);
+
+        data.write_all(b"Yoshi").unwrap();
+        data.set_position(0);
+
+        let mut buf = BufReaderWriter::new(data);
+
+        assert_eq!(buf.position(), 0);
+        assert!(matches!(buf.stream_position(), Ok(0)));
+
+        let result = buf.seek(std::io::SeekFrom::Current(-6));
+        assert!(result.is_err());
+
+        assert_eq!(buf.position(), 0);
+        assert!(matches!(buf.stream_position(), Ok(0)));
+    }
+
+    #[test]
+    fn test_seek_current_forward() {
+        let mut rng = rand::rng();
+        let mut cursor = Cursor::new(vec![]);
+        let mut buf = BufReaderWriter::new(&mut cursor);
+        let buf_capacity = buf.capacity();
+
+        buf.inner.get_mut().resize(buf_capacity * 4, 0u8);
+        for v in buf.inner.get_mut() {
+            *v = rng.random();
+        }
+
+        let expected = buf.inner().get_ref().to_vec();
+
+        let mut c = [0u8];
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(c[0], expected[0]);
+
+        let n = buf.seek(std::io::SeekFrom::Current(1)).unwrap();
+        assert_eq!(n, 2);
+
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(c[0], expected[2]);
+
+        // Seek past buffer
+        let n = buf
+            .seek(std::io::SeekFrom::Current(buf_capacity as i64))
+            .unwrap();
+        assert_eq!(n, buf_capacity as u64 + 3);
+
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(c[0], expected[buf_capacity + 3])
+    }
+
+    #[test]
+    fn test_seek_current_at_buffer_boundary() {
+        let mut rng = rand::rng();
+        let mut cursor = Cursor::new(vec![]);
+        let mut buf = BufReaderWriter::new(&mut cursor);
+        let buf_capacity = buf.capacity();
+
+        // Fill the underlying source with some random data
+        buf.inner
+            .get_mut()
+            .resize(buf_capacity + buf_capacity / 2, 0u8);
+        for v in buf.inner.get_mut() {
+            *v = rng.random();
+        }
+
+        // Clone it to have access to it without borrow problems
+        let mut expected = buf.inner().get_ref().to_vec();
+
+        let mut c = [0u8];
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(c[0], expected[0]);
+        assert_eq!(buf.buffer.is_dirty(), false);
+        assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), buf_capacity - 1);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 1);
+        assert_eq!(buf.position(), 1);
+
+        let n = buf
+            .seek(std::io::SeekFrom::Current(buf_capacity as i64 - 2))
+            .unwrap();
+        assert_eq!(n, buf_capacity as u64 - 1);
+        assert_eq!(buf.buffer.is_dirty(), false);
+        assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 1);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), 1);
+
+        // This read_exact should trigger a refill as it crosses the buffer boundary
+        let mut c = [0u8; 2];
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(&c, &expected[buf_capacity - 1..buf_capacity + 1]);
+        assert_eq!(buf.buffer.is_dirty(), false);
+        assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity / 2);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), buf_capacity / 2 - 1);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 1);
+
+        // Seek back to before reading the 2 bytes
+        let n = buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
+        assert_eq!(n, buf_capacity as u64 - 1);
+        assert_eq!(buf.buffer.is_dirty(), false);
+        assert_eq!(buf.buffer.num_valid_bytes(), 0);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity);
+
+        let c2 = [c[0].wrapping_add(1), c[1].wrapping_add(1)];
+
+        buf.write_all(&c2).unwrap();
+        assert_eq!(buf.buffer.is_dirty(), true);
+        assert_eq!(buf.buffer.num_valid_bytes(), 2);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 2);
+        expected[n as usize] = c2[0];
+        expected[n as usize + 1] = c2[1];
+
+        // Seek back to before reading the 2 bytes
+        let n = buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
+        assert_eq!(n, buf_capacity as u64 - 1);
+        assert_eq!(buf.buffer.is_dirty(), true);
+        assert_eq!(buf.buffer.num_valid_bytes(), 2);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 2);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity);
+
+        let n = buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
+        assert_eq!(n, buf_capacity as u64 - 3);
+        assert_eq!(buf.buffer.is_dirty(), false); // a dump should have been done
+        assert_eq!(buf.buffer.num_valid_bytes(), 0);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity);
+
+        let mut c = vec![0u8; 4];
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(&c, &expected[buf_capacity - 3..buf_capacity + 1]);
+        assert_eq!(buf.buffer.is_dirty(), false);
+        assert_eq!(
+            buf.buffer.num_valid_bytes(),
+            expected.len() - (buf_capacity - 3)
+        );
+        assert_eq!(
+            buf.buffer.num_readable_bytes_left(),
+            buf.buffer.num_valid_bytes() - 4
+        );
+        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 4);
+
+        buf.flush().unwrap();
+        assert_eq!(buf.inner.get_ref(), expected.as_slice());
+    }
+
+    #[test]
+    fn test_drop_flushes() {
+        let mut cursor = Cursor::new(vec![]);
+        let mut buf = BufReaderWriter::new(&mut cursor);
+
+        assert_eq!(buf.position(), 0);
+        assert!(matches!(buf.stream_position(), Ok(0)));
+
+        assert_eq!(buf.buffer.is_dirty(), false);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
+        assert_eq!(buf.position(), 0);
+
+        let data = b"Eco Dome Aldani";
+        buf.write_all(data).unwrap();
+
+        assert_eq!(buf.buffer.is_dirty(), true);
+        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
+        assert_eq!(buf.position(), data.len() as u64);
+
+        // Nothing was actually written yet
+        assert_eq!(buf.inner().position(), 0);
+
+        drop(buf);
+
+        assert_eq!(cursor.position(), data.len() as u64);
+        let s = String::from_utf8(cursor.into_inner()).unwrap();
+        assert_eq!(s.as_bytes(), data);
+    }
+
+    #[test]
+    fn write_more_than_buffer_capacity() {
+        {
+            // First, the simple case, where we never wrote not read anything
+            // thus the buffer is empty
+
+            let mut cursor = Cursor::new(vec![]);
+            let mut buf = BufReaderWriter::new(&mut cursor);
+
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+
+            let mut rng = rand::rng();
+            let mut data = vec![0u8; buf.capacity()];
+            for v in data.iter_mut() {
+                *v = rng.random();
+            }
+
+            // Check that nothing was written in the buffer,
+            // instead we wrote directly to the source
+            buf.write_all(&data).unwrap();
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+            assert_eq!(buf.inner().get_ref(), &data);
+        }
+
+        {
+            // We wrote something before trying a write
+            // with >= capacity
+
+            let mut cursor = Cursor::new(vec![]);
+            let mut buf = BufReaderWriter::new(&mut cursor);
+
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+
+            let mut rng = rand::rng();
+            let mut data = vec![0u8; buf.capacity() + 50];
+            for v in data.iter_mut() {
+                *v = rng.random();
+            }
+
+            let (first_write, second_write) = data.split_at_mut(50);
+
+            buf.write_all(first_write).unwrap();
+
+            assert_eq!(buf.buffer.is_dirty(), true);
+            assert_eq!(buf.buffer.num_valid_bytes(), 50);
+            assert!(buf.inner().get_ref().is_empty());
+
+            buf.write_all(second_write).unwrap();
+            // The buffer has been dumped
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+            assert_eq!(buf.inner().get_ref(), data.as_slice());
+        }
+    }
+
+    #[test]
+    fn read_more_than_buffer_capacity() {
+        {
+            // First, the simple case, where we never wrote not read anything
+            // thus the buffer is empty
+
+            let mut rng = rand::rng();
+            let mut cursor = Cursor::new(vec![]);
+            let mut buf = BufReaderWriter::new(&mut cursor);
+            let buf_capacity = buf.capacity();
+            let n = 4;
+
+            buf.inner.get_mut().resize(buf_capacity * 4, 0u8);
+            for v in buf.inner.get_mut() {
+                *v = rng.random();
+            }
+
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+
+            let mut request = vec![0u8; buf.capacity()];
+            for i in 0..n {
+                buf.read_exact(&mut request).unwrap();
+                assert_eq!(buf.buffer.is_dirty(), false);
+                assert_eq!(buf.buffer.num_valid_bytes(), 0);
+                assert_eq!(
+                    &buf.inner().get_ref()[i * buf_capacity..(i + 1) * buf_capacity],
+                    &request
+                );
+            }
+        }
+
+        {
+            // We read a small thing before trying a big read
+
+            let mut rng = rand::rng();
+            let mut cursor = Cursor::new(vec![]);
+            let mut buf = BufReaderWriter::new(&mut cursor);
+            let buf_capacity = buf.capacity();
+
+            buf.inner.get_mut().resize((buf_capacity * 4) + 77, 0u8);
+            for v in buf.inner.get_mut() {
+                *v = rng.random();
+            }
+
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+
+            let mut first_request = vec![0u8; 104];
+            buf.read_exact(&mut first_request).unwrap();
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
+            assert_eq!(
+                buf.buffer.num_readable_bytes_left(),
+                buf_capacity - first_request.len()
+            );
+            assert_eq!(&buf.inner().get_ref()[..104], &first_request);
+
+            let cloned_data = buf.inner().get_ref().to_vec();
+            let mut request = vec![0u8; buf.inner().get_ref().len() - first_request.len()];
+            for (chunk_to_read, expected) in request
+                .chunks_mut(buf_capacity)
+                .zip(cloned_data[first_request.len()..].chunks(buf_capacity))
+            {
+                buf.read_exact(chunk_to_read).unwrap();
+                assert_eq!(buf.buffer.is_dirty(), false);
+                assert_eq!(&chunk_to_read, &expected);
+            }
+        }
+
+        {
+            // We write a small thing before trying a big read
+
+            let mut rng = rand::rng();
+            let mut cursor = Cursor::new(vec![]);
+            let mut buf = BufReaderWriter::new(&mut cursor);
+            let buf_capacity = buf.capacity();
+
+            buf.inner.get_mut().resize((buf_capacity * 4) + 77, 0u8);
+            for v in buf.inner.get_mut() {
+                *v = rng.random();
+            }
+
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+
+            let mut cloned_data = buf.inner().get_ref().to_vec();
+            let mut data_to_write = vec![0u8; 77];
+            for v in data_to_write.iter_mut() {
+                *v = rng.random();
+            }
+            buf.write_all(&data_to_write).unwrap();
+            assert_eq!(buf.buffer.is_dirty(), true);
+            cloned_data[..data_to_write.len()].copy_from_slice(&data_to_write);
+            assert_eq!(buf.position(), data_to_write.len() as u64);
+
+            let mut request = vec![0u8; cloned_data.len() - data_to_write.len()];
+            for (chunk_to_read, expected) in request
+                .chunks_mut(buf_capacity)
+                .zip(cloned_data[data_to_write.len()..].chunks(buf_capacity))
+            {
+                buf.read_exact(chunk_to_read).unwrap();
+                assert_eq!(buf.buffer.is_dirty(), false);
+                assert_eq!(&chunk_to_read, &expected);
+            }
+            assert_eq!(buf.inner.get_ref(), &cloned_data);
+        }
+
+        {
+            // We read and write a small thing before trying a big read
+
+            let mut rng = rand::rng();
+            let mut cursor = Cursor::new(vec![]);
+            let mut buf = BufReaderWriter::new(&mut cursor);
+            let buf_capacity = buf.capacity();
+
+            buf.inner.get_mut().resize((buf_capacity * 4) + 77, 0u8);
+            for v in buf.inner.get_mut() {
+                *v = rng.random();
+            }
+
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+
+            let mut first_request = vec![0u8; 104];
+            buf.read_exact(&mut first_request).unwrap();
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
+            assert_eq!(
+                buf.buffer.num_readable_bytes_left(),
+                buf_capacity - first_request.len()
+            );
+            assert_eq!(
+                &buf.inner().get_ref()[..first_request.len()],
+                &first_request
+            );
+            assert_eq!(buf.position(), first_request.len() as u64);
+
+            let mut cloned_data = buf.inner().get_ref().to_vec();
+            let mut data_to_write = vec![0u8; 77];
+            for v in data_to_write.iter_mut() {
+                *v = rng.random();
+            }
+            buf.write_all(&data_to_write).unwrap();
+            assert_eq!(buf.buffer.is_dirty(), true);
+            cloned_data[first_request.len()..data_to_write.len() + first_request.len()]
+                .copy_from_slice(&data_to_write);
+            assert_eq!(
+                buf.position(),
+                first_request.len() as u64 + data_to_write.len() as u64
+            );
+
+            let mut request =
+                vec![0u8; cloned_data.len() - first_request.len() - data_to_write.len()];
+            for (chunk_to_read, expected) in request
+                .chunks_mut(buf_capacity)
+                .zip(cloned_data[first_request.len() + data_to_write.len()..].chunks(buf_capacity))
+            {
+                buf.read_exact(chunk_to_read).unwrap();
+                assert_eq!(buf.buffer.is_dirty(), false);
+                assert_eq!(&chunk_to_read, &expected);
+            }
+            assert_eq!(buf.inner.get_ref(), &cloned_data);
+        }
+    }
+
+    #[test]
+    fn test_pause_resume_buffering() {
+        let mut expected = (0..=255u8).cycle().take(20_000).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::new(Cursor::new(expected.clone()));
+
+        // Buffered phase: read then write, leaving the buffer dirty
+        let mut c = [0u8; 10];
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(&c, &expected[..10]);
+        buf.write_all(b"hello").unwrap();
+        expected[10..15].copy_from_slice(b"hello");
+        assert_eq!(buf.buffer.is_dirty(), true);
+
+        // Pausing flushes the dirty data and drops the cache
+        buf.pause_buffering().unwrap();
+        assert!(buf.is_buffering_paused());
+        assert_eq!(buf.buffer.num_valid_bytes(), 0);
+        assert_eq!(buf.position(), 15);
+        assert_eq!(buf.inner().position(), 15);
+        assert_eq!(buf.inner().get_ref(), &expected);
+
+        // Paused phase: everything goes to the inner stream
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(&c, &expected[15..25]);
+        assert_eq!(buf.position(), 25);
+        assert_eq!(buf.inner().position(), 25);
+
+        let n = buf.seek(std::io::SeekFrom::Start(10_000)).unwrap();
+        assert_eq!(n, 10_000);
+        assert_eq!(buf.inner().position(), 10_000);
+
+        buf.write_all(b"world").unwrap();
+        expected[10_000..10_005].copy_from_slice(b"world");
+        assert_eq!(buf.buffer.num_valid_bytes(), 0);
+        assert_eq!(buf.inner().get_ref(), &expected);
+
+        let n = buf.seek(std::io::SeekFrom::Current(-9_000)).unwrap();
+        assert_eq!(n, 1_005);
+        assert_eq!(buf.position(), 1_005);
+
+        // Buffered again
+        buf.resume_buffering();
+        assert!(!buf.is_buffering_paused());
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(&c, &expected[1_005..1_015]);
+        assert_eq!(buf.buffer.num_valid_bytes(), buf.capacity());
+        buf.write_all(b"again").unwrap();
+        expected[1_015..1_020].copy_from_slice(b"again");
+        assert_eq!(buf.position(), 1_020);
+
+        buf.pause_buffering().unwrap();
+        assert_eq!(buf.position(), 1_020);
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut all = vec![0u8; expected.len()];
+        buf.read_exact(&mut all).unwrap();
+        assert_eq!(all, expected);
+        buf.resume_buffering();
+
+        assert_eq!(buf.into_inner().unwrap().into_inner(), expected);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_resume_state_is_serializable() {
+        fn assert_serde<S: serde::Serialize + serde::de::DeserializeOwned>() {}
+        assert_serde::<crate::ResumeState>();
+    }
+
+    #[test]
+    fn test_flush_writes_does_not_flush_inner() {
+        let mut counter = CountingStream::new(Cursor::new(vec![]));
+
+        {
+            let mut buf = BufReaderWriter::new(&mut counter);
+            buf.write_all(b"Hello").unwrap();
+            buf.flush_writes().unwrap();
+            assert_eq!(buf.buffer.is_dirty(), false);
+            assert_eq!(buf.position(), 5);
+            assert_eq!(buf.inner().get_ref().get_ref(), b"Hello");
+            assert_eq!(buf.inner().flushes(), 0);
+
+            buf.write_all(b" World").unwrap();
+            buf.flush().unwrap();
+            assert_eq!(buf.inner().get_ref().get_ref(), b"Hello World");
+            assert_eq!(buf.inner().flushes(), 1);
+
+            buf.write_all(b"!").unwrap();
+        }
+        // Drop wrote the data and flushed the inner stream
+        assert_eq!(counter.get_ref().get_ref(), b"Hello World!");
+        assert_eq!(counter.flushes(), 2);
+
+        counter.get_mut().set_position(0);
+        {
+            let mut buf = BufReaderWriter::new(&mut counter);
+            buf.set_flush_inner_on_drop(false);
+            buf.write_all(b"?").unwrap();
+        }
+        // Drop wrote the data but did not flush the inner stream
+        assert_eq!(counter.get_ref().get_ref(), b"?ello World!");
+        assert_eq!(counter.flushes(), 2);
+    }
+
+    #[test]
+    fn test_swap_ranges_larger_than_capacity() {
+        let mut rng = rand::rng();
+        let mut expected = vec![0u8; 1000];
+        rng.fill(expected.as_mut_slice());
+
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(expected.clone()), 64);
+        buf.seek(std::io::SeekFrom::Start(33)).unwrap();
+        buf.write_all(b"dirty").unwrap();
+        expected[33..38].copy_from_slice(b"dirty");
+
+        buf.swap_ranges(700, 10, 250).unwrap();
+        let (first, second) = expected.split_at_mut(700);
+        first[10..260].swap_with_slice(&mut second[..250]);
+
+        assert_eq!(buf.position(), 38);
+        assert_eq!(buf.inner().get_ref(), &expected);
+
+        // Overlapping ranges are rejected
+        let err = buf.swap_ranges(10, 100, 91).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Going past the end
+        let err = buf.swap_ranges(0, 900, 101).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.position(), 38);
+    }
+
+    #[test]
+    fn test_swap_ranges_in_buffer() {
+        let mut buf = BufReaderWriter::new(Cursor::new(b"0123456789".to_vec()));
+        let mut c = [0u8; 2];
+        buf.read_exact(&mut c).unwrap();
+
+        buf.swap_ranges(6, 1, 3).unwrap();
+        assert_eq!(buf.buffer.is_dirty(), true);
+        assert_eq!(&buf.buffer.data[..10], b"0678451239");
+        // Nothing was written yet
+        assert_eq!(buf.inner().get_ref(), b"0123456789");
+        assert_eq!(buf.position(), 2);
+
+        buf.read_exact(&mut c).unwrap();
+        assert_eq!(&c, b"78");
+        assert_eq!(buf.into_inner().unwrap().into_inner(), b"0678451239");
+    }
+
+    #[test]
+    fn test_rev_records_fills_backward_windows() {
+        let record_size = 10;
+        let num_records = 100;
+        let data = (0..num_records * record_size)
+            .map(|i| (i / record_size) as u8)
+            .collect::<Vec<_>>();
+
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 95);
+        let mut expected = num_records;
+        for record in buf.rev_records(record_size) {
+            expected -= 1;
+            assert_eq!(record.unwrap(), vec![expected as u8; record_size]);
+        }
+        assert_eq!(expected, 0);
+        assert_eq!(buf.position(), 0);
+        // Each fill serves 9 records, the first fill also reads the end of the stream
+        assert_eq!(buf.inner().reads(), num_records.div_ceil(9) + 1);
+        assert!(buf.inner().seeks() <= num_records.div_ceil(9) + 2);
+    }
+
+    #[test]
+    fn test_rev_records_partial_record() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"aabbc".to_vec()), 3);
+        let mut records = buf.rev_records(2);
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(records.next().is_none());
+
+        // Dirty data is part of the stream
+        buf.seek(std::io::SeekFrom::End(0)).unwrap();
+        buf.write_all(b"c").unwrap();
+        buf.write_all(b"d").unwrap();
+        let records = buf
+            .rev_records(2)
+            .allow_partial(true)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records, [&b"d"[..], b"cc", b"bb", b"aa"]);
+        assert_eq!(buf.position(), 0);
+        assert_eq!(buf.into_inner().unwrap().into_inner(), b"aabbccd");
+    }
+
+    #[test]
+    fn test_apply_patches() {
+        let mut rng = rand::rng();
+        let mut expected = vec![0u8; 10 * 1024 * 1024];
+        rng.fill(expected.as_mut_slice());
+
+        // Non overlapping patches, at most one every 10KiB
+        let mut patches = (0..1000u64)
+            .map(|i| {
+                let len = rng.random_range(1..=16);
+                let offset = i * 10 * 1024 + rng.random_range(0..10 * 1024 - 16);
+                let mut bytes = vec![0u8; len];
+                rng.fill(bytes.as_mut_slice());
+                (offset, bytes)
+            })
+            .collect::<Vec<_>>();
+        for (offset, bytes) in &patches {
+            let offset = *offset as usize;
+            expected[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        // The windows touched by the patches, given they are applied in order
+        let capacity = 8192;
+        let mut num_windows = 0;
+        let mut window_end = 0;
+        for (offset, bytes) in &patches {
+            if *offset + bytes.len() as u64 > window_end {
+                num_windows += 1;
+                window_end = *offset + capacity as u64;
+            }
+        }
+
+        patches.shuffle(&mut rng);
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(expected.clone())), capacity);
+        buf.seek(std::io::SeekFrom::Start(5)).unwrap();
+        buf.apply_patches(&mut patches).unwrap();
+        assert_eq!(buf.position(), 5);
+        buf.flush_writes().unwrap();
+
+        assert_eq!(buf.inner().writes(), num_windows);
+        assert_eq!(buf.inner().reads(), num_windows);
+        assert!(buf.inner().get_ref().get_ref() == &expected);
+    }
+
+    #[test]
+    fn test_apply_patches_rejects_overlaps() {
+        let mut buf = BufReaderWriter::new(Cursor::new(b"0123456789".to_vec()));
+        let err = buf
+            .apply_patches(&mut [(4, b"ab".to_vec()), (0, b"c".to_vec()), (3, b"de".to_vec())])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(buf.into_inner().unwrap().into_inner(), b"0123456789");
+
+        // Patches can touch each other, and go past the end
+        let mut buf = BufReaderWriter::new(Cursor::new(b"0123456789".to_vec()));
+        buf.apply_patches(&mut [(8, b"abcd".to_vec()), (3, b"ef".to_vec()), (5, b"g".to_vec())])
+            .unwrap();
+        assert_eq!(buf.into_inner().unwrap().into_inner(), b"012efg67abcd");
+    }
+
+    #[test]
+    fn test_read_scattered() {
+        let mut rng = rand::rng();
+        let mut expected = vec![0u8; 1024 * 1024];
+        rng.fill(expected.as_mut_slice());
+
+        let capacity = 4096;
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(expected.clone())), capacity);
+        // Pending dirty data must be observed
+        buf.seek(std::io::SeekFrom::Start(1000)).unwrap();
+        buf.write_all(&[0xAA; 100]).unwrap();
+        expected[1000..1100].fill(0xAA);
+
+        let ranges = (0..500)
+            .map(|_| {
+                let offset = rng.random_range(0..expected.len() - 64);
+                (offset, rng.random_range(0..64))
+            })
+            .chain([(990, 20), (expected.len() - 10, 10)])
+            .collect::<Vec<_>>();
+        let mut outputs = ranges.iter().map(|(_, len)| vec![0u8; *len]).collect::<Vec<_>>();
+
+        let mut sorted = ranges.clone();
+        sorted.sort();
+        let mut num_windows = 1; // The one holding the dirty data
+        let mut window = (1000, 1100);
+        for (offset, len) in sorted {
+            if len != 0 && (offset < window.0 || offset + len > window.1) {
+                num_windows += 1;
+                window = (offset, offset + capacity);
+            }
+        }
+
+        let mut requests = ranges
+            .iter()
+            .zip(outputs.iter_mut())
+            .map(|((offset, _), out)| (*offset as u64, out.as_mut_slice()))
+            .collect::<Vec<_>>();
+        buf.read_scattered(&mut requests).unwrap();
+        assert_eq!(buf.position(), 1100);
+
+        for ((offset, len), out) in ranges.iter().zip(outputs.iter()) {
+            assert_eq!(out.as_slice(), &expected[*offset..*offset + *len]);
+        }
+        // One fill per window except the one that was already there, the last one reaches the end
+        assert!(buf.inner().reads() <= num_windows);
+
+        let err = buf
+            .read_scattered(&mut [(expected.len() as u64 - 1, &mut [0u8; 2])])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_rewrite_with() {
+        let mut rng = rand::rng();
+        let capacity = 100;
+        let mut data = vec![0u8; 5 * capacity + 17];
+        rng.fill(data.as_mut_slice());
+
+        for chunk_size in [1, 7, 33, 100, 150] {
+            let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data.clone())), capacity);
+            buf.seek(std::io::SeekFrom::Start(3)).unwrap();
+
+            let mut next_offset = 3;
+            let n = buf
+                .rewrite_with(chunk_size, |offset, chunk| {
+                    assert_eq!(offset, next_offset);
+                    next_offset += chunk.len() as u64;
+                    if offset + chunk.len() as u64 != data.len() as u64 {
+                        assert_eq!(chunk.len(), chunk_size);
+                    }
+                    assert_eq!(chunk, &data[offset as usize..][..chunk.len()]);
+                    chunk.iter_mut().for_each(|b| *b = b.wrapping_add(1));
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(n, data.len() as u64 - 3);
+            assert_eq!(buf.position(), data.len() as u64);
+
+            let mut expected = data.clone();
+            expected[3..].iter_mut().for_each(|b| *b = b.wrapping_add(1));
+            assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_with_unmodified_chunks_are_not_written() {
+        let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data.clone())), 100);
+        let n = buf
+            .rewrite_with(30, |offset, chunk| {
+                if offset == 600 {
+                    chunk[0] = 0xFF;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(n, 1000);
+        assert_eq!(buf.inner().writes(), 1);
+
+        let mut expected = data;
+        expected[600] = 0xFF;
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected);
+    }
+
+    #[test]
+    fn test_seek_to_current_position_is_a_no_op() {
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(vec![0u8; 100])), 50);
+
+        // Empty cache
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        // End of the cached range
+        let mut data = [0u8; 10];
+        buf.read_exact(&mut data).unwrap();
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        buf.stream_position().unwrap();
+        assert_eq!(buf.inner().seeks(), 0);
+
+        // The length is not known yet
+        assert_eq!(buf.seek(std::io::SeekFrom::End(-20)).unwrap(), 80);
+        assert_eq!(buf.inner().seeks(), 1);
+        assert_eq!(buf.seek(std::io::SeekFrom::End(-20)).unwrap(), 80);
+        assert_eq!(buf.seek(std::io::SeekFrom::Start(80)).unwrap(), 80);
+        assert_eq!(buf.inner().seeks(), 1);
+
+        // Buffered data extends the stream
+        buf.write_all(&[1u8; 25]).unwrap();
+        assert_eq!(buf.seek(std::io::SeekFrom::End(0)).unwrap(), 105);
+        assert_eq!(buf.inner().seeks(), 1);
+        assert_eq!(buf.inner().reads(), 1);
+
+        assert_eq!(buf.seek(std::io::SeekFrom::End(-10)).unwrap(), 95);
+        assert_eq!(buf.seek(std::io::SeekFrom::End(0)).unwrap(), 105);
+        buf.write_all(&[2u8; 5]).unwrap();
+        let mut expected = vec![0u8; 80];
+        expected.extend_from_slice(&[1u8; 25]);
+        expected.extend_from_slice(&[2u8; 5]);
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected);
+    }
+
+    #[test]
+    fn test_default_capacity() {
+        let before = BufReaderWriter::new(Cursor::new(vec![]));
+        let default = crate::default_capacity();
+        assert_eq!(before.capacity(), default);
+
+        crate::set_default_capacity(2 * default);
+        let after = BufReaderWriter::new(Cursor::new(vec![]));
+        crate::set_default_capacity(default);
+
+        assert_eq!(before.capacity(), default);
+        assert_eq!(after.capacity(), 2 * default);
+        assert_eq!(BufReaderWriter::new(Cursor::new(vec![])).capacity(), default);
+
+    }
+
+    #[test]
+    fn test_whole_file_mode() {
+        let mut rng = rand::rng();
+        let mut expected = vec![0u8; 2048];
+        rng.fill(expected.as_mut_slice());
+
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(expected.clone())), 512);
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        buf.set_whole_file_threshold(Some(4096));
+        assert_eq!(buf.capacity(), 512);
+        assert_eq!(buf.inner().reads(), 0);
+
+        let mut data = [0u8; 600];
+        buf.read_exact(&mut data).unwrap();
+        assert_eq!(data, expected[100..700]);
+        buf.seek(std::io::SeekFrom::Start(1900)).unwrap();
+        buf.write_all(b"edit").unwrap();
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        buf.write_all(b"other edit").unwrap();
+        assert_eq!(buf.seek(std::io::SeekFrom::End(0)).unwrap(), 2048);
+        buf.seek(std::io::SeekFrom::Start(2048)).unwrap();
+        buf.flush_writes().unwrap();
+
+        expected[1900..1904].copy_from_slice(b"edit");
+        expected[10..20].copy_from_slice(b"other edit");
+        assert_eq!(buf.inner().reads(), 1);
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected);
+
+        // Too large, nothing is loaded
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(vec![0u8; 2048])), 512);
+        buf.set_whole_file_threshold(Some(1024));
+        buf.read_exact(&mut data[..10]).unwrap();
+        assert_eq!(buf.capacity(), 512);
+        assert_eq!(buf.stats().bytes_read, 512);
+
+        // The settings of the buffer are kept
+        let mut buf = BufReaderWriter::with_capacities(Cursor::new(vec![0u8; 2048]), 512, 256);
+        buf.set_whole_file_threshold(Some(4096));
+        buf.read_exact(&mut data[..10]).unwrap();
+        assert_eq!(buf.capacity(), 2048);
+        assert_eq!((buf.read_capacity(), buf.write_capacity()), (2048, 256));
+        let mut buf =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(vec![0u8; 2000]), 512, 512).unwrap();
+        buf.set_whole_file_threshold(Some(4096));
+        buf.seek(std::io::SeekFrom::Start(1000)).unwrap();
+        assert_eq!(buf.capacity(), 2048);
+        assert_eq!(buf.alignment.map(|alignment| alignment.size()), Some(512));
+        assert_eq!(buf.buffer.data.as_ptr() as usize % 512, 0);
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let mut buf = BufReaderWriter::in_memory();
+        buf.write_all(b"Hello").unwrap();
+        assert_eq!(buf.into_vec().unwrap(), b"Hello");
+
+        let mut buf = BufReaderWriter::from_vec(b"Hello World".to_vec());
+        let mut data = [0u8; 5];
+        buf.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"Hello");
+        buf.write_all(b"_").unwrap();
+        assert_eq!(buf.into_vec().unwrap(), b"Hello_World");
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(vec![0u8; 100])), 10);
+        let mut data = [0u8; 20];
+        buf.read_exact(&mut data[..5]).unwrap();
+        buf.read_exact(&mut data).unwrap();
+        buf.seek(std::io::SeekFrom::Start(50)).unwrap();
+        buf.write_all(&data[..5]).unwrap();
+        buf.write_all(&data).unwrap();
+
+        let stats = buf.stats();
+        assert_eq!(stats.fills as usize, buf.inner().reads() - stats.direct_reads as usize);
+        assert_eq!(stats.fills, 1);
+        assert_eq!(stats.direct_reads, 1);
+        assert_eq!(stats.bytes_read, 10 + 15);
+        assert_eq!(stats.dumps, 1);
+        assert_eq!(stats.direct_writes, 1);
+        assert_eq!(stats.bytes_written, 25);
+        assert_eq!(stats.seeks as usize, buf.inner().seeks());
+
+        let state = buf.suspend().unwrap();
+        assert_eq!(state.stats(), buf.stats());
+        let buf = BufReaderWriter::resume(buf.into_inner().unwrap(), state.clone()).unwrap();
+        assert_eq!(buf.stats(), state.stats());
+    }
+
+    #[test]
+    fn test_read_gaps_as_zero() {
+        let mut buf = BufReaderWriter::in_memory();
+        buf.set_read_gaps_as_zero(true);
+        buf.seek(std::io::SeekFrom::Start(100_000)).unwrap();
+        buf.write_all(b"end").unwrap();
+
+        buf.seek(std::io::SeekFrom::Start(50_000)).unwrap();
+        let mut data = [1u8; 100];
+        buf.read_exact(&mut data).unwrap();
+        assert_eq!(data, [0u8; 100]);
+
+        // The inner stream got shorter than what was written
+        buf.flush_writes().unwrap();
+        buf.inner_mut().get_mut().truncate(60_000);
+        buf.seek(std::io::SeekFrom::Start(59_990)).unwrap();
+        let mut data = vec![1u8; 40_013];
+        buf.read_exact(&mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+        assert_eq!(buf.position(), 100_003);
+        assert_eq!(buf.read(&mut data).unwrap(), 0);
+
+        buf.set_read_gaps_as_zero(false);
+        buf.seek(std::io::SeekFrom::Start(60_000)).unwrap();
+        assert_eq!(buf.read(&mut data).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_flush_semantics() {
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(b"0123456789".to_vec())), 8);
+        let mut data = [0u8; 3];
+        buf.read_exact(&mut data).unwrap();
+        buf.write_all(b"abc").unwrap();
+        let reads = buf.inner().reads();
+
+        // flush_writes: written, cache kept, position unchanged, inner not flushed
+        buf.flush_writes().unwrap();
+        assert_eq!(buf.position(), 6);
+        assert_eq!(buf.inner().get_ref().get_ref(), b"012abc6789");
+        assert_eq!(buf.inner().flushes(), 0);
+        let writes = buf.inner().writes();
+        // Nothing to write anymore
+        buf.flush_writes().unwrap();
+        assert_eq!(buf.inner().writes(), writes);
+
+        // Write::flush: same, and the inner is flushed
+        buf.write_all(b"d").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.position(), 7);
+        assert_eq!(buf.inner().get_ref().get_ref(), b"012abcd789");
+        assert_eq!(buf.inner().flushes(), 1);
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        buf.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"012");
+        assert_eq!(buf.inner().reads(), reads);
+
+        // sync_cache: the cache is dropped, the position unchanged
+        buf.inner_mut().get_mut().get_mut()[3] = b'X';
+        buf.sync_cache().unwrap();
+        assert_eq!(buf.position(), 3);
+        buf.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"Xbc");
+        assert_eq!(buf.inner().reads(), reads + 1);
+        assert_eq!(buf.inner().flushes(), 1);
+    }
+
+    #[test]
+    fn test_no_flush_on_drop() {
+        let mut counter = CountingStream::new(Cursor::new(b"0123456789".to_vec()));
+        {
+            let mut buf = BufReaderWriter::new(&mut counter);
+            buf.set_flush_on_drop(false);
+            assert_eq!(buf.has_dirty_data(), false);
+            buf.write_all(b"abc").unwrap();
+            assert_eq!(buf.has_dirty_data(), true);
+            buf.flush_writes().unwrap();
+            assert_eq!(buf.has_dirty_data(), false);
+            buf.write_all(b"def").unwrap();
+        }
+        // The dirty data was dropped, and the inner was not flushed
+        assert_eq!(counter.get_ref().get_ref(), b"abc3456789");
+        assert_eq!(counter.writes(), 1);
+        assert_eq!(counter.flushes(), 0);
+
+        counter.get_mut().set_position(0);
+        let mut buf = BufReaderWriter::new(&mut counter);
+        buf.set_flush_on_drop(false);
+        buf.write_all(b"def").unwrap();
+        buf.into_inner().unwrap();
+        assert_eq!(counter.get_ref().get_ref(), b"def3456789");
+    }
+
+    #[test]
+    fn test_buf_read() {
+        use std::io::BufRead;
+
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"first\nsecond\nthird".to_vec()), 4);
+        let mut line = String::new();
+        buf.read_line(&mut line).unwrap();
+        assert_eq!(line, "first\n");
+        assert_eq!(buf.position(), 6);
+
+        buf.write_all(b"SEC").unwrap();
+        line.clear();
+        buf.read_line(&mut line).unwrap();
+        assert_eq!(line, "ond\n");
+
+        // fill_buf while paused
+        buf.pause_buffering().unwrap();
+        assert_eq!(buf.fill_buf().unwrap(), b"thir");
+        buf.consume(2);
+        assert_eq!(buf.position(), 15);
+        let mut rest = String::new();
+        buf.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "ird");
+        buf.resume_buffering();
+
+        assert_eq!(buf.into_vec().unwrap(), b"first\nSECond\nthird");
+    }
+
+    #[test]
+    fn test_try_with_capacity() {
+        let buf = BufReaderWriter::try_with_capacity(Cursor::new(Vec::<u8>::new()), 64).unwrap();
+        assert_eq!(buf.capacity(), 64);
+
+        let result = BufReaderWriter::try_with_capacity(Cursor::new(Vec::<u8>::new()), usize::MAX);
+        assert!(result.is_err());
+        let result = BufReaderWriter::try_with_capacity(Cursor::new(Vec::<u8>::new()), isize::MAX as usize);
+        assert!(result.is_err());
+    }
+
+    /// Stream that misbehaves while respecting the signatures of the traits
+    #[derive(Default)]
+    struct LyingStream {
+        over_report_reads: bool,
+        seeks: u64,
+    }
+
+    impl Read for LyingStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(8);
+            buf[..n].fill(b'x');
+            Ok(if self.over_report_reads { buf.len() + 1 } else { n })
+        }
+    }
+
+    impl Write for LyingStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for LyingStream {
+        fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
+            // Never where it was asked to go
+            self.seeks += 1;
+            Ok(self.seeks)
+        }
+    }
+
+    #[test]
+    fn test_misbehaving_inner_stream_errors_instead_of_panicking() {
+        let inner = LyingStream {
+            over_report_reads: true,
+            ..Default::default()
+        };
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        let mut bytes = [0u8; 4];
+        assert!(buf.read(&mut bytes).is_err());
+        assert!(buf.read_exact(&mut bytes).is_err());
+
+        let mut buf = BufReaderWriter::with_capacity(LyingStream::default(), 16);
+        buf.read_exact(&mut bytes[..2]).unwrap();
+        buf.write_all(b"y").unwrap();
+        // Dumping seeks back to the start of the dirty data, which the inner stream does not honor
+        assert!(buf.seek(std::io::SeekFrom::Start(100)).is_err());
+    }
+
+    #[test]
+    fn test_seek_current_extreme_deltas() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        for start in [0, 5, 50, 98] {
+            let mut buf = BufReaderWriter::with_capacity(Cursor::new(data.clone()), 16);
+            buf.seek(std::io::SeekFrom::Start(start)).unwrap();
+            let mut byte = [0u8];
+            buf.read_exact(&mut byte).unwrap();
+
+            let err = buf.seek(std::io::SeekFrom::Current(i64::MIN)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(buf.position(), start + 1);
+            buf.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0] as u64, start + 1);
+
+            let target = start + 2 + i64::MAX as u64;
+            assert_eq!(buf.seek(std::io::SeekFrom::Current(i64::MAX)).unwrap(), target);
+            assert_eq!(buf.position(), target);
+            // i64::MAX + 1 is i64::MIN.unsigned_abs()
+            assert_eq!(buf.seek(std::io::SeekFrom::Current(i64::MIN)).unwrap(), start + 1);
+
+            buf.seek(std::io::SeekFrom::Start(start)).unwrap();
+            buf.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0] as u64, start);
+        }
+    }
+
+    #[test]
+    fn test_read_buffered_only_never_reaches_inner() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut bytes = [0u8; 10];
+        assert_eq!(buf.buffered_len(), 0);
+        assert_eq!(buf.read_buffered_only(&mut bytes), 0);
+        assert_eq!(buf.inner().reads(), 0);
+
+        buf.read_exact(&mut bytes[..4]).unwrap();
+        assert_eq!(buf.inner().reads(), 1);
+        assert_eq!(buf.buffered_len(), 12);
+
+        assert_eq!(buf.read_buffered_only(&mut bytes), 10);
+        assert_eq!(bytes, [4, 5, 6, 7, 8, 9, 10, 11, 12, 13]);
+        assert_eq!(buf.read_buffered_only(&mut bytes), 2);
+        assert_eq!(bytes[..2], [14, 15]);
+        assert_eq!(buf.read_buffered_only(&mut bytes), 0);
+        assert_eq!(buf.buffered_len(), 0);
+        assert_eq!(buf.position(), 16);
+        assert_eq!(buf.inner().reads(), 1);
+        assert_eq!(buf.inner().seeks(), 0);
+
+        // A regular read refills
+        buf.read_exact(&mut bytes[..1]).unwrap();
+        assert_eq!(bytes[0], 16);
+        assert_eq!(buf.inner().reads(), 2);
+    }
+
+    #[test]
+    fn test_with_initial_contents_skips_reading_the_primed_range() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut inner = CountingStream::new(Cursor::new(data));
+        let mut sniffed = [0u8; 32];
+        inner.read_exact(&mut sniffed).unwrap();
+        inner.reset();
+
+        let mut buf = BufReaderWriter::with_initial_contents(inner, 0, &sniffed, 4).unwrap();
+        assert_eq!(buf.position(), 4);
+        let mut bytes = [0u8; 28];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes[..], sniffed[4..]);
+        assert_eq!(buf.inner().reads(), 0);
+        assert_eq!(buf.inner().seeks(), 1);
+
+        // Past the primed range, the reads are the usual ones
+        buf.read_exact(&mut bytes[..4]).unwrap();
+        assert_eq!(bytes[..4], [32, 33, 34, 35]);
+        assert_eq!(buf.inner().reads(), 1);
+
+        // Writes in the primed range go to the right place
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        buf.write_all(b"ab").unwrap();
+        let data = buf.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&data[..3], &[b'a', b'b', 2]);
+        assert_eq!(data[32..36], [32, 33, 34, 35]);
+
+        let inner = Cursor::new(vec![0u8; 100]);
+        let result = BufReaderWriter::with_initial_contents(inner, 10, &[0u8; 8], 19);
+        assert_eq!(result.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+        let inner = Cursor::new(vec![0u8; 100]);
+        let buf = BufReaderWriter::with_initial_contents(inner, 10, &[0u8; 8], 18).unwrap();
+        assert_eq!(buf.position(), 18);
     }
 
-    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let _n = self.write(buf)?;
-        debug_assert_eq!(_n, buf.len());
-        Ok(())
+    #[test]
+    fn test_uncached_writes_are_coherent_with_the_cache() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 64);
+
+        // Overlapping the dirty data: the dirty bytes not overwritten are kept
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        buf.write_all(b"abcdef").unwrap();
+        buf.seek(std::io::SeekFrom::Start(8)).unwrap();
+        assert_eq!(buf.write_uncached(b"WXYZ").unwrap(), 4);
+        assert_eq!(buf.position(), 12);
+        assert!(!buf.has_dirty_data());
+        let mut bytes = [0u8; 8];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"cdef\x10\x11\x12\x13");
+
+        // Overlapping clean cached data: the cache does not serve stale bytes,
+        // and the write happens at the position, not at the end of the cache
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        buf.read_exact(&mut bytes[..4]).unwrap();
+        let writes = buf.inner().writes();
+        buf.write_uncached(b"new").unwrap();
+        assert_eq!(buf.inner().writes(), writes + 1);
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, &[100, 101, 102, 103, b'n', b'e', b'w', 107]);
+
+        let data = buf.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&data[8..16], b"WXYZcdef");
+        assert_eq!(&data[104..107], b"new");
+        assert_eq!(data.len(), 200);
     }
-}
 
-impl<T> Seek for BufReaderWriter<T>
-where
-    T: Write + Seek,
-{
-    /// Seek to an offset, in bytes,
-    ///
-    /// If the target position falls into the currently stored buffer,
-    /// no seek in the underlying reader will happen.
-    fn seek(&mut self, seek_from: SeekFrom) -> std::io::Result<u64> {
-        match seek_from {
-            SeekFrom::Start(pos) => {
-                let in_mem_range = self.start_position_in_source()
-                    ..self.start_position_in_source() + self.buffer.num_valid_bytes() as u64;
-                if in_mem_range.contains(&pos) {
-                    // We just need to adjust the position inside the buffer
-                    self.buffer
-                        .set_position(pos - self.start_position_in_source());
-                    Ok(self.position())
-                } else {
-                    if self.buffer.is_dirty {
-                        self.flush_buffer()?;
-                    }
-                    self.buffer.clear();
-                    self.pos = self.inner.seek(SeekFrom::Start(pos))?;
-                    self.n = 0;
-                    Ok(self.position())
-                }
-            }
-            SeekFrom::End(pos) => {
-                if self.buffer.is_dirty {
-                    self.flush_buffer()?;
-                }
-                self.buffer.clear();
+    #[test]
+    fn test_uncached_reads_see_dirty_data() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 64);
+        buf.seek(std::io::SeekFrom::Start(20)).unwrap();
+        buf.write_all(b"dirty").unwrap();
+
+        buf.seek(std::io::SeekFrom::Start(18)).unwrap();
+        let mut bytes = [0u8; 9];
+        assert_eq!(buf.read_uncached(&mut bytes).unwrap(), 9);
+        assert_eq!(&bytes, b"\x12\x13dirty\x19\x1a");
+        assert_eq!(buf.position(), 27);
+        assert_eq!(buf.inner().bytes_read(), 9);
+
+        // The position is right for the buffered operations that follow
+        buf.read_exact(&mut bytes[..2]).unwrap();
+        assert_eq!(bytes[..2], [27, 28]);
+        buf.write_all(b"!").unwrap();
+        let data = buf.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&data[18..30], b"\x12\x13dirty\x19\x1a\x1b\x1c!");
+    }
 
-                self.pos = self.inner.seek(SeekFrom::End(pos))?;
-                self.n = 0;
-                Ok(self.position())
-            }
-            SeekFrom::Current(direction) => {
-                if direction == 0 {
-                    // Shortcut as doing SeekFrom::Current(0) is common to get
-                    // the position
-                    Ok(self.position())
-                } else if direction < 0 {
-                    // Seeking backward by:
-                    let abs_d = (-direction) as usize;
+    #[test]
+    fn test_flush_range_writes_only_the_requested_span() {
+        use crate::counting::Op;
+        use std::io::SeekFrom;
+
+        let inner = CountingStream::with_log(Cursor::new(vec![b'.'; 100]));
+        let mut buf = BufReaderWriter::with_capacity(inner, 64);
+        // Cache 0..64, so that both writes are buffered together
+        let mut byte = [0u8; 1];
+        buf.read_exact(&mut byte).unwrap();
+        buf.seek(SeekFrom::Start(10)).unwrap();
+        buf.write_all(b"header").unwrap();
+        buf.seek(SeekFrom::Start(30)).unwrap();
+        buf.write_all(b"payload").unwrap();
+        buf.inner_mut().reset();
+
+        // Clean ranges and ranges out of the cache write nothing
+        assert_eq!(buf.flush_range(80, 10).unwrap(), 0);
+        assert!(buf.inner().log().is_empty());
+
+        // Up to the end of the dirty data
+        assert_eq!(buf.flush_range(28, 11).unwrap(), 9);
+        assert_eq!(
+            buf.inner().log(),
+            [
+                Op::Seek {
+                    from: SeekFrom::Start(28),
+                    position: 28
+                },
+                Op::Write {
+                    requested: 9,
+                    written: 9
+                },
+                Op::Seek {
+                    from: SeekFrom::Start(64),
+                    position: 64
+                },
+            ]
+        );
+        assert_eq!(&buf.inner().get_ref().get_ref()[8..40], b"......................payload...");
+        assert!(buf.has_dirty_data());
+        assert_eq!(buf.position(), 37);
 
-                    if abs_d > self.buffer.position() {
-                        // Trying to seek to a place that is before what the buffer contains
-                        if abs_d as u64 > self.position() {
-                            return Err(std::io::Error::other("Seeking before start"));
-                        }
+        // The rest still flushes, and the position is untouched
+        buf.write_all(b"!").unwrap();
+        buf.flush().unwrap();
+        assert!(!buf.has_dirty_data());
+        assert_eq!(&buf.inner().get_ref().get_ref()[8..40], b"..header..............payload!..");
+
+        // Flushing the whole cached range leaves nothing dirty
+        buf.write_all(b"?").unwrap();
+        assert!(buf.has_dirty_data());
+        assert_eq!(buf.flush_range(0, u64::MAX).unwrap(), 1);
+        assert!(!buf.has_dirty_data());
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner()[38], b'?');
+    }
 
-                        if self.buffer.is_dirty {
-                            self.flush_buffer()?;
-                        }
+    #[test]
+    fn test_fill_buf_min() {
+        use std::io::BufRead;
+
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+
+        // Straddling the refill boundary: 14..16 is cached, 16..22 is read
+        let mut bytes = [0u8; 14];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(buf.fill_buf_min(8).unwrap()[..8], [14, 15, 16, 17, 18, 19, 20, 21]);
+        assert_eq!(buf.inner().reads(), 2);
+        assert_eq!(buf.position(), 14);
+        buf.consume(8);
+        buf.read_exact(&mut bytes[..2]).unwrap();
+        assert_eq!(bytes[..2], [22, 23]);
+
+        // The whole capacity, with dirty data in the tail
+        buf.write_all(b"ab").unwrap();
+        let window = buf.fill_buf_min(16).unwrap();
+        assert_eq!(window.len(), 16);
+        assert_eq!(window[..4], [26, 27, 28, 29]);
+        assert_eq!(buf.position(), 26);
+        assert!(!buf.has_dirty_data());
+        assert_eq!(&buf.inner().get_ref().get_ref()[24..26], b"ab");
+
+        let err = buf.fill_buf_min(17).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // The end of the stream comes first
+        buf.seek(std::io::SeekFrom::Start(90)).unwrap();
+        let err = buf.fill_buf_min(12).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.position(), 90);
+        assert_eq!(buf.fill_buf_min(10).unwrap(), [90, 91, 92, 93, 94, 95, 96, 97, 98, 99]);
+    }
 
-                        self.pos = self.inner.seek(SeekFrom::Current(
-                            direction - (self.n as i64 - self.buffer.position() as i64),
-                        ))?;
-                        self.buffer.clear();
-                        self.n = 0;
-                        Ok(self.pos)
-                    } else {
-                        // Trying to seek to a place that is within the buffer
-                        self.buffer
-                            .set_position((self.buffer.position() - abs_d) as u64);
-                        Ok(self.position())
-                    }
-                } else {
-                    // Seeking forward
-                    let amount = direction as u64;
+    #[test]
+    fn test_write_past_the_space_left_is_staged() {
+        let inner = CountingStream::new(Cursor::new(vec![0u8; 20_000]));
+        let mut buf = BufReaderWriter::with_capacity(inner, 1024);
+        buf.set_overflow_limit(512);
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        buf.write_all(&[1u8; 600]).unwrap();
+        let data = (0..900u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        buf.write_all(&data).unwrap();
+        assert_eq!(buf.inner().writes(), 0);
+        assert_eq!(buf.position(), 1600);
+        assert_eq!(buf.capacity(), 1500);
+
+        // The middle of the write is read back from the staged data
+        buf.seek(std::io::SeekFrom::Start(1000)).unwrap();
+        let mut middle = [0u8; 400];
+        buf.read_exact(&mut middle).unwrap();
+        assert_eq!(middle, data[300..700]);
+        assert_eq!(buf.inner().writes(), 0);
+
+        // Written at the next dump, then the buffer goes back to its capacity
+        buf.seek(std::io::SeekFrom::Start(10_000)).unwrap();
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(&buf.inner().get_ref().get_ref()[700..1600], &data[..]);
+        buf.read_exact(&mut middle).unwrap();
+        assert_eq!(buf.capacity(), 1024);
+
+        // Beyond the limit, the buffer is written first
+        buf.seek(std::io::SeekFrom::Start(10_900)).unwrap();
+        buf.inner_mut().reset();
+        buf.write_all(&[2u8; 1000]).unwrap();
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(buf.capacity(), 1024);
+    }
 
-                    if amount >= self.buffer.num_readable_bytes_left() as u64 {
-                        let saved_positon = self.position() as i64;
-                        // Trying to seek to a place that is past what the buffer contains
-                        if self.buffer.is_dirty {
-                            self.flush_buffer()?;
-                        }
-                        self.buffer.clear();
-                        self.n = 0;
+    /// Stream whose Nth seek fails, after moving it anywhere
+    struct FailingSeek {
+        inner: Cursor<Vec<u8>>,
+        seeks: usize,
+        fail_at: usize,
+    }
 
-                        let new_position = self.position() as i64;
+    impl Read for FailingSeek {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
 
-                        self.pos = self
-                            .inner
-                            .seek(SeekFrom::Current(saved_positon - new_position + direction))?;
-                        Ok(self.position())
-                    } else {
-                        // Trying to seek to a place that is within the buffer
-                        self.buffer
-                            .set_position(self.buffer.position() as u64 + amount);
-                        Ok(self.position())
-                    }
+    impl Write for FailingSeek {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingSeek {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.seeks += 1;
+            if self.seeks == self.fail_at {
+                self.inner.set_position(1000);
+                return Err(std::io::Error::other("seek failed"));
+            }
+            self.inner.seek(pos)
+        }
+    }
+
+    type Op = fn(&mut BufReaderWriter<FailingSeek>) -> std::io::Result<Vec<u8>>;
+
+    fn read_4(buf: &mut BufReaderWriter<FailingSeek>) -> std::io::Result<Vec<u8>> {
+        let mut bytes = vec![0u8; 4];
+        buf.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn seek_to(buf: &mut BufReaderWriter<FailingSeek>, from: std::io::SeekFrom) -> std::io::Result<Vec<u8>> {
+        buf.seek(from).map(|_| Vec::new())
+    }
+
+    const SEEK_FAILURE_WORKLOAD: &[Op] = &[
+        read_4,
+        |buf| buf.write_all(b"ab").map(|_| Vec::new()),
+        |buf| seek_to(buf, std::io::SeekFrom::Start(50)),
+        read_4,
+        |buf| buf.write_all(b"cd").map(|_| Vec::new()),
+        |buf| seek_to(buf, std::io::SeekFrom::Current(30)),
+        |buf| buf.write_all(b"ef").map(|_| Vec::new()),
+        |buf| seek_to(buf, std::io::SeekFrom::Current(-60)),
+        read_4,
+        |buf| buf.write_all(b"0123456789abcdef").map(|_| Vec::new()),
+        |buf| seek_to(buf, std::io::SeekFrom::End(-5)),
+        |buf| buf.write_all(b"gh").map(|_| Vec::new()),
+        |buf| buf.flush().map(|_| Vec::new()),
+        |buf| seek_to(buf, std::io::SeekFrom::Start(10)),
+        |buf| buf.write_all(b"ij").map(|_| Vec::new()),
+        |buf| buf.flush_range(0, 100).map(|_| Vec::new()),
+        |buf| {
+            let mut bytes = vec![0u8; 6];
+            let n = buf.read_uncached(&mut bytes)?;
+            bytes.truncate(n);
+            Ok(bytes)
+        },
+        |buf| buf.fill_buf_min(8).map(|data| data[..8].to_vec()),
+        |buf| buf.write_all(b"kl").map(|_| Vec::new()),
+        |buf| {
+            buf.set_whole_file_threshold(Some(200));
+            Ok(Vec::new())
+        },
+        |buf| seek_to(buf, std::io::SeekFrom::Current(-1)),
+        read_4,
+    ];
+
+    /// Runs the workload, retrying each failed operation once,
+    /// and returns what was read along with the final data
+    fn run_with_seek_failure(fail_at: usize) -> (Vec<Vec<u8>>, Vec<u8>, usize) {
+        let inner = FailingSeek {
+            inner: Cursor::new((0..100u8).collect()),
+            seeks: 0,
+            fail_at,
+        };
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        let mut outputs = Vec::new();
+        for (i, op) in SEEK_FAILURE_WORKLOAD.iter().enumerate() {
+            let position = buf.position();
+            match op(&mut buf) {
+                Ok(output) => outputs.push(output),
+                Err(e) => {
+                    assert_eq!(e.to_string(), "seek failed", "op {i}, failing seek {fail_at}");
+                    assert_eq!(buf.position(), position, "op {i}, failing seek {fail_at}");
+                    outputs.push(op(&mut buf).unwrap());
                 }
             }
         }
+        let inner = buf.into_inner().unwrap();
+        (outputs, inner.inner.into_inner(), inner.seeks)
     }
 
-    fn stream_position(&mut self) -> std::io::Result<u64> {
-        Ok(self.position())
+    #[test]
+    fn test_failed_inner_seeks_leave_a_consistent_state() {
+        let (expected_outputs, expected_data, num_seeks) = run_with_seek_failure(0);
+        assert!(num_seeks > 10);
+        for fail_at in 1..=num_seeks {
+            let (outputs, data, _) = run_with_seek_failure(fail_at);
+            assert_eq!(outputs, expected_outputs, "failing seek {fail_at}");
+            assert_eq!(data, expected_data, "failing seek {fail_at}");
+        }
+
+        // A failed swap is not undone, but the stream is still usable
+        let inner = FailingSeek {
+            inner: Cursor::new((0..100u8).collect()),
+            seeks: 0,
+            fail_at: 3,
+        };
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        buf.seek(std::io::SeekFrom::Start(40)).unwrap();
+        assert!(buf.swap_ranges(0, 60, 8).is_err());
+        assert_eq!(buf.position(), 40);
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [40, 41, 42, 43]);
     }
-}
 
-impl<T> Drop for BufReaderWriter<T>
-where
-    T: Write + Seek,
-{
-    fn drop(&mut self) {
-        if self.buffer.is_dirty {
-            let _ = self.flush();
+    #[test]
+    fn test_read_vectored() {
+        use std::io::IoSliceMut;
+
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let (mut a, mut b, mut c) = ([0u8; 3], [0u8; 5], [0u8; 10]);
+
+        // Drained across the slices, skipping the empty ones
+        let n = buf
+            .read_vectored(&mut [
+                IoSliceMut::new(&mut a),
+                IoSliceMut::new(&mut []),
+                IoSliceMut::new(&mut b),
+            ])
+            .unwrap();
+        assert_eq!(n, 8);
+        assert_eq!((a, b), ([0, 1, 2], [3, 4, 5, 6, 7]));
+        assert_eq!(buf.inner().reads(), 1);
+
+        // Straddling the end of the cached data, only the cached data is returned
+        let n = buf
+            .read_vectored(&mut [IoSliceMut::new(&mut b), IoSliceMut::new(&mut a)])
+            .unwrap();
+        assert_eq!(n, 8);
+        assert_eq!((b, a), ([8, 9, 10, 11, 12], [13, 14, 15]));
+        let n = buf.read_vectored(&mut [IoSliceMut::new(&mut c)]).unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(c, [16, 17, 18, 19, 20, 21, 22, 23, 24, 25]);
+        assert_eq!(buf.inner().reads(), 2);
+
+        // The dirty data is written before reading directly
+        buf.read_exact(&mut [0u8; 6]).unwrap();
+        buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
+        buf.write_all(b"xy").unwrap();
+        let n = buf
+            .read_vectored(&mut [
+                IoSliceMut::new(&mut c),
+                IoSliceMut::new(&mut b),
+                IoSliceMut::new(&mut a),
+            ])
+            .unwrap();
+        // The inner stream does not override read_vectored, so only fills the first slice
+        assert_eq!(n, 10);
+        assert_eq!(c, [32, 33, 34, 35, 36, 37, 38, 39, 40, 41]);
+        assert_eq!(buf.inner().reads(), 3);
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(buf.buffered_len(), 0);
+        assert_eq!(buf.position(), 42);
+        assert_eq!(&buf.into_inner().unwrap().into_inner().into_inner()[30..32], b"xy");
+    }
+
+    #[test]
+    fn test_debug_shows_the_state_only() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"secret data here".to_vec()), 8);
+        buf.seek(std::io::SeekFrom::Start(4)).unwrap();
+        buf.read_exact(&mut [0u8; 2]).unwrap();
+        buf.write_all(b"XY").unwrap();
+        assert_eq!(
+            format!("{buf:?}"),
+            "BufReaderWriter { position: 8, capacity: 8, cache_start: 4, \
+             valid_bytes: 8, unread_bytes: 4, dirty: true, .. }"
+        );
+
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Holder {
+            rw: BufReaderWriter<Cursor<Vec<u8>>>,
         }
+        let holder = Holder { rw: buf };
+        assert!(!format!("{holder:?}").contains("secret"));
     }
-}
 
-/// After executing a command, all the requested bytes should have been written
-/// unless an error occurred
-enum WriteAllCommand {
-    /// The buffer has enough capacity to store the data
-    ///
-    /// So, write to the buffer
-    Write,
-    /// The buffer does not have enough capacity to store the data
-    ///
-    /// Write to the buffer, then dump the buffer to the source
-    /// and finally, write again to the buffer
-    WriteDumpWrite(usize),
-    /// Dump the buffer, then write directly to the source
-    DumpWriteDirect,
-    /// Write directly to the source
-    WriteDirect,
-}
+    #[test]
+    fn test_seek_relative_keeps_the_cached_data() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        buf.seek(std::io::SeekFrom::Start(20)).unwrap();
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"ab").unwrap();
+        let seeks = buf.inner().seeks();
+
+        // To both ends of the cached region, 20..36
+        buf.seek_relative(10).unwrap();
+        assert_eq!(buf.position(), 36);
+        buf.seek_relative(-16).unwrap();
+        assert_eq!(buf.position(), 20);
+        buf.seek_relative(0).unwrap();
+        buf.seek(std::io::SeekFrom::Current(16)).unwrap();
+        assert_eq!(buf.position(), 36);
+        assert_eq!(buf.inner().seeks(), seeks);
+        assert_eq!(buf.inner().writes(), 0);
+        assert!(buf.has_dirty_data());
+
+        let err = buf.seek_relative(-37).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(buf.position(), 36);
+        assert!(buf.has_dirty_data());
+
+        // Leaving the region
+        buf.seek_relative(-30).unwrap();
+        assert_eq!(buf.position(), 6);
+        assert_eq!(buf.inner().writes(), 1);
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [6, 7, 8, 9]);
+        buf.seek_relative(14).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [b'a', b'b', 26, 27]);
+    }
 
-/// After executing a command, not all bytes may have been read
-enum ReadCommand {
-    /// Read `n` bytes from the buffer
-    Read(usize),
-    /// Fill the buffer, then read all the bytes from the original request
-    ///
-    /// The buffer may need to be dumped before being refilled
-    FillRead { dump_before_fill: bool },
-    /// Read directly all the bytes from the original request from the source
-    /// (skip the buffer)
-    ///
-    /// The buffer may need to be dumped before
-    ReadDirect { dump_before: bool },
-}
+    #[test]
+    fn test_rewind_within_the_cache_does_not_reach_inner() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut header = [0u8; 4];
+        buf.read_exact(&mut header).unwrap();
+        buf.write_all(b"ab").unwrap();
+        let (reads, seeks) = (buf.inner().reads(), buf.inner().seeks());
+
+        for _ in 0..3 {
+            buf.rewind().unwrap();
+            buf.read_exact(&mut header).unwrap();
+            assert_eq!(header, [0, 1, 2, 3]);
+            buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+            buf.read_exact(&mut header[..2]).unwrap();
+            buf.seek(std::io::SeekFrom::Start(16)).unwrap();
+        }
+        assert_eq!(buf.inner().reads(), reads);
+        assert_eq!(buf.inner().seeks(), seeks);
+        assert_eq!(buf.inner().writes(), 0);
+        assert!(buf.has_dirty_data());
+
+        // Once the cache moved, rewinding seeks the inner stream
+        buf.seek(std::io::SeekFrom::Start(50)).unwrap();
+        buf.read_exact(&mut header).unwrap();
+        buf.rewind().unwrap();
+        buf.read_exact(&mut header).unwrap();
+        assert_eq!(header, [0, 1, 2, 3]);
+        buf.read_exact(&mut header).unwrap();
+        assert_eq!(header, [b'a', b'b', 6, 7]);
+    }
 
-/// After executing a command, all bytes will be read
-enum ReadExactCommand {
-    /// The whole output can be filled bu reading from the buffer
-    Read,
-    /// Read from the buffer, re-fill the buffer, then read all the bytes from the original request
-    ///
-    /// The buffer may need to be dumped before being refilled
-    ReadFillRead {
-        split: usize,
-        dump_before_fill: bool,
-    },
-    FillRead {
-        dump_before_fill: bool,
-    },
-    /// Read directly all the bytes from the original request from the source
-    /// (skip the buffer)
-    ///
-    /// The buffer may need to be dumped before
-    ReadDirect {
-        dump_before: bool,
-    },
-    /// Read from buffer, then finish reading from the source
-    ReadReadDirect {
-        split: usize,
-        dump_before: bool,
-    },
-}
+    #[test]
+    fn test_stream_len_keeps_the_buffer() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        buf.seek(std::io::SeekFrom::Start(90)).unwrap();
+        buf.read_exact(&mut [0u8; 4]).unwrap();
+        buf.write_all(b"0123456789").unwrap();
+        let reads = buf.inner().reads();
+
+        assert_eq!(buf.stream_len().unwrap(), 104);
+        let seeks = buf.inner().seeks();
+        assert_eq!(buf.stream_len().unwrap(), 104);
+        assert_eq!(buf.inner().seeks(), seeks);
+        assert_eq!(buf.position(), 104);
+        assert_eq!(buf.inner().writes(), 0);
+        assert!(buf.has_dirty_data());
+
+        buf.seek(std::io::SeekFrom::Start(92)).unwrap();
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [92, 93, b'0', b'1']);
+        assert_eq!(buf.inner().reads(), reads);
+    }
 
-struct Buffer {
-    data: Box<[u8]>,
-    pos: usize,
-    filled: usize,
-    is_dirty: bool,
-}
+    #[test]
+    fn test_read_to_end_skips_the_buffer() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        buf.read_exact(&mut [0u8; 2]).unwrap();
+        buf.write_all(b"ab").unwrap();
+
+        let mut content = b"prefix".to_vec();
+        assert_eq!(buf.read_to_end(&mut content).unwrap(), 86);
+        assert_eq!(&content[..6], b"prefix");
+        assert_eq!(content[6..], (14..100u8).collect::<Vec<_>>());
+        assert_eq!(buf.position(), 100);
+        assert_eq!(buf.buffered_len(), 0);
+        assert!(!buf.has_dirty_data());
+        assert_eq!(buf.stats().fills, 1);
+        assert_eq!(buf.stats().direct_reads, 1);
+
+        // The bookkeeping is right for the following operations
+        buf.seek(std::io::SeekFrom::Current(-90)).unwrap();
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [10, 11, b'a', b'b']);
+        assert_eq!(buf.read_to_end(&mut Vec::new()).unwrap(), 86);
+        assert_eq!(buf.read_to_end(&mut Vec::new()).unwrap(), 0);
+    }
 
-impl Buffer {
-    fn with_capacity(capacity: usize) -> Self {
-        let data = vec![0u8; capacity].into_boxed_slice();
-        Self::with_buffer(data)
+    #[test]
+    fn test_read_to_string_across_the_buffer_boundary() {
+        // 'é' straddles the end of the first fill
+        let text = "aaaaaaaaaaaaaaaé日本語 and ünïcödé";
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(text.as_bytes().to_vec()), 16);
+        buf.read_exact(&mut [0u8; 3]).unwrap();
+        buf.write_all(b"b").unwrap();
+
+        let mut content = String::from("prefix ");
+        let n = buf.read_to_string(&mut content).unwrap();
+        assert_eq!(n, text.len() - 4);
+        assert_eq!(content, format!("prefix {}", &text[4..]));
+
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        content.clear();
+        buf.read_to_string(&mut content).unwrap();
+        assert_eq!(content, format!("aaab{}", &text[4..]));
+
+        // Invalid data is not appended
+        buf.seek(std::io::SeekFrom::Start(16)).unwrap();
+        let err = buf.read_to_string(&mut content).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(content, format!("aaab{}", &text[4..]));
     }
 
-    fn with_buffer(buffer: Box<[u8]>) -> Self {
-        Self {
-            data: buffer,
-            pos: 0,
-            filled: 0,
-            is_dirty: false,
+    #[test]
+    fn test_write_fmt_formats_into_the_buffer() {
+        struct Failing;
+        impl std::fmt::Display for Failing {
+            fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                Err(std::fmt::Error)
+            }
+        }
+
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(Vec::new())), 16);
+        for i in 0..3 {
+            writeln!(buf, "{i},{}", i * 10).unwrap();
         }
+        assert_eq!(buf.inner().writes(), 0);
+        // Larger than the remaining space, then larger than the capacity
+        let word = String::from("abcdef");
+        write!(buf, "{word}|{}", "x".repeat(20)).unwrap();
+        assert!(buf.inner().writes() >= 1);
+        write!(buf, "end").unwrap();
+
+        let err = write!(buf, "{}{}", 1, Failing).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+        assert_eq!(buf.position(), 45);
+
+        let expected = format!("0,0\n1,10\n2,20\nabcdef|{}end1", "x".repeat(20));
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected.as_bytes());
     }
 
-    #[inline]
-    fn has_readable_bytes_left(&self) -> bool {
-        self.pos != self.filled
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn test_read_buf_into_uninitialized_memory() {
+        use std::io::BorrowedBuf;
+        use std::mem::MaybeUninit;
+
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut memory = [MaybeUninit::<u8>::uninit(); 64];
+
+        // Partially filled already, only the rest is read
+        let mut borrowed = BorrowedBuf::from(&mut memory[..10]);
+        borrowed.unfilled().append(&[0xFF; 4]);
+        buf.read_buf(borrowed.unfilled()).unwrap();
+        assert_eq!(borrowed.filled(), [0xFF, 0xFF, 0xFF, 0xFF, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(buf.inner().reads(), 1);
+
+        // Straddling the cached data
+        let mut borrowed = BorrowedBuf::from(&mut memory[..14]);
+        buf.read_buf_exact(borrowed.unfilled()).unwrap();
+        assert_eq!(borrowed.filled(), (6..20).collect::<Vec<_>>());
+        assert_eq!(buf.inner().reads(), 2);
+
+        // Large reads go directly to the inner stream, after writing the dirty data
+        buf.write_all(b"ab").unwrap();
+        buf.seek(std::io::SeekFrom::Start(32)).unwrap();
+        let mut borrowed = BorrowedBuf::from(&mut memory[..]);
+        buf.read_buf_exact(borrowed.unfilled()).unwrap();
+        assert_eq!(borrowed.filled(), (32..96).collect::<Vec<_>>());
+        assert_eq!(buf.position(), 96);
+        assert_eq!(buf.inner().writes(), 1);
+
+        let mut borrowed = BorrowedBuf::from(&mut memory[..]);
+        let err = buf.read_buf_exact(borrowed.unfilled()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(borrowed.filled(), [96, 97, 98, 99]);
+        assert_eq!(&buf.into_inner().unwrap().into_inner().into_inner()[20..22], b"ab");
     }
 
-    #[inline]
-    fn num_readable_bytes_left(&self) -> usize {
-        self.filled - self.pos
+    #[test]
+    fn test_clone_shares_nothing() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+        buf.read_exact(&mut [0u8; 2]).unwrap();
+        buf.write_all(b"ab").unwrap();
+
+        let mut clone = buf.clone();
+        assert_eq!(clone.position(), 4);
+        assert!(clone.has_dirty_data());
+        assert_eq!(clone.stats(), buf.stats());
+
+        clone.flush().unwrap();
+        assert_eq!(clone.inner().get_ref(), b"01ab456789");
+        assert_eq!(buf.inner().get_ref(), b"0123456789");
+        assert!(buf.has_dirty_data());
+        assert_ne!(clone.stats(), buf.stats());
+        buf.flush().unwrap();
+        assert_eq!(buf.inner().get_ref(), clone.inner().get_ref());
+
+        // Diverging edits
+        buf.write_all(b"XY").unwrap();
+        clone.write_all(b"xy").unwrap();
+        assert_eq!(buf.into_vec().unwrap(), b"01abXY6789");
+        assert_eq!(clone.into_vec().unwrap(), b"01abxy6789");
     }
 
-    #[inline]
-    fn num_writable_bytes_left(&self) -> usize {
-        self.capacity() - self.pos
+    #[test]
+    fn test_skip_across_refills() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut byte = [0u8];
+        buf.read_exact(&mut byte).unwrap();
+
+        // Within the cached data, then straddling one refill, then several
+        buf.skip(4).unwrap();
+        buf.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 5);
+        buf.skip(20).unwrap();
+        buf.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 26);
+        assert_eq!(buf.inner().reads(), 2);
+        buf.skip(50).unwrap();
+        buf.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 77);
+        assert_eq!(buf.inner().seeks(), 0);
+
+        // Far skips seek
+        buf.skip(100).unwrap();
+        assert!(buf.inner().seeks() > 0);
+        buf.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 178);
+
+        let err = buf.skip(30).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.position(), 200);
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        let err = buf.skip(300).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.position(), 200);
     }
 
-    #[inline]
-    fn num_valid_bytes(&self) -> usize {
-        self.filled
+    #[test]
+    fn test_peek_does_not_consume() {
+        let data = (0..40u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data), 16);
+        let (mut peeked, mut read) = ([0u8; 6], [0u8; 6]);
+
+        // Right after a write
+        buf.read_exact(&mut read[..2]).unwrap();
+        buf.write_all(b"ab").unwrap();
+        assert_eq!(buf.peek(&mut peeked).unwrap(), 6);
+        assert_eq!(peeked, [4, 5, 6, 7, 8, 9]);
+        assert_eq!(buf.position(), 4);
+        buf.read_exact(&mut read).unwrap();
+        assert_eq!(read, peeked);
+
+        // Across the end of the cached data
+        buf.seek(std::io::SeekFrom::Start(13)).unwrap();
+        assert_eq!(buf.peek(&mut peeked).unwrap(), 6);
+        assert_eq!(peeked, [13, 14, 15, 16, 17, 18]);
+        buf.read_exact(&mut read).unwrap();
+        assert_eq!(read, peeked);
+
+        // Clamped to the capacity, and short at the end of the stream
+        let mut large = [0u8; 32];
+        assert_eq!(buf.peek(&mut large).unwrap(), 16);
+        assert_eq!(large[..16], (19..35).collect::<Vec<_>>());
+        buf.seek(std::io::SeekFrom::Start(36)).unwrap();
+        assert_eq!(buf.peek(&mut peeked).unwrap(), 4);
+        assert_eq!(peeked[..4], [36, 37, 38, 39]);
+        assert_eq!(buf.position(), 36);
+
+        assert_eq!(&buf.into_vec().unwrap()[..4], [0, 1, b'a', b'b']);
     }
 
-    #[inline]
-    fn capacity(&self) -> usize {
-        self.data.len()
+    #[test]
+    fn test_peek_at_sees_the_pending_writes() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        buf.seek(std::io::SeekFrom::Start(40)).unwrap();
+        buf.read_exact(&mut [0u8; 2]).unwrap();
+        buf.write_all(b"abcd").unwrap();
+        let mut bytes = [0u8; 6];
+
+        // Within the cached data
+        assert_eq!(buf.peek_at(41, &mut bytes).unwrap(), 6);
+        assert_eq!(bytes, [41, b'a', b'b', b'c', b'd', 46]);
+        assert_eq!(buf.inner().seeks(), 1);
+
+        // Partially overlapping the dirty data, on both sides
+        assert_eq!(buf.peek_at(36, &mut bytes).unwrap(), 6);
+        assert_eq!(bytes, [36, 37, 38, 39, 40, 41]);
+        assert_eq!(buf.peek_at(54, &mut bytes).unwrap(), 6);
+        assert_eq!(bytes, [54, 55, 56, 57, 58, 59]);
+        buf.seek(std::io::SeekFrom::Start(38)).unwrap();
+        buf.write_all(b"XY").unwrap();
+        assert_eq!(buf.peek_at(37, &mut bytes).unwrap(), 6);
+        assert_eq!(bytes, [37, b'X', b'Y', 40, 41, b'a']);
+
+        // Short at the end of the stream
+        assert_eq!(buf.peek_at(97, &mut bytes).unwrap(), 3);
+        assert_eq!(bytes[..3], [97, 98, 99]);
+
+        assert_eq!(buf.position(), 40);
+        assert!(buf.has_dirty_data());
+        assert_eq!(buf.inner().writes(), 1);
+        let mut next = [0u8; 4];
+        buf.read_exact(&mut next).unwrap();
+        assert_eq!(next, [40, 41, b'a', b'b']);
     }
 
-    /// Fill the `self` from the `source`.
-    ///
-    /// This discards any data already present in `self`
-    fn fill_from(&mut self, mut source: impl Read) -> std::io::Result<usize> {
-        debug_assert!(!self.has_readable_bytes_left());
-        let n = source.read(&mut self.data)?;
-        self.filled = n;
-        self.pos = 0;
-        self.is_dirty = false;
+    #[test]
+    fn test_peek_at_writes_past_the_end() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"0123".to_vec()), 16);
+        buf.seek(std::io::SeekFrom::Start(6)).unwrap();
+        buf.write_all(b"ab").unwrap();
+        let mut bytes = [0xFFu8; 10];
+        assert_eq!(buf.peek_at(2, &mut bytes).unwrap(), 6);
+        assert_eq!(bytes[..6], [b'2', b'3', 0, 0, b'a', b'b']);
+        assert_eq!(buf.into_vec().unwrap(), b"0123\0\0ab");
+    }
 
-        Ok(n)
+    #[test]
+    fn test_unread_across_the_start_of_the_cache() {
+        let data = (0..26u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut bytes = [0u8; 20];
+        buf.read_exact(&mut bytes[..14]).unwrap();
+
+        // Still cached
+        buf.unread(&bytes[10..14]).unwrap();
+        assert_eq!(buf.position(), 10);
+        buf.read_exact(&mut bytes[10..18]).unwrap();
+        assert_eq!(bytes[..18], (0..18).collect::<Vec<_>>());
+
+        // The buffer was refilled with 16..26, with 2 bytes read from it
+        buf.unread(&bytes[12..18]).unwrap();
+        assert_eq!(buf.position(), 12);
+        let mut again = [0u8; 10];
+        buf.read_exact(&mut again).unwrap();
+        assert_eq!(again, [12, 13, 14, 15, 16, 17, 18, 19, 20, 21]);
+        assert_eq!(buf.inner().reads(), 2);
+        assert_eq!(buf.inner().seeks(), 0);
+
+        let err = buf.unread(&[0u8; 40]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(buf.position(), 22);
     }
 
-    #[inline]
-    fn set_position(&mut self, pos: u64) {
-        debug_assert!(pos < self.filled as u64);
-        self.pos = pos.min(self.filled as u64) as usize;
+    #[test]
+    fn test_unread_without_room_seeks() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut bytes = [0u8; 22];
+        buf.read_exact(&mut bytes[..14]).unwrap();
+        buf.read_exact(&mut bytes[14..]).unwrap();
+
+        // The buffer is full with 16..32
+        buf.unread(&bytes[12..]).unwrap();
+        assert_eq!(buf.position(), 12);
+        assert_eq!(buf.inner().seeks(), 1);
+        let mut again = [0u8; 12];
+        buf.read_exact(&mut again).unwrap();
+        assert_eq!(again, [12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23]);
     }
 
-    #[inline]
-    fn position(&self) -> usize {
-        self.pos
+    #[test]
+    fn test_fill_at_least() {
+        use std::io::BufRead;
+
+        struct ShortReads(Cursor<Vec<u8>>);
+        impl Read for ShortReads {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(3);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        impl Write for ShortReads {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for ShortReads {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        let data = (0..20u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(ShortReads(Cursor::new(data)), 16);
+        // Several inner reads
+        assert_eq!(buf.fill_at_least(7).unwrap()[..7], [0, 1, 2, 3, 4, 5, 6]);
+        buf.consume(4);
+
+        // Right after a write, the dirty data is written before the unread bytes move
+        buf.write_all(b"ab").unwrap();
+        assert_eq!(buf.fill_at_least(12).unwrap()[..12], [6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17]);
+        assert_eq!(buf.position(), 6);
+        assert!(!buf.has_dirty_data());
+
+        // Hitting the end of the stream
+        buf.consume(10);
+        assert_eq!(buf.fill_at_least(8).unwrap(), [16, 17, 18, 19]);
+        let err = buf.fill_at_least(17).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(&buf.into_inner().unwrap().0.into_inner()[..8], [0, 1, 2, 3, b'a', b'b', 6, 7]);
+    }
+
+    #[test]
+    fn test_read_in_place_fixed_records() {
+        let data = (0..50u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        for i in 0..10u8 {
+            let record = buf.read_in_place(5).unwrap();
+            assert_eq!(record, [5 * i, 5 * i + 1, 5 * i + 2, 5 * i + 3, 5 * i + 4]);
+        }
+        assert_eq!(buf.position(), 50);
+        assert_eq!(buf.inner().reads(), 4);
+
+        buf.seek(std::io::SeekFrom::Start(47)).unwrap();
+        let err = buf.read_in_place(5).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.position(), 47);
+        assert_eq!(buf.read_in_place(3).unwrap(), [47, 48, 49]);
+        let err = buf.read_in_place(17).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_write_reserve_and_commit() {
+        let data = (0..40u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data), 16);
+        buf.write_all(b"ab").unwrap();
+        let space = buf.write_reserve(4).unwrap();
+        space.copy_from_slice(b"cdef");
+        buf.commit(3).unwrap();
+        assert_eq!(buf.position(), 5);
+        buf.write_all(b"g").unwrap();
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [6, 7]);
+
+        // Not enough room left in the buffer, the dirty data is written first
+        buf.write_reserve(10).unwrap().fill(b'x');
+        buf.commit(10).unwrap();
+        assert_eq!(buf.position(), 18);
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut content = [0u8; 20];
+        buf.read_exact(&mut content).unwrap();
+        assert_eq!(&content, b"abcdeg\x06\x07xxxxxxxxxx\x12\x13");
+
+        // Committing nothing is fine, even without reservation
+        buf.commit(0).unwrap();
+        buf.write_reserve(2).unwrap();
+        buf.commit(0).unwrap();
+        assert_eq!(buf.position(), 20);
+        assert!(buf.write_reserve(17).is_err());
+    }
+
+    #[test]
+    fn test_commit_more_than_reserved() {
+        let mut buf = BufReaderWriter::new(Cursor::new(Vec::new()));
+        buf.write_reserve(2).unwrap();
+        let err = buf.commit(3).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "committing 3 bytes, but 2 bytes are reserved");
+        assert_eq!(buf.position(), 0);
+    }
+
+    #[test]
+    fn test_commit_after_another_operation() {
+        let mut buf = BufReaderWriter::new(Cursor::new(Vec::new()));
+        buf.write_reserve(2).unwrap();
+        buf.write_all(b"a").unwrap();
+        let err = buf.commit(2).unwrap_err();
+        assert_eq!(err.to_string(), "committing 2 bytes, but 0 bytes are reserved");
+        assert_eq!(buf.into_vec().unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_write_zeros_over_dirty_data() {
+        let data = vec![0xFFu8; 100];
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        buf.seek(std::io::SeekFrom::Start(5)).unwrap();
+        buf.write_all(b"abc").unwrap();
+        buf.write_zeros(50).unwrap();
+        assert_eq!(buf.position(), 58);
+        buf.write_all(b"d").unwrap();
+        buf.seek(std::io::SeekFrom::Start(110)).unwrap();
+        buf.write_zeros(0).unwrap();
+        buf.write_zeros(3).unwrap();
+        assert_eq!(buf.position(), 113);
+
+        let content = buf.into_inner().unwrap().into_inner().into_inner();
+        let mut expected = vec![0xFFu8; 100];
+        expected[5..8].copy_from_slice(b"abc");
+        expected[8..58].fill(0);
+        expected[58] = b'd';
+        expected.resize(113, 0);
+        assert_eq!(content, expected);
+    }
+
+    #[test]
+    fn test_copy_from() {
+        let data = vec![b'-'; 20];
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        buf.seek(std::io::SeekFrom::Start(2)).unwrap();
+        assert_eq!(buf.copy_from(&mut &b"abc"[..], 0).unwrap(), 0);
+        assert_eq!(buf.position(), 2);
+
+        assert_eq!(buf.copy_from(&mut &b"abcdef"[..], 4).unwrap(), 4);
+        assert_eq!(buf.position(), 6);
+        assert_eq!(buf.inner().writes(), 0);
+
+        let src = (0..200u8).collect::<Vec<_>>();
+        assert_eq!(buf.copy_from(&mut src.as_slice(), 1000).unwrap(), 200);
+        assert_eq!(buf.position(), 206);
+        buf.flush().unwrap();
+        // Each write but the last is a full buffer
+        assert_eq!(buf.inner().writes(), 13);
+
+        let content = buf.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&content[..6], b"--abcd");
+        assert_eq!(&content[6..], src.as_slice());
+    }
+
+    #[test]
+    fn test_copy_to_overlapping_unflushed_writes() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data.clone()), 16);
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        buf.write_all(b"abcd").unwrap();
+        buf.seek(std::io::SeekFrom::Start(30)).unwrap();
+        buf.write_all(b"efgh").unwrap();
+        assert!(buf.has_dirty_data());
+
+        buf.seek(std::io::SeekFrom::Start(8)).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(buf.copy_to(&mut out, 0).unwrap(), 0);
+        assert_eq!(buf.copy_to(&mut out, 40).unwrap(), 40);
+        assert_eq!(buf.position(), 48);
+
+        let mut expected = data[8..48].to_vec();
+        expected[2..6].copy_from_slice(b"abcd");
+        expected[22..26].copy_from_slice(b"efgh");
+        assert_eq!(out, expected);
+
+        assert_eq!(buf.copy_to(&mut out, 1000).unwrap(), 52);
+        assert_eq!(out.len(), 92);
+    }
+
+    #[test]
+    fn test_dirty_len() {
+        let data = vec![b'-'; 100];
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data), 32);
+        assert!(!buf.is_dirty());
+        assert_eq!(buf.dirty_len(), 0);
+
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(buf.dirty_len(), 0);
+
+        buf.write_all(b"ab").unwrap();
+        assert!(buf.is_dirty());
+        assert_eq!(buf.dirty_len(), 2);
+        buf.write_all(b"cde").unwrap();
+        assert_eq!(buf.dirty_len(), 5);
+
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(buf.dirty_len(), 5);
+        buf.seek(std::io::SeekFrom::Start(6)).unwrap();
+        buf.write_all(b"xyz").unwrap();
+        assert_eq!(buf.dirty_len(), 5);
+        buf.seek(std::io::SeekFrom::Start(20)).unwrap();
+        buf.write_all(b"w").unwrap();
+        assert_eq!(buf.dirty_len(), 17);
+
+        buf.flush().unwrap();
+        assert!(!buf.is_dirty());
+        assert_eq!(buf.dirty_len(), 0);
+        assert_eq!(&buf.inner().get_ref()[..10], b"----abxyz-");
+    }
+
+    #[test]
+    fn test_invalidate_after_inner_modifications() {
+        let data = b"0123456789abcdef".to_vec();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data), 8);
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"XY").unwrap();
+
+        assert!(buf.discard_cache().is_err());
+        assert_eq!(buf.position(), 4);
+        assert!(buf.has_dirty_data());
+
+        let inner = buf.inner_mut();
+        inner.get_mut()[10..12].copy_from_slice(b"AB");
+        inner.set_position(9);
+        buf.invalidate().unwrap();
+        assert_eq!(buf.position(), 9);
+        assert!(!buf.has_dirty_data());
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"9A");
+
+        buf.inner_mut().get_mut()[2..4].copy_from_slice(b"cd");
+        buf.inner_mut().set_position(1);
+        buf.discard_cache().unwrap();
+        let mut content = String::new();
+        buf.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "1cd456789ABcdef");
     }
 
-    fn dump(&mut self, mut dst: impl Write) -> std::io::Result<usize> {
-        let n = self.filled;
-        dst.write_all(&self.data[..n])?;
-        Ok(n)
+    #[test]
+    fn test_with_inner_truncation() {
+        let file = tempfile::tempfile().unwrap();
+        let mut buf = BufReaderWriter::with_capacity(file, 8);
+        buf.write_all(b"0123456789abcdef").unwrap();
+        buf.seek(std::io::SeekFrom::Start(2)).unwrap();
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"XY").unwrap();
+
+        let len = buf
+            .with_inner(|file| {
+                file.seek(std::io::SeekFrom::End(0))?;
+                file.set_len(10)?;
+                Ok(file.metadata()?.len())
+            })
+            .unwrap();
+        assert_eq!(len, 10);
+        assert_eq!(buf.position(), 6);
+        assert_eq!(buf.stream_len().unwrap(), 10);
+
+        let mut content = Vec::new();
+        buf.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"6789");
+        buf.rewind().unwrap();
+        content.clear();
+        buf.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"0123XY6789");
+
+        let err = buf
+            .with_inner(|file| {
+                file.seek(std::io::SeekFrom::Start(0))?;
+                Err::<(), _>(std::io::Error::other("failed"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+        assert_eq!(buf.position(), 10);
+        buf.write_all(b"!").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.stream_len().unwrap(), 11);
     }
 
-    #[inline]
-    fn clear(&mut self) {
-        self.pos = 0;
-        self.filled = 0;
-        self.is_dirty = false;
+    #[test]
+    fn test_replace_inner_chains_streams() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"aaaa".to_vec()), 8);
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"AA").unwrap();
+
+        let mut second = Cursor::new(b"bbbbbbbbbbbb".to_vec());
+        second.set_position(10);
+        let first = buf.replace_inner(second).unwrap();
+        assert_eq!(first.get_ref(), b"aaAA");
+        assert_eq!(buf.position(), 10);
+        buf.write_all(b"BBBB").unwrap();
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"bb");
+
+        let second = buf.replace_inner(Cursor::new(Vec::new())).unwrap();
+        assert_eq!(second.get_ref(), b"bbbbbbbbbbBBBB");
+        assert_eq!(buf.position(), 0);
+        buf.write_all(b"cc").unwrap();
+        assert_eq!(buf.read(&mut bytes).unwrap(), 0);
+
+        let third = buf.into_parts().unwrap();
+        assert_eq!(third.0.get_ref(), b"cc");
+        assert_eq!(third.1.len(), 8);
     }
 
-    #[inline]
-    fn get_read_command(&self, buf: &[u8]) -> ReadCommand {
-        if self.has_readable_bytes_left() {
-            ReadCommand::Read(buf.len().min(self.num_readable_bytes_left()))
-        } else if buf.len() >= self.capacity() {
-            ReadCommand::ReadDirect {
-                dump_before: self.is_dirty,
-            }
-        } else {
-            ReadCommand::FillRead {
-                dump_before_fill: self.is_dirty,
+    #[test]
+    fn test_map_inner_into_a_wrapper() {
+        struct Wrapper(CountingStream<Cursor<Vec<u8>>>);
+
+        impl Read for Wrapper {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buf)
             }
         }
-    }
 
-    #[inline]
-    fn get_read_exact_command(&self, buf: &[u8]) -> ReadExactCommand {
-        if buf.len() >= self.capacity() {
-            if self.has_readable_bytes_left() {
-                ReadExactCommand::ReadReadDirect {
-                    split: self.num_readable_bytes_left(),
-                    dump_before: self.is_dirty,
-                }
-            } else {
-                ReadExactCommand::ReadDirect {
-                    dump_before: self.is_dirty,
-                }
-            }
-        } else if self.num_readable_bytes_left() >= buf.len() {
-            ReadExactCommand::Read
-        } else if self.num_readable_bytes_left() < buf.len() {
-            ReadExactCommand::ReadFillRead {
-                split: self.num_readable_bytes_left(),
-                dump_before_fill: self.is_dirty,
+        impl Write for Wrapper {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
             }
-        } else {
-            debug_assert!(self.num_readable_bytes_left() == 0);
-            ReadExactCommand::FillRead {
-                dump_before_fill: self.is_dirty,
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.flush()
             }
         }
-    }
 
-    #[inline]
-    fn get_write_exact_command(&self, buf: &[u8]) -> WriteAllCommand {
-        if buf.len() >= self.capacity() {
-            if self.is_dirty && self.num_valid_bytes() != 0 {
-                WriteAllCommand::DumpWriteDirect
-            } else {
-                WriteAllCommand::WriteDirect
+        impl Seek for Wrapper {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
             }
-        } else if self.num_writable_bytes_left() >= buf.len() {
-            WriteAllCommand::Write
-        } else {
-            WriteAllCommand::WriteDumpWrite(self.num_writable_bytes_left())
         }
-    }
 
-    #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let n = self.num_readable_bytes_left().min(buf.len());
-        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        let data = b"0123456789abcdef".to_vec();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 8);
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"XY").unwrap();
+        assert_eq!(buf.stream_len().unwrap(), 16);
+
+        let mut buf = buf.map_inner(Wrapper).unwrap();
+        assert_eq!(buf.position(), 4);
+        // The length is still known
+        let seeks = buf.inner().0.seeks();
+        assert_eq!(buf.stream_len().unwrap(), 16);
+        assert_eq!(buf.inner().0.seeks(), seeks);
+        // Only the dirty bytes
+        assert_eq!(buf.stats().bytes_written, 2);
+        assert_eq!(buf.inner().0.writes(), 1);
+        // The cached data is still served
+        let reads = buf.inner().0.reads();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"45");
+        assert_eq!(buf.inner().0.reads(), reads);
+
+        buf.seek(std::io::SeekFrom::Start(10)).unwrap();
+        buf.write_all(b"ZZ").unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"cd");
+
+        let content = buf.into_inner().unwrap().0.into_inner().into_inner();
+        assert_eq!(content, b"01XY456789ZZcdef");
+
+        // The alignment needs a readable stream
+        let buf =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(Vec::new()), 512, 512).unwrap();
+        let buf = buf.map_inner(Box::new).unwrap();
+        assert!(buf.alignment.is_none());
+    }
 
-        // SAFETY: n is always <= buf.len() and <= `self.filled - self.pos`
-        debug_assert!(n <= buf.len());
-        debug_assert!(self.pos + n <= self.filled);
-        unsafe {
-            std::ptr::copy_nonoverlapping(self.data.as_ptr().wrapping_add(self.pos), buf.as_mut_ptr(), n);
-        }
+    #[test]
+    fn test_try_into_inner() {
+        let data = b"0123456789".to_vec();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 4);
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"ab").unwrap();
+        let (reads, seeks) = (buf.inner().reads(), buf.inner().seeks());
+
+        let mut buf = buf.try_into_inner().err().unwrap();
+        assert_eq!(buf.inner().writes(), 0);
+        assert_eq!(buf.inner().seeks(), seeks);
+        assert_eq!(buf.position(), 4);
+        assert!(buf.has_dirty_data());
 
-        self.pos += n;
-        debug_assert!(self.pos <= self.data.len());
-        Ok(n)
+        buf.flush().unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        let (writes, seeks) = (buf.inner().writes(), buf.inner().seeks());
+        let inner = buf.try_into_inner().ok().unwrap();
+        assert_eq!(inner.writes(), writes);
+        assert_eq!(inner.seeks(), seeks);
+        assert!(inner.reads() > reads);
+        assert_eq!(inner.into_inner().into_inner(), b"01ab456789");
     }
 
-    #[inline]
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let n = self.num_writable_bytes_left().min(buf.len());
-        if n == 0 {
-            return Ok(0);
-        }
-
-        debug_assert!(self.pos + n <= self.capacity());
-        if self.pos + n > self.filled {
-            self.filled = self.pos + n;
+    #[test]
+    fn test_with_buffer_reuses_the_allocation() {
+        let buf = BufReaderWriter::with_buffer(Cursor::new(Vec::new()), Box::new([]));
+        assert_eq!(buf.capacity(), crate::default_capacity());
+
+        let mut buffer = vec![0xFFu8; 8].into_boxed_slice();
+        let address = buffer.as_ptr();
+        for i in 0..3u8 {
+            let data = vec![i; 20];
+            let mut buf = BufReaderWriter::with_buffer(Cursor::new(data), buffer);
+            assert_eq!(buf.capacity(), 8);
+            let mut bytes = [0u8; 3];
+            buf.read_exact(&mut bytes).unwrap();
+            assert_eq!(bytes, [i; 3]);
+            buf.write_all(b"abc").unwrap();
+
+            let (inner, b) = buf.into_parts().unwrap();
+            assert_eq!(b.as_ptr(), address);
+            let mut expected = vec![i; 20];
+            expected[3..6].copy_from_slice(b"abc");
+            assert_eq!(inner.into_inner(), expected);
+            buffer = b;
         }
-        self.data[self.pos..self.pos + n].copy_from_slice(&buf[..n]);
-        self.pos += n;
-        self.is_dirty = true;
-
-        debug_assert!(self.pos <= self.filled);
-
-        Ok(n)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    #![allow(clippy::bool_assert_comparison)]
-    use crate::BufReaderWriter;
-    use rand::Rng;
-    use std::io::{Cursor, Read, Seek, Write};
 
     #[test]
-    fn test_seek_end_then_write() {
-        let mut data = Cursor::new(vec![]);
-
-        data.write_all(b"Yoshi").unwrap();
-        data.set_position(0);
-
-        let mut buf = BufReaderWriter::new(data);
-
-        let n = buf.seek(std::io::SeekFrom::End(-3)).unwrap();
-        assert_eq!(n, 2);
-
-        buf.write_all(b"Yoshi").unwrap();
-        assert!(buf.buffer.is_dirty);
-        let n = buf.seek(std::io::SeekFrom::Start(0)).unwrap();
-        assert_eq!(n, 0);
-
-        let mut bytes = [0u8; 7];
-        buf.read_exact(bytes.as_mut_slice()).unwrap();
-        assert_eq!(&bytes, b"YoYoshi");
+    fn test_separate_read_and_write_capacities() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacities(
+            CountingStream::with_log(Cursor::new(data.clone())),
+            64,
+            8,
+        );
+        let mut bytes = [0u8; 10];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.read_exact(&mut [0u8; 70]).unwrap();
+        buf.fill_buf_min(40).unwrap();
+        let read_sizes = buf
+            .inner()
+            .log()
+            .iter()
+            .filter_map(|op| match op {
+                crate::counting::Op::Read { requested, .. } => Some(*requested),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(read_sizes.iter().all(|size| *size <= 64), "{read_sizes:?}");
+
+        // Small patches are accumulated in the cache until they span 8 bytes
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        buf.inner_mut().reset();
+        buf.write_all(b"abc").unwrap();
+        buf.seek(std::io::SeekFrom::Start(104)).unwrap();
+        buf.write_all(b"de").unwrap();
+        assert_eq!(buf.inner().writes(), 0);
+        buf.write_all(b"fgh").unwrap();
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(buf.dirty_len(), 3);
+        buf.write_all(&[b'!'; 8]).unwrap();
+        assert_eq!(buf.inner().writes(), 3);
+        assert_eq!(buf.inner().reads(), 0);
+
+        let content = buf.into_inner().unwrap().into_inner().into_inner();
+        let mut expected = data;
+        expected[100..103].copy_from_slice(b"abc");
+        expected[104..109].copy_from_slice(b"defgh");
+        expected[109..117].fill(b'!');
+        assert_eq!(content, expected);
     }
 
     #[test]
-    fn test_seek_current_negative_too_far() {
-        let mut data = Cursor::new(vec![]);
+    fn test_bypass_threshold() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf =
+            BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data.clone())), 8);
+        buf.set_bypass_threshold(usize::MAX, usize::MAX);
+        let mut bytes = [0u8; 20];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes[..], data[..20]);
+        assert_eq!(buf.read(&mut bytes).unwrap(), 4);
+        assert_eq!(buf.stats().direct_reads, 0);
+        assert_eq!(buf.inner().reads(), 3);
+
+        buf.write_all(&[b'a'; 20]).unwrap();
+        assert_eq!(buf.position(), 44);
+        assert_eq!(buf.stats().direct_writes, 0);
+        assert_eq!(buf.inner().writes(), 2);
+
+        // Bypassing at half the capacity
+        buf.set_bypass_threshold(4, 4);
+        buf.write_all(b"bcde").unwrap();
+        assert_eq!(buf.stats().direct_writes, 1);
+        buf.read_exact(&mut bytes[..4]).unwrap();
+        assert_eq!(buf.stats().direct_reads, 1);
+        buf.read_exact(&mut bytes[..3]).unwrap();
+        assert_eq!(buf.stats().direct_reads, 1);
+
+        let content = buf.into_inner().unwrap().into_inner().into_inner();
+        let mut expected = data;
+        expected[24..44].fill(b'a');
+        expected[44..48].copy_from_slice(b"bcde");
+        assert_eq!(content, expected);
+    }
 
-        data.write_all(b"Yoshi").unwrap();
-        data.set_position(0);
+    #[test]
+    fn test_starting_at_the_inner_position() {
+        let mut inner = CountingStream::new(Cursor::new(vec![b'-'; 110]));
+        inner.seek(std::io::SeekFrom::Start(100)).unwrap();
+        let mut buf = BufReaderWriter::at_current_position(inner).unwrap();
+        assert_eq!(buf.position(), 100);
+        buf.write_all(b"abc").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.position(), 103);
+        assert_eq!(&buf.inner().get_ref().get_ref()[98..105], b"--abc--");
 
-        let mut buf = BufReaderWriter::new(data);
+        buf.seek(std::io::SeekFrom::Start(108)).unwrap();
+        buf.write_all(b"de").unwrap();
+        let content = buf.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&content[100..], b"abc-----de");
+    }
 
-        assert_eq!(buf.position(), 0);
-        assert!(matches!(buf.stream_position(), Ok(0)));
+    #[test]
+    fn test_from_parts_continues_records() {
+        let record = |i: u8| [i; 6];
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 16);
+        for i in 0..5 {
+            buf.write_all(&record(i)).unwrap();
+        }
+        let position = buf.position();
+        let (mut inner, buffer) = buf.into_parts().unwrap();
+        inner.set_position(0);
+
+        let mut buf = BufReaderWriter::from_parts(inner, buffer, position).unwrap();
+        assert_eq!(buf.capacity(), 16);
+        assert_eq!(buf.position(), 30);
+        for i in 5..10 {
+            buf.write_all(&record(i)).unwrap();
+        }
+        buf.seek(std::io::SeekFrom::Start(24)).unwrap();
+        let mut bytes = [0u8; 12];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [[4; 6], [5; 6]].concat().as_slice());
+
+        let content = buf.into_inner().unwrap().into_inner();
+        let expected = (0..10).flat_map(record).collect::<Vec<_>>();
+        assert_eq!(content, expected);
+    }
 
-        let result = buf.seek(std::io::SeekFrom::Current(-6));
-        assert!(result.is_err());
+    #[test]
+    fn test_zero_capacity_passes_through() {
+        let data = (0..50u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 0);
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"ab").unwrap();
+        assert!(!buf.has_dirty_data());
+        assert_eq!(buf.inner().writes(), 1);
+
+        buf.write_zeros(3).unwrap();
+        buf.copy_from(&mut &b"cdef"[..], 3).unwrap();
+        assert_eq!(buf.position(), 12);
+        buf.seek(std::io::SeekFrom::Start(2)).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(buf.copy_to(&mut out, 10).unwrap(), 10);
+        assert_eq!(out, b"\x02\x03ab\0\0\0cde");
+        assert!(!buf.has_dirty_data());
+        assert_eq!(buf.stats().dumps, 0);
+    }
 
-        assert_eq!(buf.position(), 0);
-        assert!(matches!(buf.stream_position(), Ok(0)));
+    #[test]
+    fn test_take_and_put_buffer_midway() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let mut expected = data.clone();
+        let mut rw = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 32);
+
+        let mut buf = [0u8; 10];
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[..10]);
+        rw.write_all(b"dirty").unwrap();
+        expected[10..15].copy_from_slice(b"dirty");
+
+        let taken = rw.take_buffer().unwrap();
+        assert_eq!(taken.len(), 32);
+        assert_eq!(rw.capacity(), 0);
+        assert_eq!(rw.position(), 15);
+        assert_eq!(&rw.inner().get_ref().get_ref()[10..15], b"dirty");
+
+        // Direct while no buffer is installed
+        rw.inner_mut().reset();
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[15..25]);
+        rw.write_all(b"direct").unwrap();
+        expected[25..31].copy_from_slice(b"direct");
+        assert_eq!(rw.inner().reads(), 1);
+        assert_eq!(rw.inner().writes(), 1);
+
+        rw.put_buffer(vec![0u8; 64].into_boxed_slice()).unwrap();
+        assert_eq!(rw.capacity(), 64);
+        rw.seek(std::io::SeekFrom::Start(5)).unwrap();
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[5..15]);
+        rw.write_all(b"again").unwrap();
+        expected[15..20].copy_from_slice(b"again");
+        rw.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut all = Vec::new();
+        rw.read_to_end(&mut all).unwrap();
+        assert_eq!(all, expected);
+
+        // Another adapter reuses the allocation
+        let address = taken.as_ptr();
+        let other = BufReaderWriter::with_buffer(Cursor::new(Vec::new()), taken);
+        assert_eq!(other.into_parts().unwrap().1.as_ptr(), address);
+        assert_eq!(rw.into_inner().unwrap().into_inner().into_inner(), expected);
     }
 
     #[test]
-    fn test_seek_current_forward() {
-        let mut rng = rand::rng();
-        let mut cursor = Cursor::new(vec![]);
-        let mut buf = BufReaderWriter::new(&mut cursor);
-        let buf_capacity = buf.capacity();
+    fn test_into_reader_continues_at_the_position() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 16);
+        let mut buf = [0u8; 5];
+        rw.read_exact(&mut buf).unwrap();
+        rw.write_all(b"xyz").unwrap();
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [8, 9, 10, 11, 12]);
+
+        let mut reader = rw.into_reader().unwrap();
+        assert_eq!(reader.capacity(), 16);
+        assert_eq!(reader.get_ref().get_ref().position(), 13);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, (13..100u8).collect::<Vec<_>>());
+
+        let data = reader.into_inner().into_inner().into_inner();
+        assert_eq!(&data[..10], &[0, 1, 2, 3, 4, b'x', b'y', b'z', 8, 9]);
+    }
 
-        buf.inner.get_mut().resize(buf_capacity * 4, 0u8);
-        for v in buf.inner.get_mut() {
-            *v = rng.random();
-        }
+    #[test]
+    fn test_into_writer_inside_the_cached_data() {
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![b'.'; 40]), 16);
+        let mut buf = [0u8; 12];
+        rw.read_exact(&mut buf).unwrap();
+        rw.seek(std::io::SeekFrom::Start(2)).unwrap();
+        rw.write_all(b"ab").unwrap();
+        // Behind the inner stream, which is at the end of the cached data
+        rw.seek(std::io::SeekFrom::Start(6)).unwrap();
+        assert_eq!(rw.inner().position(), 16);
+
+        let mut writer = rw.into_writer().unwrap();
+        assert_eq!(writer.capacity(), 16);
+        writer.write_all(b"cd").unwrap();
+        writer.write_all(&[b'e'; 40]).unwrap();
+        let data = writer.into_inner().unwrap().into_inner();
+        assert_eq!(&data[..8], b"..ab..cd");
+        assert_eq!(data.len(), 48);
+        assert!(data[8..].iter().all(|b| *b == b'e'));
+    }
 
-        let expected = buf.inner().get_ref().to_vec();
+    #[test]
+    fn test_set_capacity_keeps_the_buffered_data() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 32);
+        let mut buf = [0u8; 4];
+        rw.read_exact(&mut buf).unwrap();
+        rw.write_all(b"ab").unwrap();
+
+        // Growing does not reach the inner stream
+        rw.set_capacity(64).unwrap();
+        assert_eq!(rw.capacity(), 64);
+        assert_eq!(rw.inner().writes(), 0);
+        assert_eq!(rw.buffered_len(), 26);
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [6, 7, 8, 9]);
+
+        // Shrinking keeps the dirty data and the cached data after it, as much as fits
+        rw.seek(std::io::SeekFrom::Start(4)).unwrap();
+        rw.set_capacity(8).unwrap();
+        assert_eq!(rw.inner().writes(), 0);
+        assert_eq!(rw.buffer(), &[b'a', b'b', 6, 7, 8, 9, 10, 11]);
+        let reads = rw.inner().reads();
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [b'a', b'b', 6, 7]);
+        assert_eq!(rw.inner().reads(), reads);
+
+        // The dirty data that does not fit is written first
+        rw.write_all(b"cd").unwrap();
+        rw.seek(std::io::SeekFrom::Start(9)).unwrap();
+        rw.set_capacity(2).unwrap();
+        assert_eq!(rw.inner().writes(), 1);
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [b'd', 10, 11, 12]);
+
+        let data = rw.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&data[..12], &[0, 1, 2, 3, b'a', b'b', 6, 7, b'c', b'd', 10, 11]);
+    }
 
-        let mut c = [0u8];
-        buf.read_exact(&mut c).unwrap();
-        assert_eq!(c[0], expected[0]);
+    #[test]
+    fn test_shrink_to_then_keep_going() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut expected = data.clone();
+        let mut rw = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 64);
+        let mut buf = [0u8; 8];
+        rw.read_exact(&mut buf).unwrap();
+        rw.write_all(b"xy").unwrap();
+        expected[8..10].copy_from_slice(b"xy");
+
+        rw.shrink_to(100).unwrap();
+        assert_eq!(rw.capacity(), 64);
+        rw.shrink_to(4).unwrap();
+        assert_eq!(rw.capacity(), 4);
+        assert_eq!(rw.buffered_len(), 0);
+        assert_eq!(&rw.inner().get_ref().get_ref()[8..10], b"xy");
+        assert_eq!(rw.position(), 10);
+
+        rw.inner_mut().reset();
+        rw.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(buf[..3], [10, 11, 12]);
+        assert_eq!(rw.inner().reads(), 1);
+        rw.write_all(b"z").unwrap();
+        expected[13] = b'z';
+        rw.write_all(b"0123456789").unwrap();
+        expected[14..24].copy_from_slice(b"0123456789");
+        rw.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut all = Vec::new();
+        rw.read_to_end(&mut all).unwrap();
+        assert_eq!(all, expected);
+    }
 
-        let n = buf.seek(std::io::SeekFrom::Current(1)).unwrap();
-        assert_eq!(n, 2);
+    #[test]
+    fn test_preload() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data)), 32);
+        rw.seek(std::io::SeekFrom::Start(10)).unwrap();
+        assert_eq!(rw.preload().unwrap(), 32);
+        assert_eq!(rw.inner().reads(), 1);
+
+        let mut buf = [0u8; 20];
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 10);
+        assert_eq!(rw.inner().reads(), 1);
+
+        // Nothing to do with cached or dirty data
+        assert_eq!(rw.preload().unwrap(), 12);
+        rw.seek(std::io::SeekFrom::Start(42)).unwrap();
+        rw.write_all(b"dirty").unwrap();
+        assert_eq!(rw.preload().unwrap(), 0);
+        assert_eq!(rw.inner().reads(), 1);
+        assert_eq!(rw.inner().writes(), 0);
+    }
 
-        buf.read_exact(&mut c).unwrap();
-        assert_eq!(c[0], expected[2]);
+    #[test]
+    fn test_prefetch_then_seek_reads_from_the_cache() {
+        let data = (0..=255u8).cycle().take(1000).collect::<Vec<_>>();
+        let mut rw =
+            BufReaderWriter::with_capacity(CountingStream::with_log(Cursor::new(data.clone())), 64);
+        let mut buf = [0u8; 10];
+        rw.read_exact(&mut buf).unwrap();
+        rw.write_all(b"dirty").unwrap();
+
+        rw.prefetch(500, 32).unwrap();
+        assert_eq!(rw.position(), 15);
+        assert!(rw.buffer().is_empty());
+        assert_eq!(&rw.inner().get_ref().get_ref()[10..15], b"dirty");
+
+        let ops = rw.inner().log().len();
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[15..25]);
+        assert!(rw.inner().log().len() > ops);
+
+        // Seeking into the prefetched data does not reach the inner stream
+        rw.prefetch(700, 32).unwrap();
+        let ops = rw.inner().log().len();
+        rw.seek(std::io::SeekFrom::Current(700 - 25)).unwrap();
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[700..710]);
+        rw.prefetch(705, 10).unwrap();
+        rw.seek(std::io::SeekFrom::Start(760)).unwrap();
+        rw.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(buf[..4], data[760..764]);
+        assert_eq!(rw.inner().log().len(), ops);
+    }
 
-        // Seek past buffer
-        let n = buf
-            .seek(std::io::SeekFrom::Current(buf_capacity as i64))
-            .unwrap();
-        assert_eq!(n, buf_capacity as u64 + 3);
+    #[test]
+    fn test_fill_sizes() {
+        let data = (0..=255u8).cycle().take(100_000).collect::<Vec<_>>();
+        let read_sizes = |rw: &BufReaderWriter<CountingStream<Cursor<Vec<u8>>>>| {
+            rw.inner()
+                .log()
+                .iter()
+                .filter_map(|op| match op {
+                    crate::counting::Op::Read { requested, .. } => Some(*requested),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let workload = |rw: &mut BufReaderWriter<CountingStream<Cursor<Vec<u8>>>>| {
+            let mut buf = [0u8; 100];
+            rw.seek(std::io::SeekFrom::Start(50_000)).unwrap();
+            rw.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0], (50_000 % 256) as u8);
+            rw.seek(std::io::SeekFrom::Start(10_000)).unwrap();
+            let mut scan = vec![0u8; 12_000];
+            for chunk in scan.chunks_mut(1000) {
+                rw.read_exact(chunk).unwrap();
+            }
+            assert_eq!(scan, data[10_000..22_000]);
+        };
+
+        let mut rw =
+            BufReaderWriter::with_capacity(CountingStream::with_log(Cursor::new(data.clone())), 4096);
+        workload(&mut rw);
+        assert_eq!(read_sizes(&rw), [4096, 4096, 4096, 4096]);
+
+        let mut rw =
+            BufReaderWriter::with_capacity(CountingStream::with_log(Cursor::new(data.clone())), 4096);
+        rw.set_fill_size(512, 4096).unwrap();
+        workload(&mut rw);
+        // Growing again after the seek
+        assert_eq!(read_sizes(&rw), [512, 512, 1024, 2048, 4096, 4096, 4096]);
+    }
 
-        buf.read_exact(&mut c).unwrap();
-        assert_eq!(c[0], expected[buf_capacity + 3])
+    #[test]
+    fn test_write_through_keeps_the_cache_coherent() {
+        let mut expected = b"0123456789abcdef".repeat(4);
+        let mut buf =
+            BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(expected.clone())), 16);
+        buf.set_write_through(true).unwrap();
+        let mut data = [0u8; 6];
+
+        buf.read_exact(&mut data[..4]).unwrap();
+        buf.write_all(b"AB").unwrap();
+        expected[4..6].copy_from_slice(b"AB");
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+
+        buf.seek(std::io::SeekFrom::Start(2)).unwrap();
+        buf.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"23AB67");
+        buf.write_all(b"xyz").unwrap();
+        expected[8..11].copy_from_slice(b"xyz");
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+        assert!(!buf.has_dirty_data());
+
+        // Past the end of the buffer, the cached data is dropped
+        buf.seek(std::io::SeekFrom::Start(14)).unwrap();
+        buf.write_all(b"WXYZ").unwrap();
+        expected[14..18].copy_from_slice(b"WXYZ");
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+        buf.read_exact(&mut data[..2]).unwrap();
+        assert_eq!(&data[..2], b"23");
+
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut all = vec![0u8; 16];
+        buf.read_exact(&mut all).unwrap();
+        assert_eq!(all, &expected[..16]);
+        let reads = buf.inner().reads();
+
+        // Appending extends the cached data
+        buf.seek(std::io::SeekFrom::End(0)).unwrap();
+        buf.write_all(b"end").unwrap();
+        expected.extend_from_slice(b"end");
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+        buf.seek(std::io::SeekFrom::Current(-3)).unwrap();
+        buf.read_exact(&mut data[..3]).unwrap();
+        assert_eq!(&data[..3], b"end");
+        assert_eq!(buf.inner().reads(), reads);
+
+        // Reserved bytes are written when the next operation starts
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        buf.write_reserve(2).unwrap().copy_from_slice(b"!!");
+        buf.commit(2).unwrap();
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        expected[..2].copy_from_slice(b"!!");
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+        assert!(!buf.has_dirty_data());
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected);
     }
 
     #[test]
-    fn test_seek_current_at_buffer_boundary() {
-        let mut rng = rand::rng();
-        let mut cursor = Cursor::new(vec![]);
-        let mut buf = BufReaderWriter::new(&mut cursor);
-        let buf_capacity = buf.capacity();
+    fn test_write_through_writes_the_dirty_data_when_enabled() {
+        let mut buf = BufReaderWriter::new(Cursor::new(Vec::new()));
+        buf.write_all(b"dirty").unwrap();
+        assert!(buf.inner().get_ref().is_empty());
+        buf.set_write_through(true).unwrap();
+        assert_eq!(buf.inner().get_ref(), b"dirty");
+        write!(buf, " {}", 42).unwrap();
+        buf.write_zeros(2).unwrap();
+        assert_eq!(buf.inner().get_ref(), b"dirty 42\0\0");
+        assert_eq!(buf.position(), 10);
+    }
 
-        // Fill the underlying source with some random data
-        buf.inner
-            .get_mut()
-            .resize(buf_capacity + buf_capacity / 2, 0u8);
-        for v in buf.inner.get_mut() {
-            *v = rng.random();
+    #[test]
+    fn test_max_dirty_writes_without_dropping_the_cache() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let mut buf =
+            BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data.clone())), 128);
+        buf.set_max_dirty(10);
+        let mut expected = data.clone();
+        let mut byte = [0u8; 1];
+        buf.read_exact(&mut byte).unwrap();
+
+        for i in 0..3 {
+            buf.write_all(b"abc").unwrap();
+            expected[1 + 3 * i..4 + 3 * i].copy_from_slice(b"abc");
+            assert_eq!(buf.inner().writes(), 0);
+            assert_eq!(buf.dirty_len(), 3 * (i + 1));
         }
+        // Crossing the threshold
+        buf.write_all(b"abc").unwrap();
+        expected[10..13].copy_from_slice(b"abc");
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+        assert!(!buf.has_dirty_data());
+
+        // The cached data is still there, with the writes
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut all = vec![0u8; 100];
+        buf.read_exact(&mut all).unwrap();
+        assert_eq!(all, &expected[..100]);
+        assert_eq!(buf.inner().reads(), 1);
+
+        // In-place writes are written when the next operation starts
+        buf.seek(std::io::SeekFrom::Start(20)).unwrap();
+        buf.write_reserve(10).unwrap().fill(b'z');
+        buf.commit(10).unwrap();
+        expected[20..30].fill(b'z');
+        assert_eq!(buf.position(), 30);
+        assert_eq!(buf.inner().writes(), 1);
+        buf.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 30);
+        assert_eq!(buf.inner().writes(), 2);
+        assert_eq!(buf.inner().get_ref().get_ref(), &expected);
+        assert_eq!(buf.inner().reads(), 1);
+    }
 
-        // Clone it to have access to it without borrow problems
-        let mut expected = buf.inner().get_ref().to_vec();
+    #[test]
+    fn test_read_exact_direct_with_short_reads() {
+        struct ShortReads(Cursor<Vec<u8>>);
+        impl Read for ShortReads {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(7);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        impl Write for ShortReads {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for ShortReads {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
 
-        let mut c = [0u8];
-        buf.read_exact(&mut c).unwrap();
-        assert_eq!(c[0], expected[0]);
-        assert_eq!(buf.buffer.is_dirty, false);
-        assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), buf_capacity - 1);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 1);
-        assert_eq!(buf.position(), 1);
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(ShortReads(Cursor::new(data.clone())), 16);
+        let mut byte = [0u8; 1];
+        buf.read_exact(&mut byte).unwrap();
+
+        // Partly from the buffer, then directly
+        let mut large = [0u8; 40];
+        buf.read_exact(&mut large).unwrap();
+        assert_eq!(large, data[1..41]);
+        assert_eq!(buf.position(), 41);
+
+        // Only directly
+        let mut large = [0u8; 30];
+        buf.read_exact(&mut large).unwrap();
+        assert_eq!(large, data[41..71]);
+        assert_eq!(buf.position(), 71);
+
+        let mut large = [0u8; 40];
+        let err = buf.read_exact(&mut large).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(large[..29], data[71..]);
+        assert_eq!(buf.position(), 100);
+    }
 
-        let n = buf
-            .seek(std::io::SeekFrom::Current(buf_capacity as i64 - 2))
-            .unwrap();
-        assert_eq!(n, buf_capacity as u64 - 1);
-        assert_eq!(buf.buffer.is_dirty, false);
-        assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 1);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), 1);
+    #[test]
+    fn test_read_exact_through_the_buffer_past_the_end() {
+        let data = (0..20u8).collect::<Vec<_>>();
+
+        // One byte past the end, after reading from the buffer
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data.clone()), 16);
+        let mut record = [0u8; 10];
+        buf.read_exact(&mut record).unwrap();
+        let mut tail = [0u8; 11];
+        let err = buf.read_exact(&mut tail).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // A whole record past the end, with a fill first
+        buf.seek(std::io::SeekFrom::Start(20)).unwrap();
+        let err = buf.read_exact(&mut record).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        buf.seek(std::io::SeekFrom::Start(12)).unwrap();
+        let err = buf.read_exact(&mut record).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // An empty stream
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 16);
+        let err = buf.read_exact(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        buf.read_exact(&mut []).unwrap();
+    }
 
-        // This read_exact should trigger a refill as it crosses the buffer boundary
-        let mut c = [0u8; 2];
-        buf.read_exact(&mut c).unwrap();
-        assert_eq!(&c, &expected[buf_capacity - 1..buf_capacity + 1]);
-        assert_eq!(buf.buffer.is_dirty, false);
-        assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity / 2);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), buf_capacity / 2 - 1);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 1);
+    #[test]
+    fn test_read_exact_refills_after_short_fills() {
+        struct Throttled(CountingStream<Cursor<Vec<u8>>>);
+        impl Read for Throttled {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(3);
+                self.0.read(&mut buf[..len])
+            }
+        }
+        impl Write for Throttled {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for Throttled {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
 
-        // Seek back to before reading the 2 bytes
-        let n = buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
-        assert_eq!(n, buf_capacity as u64 - 1);
-        assert_eq!(buf.buffer.is_dirty, false);
-        assert_eq!(buf.buffer.num_valid_bytes(), 0);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity);
+        let data = (0..100u8).collect::<Vec<_>>();
+        let inner = Throttled(CountingStream::new(Cursor::new(data.clone())));
+        let mut buf = BufReaderWriter::with_capacity(inner, 8192);
+        // A short read is fine for `read`
+        let mut record = [0u8; 20];
+        assert_eq!(buf.read(&mut record[..2]).unwrap(), 2);
+
+        // Straddling the cached data, then from an empty buffer
+        buf.read_exact(&mut record).unwrap();
+        assert_eq!(record, data[2..22]);
+        buf.read_exact(&mut record).unwrap();
+        assert_eq!(record, data[22..42]);
+        assert_eq!(buf.position(), 42);
+        assert_eq!(buf.inner().0.reads(), 14);
+    }
 
-        let c2 = [c[0].wrapping_add(1), c[1].wrapping_add(1)];
+    #[test]
+    fn test_direct_write_after_a_partial_read() {
+        let data = (0..=255u8).cycle().take(400).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(data.clone()), 128);
+        let mut record = [0u8; 100];
+        buf.read_exact(&mut record).unwrap();
+
+        // The inner stream is at the end of the cached data
+        buf.write_all(&[0xEE; 128]).unwrap();
+        assert_eq!(buf.stats().direct_writes, 1);
+        assert_eq!(buf.position(), 228);
+        let mut byte = [0u8; 1];
+        buf.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], data[228]);
+
+        let written = buf.into_inner().unwrap().into_inner();
+        assert_eq!(written[..100], data[..100]);
+        assert_eq!(written[100..228], [0xEE; 128]);
+        assert_eq!(written[228..], data[228..]);
+    }
 
-        buf.write_all(&c2).unwrap();
-        assert_eq!(buf.buffer.is_dirty, true);
-        assert_eq!(buf.buffer.num_valid_bytes(), 2);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 2);
-        expected[n as usize] = c2[0];
-        expected[n as usize + 1] = c2[1];
+    #[test]
+    fn test_short_and_zero_writes_of_the_inner_stream() {
+        struct ShortWrites(Cursor<Vec<u8>>, usize);
+        impl Write for ShortWrites {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(self.1);
+                self.0.write(&buf[..len])
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for ShortWrites {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
 
-        // Seek back to before reading the 2 bytes
-        let n = buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
-        assert_eq!(n, buf_capacity as u64 - 1);
-        assert_eq!(buf.buffer.is_dirty, true);
-        assert_eq!(buf.buffer.num_valid_bytes(), 2);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 2);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity);
+        let mut buf = BufReaderWriter::with_capacity(ShortWrites(Cursor::new(Vec::new()), 10), 16);
+        // Buffered, then dumped in several writes
+        buf.write_all(b"0123456789abc").unwrap();
+        // Direct, the short count is returned
+        let block = (0..64u8).collect::<Vec<_>>();
+        assert_eq!(buf.write(&block).unwrap(), 10);
+        assert_eq!(buf.position(), 23);
+        buf.write_all(&block[10..]).unwrap();
+        assert_eq!(buf.position(), 77);
+        buf.flush().unwrap();
+        let data = buf.into_inner().unwrap().0.into_inner();
+        assert_eq!(&data[..13], b"0123456789abc");
+        assert_eq!(data[13..], block);
+
+        // Writing nothing is an error rather than an endless loop
+        let mut buf = BufReaderWriter::with_capacity(ShortWrites(Cursor::new(Vec::new()), 0), 16);
+        let err = buf.write_all(&block).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(buf.position(), 0);
+        buf.write_all(b"abc").unwrap();
+        let err = buf.flush().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WriteZero);
+        assert!(buf.has_dirty_data());
+        assert_eq!(buf.position(), 3);
+        buf.set_flush_on_drop(false);
+    }
 
-        let n = buf.seek(std::io::SeekFrom::Current(-2)).unwrap();
-        assert_eq!(n, buf_capacity as u64 - 3);
-        assert_eq!(buf.buffer.is_dirty, false); // a dump should have been done
-        assert_eq!(buf.buffer.num_valid_bytes(), 0);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity);
+    #[test]
+    fn test_read_only_session_does_not_write() {
+        use std::io::BufRead;
+
+        let data = (0..=255u8).cycle().take(1000).collect::<Vec<_>>();
+        let inner = CountingStream::with_log(Cursor::new(data.clone()));
+        let mut buf = BufReaderWriter::with_capacity(inner, 64);
+        let mut record = [0u8; 10];
+        buf.read_exact(&mut record).unwrap();
+        buf.flush().unwrap();
+        buf.seek(std::io::SeekFrom::Current(-5)).unwrap();
+        buf.read_exact(&mut record).unwrap();
+        buf.seek(std::io::SeekFrom::Start(500)).unwrap();
+        let mut large = [0u8; 100];
+        buf.read_exact(&mut large).unwrap();
+        buf.fill_buf().unwrap();
+        buf.seek(std::io::SeekFrom::End(-10)).unwrap();
+        let mut rest = Vec::new();
+        buf.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[990..]);
+        buf.flush().unwrap();
+        buf.flush_writes().unwrap();
+
+        assert_eq!(buf.inner().writes(), 0);
+        assert!(
+            !buf.inner()
+                .log()
+                .iter()
+                .any(|op| matches!(op, crate::counting::Op::Write { .. }))
+        );
+        assert_eq!(buf.into_inner().unwrap().writes(), 0);
+    }
 
-        let mut c = vec![0u8; 4];
-        buf.read_exact(&mut c).unwrap();
-        assert_eq!(&c, &expected[buf_capacity - 3..buf_capacity + 1]);
-        assert_eq!(buf.buffer.is_dirty, false);
+    #[test]
+    fn test_dump_writes_only_the_dirty_range() {
+        use crate::counting::Op;
+
+        let data = (0..=255u8).cycle().take(1000).collect::<Vec<_>>();
+        let inner = CountingStream::with_log(Cursor::new(data.clone()));
+        let mut buf = BufReaderWriter::with_capacity(inner, 256);
+        let mut expected = data.clone();
+
+        // A small patch in the middle of the cached data
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        let mut record = [0u8; 10];
+        buf.read_exact(&mut record).unwrap();
+        buf.write_all(b"patch").unwrap();
+        expected[110..115].copy_from_slice(b"patch");
+        buf.inner_mut().reset();
+        buf.flush_writes().unwrap();
+        assert_eq!(buf.position(), 115);
         assert_eq!(
-            buf.buffer.num_valid_bytes(),
-            expected.len() - (buf_capacity - 3)
+            buf.inner().log(),
+            [
+                Op::Seek { from: std::io::SeekFrom::Current(-246), position: 110 },
+                Op::Write { requested: 5, written: 5 },
+                Op::Seek { from: std::io::SeekFrom::Start(356), position: 356 },
+            ]
         );
+        // The reads continue after the cached data
+        buf.inner_mut().reset();
+        let mut rest = [0u8; 241];
+        buf.read_exact(&mut rest).unwrap();
+        assert_eq!(rest, data[115..356]);
+        buf.read_exact(&mut record[..1]).unwrap();
+        assert_eq!(record[0], data[356]);
+        assert!(matches!(buf.inner().log(), [Op::Read { .. }]));
+
+        // Two writes apart, then a seek far away: the range spanning both
+        buf.seek(std::io::SeekFrom::Start(400)).unwrap();
+        buf.write_all(b"a").unwrap();
+        buf.seek(std::io::SeekFrom::Start(420)).unwrap();
+        buf.write_all(b"bc").unwrap();
+        expected[400] = b'a';
+        expected[420..422].copy_from_slice(b"bc");
+        buf.inner_mut().reset();
+        buf.seek(std::io::SeekFrom::Start(900)).unwrap();
         assert_eq!(
-            buf.buffer.num_readable_bytes_left(),
-            buf.buffer.num_valid_bytes() - 4
+            buf.inner().log(),
+            [
+                Op::Seek { from: std::io::SeekFrom::Current(-212), position: 400 },
+                Op::Write { requested: 22, written: 22 },
+                Op::Seek { from: std::io::SeekFrom::Start(900), position: 900 },
+            ]
         );
-        assert_eq!(buf.buffer.num_writable_bytes_left(), buf_capacity - 4);
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected);
+    }
 
+    #[test]
+    fn test_sequential_writes_do_not_seek() {
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(Vec::new())), 16);
+        for i in 0..100u8 {
+            buf.write_all(&[i; 3]).unwrap();
+        }
         buf.flush().unwrap();
-        assert_eq!(buf.inner.get_ref(), expected.as_slice());
+        assert_eq!(buf.inner().seeks(), 0);
+        assert_eq!(buf.inner().writes(), 19);
+
+        // Appending after reading everything
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut data = Vec::new();
+        buf.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 300);
+        buf.inner_mut().reset();
+        buf.write_all(b"tail").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.inner().seeks(), 0);
+        assert_eq!(buf.inner().writes(), 1);
+
+        // Patching the end of the cached data needs a single seek
+        buf.seek(std::io::SeekFrom::Start(290)).unwrap();
+        let mut bytes = [0u8; 10];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.seek(std::io::SeekFrom::Start(301)).unwrap();
+        buf.inner_mut().reset();
+        buf.write_all(b"xyz").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.inner().seeks(), 1);
+        assert_eq!(buf.inner().writes(), 1);
     }
 
     #[test]
-    fn test_drop_flushes() {
-        let mut cursor = Cursor::new(vec![]);
-        let mut buf = BufReaderWriter::new(&mut cursor);
-
-        assert_eq!(buf.position(), 0);
-        assert!(matches!(buf.stream_position(), Ok(0)));
-
-        assert_eq!(buf.buffer.is_dirty, false);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
-        assert_eq!(buf.position(), 0);
-
-        let data = b"Eco Dome Aldani";
-        buf.write_all(data).unwrap();
-
-        assert_eq!(buf.buffer.is_dirty, true);
-        assert_eq!(buf.buffer.num_readable_bytes_left(), 0);
-        assert_eq!(buf.position(), data.len() as u64);
-
-        // Nothing was actually written yet
-        assert_eq!(buf.inner().position(), 0);
-
-        drop(buf);
-
-        assert_eq!(cursor.position(), data.len() as u64);
-        let s = String::from_utf8(cursor.into_inner()).unwrap();
-        assert_eq!(s.as_bytes(), data);
+    fn test_seek_before_start_keeps_the_cache() {
+        use std::io::SeekFrom;
+
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data.clone())), 32);
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"ab").unwrap();
+
+        // The length is not known yet, then it is
+        for from in [
+            SeekFrom::End(-101),
+            SeekFrom::End(-101),
+            SeekFrom::End(i64::MIN),
+            SeekFrom::Current(-7),
+            SeekFrom::Current(i64::MIN),
+        ] {
+            let err = buf.seek(from).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{from:?}");
+            assert_eq!(buf.position(), 6);
+            assert!(buf.has_dirty_data());
+        }
+        assert_eq!(buf.inner().writes(), 0);
+
+        // The cached data is still there
+        let reads = buf.inner().reads();
+        buf.seek(SeekFrom::Start(0)).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [0, 1, 2, 3]);
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"ab\x06\x07");
+        assert_eq!(buf.inner().reads(), reads);
+
+        // Valid seeks from the end still work, with the dirty data written once
+        assert_eq!(buf.seek(SeekFrom::End(-100)).unwrap(), 0);
+        assert_eq!(buf.seek(SeekFrom::End(-10)).unwrap(), 90);
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [90, 91, 92, 93]);
+        assert_eq!(buf.inner().writes(), 1);
+        let written = buf.into_inner().unwrap().into_inner().into_inner();
+        assert_eq!(&written[..6], b"\0\x01\x02\x03ab");
     }
 
     #[test]
-    fn write_more_than_buffer_capacity() {
-        {
-            // First, the simple case, where we never wrote not read anything
-            // thus the buffer is empty
-
-            let mut cursor = Cursor::new(vec![]);
-            let mut buf = BufReaderWriter::new(&mut cursor);
-
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
-
-            let mut rng = rand::rng();
-            let mut data = vec![0u8; buf.capacity()];
-            for v in data.iter_mut() {
-                *v = rng.random();
-            }
+    fn test_patches_into_prefetched_window_are_written() {
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![0u8; 100_000]), 4096);
+        rw.prefetch(50_000, 100).unwrap();
+        rw.apply_patches(&mut [(50_010, b"PATCH".to_vec())]).unwrap();
+        rw.seek(std::io::SeekFrom::Start(50_010)).unwrap();
+        let mut buf = [0u8; 5];
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"PATCH");
+        rw.flush().unwrap();
+        assert_eq!(&rw.inner().get_ref()[50_010..50_015], b"PATCH");
+    }
 
-            // Check that nothing was written in the buffer,
-            // instead we wrote directly to the source
-            buf.write_all(&data).unwrap();
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
-            assert_eq!(buf.inner().get_ref(), &data);
-        }
+    #[test]
+    fn test_swap_in_prefetched_window_is_written() {
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(data.clone()), 4096);
+        rw.prefetch(50_000, 100).unwrap();
+        rw.swap_ranges(50_000, 50_010, 5).unwrap();
+        rw.seek(std::io::SeekFrom::Start(90_000)).unwrap();
+        rw.flush().unwrap();
+        let inner = rw.inner().get_ref();
+        assert_eq!(inner[50_000..50_005], data[50_010..50_015]);
+        assert_eq!(inner[50_010..50_015], data[50_000..50_005]);
+    }
 
-        {
-            // We wrote something before trying a write
-            // with >= capacity
+    #[test]
+    fn test_swap_past_end_does_not_write() {
+        let data = (0..1000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(data.clone()), 16);
+        let err = rw.swap_ranges(0, 900, 200).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(rw.position(), 0);
+        assert_eq!(rw.into_inner().unwrap().into_inner(), data);
+    }
 
-            let mut cursor = Cursor::new(vec![]);
-            let mut buf = BufReaderWriter::new(&mut cursor);
+    #[test]
+    fn test_resume_restores_the_configuration() {
+        let mut rw = BufReaderWriter::with_capacities(Cursor::new(vec![0u8; 100]), 64, 32);
+        rw.set_flush_on_drop(false);
+        rw.set_flush_inner_on_drop(false);
+        rw.set_write_through(true).unwrap();
+        rw.set_max_dirty(16);
+        rw.set_bypass_threshold(32, usize::MAX);
+        rw.set_fill_size(16, 64).unwrap();
+        rw.set_overflow_limit(4096);
+        let state = rw.suspend().unwrap();
+        let mut rw = BufReaderWriter::resume(rw.into_inner().unwrap(), state.clone()).unwrap();
+        assert_eq!(rw.suspend().unwrap(), state);
+        assert_eq!(rw.overflow_limit, 4096);
+        assert_eq!(rw.buffer.min_fill, 16);
+        assert_eq!((rw.read_capacity(), rw.write_capacity()), (64, 32));
+        assert_eq!((rw.buffer.read_bypass, rw.buffer.write_bypass), (32, usize::MAX));
+        assert_eq!(rw.max_dirty(), 16);
+        assert!(rw.is_write_through());
+        assert!(!rw.flush_inner_on_drop);
+        assert!(!rw.flush_on_drop);
+    }
 
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+    #[test]
+    fn test_resume_keeps_the_alignment() {
+        let mut rw =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(vec![0u8; 8192]), 4096, 512)
+                .unwrap();
+        let state = rw.suspend().unwrap();
+        let mut rw = BufReaderWriter::resume(rw.into_inner().unwrap(), state.clone()).unwrap();
+        assert_eq!(rw.suspend().unwrap(), state);
+        assert_eq!(rw.alignment.map(|alignment| alignment.size()), Some(512));
+    }
 
-            let mut rng = rand::rng();
-            let mut data = vec![0u8; buf.capacity() + 50];
-            for v in data.iter_mut() {
-                *v = rng.random();
-            }
+    #[test]
+    fn test_invalid_sizes_are_errors() {
+        let mut rw = BufReaderWriter::from_vec(b"Hello".to_vec());
+        let invalid = std::io::ErrorKind::InvalidInput;
+        assert_eq!(rw.set_fill_size(0, 16).unwrap_err().kind(), invalid);
+        assert_eq!(rw.set_fill_size(32, 16).unwrap_err().kind(), invalid);
+        assert_eq!(rw.rewrite_with(0, |_, _| Ok(())).unwrap_err().kind(), invalid);
+        let mut records = rw.rev_records(0);
+        assert_eq!(records.next().unwrap().unwrap_err().kind(), invalid);
+        assert!(records.next().is_none());
+        assert_eq!(rw.into_vec().unwrap(), b"Hello");
+    }
 
-            let (first_write, second_write) = data.split_at_mut(50);
+    #[test]
+    fn test_oversized_write_is_staged_without_bypass() {
+        let inner = CountingStream::new(Cursor::new(vec![0u8; 20_000]));
+        let mut buf = BufReaderWriter::with_capacity(inner, 1024);
+        buf.set_bypass_threshold(usize::MAX, usize::MAX);
+        buf.set_overflow_limit(2048);
+        buf.seek(std::io::SeekFrom::Start(100)).unwrap();
+        let data = (0..3072u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        buf.write_all(&data).unwrap();
+        assert_eq!(buf.inner().writes(), 0);
+        assert_eq!(buf.position(), 3172);
+
+        // The middle of the write is read back from the staged data
+        buf.seek(std::io::SeekFrom::Start(1100)).unwrap();
+        let mut middle = [0u8; 1024];
+        buf.read_exact(&mut middle).unwrap();
+        assert_eq!(middle, data[1000..2024]);
+        assert_eq!(buf.inner().reads(), 0);
+        assert_eq!(buf.inner().writes(), 0);
+
+        // Written at the next dump, then the buffer goes back to its capacity
+        buf.seek(std::io::SeekFrom::Start(10_000)).unwrap();
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(&buf.inner().get_ref().get_ref()[100..3172], &data[..]);
+        buf.read_exact(&mut middle).unwrap();
+        assert_eq!(buf.capacity(), 1024);
+
+        // Beyond the limit, the write goes through the buffer in chunks until the rest fits
+        buf.inner_mut().reset();
+        buf.write_all(&[1u8; 4096]).unwrap();
+        assert_eq!(buf.inner().writes(), 1);
+        assert_eq!(buf.capacity(), 1024 + 2048);
+    }
 
-            buf.write_all(first_write).unwrap();
+    #[test]
+    fn test_staged_write_in_aligned_buffer() {
+        let mut buf =
+            BufReaderWriter::with_aligned_capacity(Cursor::new(vec![0u8; 8192]), 512, 512).unwrap();
+        buf.set_overflow_limit(1024);
+        buf.seek(std::io::SeekFrom::Start(700)).unwrap();
+        buf.write_all(&[7u8; 1000]).unwrap();
+        assert_eq!(buf.stats().bytes_written, 0);
+        assert_eq!(buf.capacity() % 512, 0);
+        buf.flush().unwrap();
+        buf.seek(std::io::SeekFrom::Start(1000)).unwrap();
+        assert_eq!(buf.capacity(), 512);
+        let inner = buf.inner().get_ref();
+        assert_eq!((inner[699], inner[1700]), (0, 0));
+        assert!(inner[700..1700].iter().all(|b| *b == 7));
+        let state = buf.suspend().unwrap();
+        assert_eq!(state.capacity(), 512);
+    }
 
-            assert_eq!(buf.buffer.is_dirty, true);
-            assert_eq!(buf.buffer.num_valid_bytes(), 50);
-            assert!(buf.inner().get_ref().is_empty());
+    #[test]
+    fn test_large_read_in_cached_data_with_low_bypass_threshold() {
+        let data = (0..=255u8).collect::<Vec<_>>();
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(data.clone())), 256);
+        let mut byte = [0u8; 1];
+        buf.read_exact(&mut byte).unwrap();
+        buf.set_bypass_threshold(64, 64);
+        let mut bytes = [0u8; 100];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, data[1..101]);
+        assert_eq!(buf.inner().reads(), 1);
+    }
 
-            buf.write_all(second_write).unwrap();
-            // The buffer has been dumped
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
-            assert_eq!(buf.inner().get_ref(), data.as_slice());
+    #[test]
+    fn test_max_dirty_applies_to_formatted_writes() {
+        let mut buf = BufReaderWriter::with_capacity(CountingStream::new(Cursor::new(Vec::new())), 1024);
+        buf.set_max_dirty(64);
+        for i in 0..20 {
+            writeln!(buf, "line {i:>4}").unwrap();
+            assert!(buf.dirty_len() < 64);
         }
+        assert_eq!(buf.inner().writes(), 3);
+        let expected = (0..20).map(|i| format!("line {i:>4}\n")).collect::<String>();
+        assert_eq!(buf.into_inner().unwrap().into_inner().into_inner(), expected.as_bytes());
     }
 
     #[test]
-    fn read_more_than_buffer_capacity() {
+    fn test_drop_flushes_inner_only_after_writes() {
+        let mut counter = CountingStream::new(Cursor::new(b"Hello".to_vec()));
         {
-            // First, the simple case, where we never wrote not read anything
-            // thus the buffer is empty
-
-            let mut rng = rand::rng();
-            let mut cursor = Cursor::new(vec![]);
-            let mut buf = BufReaderWriter::new(&mut cursor);
-            let buf_capacity = buf.capacity();
-            let n = 4;
-
-            buf.inner.get_mut().resize(buf_capacity * 4, 0u8);
-            for v in buf.inner.get_mut() {
-                *v = rng.random();
-            }
-
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
-
-            let mut request = vec![0u8; buf.capacity()];
-            for i in 0..n {
-                buf.read_exact(&mut request).unwrap();
-                assert_eq!(buf.buffer.is_dirty, false);
-                assert_eq!(buf.buffer.num_valid_bytes(), 0);
-                assert_eq!(
-                    &buf.inner().get_ref()[i * buf_capacity..(i + 1) * buf_capacity],
-                    &request
-                );
-            }
+            let mut buf = BufReaderWriter::new(&mut counter);
+            let mut bytes = [0u8; 2];
+            buf.read_exact(&mut bytes).unwrap();
         }
+        assert_eq!(counter.flushes(), 0);
 
         {
-            // We read a small thing before trying a big read
+            let mut buf = BufReaderWriter::new(&mut counter);
+            buf.write_all(b"J").unwrap();
+            buf.flush().unwrap();
+        }
+        assert_eq!(counter.flushes(), 1);
 
-            let mut rng = rand::rng();
-            let mut cursor = Cursor::new(vec![]);
-            let mut buf = BufReaderWriter::new(&mut cursor);
-            let buf_capacity = buf.capacity();
+        {
+            let mut buf = BufReaderWriter::new(&mut counter);
+            buf.write_all(b"Y").unwrap();
+            buf.flush_writes().unwrap();
+        }
+        assert_eq!(counter.flushes(), 2);
+    }
 
-            buf.inner.get_mut().resize((buf_capacity * 4) + 77, 0u8);
-            for v in buf.inner.get_mut() {
-                *v = rng.random();
-            }
+    #[test]
+    fn test_flush_range_of_a_write_only_window() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new((0..86).collect::<Vec<u8>>()), 33);
+        buf.seek(std::io::SeekFrom::Start(20)).unwrap();
+        buf.write_all(&[0xAA; 39]).unwrap();
+        buf.write_all(&[0xBB; 13]).unwrap();
+        assert_eq!(buf.flush_range(0, 100).unwrap(), 13);
+        assert!(!buf.has_dirty_data());
+        let mut bytes = [0u8; 3];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [72, 73, 74]);
+        assert_eq!(buf.position(), 75);
+    }
 
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+    #[test]
+    fn test_staged_writes_do_not_size_the_next_operations() {
+        let staged = || {
+            let mut buf = BufReaderWriter::with_capacity(Cursor::new(Vec::new()), 4);
+            buf.set_overflow_limit(16);
+            buf.set_bypass_threshold(usize::MAX, usize::MAX);
+            buf.write_all(&[1; 1]).unwrap();
+            buf.write_all(&[2; 5]).unwrap();
+            assert_eq!(buf.capacity(), 6);
+            buf
+        };
+
+        let mut buf = staged();
+        buf.flush().unwrap();
+        buf.write_zeros(10).unwrap();
+        assert_eq!(buf.capacity(), 4);
+        assert_eq!(buf.into_vec().unwrap(), [1, 2, 2, 2, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
 
-            let mut first_request = vec![0u8; 104];
-            buf.read_exact(&mut first_request).unwrap();
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
-            assert_eq!(
-                buf.buffer.num_readable_bytes_left(),
-                buf_capacity - first_request.len()
-            );
-            assert_eq!(&buf.inner().get_ref()[..104], &first_request);
+        let mut buf = staged();
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        buf.copy_from(&mut &[3u8; 10][..], 10).unwrap();
+        assert_eq!(buf.into_vec().unwrap(), [3; 10]);
 
-            let cloned_data = buf.inner().get_ref().to_vec();
-            let mut request = vec![0u8; buf.inner().get_ref().len() - first_request.len()];
-            for (chunk_to_read, expected) in request
-                .chunks_mut(buf_capacity)
-                .zip(cloned_data[first_request.len()..].chunks(buf_capacity))
-            {
-                buf.read_exact(chunk_to_read).unwrap();
-                assert_eq!(buf.buffer.is_dirty, false);
-                assert_eq!(&chunk_to_read, &expected);
-            }
-        }
+        let mut buf = staged();
+        buf.flush().unwrap();
+        buf.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut bytes = [0u8; 64];
+        assert_eq!(buf.peek(&mut bytes).unwrap(), 4);
+        assert_eq!(bytes[..4], [1, 2, 2, 2]);
+    }
 
-        {
-            // We write a small thing before trying a big read
+    #[test]
+    fn test_prefetch_past_the_end() {
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![7u8; 16]), 4);
+        rw.prefetch(25, 25).unwrap();
+        assert_eq!(rw.stream_len().unwrap(), 16);
+        assert_eq!(
+            rw.skip(24).unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
 
-            let mut rng = rand::rng();
-            let mut cursor = Cursor::new(vec![]);
-            let mut buf = BufReaderWriter::new(&mut cursor);
-            let buf_capacity = buf.capacity();
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(vec![7u8; 16]), 8);
+        rw.prefetch(u64::MAX, 10).unwrap();
+        assert_eq!(rw.stream_len().unwrap(), 16);
+        let mut buf = [0u8; 4];
+        rw.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [7; 4]);
+    }
 
-            buf.inner.get_mut().resize((buf_capacity * 4) + 77, 0u8);
-            for v in buf.inner.get_mut() {
-                *v = rng.random();
-            }
+    #[test]
+    fn test_flush_data_does_not_flush_inner() {
+        let mut rw = BufReaderWriter::new(CountingStream::new(Cursor::new(vec![])));
+        rw.write_all(b"Hello").unwrap();
+        rw.flush_data().unwrap();
+        assert!(!rw.has_dirty_data());
+        assert_eq!(rw.position(), 5);
+        assert_eq!(rw.inner().get_ref().get_ref(), b"Hello");
+        assert_eq!(rw.inner().flushes(), 0);
+    }
 
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+    /// Like with the `no-panic` crate, linking fails when a panic can unwind out of
+    /// the guarded calls, which is only known with optimizations:
+    /// this is checked by `cargo test --release`
+    #[cfg(not(debug_assertions))]
+    #[test]
+    fn test_buffer_accesses_cannot_panic() {
+        unsafe extern "C" {
+            fn bufrw_buffer_access_can_panic() -> !;
+        }
 
-            let mut cloned_data = buf.inner().get_ref().to_vec();
-            let mut data_to_write = vec![0u8; 77];
-            for v in data_to_write.iter_mut() {
-                *v = rng.random();
-            }
-            buf.write_all(&data_to_write).unwrap();
-            assert_eq!(buf.buffer.is_dirty, true);
-            cloned_data[..data_to_write.len()].copy_from_slice(&data_to_write);
-            assert_eq!(buf.position(), data_to_write.len() as u64);
+        struct Guard;
 
-            let mut request = vec![0u8; cloned_data.len() - data_to_write.len()];
-            for (chunk_to_read, expected) in request
-                .chunks_mut(buf_capacity)
-                .zip(cloned_data[data_to_write.len()..].chunks(buf_capacity))
-            {
-                buf.read_exact(chunk_to_read).unwrap();
-                assert_eq!(buf.buffer.is_dirty, false);
-                assert_eq!(&chunk_to_read, &expected);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                unsafe { bufrw_buffer_access_can_panic() }
             }
-            assert_eq!(buf.inner.get_ref(), &cloned_data);
         }
 
-        {
-            // We read and write a small thing before trying a big read
-
-            let mut rng = rand::rng();
-            let mut cursor = Cursor::new(vec![]);
-            let mut buf = BufReaderWriter::new(&mut cursor);
-            let buf_capacity = buf.capacity();
-
-            buf.inner.get_mut().resize((buf_capacity * 4) + 77, 0u8);
-            for v in buf.inner.get_mut() {
-                *v = rng.random();
-            }
+        fn guarded<R>(f: impl FnOnce() -> R) -> R {
+            let guard = Guard;
+            let result = f();
+            std::mem::forget(guard);
+            result
+        }
 
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), 0);
+        let mut buffer = std::hint::black_box(crate::Buffer::with_capacity(64));
+        let data = std::hint::black_box([7u8; 100]);
+        let mut bytes = std::hint::black_box([0u8; 100]);
+        assert_eq!(guarded(|| buffer.write(&data)).unwrap(), 64);
+        guarded(|| buffer.set_position(std::hint::black_box(60)));
+        assert_eq!(guarded(|| buffer.read(&mut bytes)).unwrap(), 4);
+        assert_eq!(bytes[..4], [7; 4]);
+    }
 
-            let mut first_request = vec![0u8; 104];
-            buf.read_exact(&mut first_request).unwrap();
-            assert_eq!(buf.buffer.is_dirty, false);
-            assert_eq!(buf.buffer.num_valid_bytes(), buf_capacity);
-            assert_eq!(
-                buf.buffer.num_readable_bytes_left(),
-                buf_capacity - first_request.len()
-            );
-            assert_eq!(
-                &buf.inner().get_ref()[..first_request.len()],
-                &first_request
+    #[test]
+    fn test_zero_capacities_do_not_buffer() {
+        for (read_capacity, write_capacity) in [(0, 16), (16, 0), (0, 0)] {
+            let mut rw = BufReaderWriter::with_capacities(
+                CountingStream::new(Cursor::new(b"0123456789".to_vec())),
+                read_capacity,
+                write_capacity,
             );
-            assert_eq!(buf.position(), first_request.len() as u64);
+            assert_eq!(rw.capacity(), 0);
+            rw.write_all(b"ab").unwrap();
+            assert_eq!(rw.inner().writes(), 1);
+            let mut bytes = [0u8; 3];
+            rw.read_exact(&mut bytes).unwrap();
+            assert_eq!(&bytes, b"234");
+            assert_eq!(rw.position(), 5);
+        }
+    }
 
-            let mut cloned_data = buf.inner().get_ref().to_vec();
-            let mut data_to_write = vec![0u8; 77];
-            for v in data_to_write.iter_mut() {
-                *v = rng.random();
-            }
-            buf.write_all(&data_to_write).unwrap();
-            assert_eq!(buf.buffer.is_dirty, true);
-            cloned_data[first_request.len()..data_to_write.len() + first_request.len()]
-                .copy_from_slice(&data_to_write);
-            assert_eq!(
-                buf.position(),
-                first_request.len() as u64 + data_to_write.len() as u64
-            );
+    #[test]
+    fn test_read_scattered_errors_restore_the_position() {
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(b"Hello World".to_vec()), 4);
+        rw.seek(std::io::SeekFrom::Start(3)).unwrap();
+
+        let (mut a, mut b) = ([0u8; 2], [0u8; 2]);
+        let err = rw
+            .read_scattered(&mut [(0, &mut a), (u64::MAX, &mut b)])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(a, [0; 2]);
+        assert_eq!(rw.position(), 3);
+
+        let err = rw
+            .read_scattered(&mut [(0, &mut a), (10, &mut b)])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(rw.position(), 3);
+        let mut c = [0u8; 2];
+        rw.read_exact(&mut c).unwrap();
+        assert_eq!(&c, b"lo");
+    }
 
-            let mut request =
-                vec![0u8; cloned_data.len() - first_request.len() - data_to_write.len()];
-            for (chunk_to_read, expected) in request
-                .chunks_mut(buf_capacity)
-                .zip(cloned_data[first_request.len() + data_to_write.len()..].chunks(buf_capacity))
-            {
-                buf.read_exact(chunk_to_read).unwrap();
-                assert_eq!(buf.buffer.is_dirty, false);
-                assert_eq!(&chunk_to_read, &expected);
-            }
-            assert_eq!(buf.inner.get_ref(), &cloned_data);
-        }
+    #[test]
+    fn test_peek_at_before_a_window_past_the_end() {
+        let mut rw = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+        rw.seek(std::io::SeekFrom::Start(20)).unwrap();
+        rw.write_all(b"abc").unwrap();
+
+        let mut bytes = [1u8; 4];
+        assert_eq!(rw.peek_at(12, &mut bytes).unwrap(), 4);
+        assert_eq!(bytes, [0; 4]);
+        let mut bytes = [1u8; 6];
+        assert_eq!(rw.peek_at(8, &mut bytes).unwrap(), 6);
+        assert_eq!(&bytes, b"89\0\0\0\0");
+        let mut bytes = [1u8; 6];
+        assert_eq!(rw.peek_at(18, &mut bytes).unwrap(), 5);
+        assert_eq!(&bytes[..5], b"\0\0abc");
+        assert_eq!(rw.peek_at(30, &mut bytes).unwrap(), 0);
+        assert_eq!(rw.position(), 23);
+
+        // The same bytes once written
+        let mut before = [1u8; 20];
+        assert_eq!(rw.peek_at(5, &mut before).unwrap(), 18);
+        rw.flush().unwrap();
+        assert_eq!(&rw.inner().get_ref()[5..], &before[..18]);
     }
 }
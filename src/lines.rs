@@ -0,0 +1,200 @@
+//! Index of the line starts of a stream
+use crate::BufReaderWriter;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+/// Number of lines between two absolute offsets, the others are delta-encoded
+const BLOCK_LEN: u64 = 64;
+
+/// Byte offsets of the start of each line of a stream, created by [BufReaderWriter::build_line_index]
+///
+/// A line ends with a newline (the `0xA` byte), the last line may not have one.
+///
+/// The offsets are stored as variable length deltas, with an absolute offset
+/// every few lines, so that looking up a line is done in constant time.
+///
+/// The index stays valid as long as edits do not move newlines,
+/// e.g. rewriting fields in place with values of the same length.
+/// Inserting or removing bytes invalidates it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineIndex {
+    /// Offset of every `BLOCK_LEN`-th line, and the index of the deltas that follow it
+    blocks: Vec<(u64, usize)>,
+    /// LEB128 deltas between consecutive line starts
+    deltas: Vec<u8>,
+    line_count: u64,
+}
+
+impl LineIndex {
+    /// Returns the number of lines
+    pub fn line_count(&self) -> u64 {
+        self.line_count
+    }
+
+    /// Returns the offset of the start of the line `n` (0-based)
+    pub fn line_start(&self, n: u64) -> Option<u64> {
+        if n >= self.line_count {
+            return None;
+        }
+        let (mut offset, mut i) = self.blocks[(n / BLOCK_LEN) as usize];
+        for _ in 0..n % BLOCK_LEN {
+            let mut delta = 0u64;
+            let mut shift = 0;
+            loop {
+                let byte = self.deltas[i];
+                i += 1;
+                delta |= u64::from(byte & 0x7F) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            offset += delta;
+        }
+        Some(offset)
+    }
+
+    fn push(&mut self, offset: u64, previous: u64) {
+        if self.line_count.is_multiple_of(BLOCK_LEN) {
+            self.blocks.push((offset, self.deltas.len()));
+        } else {
+            let mut delta = offset - previous;
+            loop {
+                let byte = (delta & 0x7F) as u8;
+                delta >>= 7;
+                if delta == 0 {
+                    self.deltas.push(byte);
+                    break;
+                }
+                self.deltas.push(byte | 0x80);
+            }
+        }
+        self.line_count += 1;
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Builds the index of the lines of the whole stream, in a single pass
+    ///
+    /// Data written but not yet flushed is taken into account.
+    ///
+    /// The position is restored afterward.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::Read;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"first\nsecond\nthird\n".to_vec());
+    /// let index = rw.build_line_index()?;
+    /// assert_eq!(index.line_count(), 3);
+    ///
+    /// rw.seek_to_line(&index, 1)?;
+    /// let mut line = [0u8; 6];
+    /// rw.read_exact(&mut line)?;
+    /// assert_eq!(&line, b"second");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn build_line_index(&mut self) -> std::io::Result<LineIndex> {
+        let saved_position = self.position();
+        self.seek(SeekFrom::Start(0))?;
+
+        let mut index = LineIndex::default();
+        // Start of the line being scanned, None when it is not known to exist yet
+        let mut line_start = Some(0);
+        let mut previous = 0;
+        let mut offset = 0;
+        let result = loop {
+            let available = match self.fill_buf() {
+                Ok(available) => available,
+                Err(e) => break Err(e),
+            };
+            if available.is_empty() {
+                break Ok(());
+            }
+            let len = available.len();
+            for (i, byte) in available.iter().enumerate() {
+                if let Some(start) = line_start.take() {
+                    index.push(start, previous);
+                    previous = start;
+                }
+                if *byte == b'\n' {
+                    line_start = Some(offset + i as u64 + 1);
+                }
+            }
+            offset += len as u64;
+            self.consume(len);
+        };
+
+        self.seek(SeekFrom::Start(saved_position))?;
+        result.map(|_| index)
+    }
+
+    /// Seeks to the start of the line `n` (0-based) of the `index`
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if there is no such line.
+    pub fn seek_to_line(&mut self, index: &LineIndex, n: u64) -> std::io::Result<u64> {
+        let offset = index.line_start(n).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "line out of range")
+        })?;
+        self.seek(SeekFrom::Start(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use rand::Rng;
+    use std::io::{BufRead, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_line_index() {
+        let mut rng = rand::rng();
+        let mut data = Vec::new();
+        let mut starts = Vec::new();
+        for i in 0..1_000_000 {
+            starts.push(data.len() as u64);
+            let padding = rng.random_range(0..300);
+            data.extend_from_slice(format!("{i}:{}\n", "x".repeat(padding)).as_bytes());
+        }
+
+        let mut rw = BufReaderWriter::from_vec(data);
+        rw.seek(SeekFrom::Start(123)).unwrap();
+        let index = rw.build_line_index().unwrap();
+        assert_eq!(rw.position(), 123);
+        assert_eq!(index.line_count(), starts.len() as u64);
+        assert_eq!(index.line_start(starts.len() as u64), None);
+
+        let mut line = String::new();
+        for _ in 0..1000 {
+            let n = rng.random_range(0..starts.len());
+            assert_eq!(index.line_start(n as u64), Some(starts[n]));
+            rw.seek_to_line(&index, n as u64).unwrap();
+            line.clear();
+            rw.read_line(&mut line).unwrap();
+            assert!(line.starts_with(&format!("{n}:")), "{line}");
+        }
+        assert!(rw.seek_to_line(&index, starts.len() as u64).is_err());
+    }
+
+    #[test]
+    fn test_line_index_edge_cases() {
+        let mut rw = BufReaderWriter::in_memory();
+        assert_eq!(rw.build_line_index().unwrap().line_count(), 0);
+
+        // Dirty data, last line without a newline
+        rw.write_all(b"\n\na\nbc").unwrap();
+        let index = rw.build_line_index().unwrap();
+        assert_eq!(index.line_count(), 4);
+        let starts = (0..4).map(|n| index.line_start(n).unwrap());
+        assert_eq!(starts.collect::<Vec<_>>(), [0, 1, 2, 4]);
+
+        rw.write_all(b"\n").unwrap();
+        assert_eq!(rw.build_line_index().unwrap().line_count(), 4);
+    }
+}
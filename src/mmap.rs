@@ -0,0 +1,239 @@
+//! Reading and writing a file through a writable memory map
+use memmap2::MmapMut;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// A file read and written through a writable memory map,
+/// an alternative to [BufReaderWriter](crate::BufReaderWriter) for large files on local disks
+///
+/// The whole file is mapped, so reads and writes are copies from and to the page cache,
+/// without any read or write syscall: scattered small patches cost no more than
+/// the pages they touch. [Write::flush] writes back (`msync`) the range modified
+/// since the previous flush, which is also done on drop, ignoring errors.
+///
+/// Writing past the end of the file grows it with [File::set_len], then maps it again,
+/// which is much more expensive than a write: appending is better done with
+/// a [BufReaderWriter](crate::BufReaderWriter).
+///
+/// # Safety hazards
+///
+/// Memory maps do not go through the usual file API, so other handles to the file are
+/// a hazard this type cannot guard against:
+///
+/// * If the file is truncated by another handle or process while mapped,
+///   accessing the pages past the new end raises a `SIGBUS` signal (on unix)
+///   that kills the process, instead of returning an error.
+/// * If the file is modified by another handle or process while mapped,
+///   the modifications may or may not be visible, and may be overwritten.
+///
+/// This is why [MmapFile::open] is `unsafe`: the caller must make sure
+/// the file is not truncated or concurrently modified while it is open.
+pub struct MmapFile {
+    file: File,
+    // None while the file is empty, as empty maps are not supported everywhere
+    map: Option<MmapMut>,
+    pos: u64,
+    // Range modified since the last flush
+    dirty: Option<Range<usize>>,
+}
+
+impl MmapFile {
+    /// Maps the file, which must be opened for reading and writing
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified through other handles,
+    /// including by other processes, while the returned value exists, see [MmapFile].
+    pub unsafe fn open(file: File) -> std::io::Result<Self> {
+        let mut this = Self {
+            file,
+            map: None,
+            pos: 0,
+            dirty: None,
+        };
+        this.remap()?;
+        Ok(this)
+    }
+
+    /// Returns the length of the file
+    pub fn len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+
+    /// Returns whether the file is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the range of bytes modified since the last flush, if any
+    pub fn dirty_range(&self) -> Option<Range<u64>> {
+        self.dirty
+            .as_ref()
+            .map(|range| range.start as u64..range.end as u64)
+    }
+
+    /// Flushes the modifications, then returns the file
+    pub fn into_file(mut self) -> std::io::Result<File> {
+        self.flush()?;
+        // The map is unmapped before the file is moved out
+        self.map = None;
+        let this = std::mem::ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped, and `file` is its only field left needing a drop
+        Ok(unsafe { std::ptr::read(&this.file) })
+    }
+
+    fn remap(&mut self) -> std::io::Result<()> {
+        let len = self.file.metadata()?.len();
+        self.map = if len == 0 {
+            None
+        } else {
+            // SAFETY: the caller of `open` guarantees the file is not modified elsewhere
+            Some(unsafe { MmapMut::map_mut(&self.file)? })
+        };
+        Ok(())
+    }
+
+    /// Grows the file and the map so that they contain `end`
+    fn grow(&mut self, end: u64) -> std::io::Result<()> {
+        // Remapping drops the dirty pages tracking, so write them back first
+        self.flush()?;
+        self.map = None;
+        self.file.set_len(end)?;
+        self.remap()
+    }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(map) = &self.map else {
+            return Ok(0);
+        };
+        if self.pos >= map.len() as u64 {
+            return Ok(0);
+        }
+        let start = self.pos as usize;
+        let n = buf.len().min(map.len() - start);
+        buf[..n].copy_from_slice(&map[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MmapFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = self.pos.checked_add(buf.len() as u64).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "write end overflows")
+        })?;
+        if end > self.len() {
+            self.grow(end)?;
+        }
+        let Some(map) = &mut self.map else {
+            return Err(std::io::Error::other("the grown file is not mapped"));
+        };
+        let start = self.pos as usize;
+        let end = end as usize;
+        map[start..end].copy_from_slice(buf);
+        self.dirty = Some(match self.dirty.take() {
+            Some(range) => range.start.min(start)..range.end.max(end),
+            None => start..end,
+        });
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    /// Writes back the range modified since the last flush
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let (Some(map), Some(range)) = (&self.map, self.dirty.take()) {
+            map.flush_range(range.start, range.end - range.start)?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.len(), offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for MmapFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MmapFile;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    fn open(file: &std::fs::File) -> MmapFile {
+        // SAFETY: the temporary file is only used by the test
+        unsafe { MmapFile::open(file.try_clone().unwrap()) }.unwrap()
+    }
+
+    #[test]
+    fn test_mmap_patches() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"0123456789").unwrap();
+
+        let mut mmap = open(&file);
+        assert_eq!(mmap.len(), 10);
+        mmap.seek(SeekFrom::Start(7)).unwrap();
+        mmap.write_all(b"ab").unwrap();
+        mmap.seek(SeekFrom::Start(2)).unwrap();
+        mmap.write_all(b"cd").unwrap();
+        assert_eq!(mmap.dirty_range(), Some(2..9));
+
+        mmap.seek(SeekFrom::Start(0)).unwrap();
+        let mut content = String::new();
+        mmap.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "01cd456ab9");
+
+        mmap.flush().unwrap();
+        assert_eq!(mmap.dirty_range(), None);
+        let mut file = mmap.into_file().unwrap();
+        content.clear();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "01cd456ab9");
+    }
+
+    #[test]
+    fn test_mmap_grows() {
+        let mut file = tempfile::tempfile().unwrap();
+
+        let mut mmap = open(&file);
+        assert!(mmap.is_empty());
+        let mut bytes = [0u8; 4];
+        assert_eq!(mmap.read(&mut bytes).unwrap(), 0);
+
+        mmap.write_all(b"Hello").unwrap();
+        mmap.seek(SeekFrom::End(3)).unwrap();
+        mmap.write_all(b"World").unwrap();
+        assert_eq!(mmap.len(), 13);
+        drop(mmap);
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"Hello\0\0\0World");
+    }
+}
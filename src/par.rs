@@ -0,0 +1,203 @@
+//! Transforming the data in chunks processed in parallel with rayon
+use crate::BufReaderWriter;
+use rayon::prelude::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// A chunk read from the stream, with a copy to detect whether it was modified
+struct Chunk {
+    offset: u64,
+    data: Vec<u8>,
+    original: Vec<u8>,
+    len: usize,
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Transforms in place the data from the current position to the end of the stream,
+    /// calling `f` on several chunks in parallel on the rayon thread pool
+    ///
+    /// This is the parallel version of [Self::rewrite_with]: the chunks are read
+    /// sequentially by batches of as many chunks as the pool has threads,
+    /// transformed in parallel, then the modified ones are written back in offset order
+    /// through the buffer, before the next batch is read.
+    /// So the memory used is bounded to twice the size of a batch.
+    ///
+    /// If `f` fails on some chunks, the chunks before the first failure are written back,
+    /// and the error of the first failure is returned.
+    ///
+    /// Returns the number of bytes processed, after which the position is at the end of the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [std::io::ErrorKind::InvalidInput] if `chunk_size` is 0
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// let n = rw.par_process_chunks(4, |_, chunk| {
+    ///     chunk.make_ascii_uppercase();
+    ///     Ok(())
+    /// })?;
+    /// assert_eq!(n, 11);
+    /// assert_eq!(rw.into_vec()?, b"HELLO WORLD");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn par_process_chunks(
+        &mut self,
+        chunk_size: usize,
+        f: impl Fn(u64, &mut [u8]) -> std::io::Result<()> + Sync,
+    ) -> std::io::Result<u64> {
+        if chunk_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "chunk size must be non-zero",
+            ));
+        }
+        let start = self.position();
+        let mut position = start;
+        let mut chunks = (0..rayon::current_num_threads())
+            .map(|_| Chunk {
+                offset: 0,
+                data: vec![0u8; chunk_size],
+                original: vec![0u8; chunk_size],
+                len: 0,
+            })
+            .collect::<Vec<_>>();
+
+        loop {
+            let mut num_read = 0;
+            for chunk in chunks.iter_mut() {
+                let len = read_full(self, &mut chunk.data)?;
+                if len == 0 {
+                    break;
+                }
+                chunk.offset = position;
+                chunk.len = len;
+                chunk.original[..len].copy_from_slice(&chunk.data[..len]);
+                position += len as u64;
+                num_read += 1;
+                if len < chunk_size {
+                    break;
+                }
+            }
+            if num_read == 0 {
+                return Ok(position - start);
+            }
+
+            let results = chunks[..num_read]
+                .par_iter_mut()
+                .map(|chunk| f(chunk.offset, &mut chunk.data[..chunk.len]))
+                .collect::<Vec<_>>();
+
+            for (chunk, result) in chunks[..num_read].iter().zip(results) {
+                result?;
+                let len = chunk.len;
+                if chunk.data[..len] != chunk.original[..len] {
+                    self.seek(SeekFrom::Start(chunk.offset))?;
+                    self.write_all(&chunk.data[..len])?;
+                }
+            }
+            self.seek(SeekFrom::Start(position))?;
+
+            if chunks[num_read - 1].len < chunk_size {
+                return Ok(position - start);
+            }
+        }
+    }
+}
+
+/// Reads until `buf` is full or the end of the stream is reached
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut k = 0;
+    while k < buf.len() {
+        match reader.read(&mut buf[k..]) {
+            Ok(0) => break,
+            Ok(n) => k += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(k)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+    use std::time::{Duration, Instant};
+
+    fn transform(offset: u64, chunk: &mut [u8]) -> std::io::Result<()> {
+        std::thread::sleep(Duration::from_millis(20));
+        for (i, byte) in chunk.iter_mut().enumerate() {
+            *byte = byte
+                .wrapping_mul(3)
+                .wrapping_add((offset as usize + i) as u8);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_process_chunks_is_faster_and_identical() {
+        let capacity = 1024;
+        let data = (0..20 * capacity + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let mut serial = BufReaderWriter::with_capacity(Cursor::new(data.clone()), capacity);
+        let now = Instant::now();
+        let n = serial.rewrite_with(capacity, transform).unwrap();
+        let serial_time = now.elapsed();
+        assert_eq!(n, data.len() as u64);
+
+        // The sleeps do not need a CPU each
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let mut parallel = BufReaderWriter::with_capacity(Cursor::new(data.clone()), capacity);
+        let now = Instant::now();
+        let n = pool
+            .install(|| parallel.par_process_chunks(capacity, transform))
+            .unwrap();
+        let parallel_time = now.elapsed();
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(parallel.position(), data.len() as u64);
+
+        assert_eq!(
+            parallel.into_inner().unwrap().into_inner(),
+            serial.into_inner().unwrap().into_inner()
+        );
+        assert!(
+            parallel_time * 2 < serial_time,
+            "parallel: {parallel_time:?}, serial: {serial_time:?}"
+        );
+    }
+
+    #[test]
+    fn test_par_process_chunks_is_coherent_with_the_cache() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(vec![b'a'; 100]), 64);
+        buf.write_all(b"dirty").unwrap();
+        buf.seek(SeekFrom::Start(2)).unwrap();
+
+        let result = buf.par_process_chunks(10, |offset, chunk| {
+            if offset >= 72 {
+                return Err(std::io::Error::other("stop"));
+            }
+            chunk.make_ascii_uppercase();
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "stop");
+
+        let data = buf.into_inner().unwrap().into_inner();
+        assert_eq!(&data[..5], b"diRTY");
+        assert!(data[5..72].iter().all(|b| *b == b'A'));
+        assert!(data[72..].iter().all(|b| *b == b'a'));
+    }
+}
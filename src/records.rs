@@ -0,0 +1,102 @@
+//! Iteration over fixed size records
+use crate::BufReaderWriter;
+use std::io::{Read, Seek, Write};
+
+/// Iterator over the fixed size records of a stream, from the last one to the first one
+///
+/// Created by [BufReaderWriter::rev_records].
+///
+/// Records are aligned on the start of the stream, so when the stream length
+/// is not a multiple of the record size, the last record is partial.
+/// By default, this is reported as an error of kind [std::io::ErrorKind::InvalidData],
+/// see [RevRecords::allow_partial].
+///
+/// After a record is yielded, the position of the [BufReaderWriter] is at the start of that record.
+pub struct RevRecords<'a, T, const N: usize = 0>
+where
+    T: Read + Write + Seek,
+{
+    rw: &'a mut BufReaderWriter<T, N>,
+    record_size: usize,
+    allow_partial: bool,
+    // End of the next record to yield, None until the stream length is known
+    next_end: Option<u64>,
+    done: bool,
+}
+
+impl<'a, T, const N: usize> RevRecords<'a, T, N>
+where
+    T: Read + Write + Seek,
+{
+    pub(crate) fn new(rw: &'a mut BufReaderWriter<T, N>, record_size: usize) -> Self {
+        Self {
+            rw,
+            record_size,
+            allow_partial: false,
+            next_end: None,
+            done: false,
+        }
+    }
+
+    /// When the stream length is not a multiple of the record size,
+    /// yield the trailing partial record first instead of an error
+    pub fn allow_partial(mut self, allow: bool) -> Self {
+        self.allow_partial = allow;
+        self
+    }
+
+    fn next_record(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if self.record_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "record size must be non-zero",
+            ));
+        }
+        let end = match self.next_end {
+            Some(end) => end,
+            None => {
+                let len = self.rw.stream_len()?;
+                let partial = len % self.record_size as u64;
+                if partial != 0 && !self.allow_partial {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "stream length is not a multiple of the record size",
+                    ));
+                }
+                len
+            }
+        };
+        if end == 0 {
+            return Ok(None);
+        }
+
+        let size = match end % self.record_size as u64 {
+            0 => self.record_size as u64,
+            partial => partial,
+        };
+        let start = end - size;
+        let mut record = vec![0u8; size as usize];
+        self.rw
+            .read_window_backward(start, &mut record, self.record_size)?;
+        self.next_end = Some(start);
+        Ok(Some(record))
+    }
+}
+
+impl<T, const N: usize> Iterator for RevRecords<'_, T, N>
+where
+    T: Read + Write + Seek,
+{
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_record().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
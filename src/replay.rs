@@ -0,0 +1,448 @@
+//! Recording the calls made to a stream, and replaying them without the stream
+//!
+//! The log is a sequence of records, little endian:
+//!
+//! * read: `0u8`, requested length (u64), result
+//! * write: `1u8`, data length (u64), data, result
+//! * seek: `2u8`, `0u8` for start, `1u8` for end or `2u8` for current, offset (u64 or i64), result
+//! * flush: `3u8`, result
+//!
+//! A result is `1u8` followed by the index of the error kind (u8) for an error,
+//! or `0u8` followed by the bytes read (u64 length then bytes) for a read,
+//! the number of bytes written (u64) for a write, the position (u64) for a seek
+//! and nothing for a flush.
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+const READ: u8 = 0;
+const WRITE: u8 = 1;
+const SEEK: u8 = 2;
+const FLUSH: u8 = 3;
+
+// The kinds that can be replayed, the others are replayed as `Other`
+const ERROR_KINDS: [ErrorKind; 20] = [
+    ErrorKind::Other,
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::NotConnected,
+    ErrorKind::AddrInUse,
+    ErrorKind::AddrNotAvailable,
+    ErrorKind::BrokenPipe,
+    ErrorKind::AlreadyExists,
+    ErrorKind::WouldBlock,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::TimedOut,
+    ErrorKind::WriteZero,
+    ErrorKind::Interrupted,
+    ErrorKind::Unsupported,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::OutOfMemory,
+];
+
+fn encode_seek(log: &mut Vec<u8>, from: SeekFrom) {
+    let (variant, offset) = match from {
+        SeekFrom::Start(offset) => (0u8, offset.to_le_bytes()),
+        SeekFrom::End(offset) => (1u8, offset.to_le_bytes()),
+        SeekFrom::Current(offset) => (2u8, offset.to_le_bytes()),
+    };
+    log.push(variant);
+    log.extend_from_slice(&offset);
+}
+
+fn encode_error(log: &mut Vec<u8>, e: &std::io::Error) {
+    let kind = ERROR_KINDS
+        .iter()
+        .position(|kind| *kind == e.kind())
+        .unwrap_or(0);
+    log.push(1);
+    log.push(kind as u8);
+}
+
+/// Stream wrapper recording every call made to it, along with its result,
+/// to replay them later with a [ReplayStream]
+///
+/// The log holds the data read and written, but the stream itself is not needed
+/// to replay it, which allows to reproduce the exact calls made by an adapter
+/// on a file that cannot be shared.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::{BufReaderWriter, RecordingStream, ReplayStream};
+/// use std::io::{Cursor, Read, Write};
+///
+/// # fn main() -> std::io::Result<()> {
+/// fn workload<T: Read + Write + std::io::Seek>(rw: &mut BufReaderWriter<T>) -> std::io::Result<Vec<u8>> {
+///     let mut header = vec![0u8; 4];
+///     rw.read_exact(&mut header)?;
+///     rw.write_all(b"patch")?;
+///     rw.flush()?;
+///     Ok(header)
+/// }
+///
+/// let mut rw = BufReaderWriter::new(RecordingStream::new(Cursor::new(b"private data".to_vec())));
+/// let header = workload(&mut rw)?;
+/// let log = rw.into_inner()?.into_log();
+///
+/// let mut rw = BufReaderWriter::new(ReplayStream::new(log));
+/// assert_eq!(workload(&mut rw)?, header);
+/// assert!(rw.into_inner()?.is_finished());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct RecordingStream<T> {
+    inner: T,
+    log: Vec<u8>,
+}
+
+impl<T> RecordingStream<T> {
+    /// Wraps `inner`, with an empty log
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            log: Vec::new(),
+        }
+    }
+
+    /// Returns the log of the calls made so far
+    pub fn log(&self) -> &[u8] {
+        &self.log
+    }
+
+    /// Returns the log of the calls made, dropping the wrapped stream
+    pub fn into_log(self) -> Vec<u8> {
+        self.log
+    }
+
+    /// Gets a reference to the wrapped stream
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the wrapped stream, calls made through it are not recorded
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwraps the stream and its log
+    pub fn into_parts(self) -> (T, Vec<u8>) {
+        (self.inner, self.log)
+    }
+}
+
+impl<T: Read> Read for RecordingStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.log.push(READ);
+        self.log
+            .extend_from_slice(&(buf.len() as u64).to_le_bytes());
+        match &result {
+            Ok(n) => {
+                let n = (*n).min(buf.len());
+                self.log.push(0);
+                self.log.extend_from_slice(&(n as u64).to_le_bytes());
+                self.log.extend_from_slice(&buf[..n]);
+            }
+            Err(e) => encode_error(&mut self.log, e),
+        }
+        result
+    }
+}
+
+impl<T: Write> Write for RecordingStream<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.log.push(WRITE);
+        self.log
+            .extend_from_slice(&(buf.len() as u64).to_le_bytes());
+        self.log.extend_from_slice(buf);
+        match &result {
+            Ok(n) => {
+                self.log.push(0);
+                self.log.extend_from_slice(&(*n as u64).to_le_bytes());
+            }
+            Err(e) => encode_error(&mut self.log, e),
+        }
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let result = self.inner.flush();
+        self.log.push(FLUSH);
+        match &result {
+            Ok(()) => self.log.push(0),
+            Err(e) => encode_error(&mut self.log, e),
+        }
+        result
+    }
+}
+
+impl<T: Seek> Seek for RecordingStream<T> {
+    fn seek(&mut self, from: SeekFrom) -> std::io::Result<u64> {
+        let result = self.inner.seek(from);
+        self.log.push(SEEK);
+        encode_seek(&mut self.log, from);
+        match &result {
+            Ok(position) => {
+                self.log.push(0);
+                self.log.extend_from_slice(&position.to_le_bytes());
+            }
+            Err(e) => encode_error(&mut self.log, e),
+        }
+        result
+    }
+}
+
+/// Stream replaying the log of a [RecordingStream]
+///
+/// Each call must be the same, with the same arguments, as the next call of the log,
+/// and returns the same result.
+///
+/// # Panics
+///
+/// Every call panics if it diverges from the log, or if the log is over.
+/// The calls made after a divergence, e.g. by the adapter's `drop`, fail instead.
+#[derive(Debug, Clone)]
+pub struct ReplayStream {
+    log: Vec<u8>,
+    cursor: usize,
+    calls: usize,
+    diverged: bool,
+}
+
+impl ReplayStream {
+    /// Creates a stream replaying the `log`, from its first call
+    pub fn new(log: Vec<u8>) -> Self {
+        Self {
+            log,
+            cursor: 0,
+            calls: 0,
+            diverged: false,
+        }
+    }
+
+    /// Returns whether all the calls of the log were replayed
+    pub fn is_finished(&self) -> bool {
+        self.cursor == self.log.len()
+    }
+
+    /// Returns the number of calls replayed so far
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    fn diverge(&mut self, reason: std::fmt::Arguments) -> ! {
+        self.diverged = true;
+        panic!("replay diverged at call {}: {reason}", self.calls);
+    }
+
+    fn take(&mut self, n: usize) -> &[u8] {
+        let range = self.cursor..self.cursor + n;
+        if range.end > self.log.len() {
+            self.diverged = true;
+            panic!("replay log is truncated at call {}", self.calls);
+        }
+        self.cursor += n;
+        &self.log[range]
+    }
+
+    fn take_u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn take_u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take(8).try_into().unwrap())
+    }
+
+    /// Starts replaying the next call, which must be of the `expected` kind
+    fn next_call(&mut self, expected: u8, call: &dyn std::fmt::Debug) -> std::io::Result<()> {
+        if self.diverged {
+            return Err(std::io::Error::other("replay diverged"));
+        }
+        if self.is_finished() {
+            self.diverge(format_args!("{call:?} was made after the end of the log"));
+        }
+        let tag = self.take_u8();
+        if tag != expected {
+            let recorded = ["read", "write", "seek", "flush"]
+                .get(tag as usize)
+                .unwrap_or(&"unknown");
+            self.diverge(format_args!(
+                "{call:?} was made, but a {recorded} was recorded"
+            ));
+        }
+        Ok(())
+    }
+
+    fn check<V: PartialEq + std::fmt::Debug>(&mut self, what: &str, recorded: V, actual: V) {
+        if recorded != actual {
+            self.diverge(format_args!(
+                "{what} is {actual:?}, but {recorded:?} was recorded"
+            ));
+        }
+    }
+
+    /// Returns the recorded error, if the result is an error
+    fn take_error(&mut self) -> Option<std::io::Error> {
+        match self.take_u8() {
+            0 => None,
+            _ => {
+                let kind = ERROR_KINDS
+                    .get(self.take_u8() as usize)
+                    .copied()
+                    .unwrap_or(ErrorKind::Other);
+                Some(std::io::Error::new(kind, "replayed error"))
+            }
+        }
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.next_call(READ, &format_args!("read of {} bytes", buf.len()))?;
+        let requested = self.take_u64();
+        self.check("the read length", requested, buf.len() as u64);
+        let result = match self.take_error() {
+            Some(e) => Err(e),
+            None => {
+                let n = self.take_u64() as usize;
+                buf[..n].copy_from_slice(self.take(n));
+                Ok(n)
+            }
+        };
+        self.calls += 1;
+        result
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.next_call(WRITE, &format_args!("write of {} bytes", buf.len()))?;
+        let len = self.take_u64() as usize;
+        self.check("the write length", len, buf.len());
+        if self.take(len) != buf {
+            self.diverge(format_args!(
+                "the written data differs from the recorded one"
+            ));
+        }
+        let result = match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(self.take_u64() as usize),
+        };
+        self.calls += 1;
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.next_call(FLUSH, &"flush")?;
+        let result = match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+        self.calls += 1;
+        result
+    }
+}
+
+impl Seek for ReplayStream {
+    fn seek(&mut self, from: SeekFrom) -> std::io::Result<u64> {
+        self.next_call(SEEK, &from)?;
+        let variant = self.take_u8();
+        let offset = self.take(8).try_into().unwrap();
+        let recorded = match variant {
+            0 => SeekFrom::Start(u64::from_le_bytes(offset)),
+            1 => SeekFrom::End(i64::from_le_bytes(offset)),
+            _ => SeekFrom::Current(i64::from_le_bytes(offset)),
+        };
+        self.check("the seek", recorded, from);
+        let result = match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(self.take_u64()),
+        };
+        self.calls += 1;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordingStream, ReplayStream};
+    use crate::BufReaderWriter;
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    /// Stream failing every read
+    struct FailingReads;
+
+    impl Read for FailingReads {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::TimedOut.into())
+        }
+    }
+
+    #[test]
+    fn test_replay_results_and_errors() {
+        let mut recording = RecordingStream::new(Cursor::new(b"0123456789".to_vec()));
+        let mut bytes = [0u8; 4];
+        recording.read_exact(&mut bytes).unwrap();
+        recording.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(recording.read(&mut bytes).unwrap(), 2);
+        recording.write_all(b"ab").unwrap();
+        recording.flush().unwrap();
+        assert!(recording.seek(SeekFrom::Current(-20)).is_err());
+        let (inner, log) = recording.into_parts();
+        assert_eq!(inner.into_inner(), b"0123456789ab");
+
+        let mut replay = ReplayStream::new(log);
+        replay.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"0123");
+        assert_eq!(replay.seek(SeekFrom::End(-2)).unwrap(), 8);
+        assert_eq!(replay.read(&mut bytes).unwrap(), 2);
+        assert_eq!(&bytes[..2], b"89");
+        replay.write_all(b"ab").unwrap();
+        replay.flush().unwrap();
+        let err = replay.seek(SeekFrom::Current(-20)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(replay.is_finished());
+        assert_eq!(replay.calls(), 6);
+
+        let mut recording = RecordingStream::new(FailingReads);
+        assert!(recording.read(&mut bytes).is_err());
+        let mut replay = ReplayStream::new(recording.into_log());
+        assert_eq!(
+            replay.read(&mut bytes).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "replay diverged at call 1: the written data differs")]
+    fn test_replay_divergent_data_panics() {
+        let inner = RecordingStream::new(Cursor::new(vec![0u8; 100]));
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        buf.seek(SeekFrom::Start(50)).unwrap();
+        buf.write_all(b"expected").unwrap();
+        let log = buf.into_inner().unwrap().into_log();
+
+        let mut buf = BufReaderWriter::with_capacity(ReplayStream::new(log), 16);
+        buf.seek(SeekFrom::Start(50)).unwrap();
+        buf.write_all(b"diverged").unwrap();
+        buf.flush_writes().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "replay diverged at call 0: read of 16 bytes was made, but a seek")]
+    fn test_replay_divergent_calls_panic() {
+        let inner = RecordingStream::new(Cursor::new(vec![0u8; 100]));
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        buf.seek(SeekFrom::Start(50)).unwrap();
+        let log = buf.into_inner().unwrap().into_log();
+
+        let mut buf = BufReaderWriter::with_capacity(ReplayStream::new(log), 16);
+        let mut bytes = [0u8; 4];
+        let _ = buf.read(&mut bytes);
+    }
+}
@@ -0,0 +1,370 @@
+//! Retrying the reads and writes on the inner stream that fail with transient errors
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Which failed reads and writes on the inner stream are retried, and how,
+/// see [BufReaderWriter::set_retry_policy](crate::BufReaderWriter::set_retry_policy)
+///
+/// The delay before the `n`-th retry is `backoff * 2^(n - 1)`.
+///
+/// Errors of kind [ErrorKind::Interrupted] are always retried, immediately and
+/// without counting as attempts, with or without a policy.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::RetryPolicy;
+/// use std::io::ErrorKind;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy {
+///     kinds: &[ErrorKind::TimedOut, ErrorKind::WouldBlock],
+///     max_attempts: 5,
+///     backoff: Duration::from_millis(10),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// The kinds of errors to retry
+    pub kinds: &'static [ErrorKind],
+    /// The maximum number of attempts of an operation, including the first one
+    pub max_attempts: u32,
+    /// The delay before the first retry
+    pub backoff: Duration,
+    /// The function used to wait between attempts, [std::thread::sleep] by default
+    pub sleep: fn(Duration),
+}
+
+impl Default for RetryPolicy {
+    /// A policy that retries nothing
+    fn default() -> Self {
+        Self {
+            kinds: &[],
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+            sleep: std::thread::sleep,
+        }
+    }
+}
+
+/// The error of the last attempt of an operation that was retried until the
+/// [RetryPolicy::max_attempts] was reached
+///
+/// It is wrapped in an [std::io::Error] of the same kind as the last error,
+/// and can be retrieved with [std::io::Error::get_ref].
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    /// The number of attempts made
+    pub attempts: u32,
+    /// The error of the last attempt
+    pub source: std::io::Error,
+}
+
+impl std::fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (after {} attempts)", self.source, self.attempts)
+    }
+}
+
+impl std::error::Error for RetriesExhausted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Runs `op` until it succeeds or fails with an error the `policy` does not retry
+fn retry<R>(
+    policy: Option<&RetryPolicy>,
+    mut op: impl FnMut() -> std::io::Result<R>,
+) -> std::io::Result<R> {
+    let mut attempts = 1;
+    loop {
+        let e = match op() {
+            Ok(r) => return Ok(r),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => e,
+        };
+        let Some(policy) = policy.filter(|policy| policy.kinds.contains(&e.kind())) else {
+            return Err(e);
+        };
+        if attempts >= policy.max_attempts {
+            if attempts == 1 {
+                return Err(e);
+            }
+            return Err(std::io::Error::new(
+                e.kind(),
+                RetriesExhausted {
+                    attempts,
+                    source: e,
+                },
+            ));
+        }
+        let factor = 1u32.checked_shl(attempts - 1).unwrap_or(u32::MAX);
+        (policy.sleep)(policy.backoff.saturating_mul(factor));
+        attempts += 1;
+    }
+}
+
+/// Wraps the inner stream to retry each of its reads, writes and flushes according to the policy
+///
+/// A failed call transferred no bytes, so calling again is idempotent.
+pub(crate) struct Retrying<'a, T> {
+    pub(crate) inner: &'a mut T,
+    pub(crate) policy: Option<&'a RetryPolicy>,
+}
+
+impl<T: Read> Read for Retrying<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        retry(self.policy, || self.inner.read(buf))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [std::io::IoSliceMut<'_>]) -> std::io::Result<usize> {
+        retry(self.policy, || self.inner.read_vectored(bufs))
+    }
+
+    #[cfg(feature = "nightly")]
+    fn read_buf(&mut self, mut cursor: std::io::BorrowedCursor<'_>) -> std::io::Result<()> {
+        retry(self.policy, || self.inner.read_buf(cursor.reborrow()))
+    }
+}
+
+impl<T: Write> Write for Retrying<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        retry(self.policy, || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        retry(self.policy, || self.inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetriesExhausted, RetryPolicy};
+    use crate::BufReaderWriter;
+    use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    /// Stream failing with a transient error on the first `failures` calls out of every `period`
+    struct ChaosStream {
+        inner: Cursor<Vec<u8>>,
+        calls: u64,
+        failures: u64,
+        period: u64,
+        kind: ErrorKind,
+    }
+
+    impl ChaosStream {
+        fn new(data: Vec<u8>, failures: u64, kind: ErrorKind) -> Self {
+            Self {
+                inner: Cursor::new(data),
+                calls: 0,
+                failures,
+                period: failures + 1,
+                kind,
+            }
+        }
+
+        fn chaos(&mut self) -> std::io::Result<()> {
+            self.calls += 1;
+            if (self.calls - 1) % self.period < self.failures {
+                return Err(std::io::Error::new(self.kind, "chaos"));
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for ChaosStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.chaos()?;
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for ChaosStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.chaos()?;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for ChaosStream {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    static SLEPT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+    fn mock_sleep(duration: Duration) {
+        SLEPT_NANOS.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        kinds: &[ErrorKind::TimedOut],
+        max_attempts: 3,
+        backoff: Duration::from_millis(1),
+        sleep: mock_sleep,
+    };
+
+    #[test]
+    fn test_retry_transient_failures() {
+        let data = (0..200u8).collect::<Vec<_>>();
+        let inner = ChaosStream::new(data.clone(), 2, ErrorKind::TimedOut);
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        buf.set_retry_policy(Some(POLICY));
+        SLEPT_NANOS.store(0, Ordering::Relaxed);
+
+        // Buffered and direct reads
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [0, 1, 2, 3]);
+        let mut large = [0u8; 32];
+        buf.seek(SeekFrom::Start(100)).unwrap();
+        buf.read_exact(&mut large).unwrap();
+        assert_eq!(large[..], data[100..132]);
+
+        // Buffered and direct writes
+        buf.write_all(b"abcd").unwrap();
+        buf.write_all(&[0xAA; 32]).unwrap();
+        buf.flush().unwrap();
+
+        let calls = buf.inner().calls;
+        assert_eq!(calls % 3, 0);
+        // Each call sleeps 1ms, then 2ms
+        assert_eq!(SLEPT_NANOS.load(Ordering::Relaxed), calls / 3 * 3_000_000);
+
+        let data = buf.into_inner().unwrap().inner.into_inner();
+        assert_eq!(&data[132..136], b"abcd");
+        assert_eq!(data[136..168], [0xAA; 32]);
+    }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let inner = ChaosStream::new(vec![0u8; 100], 3, ErrorKind::TimedOut);
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        buf.set_retry_policy(Some(POLICY));
+
+        let mut bytes = [0u8; 4];
+        let err = buf.read_exact(&mut bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        let exhausted = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<RetriesExhausted>())
+            .unwrap();
+        assert_eq!(exhausted.attempts, 3);
+
+        // The next call succeeds and the stream is consistent
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(buf.position(), 4);
+
+        // Kinds out of the policy are not retried
+        let inner = ChaosStream::new(vec![0u8; 100], 1, ErrorKind::Other);
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        buf.set_retry_policy(Some(POLICY));
+        let err = buf.read_exact(&mut bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(
+            err.get_ref()
+                .unwrap()
+                .downcast_ref::<RetriesExhausted>()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_interrupted_is_always_retried() {
+        let inner = ChaosStream::new(b"Hello".to_vec(), 1, ErrorKind::Interrupted);
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+        let mut s = String::new();
+        buf.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Hello");
+        buf.write_all(b" World").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.inner().inner.get_ref(), b"Hello World");
+    }
+
+    #[test]
+    fn test_interrupted_seeks_and_flushes_are_retried() {
+        /// Stream interrupting every other call, seeks and flushes included
+        struct Interrupting {
+            inner: Cursor<Vec<u8>>,
+            calls: u64,
+            flushes: u64,
+        }
+
+        impl Interrupting {
+            fn interrupt(&mut self) -> std::io::Result<()> {
+                self.calls += 1;
+                if self.calls % 2 == 1 {
+                    return Err(ErrorKind::Interrupted.into());
+                }
+                Ok(())
+            }
+        }
+
+        impl Read for Interrupting {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.interrupt()?;
+                self.inner.read(buf)
+            }
+        }
+
+        impl Write for Interrupting {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.interrupt()?;
+                self.inner.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.interrupt()?;
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        impl Seek for Interrupting {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.interrupt()?;
+                self.inner.seek(pos)
+            }
+        }
+
+        let data = (0..200u8).collect::<Vec<_>>();
+        let inner = Interrupting {
+            inner: Cursor::new(data.clone()),
+            calls: 0,
+            flushes: 0,
+        };
+        let mut buf = BufReaderWriter::with_capacity(inner, 16);
+
+        let mut bytes = [0u8; 4];
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, [0, 1, 2, 3]);
+        buf.write_all(b"ab").unwrap();
+        // Dumping seeks back to the start of the buffer
+        buf.seek(SeekFrom::Start(100)).unwrap();
+        let mut large = [0u8; 32];
+        buf.read_exact(&mut large).unwrap();
+        assert_eq!(large[..], data[100..132]);
+        buf.write_all(b"cd").unwrap();
+        buf.write_all(&[0xAA; 32]).unwrap();
+        buf.seek(SeekFrom::Current(-34)).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"cd\xAA\xAA");
+        buf.flush().unwrap();
+        assert_eq!(buf.inner().flushes, 1);
+        assert_eq!(buf.position(), 136);
+
+        let data = buf.into_inner().unwrap().inner.into_inner();
+        assert_eq!(&data[..6], [0, 1, 2, 3, b'a', b'b']);
+        assert_eq!(&data[132..134], b"cd");
+        assert_eq!(data[134..166], [0xAA; 32]);
+    }
+}
@@ -0,0 +1,335 @@
+//! Read-only handles sharing a cache of blocks
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct SharedState<T> {
+    inner: T,
+    blocks: HashMap<u64, Arc<[u8]>>,
+    // Indices of the blocks, from the oldest to the newest, for eviction
+    order: VecDeque<u64>,
+    // Length of the inner stream, once queried
+    len: Option<u64>,
+}
+
+struct Shared<T> {
+    state: Mutex<SharedState<T>>,
+    block_size: usize,
+    max_blocks: usize,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, SharedState<T>> {
+        // A panic while holding the lock cannot leave the cache inconsistent,
+        // as blocks are only inserted once fully read
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Read-only handle to a stream, whose cached blocks are shared with its clones
+///
+/// Cloning is cheap: the clones share the inner stream and the cache,
+/// but each one has its own position.
+/// A block read by one handle is served from memory to all the others,
+/// which makes it suited to several cursors analysing the same regions of a stream,
+/// possibly from different threads.
+///
+/// The stream is cut in blocks of `block_size` bytes, aligned on its start.
+/// At most `max_blocks` blocks are cached, the oldest ones being evicted first.
+/// Each handle keeps a reference to the block it reads from,
+/// so an evicted block stays alive while a handle uses it.
+///
+/// Since the cache is shared, writing is not supported,
+/// the inner stream must not be modified while handles exist.
+///
+/// # Example
+///
+/// ```
+/// use bufrw::SharedReader;
+/// use std::io::{Cursor, Read, Seek, SeekFrom};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let mut first = SharedReader::new(Cursor::new(b"Hello World".to_vec()));
+/// let mut second = first.clone();
+/// second.seek(SeekFrom::Start(6))?;
+///
+/// let mut s = String::new();
+/// second.read_to_string(&mut s)?;
+/// assert_eq!(s, "World");
+///
+/// let mut hello = [0u8; 5];
+/// first.read_exact(&mut hello)?;
+/// assert_eq!(&hello, b"Hello");
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedReader<T> {
+    shared: Arc<Shared<T>>,
+    pos: u64,
+    // The block last read from, and its index
+    current: Option<(u64, Arc<[u8]>)>,
+}
+
+impl<T> Clone for SharedReader<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            pos: self.pos,
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<T> SharedReader<T>
+where
+    T: Read + Seek,
+{
+    /// Creates a new handle, with blocks of the [default_capacity](crate::default_capacity)
+    /// and at most 16 cached blocks
+    pub fn new(inner: T) -> Self {
+        Self::with_capacity(inner, crate::default_capacity(), 16)
+    }
+
+    /// Creates a new handle, with the given block size and maximum number of cached blocks
+    ///
+    /// If `block_size` or `max_blocks` is 0, nothing is cached: each read seeks
+    /// the inner stream to the position of the handle and reads it directly.
+    pub fn with_capacity(inner: T, block_size: usize, max_blocks: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(SharedState {
+                    inner,
+                    blocks: HashMap::new(),
+                    order: VecDeque::new(),
+                    len: None,
+                }),
+                block_size,
+                max_blocks,
+            }),
+            pos: 0,
+            current: None,
+        }
+    }
+
+    /// Returns the position of this handle
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Returns the size of the blocks
+    pub fn block_size(&self) -> usize {
+        self.shared.block_size
+    }
+
+    /// Returns the number of blocks currently cached
+    pub fn cached_blocks(&self) -> usize {
+        self.shared.lock().blocks.len()
+    }
+
+    /// Returns the block of the given index, reading it if it is not cached
+    fn block(&mut self, index: u64) -> std::io::Result<Arc<[u8]>> {
+        if let Some((i, block)) = &self.current
+            && *i == index
+        {
+            return Ok(Arc::clone(block));
+        }
+
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        let block = match state.blocks.get(&index) {
+            Some(block) => Arc::clone(block),
+            None => {
+                let start = index * shared.block_size as u64;
+                state.inner.seek(SeekFrom::Start(start))?;
+                let mut data = Vec::with_capacity(shared.block_size);
+                (&mut state.inner)
+                    .take(shared.block_size as u64)
+                    .read_to_end(&mut data)?;
+                let block: Arc<[u8]> = data.into();
+
+                if state.order.len() == shared.max_blocks
+                    && let Some(oldest) = state.order.pop_front()
+                {
+                    state.blocks.remove(&oldest);
+                }
+                state.blocks.insert(index, Arc::clone(&block));
+                state.order.push_back(index);
+                block
+            }
+        };
+        self.current = Some((index, Arc::clone(&block)));
+        Ok(block)
+    }
+}
+
+impl<T> Read for SharedReader<T>
+where
+    T: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.shared.block_size == 0 || self.shared.max_blocks == 0 {
+            let mut state = self.shared.lock();
+            state.inner.seek(SeekFrom::Start(self.pos))?;
+            let n = state.inner.read(buf)?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        let block_size = self.shared.block_size as u64;
+        let block = self.block(self.pos / block_size)?;
+        let offset = (self.pos % block_size) as usize;
+        if offset >= block.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(block.len() - offset);
+        buf[..n].copy_from_slice(&block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T> Seek for SharedReader<T>
+where
+    T: Read + Seek,
+{
+    /// Seeks to an offset, in bytes
+    ///
+    /// Only seeking from the end queries the inner stream, once for all the handles.
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => {
+                let mut state = self.shared.lock();
+                let len = match state.len {
+                    Some(len) => len,
+                    None => {
+                        let len = state.inner.seek(SeekFrom::End(0))?;
+                        state.len = Some(len);
+                        len
+                    }
+                };
+                (len, offset)
+            }
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedReader;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the reads, even once moved into the shared state
+    struct CountingReader {
+        inner: Cursor<Vec<u8>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for CountingReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn counting_reader(len: usize) -> (CountingReader, Arc<AtomicUsize>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let data = (0..len).map(|i| (i % 256) as u8).collect();
+        let reader = CountingReader {
+            inner: Cursor::new(data),
+            reads: Arc::clone(&reads),
+        };
+        (reader, reads)
+    }
+
+    #[test]
+    fn test_shared_reader_clones_share_fills() {
+        let (reader, reads) = counting_reader(1000);
+        let mut first = SharedReader::with_capacity(reader, 256, 4);
+        let mut second = first.clone();
+
+        let mut a = [0u8; 100];
+        first.seek(SeekFrom::Start(50)).unwrap();
+        first.read_exact(&mut a).unwrap();
+        let reads_after_first = reads.load(Ordering::Relaxed);
+
+        let mut b = [0u8; 100];
+        second.seek(SeekFrom::Start(100)).unwrap();
+        second.read_exact(&mut b).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), reads_after_first);
+        assert_eq!(&a[50..], &b[..50]);
+        assert_eq!(first.cached_blocks(), 1);
+        assert_eq!(first.position(), 150);
+        assert_eq!(second.position(), 200);
+    }
+
+    #[test]
+    fn test_shared_reader_reads_whole_stream() {
+        let (reader, _) = counting_reader(1000);
+        let mut first = SharedReader::with_capacity(reader, 64, 2);
+        let mut second = first.clone();
+
+        let mut content = Vec::new();
+        first.read_to_end(&mut content).unwrap();
+        assert_eq!(
+            content,
+            (0..1000).map(|i| (i % 256) as u8).collect::<Vec<_>>()
+        );
+        assert_eq!(first.cached_blocks(), 2);
+
+        assert_eq!(second.seek(SeekFrom::End(-10)).unwrap(), 990);
+        let mut tail = Vec::new();
+        second.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &content[990..]);
+
+        let err = second.seek(SeekFrom::Current(-1001)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(second.position(), 1000);
+    }
+
+    #[test]
+    fn test_shared_reader_without_cache() {
+        for (block_size, max_blocks) in [(0, 4), (64, 0)] {
+            let (reader, reads) = counting_reader(100);
+            let mut first = SharedReader::with_capacity(reader, block_size, max_blocks);
+            let mut second = first.clone();
+
+            let mut a = [0u8; 10];
+            first.seek(SeekFrom::Start(50)).unwrap();
+            first.read_exact(&mut a).unwrap();
+            let mut b = [0u8; 10];
+            second.read_exact(&mut b).unwrap();
+            assert_eq!(a[0], 50);
+            assert_eq!(b[0], 0);
+            assert_eq!(first.cached_blocks(), 0);
+            assert_eq!(reads.load(Ordering::Relaxed), 2);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Seekable editing of non-seekable inputs, spooled to memory or to a temporary file
+use crate::BufReaderWriter;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use tempfile::SpooledTempFile;
+
+impl BufReaderWriter<SpooledTempFile> {
+    /// Reads all of `reader` (e.g. stdin) into a seekable backing, to edit it like a file
+    ///
+    /// The data is kept in memory until it grows past `threshold` bytes, either while
+    /// reading `reader` or because of later writes. It is then moved to an anonymous
+    /// temporary file, which is deleted when dropped. The move is transparent,
+    /// the adapter keeps its position and cached data.
+    ///
+    /// The position is at the start of the data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Read, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// let input: &[u8] = b"Hello World";
+    /// let mut rw = BufReaderWriter::spooled(input, 1024)?;
+    /// rw.seek(SeekFrom::Start(6))?;
+    /// rw.write_all(b"Spool")?;
+    /// assert!(!rw.is_spilled());
+    ///
+    /// let mut s = String::new();
+    /// rw.into_backing()?.read_to_string(&mut s)?;
+    /// assert_eq!(s, "Hello Spool");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spooled(mut reader: impl Read, threshold: usize) -> std::io::Result<Self> {
+        let mut spool = SpooledTempFile::new(threshold);
+        std::io::copy(&mut reader, &mut spool)?;
+        spool.seek(SeekFrom::Start(0))?;
+        Ok(Self::new(spool))
+    }
+
+    /// Returns whether the data was moved from memory to a temporary file
+    pub fn is_spilled(&self) -> bool {
+        self.inner.is_rolled()
+    }
+
+    /// Writes the buffered data, then copies all the data to a new file at `path`
+    ///
+    /// The file is created, or truncated if it exists.
+    pub fn persist_to(self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut spool = self.into_backing()?;
+        let mut file = File::create(path)?;
+        std::io::copy(&mut spool, &mut file)?;
+        file.sync_all()
+    }
+
+    /// Writes the buffered data, then returns the backing, positioned at its start
+    pub fn into_backing(self) -> std::io::Result<SpooledTempFile> {
+        let mut spool = self.into_inner()?;
+        spool.seek(SeekFrom::Start(0))?;
+        Ok(spool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_spooled_input_larger_than_threshold() {
+        let input = (0..10_000u32).map(|i| (i % 256) as u8).collect::<Vec<_>>();
+        let mut rw = BufReaderWriter::spooled(input.as_slice(), 4096).unwrap();
+        assert!(rw.is_spilled());
+
+        rw.seek(SeekFrom::Start(5000)).unwrap();
+        rw.write_all(b"patched").unwrap();
+        rw.seek(SeekFrom::End(0)).unwrap();
+        rw.write_all(b"appended").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.bin");
+        rw.persist_to(&path).unwrap();
+
+        let mut expected = input;
+        expected[5000..5007].copy_from_slice(b"patched");
+        expected.extend_from_slice(b"appended");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_spooled_spills_while_editing() {
+        let input = vec![b'a'; 100];
+        let mut rw = BufReaderWriter::with_capacity(
+            BufReaderWriter::spooled(input.as_slice(), 150)
+                .unwrap()
+                .into_inner()
+                .unwrap(),
+            16,
+        );
+        let mut bytes = [0u8; 10];
+        rw.seek(SeekFrom::Start(90)).unwrap();
+        rw.read_exact(&mut bytes[..4]).unwrap();
+        assert!(!rw.is_spilled());
+
+        // The cached bytes stay valid once the backing is moved to a file
+        rw.seek(SeekFrom::Start(100)).unwrap();
+        rw.write_all(&[b'b'; 100]).unwrap();
+        assert!(rw.is_spilled());
+        rw.seek(SeekFrom::Start(95)).unwrap();
+        rw.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"aaaaabbbbb");
+
+        let mut content = Vec::new();
+        rw.into_backing().unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 200);
+        assert_eq!(content[..100], [b'a'; 100]);
+        assert_eq!(content[100..], [b'b'; 100]);
+    }
+}
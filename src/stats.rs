@@ -0,0 +1,115 @@
+//! Statistics of the operations done on the inner stream
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Snapshot of the statistics of a [BufReaderWriter](crate::BufReaderWriter)
+///
+/// All the counters are about the operations done on the inner stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    /// Number of times the buffer was filled from the inner stream
+    pub fills: u64,
+    /// Number of times the buffer was written to the inner stream
+    pub dumps: u64,
+    /// Number of reads that bypassed the buffer
+    pub direct_reads: u64,
+    /// Number of writes that bypassed the buffer
+    pub direct_writes: u64,
+    /// Number of seeks
+    pub seeks: u64,
+    /// Number of bytes read
+    pub bytes_read: u64,
+    /// Number of bytes written
+    pub bytes_written: u64,
+}
+
+/// The counters, shared with the [StatsHandle]s
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    fills: AtomicU64,
+    dumps: AtomicU64,
+    direct_reads: AtomicU64,
+    direct_writes: AtomicU64,
+    seeks: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn from_stats(stats: Stats) -> Self {
+        Self {
+            fills: AtomicU64::new(stats.fills),
+            dumps: AtomicU64::new(stats.dumps),
+            direct_reads: AtomicU64::new(stats.direct_reads),
+            direct_writes: AtomicU64::new(stats.direct_writes),
+            seeks: AtomicU64::new(stats.seeks),
+            bytes_read: AtomicU64::new(stats.bytes_read),
+            bytes_written: AtomicU64::new(stats.bytes_written),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        Stats {
+            fills: self.fills.load(Ordering::Relaxed),
+            dumps: self.dumps.load(Ordering::Relaxed),
+            direct_reads: self.direct_reads.load(Ordering::Relaxed),
+            direct_writes: self.direct_writes.load(Ordering::Relaxed),
+            seeks: self.seeks.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn fill(&self, n: usize) {
+        self.fills.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn dump(&self, n: usize) {
+        self.dumps.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn direct_read(&self, n: usize) {
+        self.direct_reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn direct_write(&self, n: usize) {
+        self.direct_writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn seek(&self) {
+        self.seeks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Handle to the statistics of a [BufReaderWriter](crate::BufReaderWriter),
+/// created by [BufReaderWriter::stats_handle](crate::BufReaderWriter::stats_handle)
+///
+/// It is cheap to clone and can be sent to other threads,
+/// to take snapshots while the adapter is in use.
+///
+/// Each counter is updated atomically, but a snapshot is not a consistent cut:
+/// taken during an operation, it may see some counters updated and not others.
+#[derive(Debug, Clone)]
+pub struct StatsHandle(pub(crate) Arc<Counters>);
+
+impl StatsHandle {
+    /// Returns the current value of the counters
+    pub fn snapshot(&self) -> Stats {
+        self.0.snapshot()
+    }
+}
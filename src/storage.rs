@@ -0,0 +1,285 @@
+//! The allocation of the internal buffer, initialized lazily
+use std::collections::TryReserveError;
+use std::mem::MaybeUninit;
+use std::ops::{Bound, Index, IndexMut, RangeBounds};
+use std::slice::SliceIndex;
+
+/// A fixed-size allocation whose bytes are zeroed when first accessed mutably,
+/// so that creating a large buffer does not cost a memset
+///
+/// Indexing reads only the initialized prefix, and panics past it,
+/// indexing mutably initializes the bytes up to the end of the range first.
+///
+/// The bytes are `data[start..start + len]`, `start` being non-zero only
+/// when the allocation was made larger to align them.
+/// When `N` is non-zero, the bytes are the `inline` array instead, and `data` is empty.
+pub(crate) struct Storage<const N: usize = 0> {
+    data: Box<[MaybeUninit<u8>]>,
+    inline: [MaybeUninit<u8>; N],
+    start: usize,
+    len: usize,
+    align: usize,
+    // The first `init` bytes are initialized
+    init: usize,
+}
+
+impl Storage {
+    pub(crate) fn new_uninit(capacity: usize) -> Self {
+        Self::allocate(capacity, 1)
+    }
+
+    /// Allocates `capacity` bytes starting at an address multiple of `align`,
+    /// which must be a power of two
+    pub(crate) fn new_aligned(capacity: usize, align: usize) -> Self {
+        debug_assert!(align.is_power_of_two());
+        Self::allocate(capacity, align)
+    }
+
+    pub(crate) fn try_new_uninit(capacity: usize) -> Result<Self, TryReserveError> {
+        let mut data = Vec::<MaybeUninit<u8>>::new();
+        data.try_reserve_exact(capacity)?;
+        // SAFETY: the capacity was reserved, and uninitialized `MaybeUninit`s are valid
+        unsafe { data.set_len(capacity) };
+        Ok(Self {
+            data: data.into_boxed_slice(),
+            inline: [],
+            start: 0,
+            len: capacity,
+            align: 1,
+            init: 0,
+        })
+    }
+}
+
+impl<const N: usize> Storage<N> {
+    /// Creates the storage of `N` bytes that are not heap-allocated
+    pub(crate) fn new_inline() -> Self {
+        Self {
+            data: Box::new([]),
+            inline: [MaybeUninit::uninit(); N],
+            start: 0,
+            len: N,
+            align: 1,
+            init: 0,
+        }
+    }
+
+    /// Allocates the bytes on the heap, `N` must be 0
+    pub(crate) fn allocate(capacity: usize, align: usize) -> Self {
+        debug_assert!(N == 0);
+        let data = Box::new_uninit_slice(capacity + align - 1);
+        let start = data.as_ptr().align_offset(align);
+        Self {
+            data,
+            inline: [MaybeUninit::uninit(); N],
+            start,
+            len: capacity,
+            align,
+            init: 0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The bytes, initialized or not
+    #[inline]
+    fn raw(&self) -> &[MaybeUninit<u8>] {
+        if N == 0 {
+            self.data.get(self.start..).unwrap_or_default()
+        } else {
+            &self.inline
+        }
+    }
+
+    #[inline]
+    fn raw_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        if N == 0 {
+            self.data.get_mut(self.start..).unwrap_or_default()
+        } else {
+            &mut self.inline
+        }
+    }
+
+    /// Zeroes the bytes up to `end` that are not initialized yet
+    #[inline]
+    fn ensure_init(&mut self, end: usize) {
+        if end > self.init {
+            let init = self.init;
+            if let Some(bytes) = self.raw_mut().get_mut(init..end) {
+                bytes.fill(MaybeUninit::new(0));
+                self.init = end;
+            }
+        }
+    }
+
+    #[inline]
+    fn initialized(&self) -> &[u8] {
+        // SAFETY: `data[..init]` is initialized, and `MaybeUninit<u8>` has the layout of `u8`
+        unsafe { std::slice::from_raw_parts(self.raw().as_ptr().cast(), self.init) }
+    }
+
+    #[inline]
+    fn initialized_mut(&mut self) -> &mut [u8] {
+        // SAFETY: same as in `initialized`
+        unsafe { std::slice::from_raw_parts_mut(self.raw_mut().as_mut_ptr().cast(), self.init) }
+    }
+
+    /// Returns the bytes in `range`, or `None` if they are not all initialized
+    #[inline]
+    pub(crate) fn get(&self, range: std::ops::Range<usize>) -> Option<&[u8]> {
+        self.initialized().get(range)
+    }
+
+    /// Returns the bytes in `range`, initializing them first,
+    /// or `None` if it is out of bounds
+    #[inline]
+    pub(crate) fn get_mut(&mut self, range: std::ops::Range<usize>) -> Option<&mut [u8]> {
+        if range.end > self.len() {
+            return None;
+        }
+        self.ensure_init(range.end);
+        self.initialized_mut().get_mut(range)
+    }
+
+    /// Copies the bytes in `src`, which must be initialized, to `dest`
+    pub(crate) fn copy_within(&mut self, src: std::ops::Range<usize>, dest: usize) {
+        self.ensure_init(dest + src.len());
+        self.initialized_mut().copy_within(src, dest);
+    }
+
+    /// Returns the address of the first byte
+    #[cfg(test)]
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.raw().as_ptr().cast()
+    }
+
+    /// Gives back the allocation, with its bytes initialized
+    ///
+    /// An aligned allocation is copied to one without the padding,
+    /// and inline bytes are copied to a new allocation.
+    pub(crate) fn into_boxed_slice(mut self) -> Box<[u8]> {
+        self.ensure_init(self.len());
+        if self.len != self.data.len() {
+            return Box::from(&self[..]);
+        }
+        let raw = Box::into_raw(self.data);
+        // SAFETY: all the bytes are initialized, and `MaybeUninit<u8>` has the layout of `u8`
+        unsafe { Box::from_raw(raw as *mut [u8]) }
+    }
+}
+
+impl From<Box<[u8]>> for Storage {
+    fn from(data: Box<[u8]>) -> Self {
+        let len = data.len();
+        let raw = Box::into_raw(data);
+        Self {
+            // SAFETY: `MaybeUninit<u8>` has the layout of `u8`
+            data: unsafe { Box::from_raw(raw as *mut [MaybeUninit<u8>]) },
+            inline: [],
+            start: 0,
+            len,
+            align: 1,
+            init: len,
+        }
+    }
+}
+
+impl<const N: usize> Clone for Storage<N> {
+    fn clone(&self) -> Self {
+        let mut clone = if N != 0 {
+            Self::new_inline()
+        } else {
+            Self::allocate(self.len(), self.align)
+        };
+        clone[..self.init].copy_from_slice(self.initialized());
+        clone
+    }
+}
+
+impl<const N: usize, R> Index<R> for Storage<N>
+where
+    R: RangeBounds<usize> + SliceIndex<[u8], Output = [u8]>,
+{
+    type Output = [u8];
+
+    #[inline]
+    fn index(&self, range: R) -> &[u8] {
+        &self.initialized()[range]
+    }
+}
+
+impl<const N: usize, R> IndexMut<R> for Storage<N>
+where
+    R: RangeBounds<usize> + SliceIndex<[u8], Output = [u8]>,
+{
+    #[inline]
+    fn index_mut(&mut self, range: R) -> &mut [u8] {
+        let end = match range.end_bound() {
+            Bound::Included(end) => end.saturating_add(1),
+            Bound::Excluded(end) => *end,
+            Bound::Unbounded => self.len(),
+        };
+        // Out of bounds ranges panic when indexing
+        self.ensure_init(end.min(self.len()));
+        &mut self.initialized_mut()[range]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Storage;
+
+    #[test]
+    fn test_storage_initializes_lazily() {
+        let mut storage = Storage::new_uninit(16);
+        assert_eq!(storage.len(), 16);
+        assert!(storage[..0].is_empty());
+
+        storage[2..4].copy_from_slice(b"ab");
+        assert_eq!(&storage[..4], b"\0\0ab");
+        storage.copy_within(2..4, 8);
+        assert_eq!(&storage[6..], b"\0\0ab");
+
+        let clone = storage.clone();
+        assert_eq!(&clone[..10], &storage[..10]);
+        let data = storage.into_boxed_slice();
+        assert_eq!(&data[..], b"\0\0ab\0\0\0\0ab\0\0\0\0\0\0");
+
+        let storage = Storage::from(data);
+        assert_eq!(&storage[14..], b"\0\0");
+        assert_eq!(Storage::try_new_uninit(4).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_aligned_storage() {
+        let mut storage = Storage::new_aligned(64, 32);
+        assert_eq!(storage.len(), 64);
+        assert_eq!(storage.as_ptr() as usize % 32, 0);
+        storage[60..].copy_from_slice(b"tail");
+        assert_eq!(storage.clone().as_ptr() as usize % 32, 0);
+
+        let data = storage.into_boxed_slice();
+        assert_eq!(data.len(), 64);
+        assert_eq!(&data[58..], b"\0\0tail");
+    }
+
+    #[test]
+    fn test_inline_storage() {
+        let mut storage = Storage::<8>::new_inline();
+        assert_eq!(storage.len(), 8);
+        storage[4..6].copy_from_slice(b"ab");
+        assert_eq!(&storage.clone()[..6], b"\0\0\0\0ab");
+        assert_eq!(&storage.into_boxed_slice()[..], b"\0\0\0\0ab\0\0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_storage_does_not_read_uninitialized_bytes() {
+        let mut storage = Storage::new_uninit(16);
+        storage[..4].fill(1);
+        let _ = &storage[..5];
+    }
+}
@@ -0,0 +1,383 @@
+//! Undo log of the bytes overwritten in the inner stream
+use crate::BufReaderWriter;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// First bytes of an undo log, the last one is the version of the format
+const MAGIC: &[u8; 8] = b"BRWUNDO\x01";
+
+/// The backup writer, and the ranges of the original stream already saved in it
+pub(crate) struct UndoLog<T> {
+    backup: Box<dyn Write + Send>,
+    // Reads the original bytes at an offset, captured where `T: Read` is known
+    read_at: fn(&mut T, u64, &mut [u8]) -> std::io::Result<()>,
+    original_len: u64,
+    // Sorted, non-overlapping and non-adjacent
+    saved: Vec<(u64, u64)>,
+}
+
+fn read_at<T: Read + Seek>(inner: &mut T, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    inner.seek(SeekFrom::Start(offset))?;
+    inner.read_exact(buf)
+}
+
+impl<T> UndoLog<T> {
+    /// Returns the parts of `start..end` of the original stream that were not saved yet
+    fn unsaved(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let end = end.min(self.original_len);
+        let mut ranges = Vec::new();
+        let mut cursor = start;
+        for &(s, e) in &self.saved {
+            if cursor >= end {
+                break;
+            }
+            if e <= cursor {
+                continue;
+            }
+            if s > cursor {
+                ranges.push((cursor, s.min(end)));
+            }
+            cursor = cursor.max(e);
+        }
+        if cursor < end {
+            ranges.push((cursor, end));
+        }
+        ranges
+    }
+
+    fn mark_saved(&mut self, start: u64, end: u64) {
+        let i = self.saved.partition_point(|&(_, e)| e < start);
+        let mut j = i;
+        let (mut s, mut e) = (start, end);
+        while j < self.saved.len() && self.saved[j].0 <= end {
+            s = s.min(self.saved[j].0);
+            e = e.max(self.saved[j].1);
+            j += 1;
+        }
+        self.saved.splice(i..j, std::iter::once((s, e)));
+    }
+
+    /// Appends a record of the original `bytes` at `offset`
+    fn append(&mut self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(16 + bytes.len());
+        record.extend_from_slice(&offset.to_le_bytes());
+        record.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        record.extend_from_slice(bytes);
+        self.backup.write_all(&record)?;
+        self.backup.flush()?;
+        self.mark_saved(offset, offset + bytes.len() as u64);
+        Ok(())
+    }
+
+    /// Saves the parts of `original`, the original bytes at `offset`, that were not saved yet
+    pub(crate) fn save(&mut self, offset: u64, original: &[u8]) -> std::io::Result<()> {
+        for (s, e) in self.unsaved(offset, offset + original.len() as u64) {
+            let bytes = &original[(s - offset) as usize..(e - offset) as usize];
+            self.append(s, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    /// Saves the original bytes of `offset..offset + len` to the undo log, if enabled,
+    /// before they are overwritten
+    pub(crate) fn save_original(&mut self, offset: u64, len: usize) -> std::io::Result<()> {
+        // The unsaved bytes in the cache were not modified, so they are the original ones
+        let cache_start = self.start_position_in_source();
+        let cache_end = cache_start + self.buffer.num_valid_bytes() as u64;
+
+        let Some(undo) = self.undo.as_mut() else {
+            return Ok(());
+        };
+        let ranges = undo.unsaved(offset, offset.saturating_add(len as u64));
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        for (s, e) in ranges {
+            bytes.clear();
+            bytes.resize((e - s) as usize, 0);
+            let mut p = s;
+            while p < e {
+                let o = (p - s) as usize;
+                if cache_start <= p && p < cache_end {
+                    let k = (e.min(cache_end) - p) as usize;
+                    let c = (p - cache_start) as usize;
+                    bytes[o..o + k].copy_from_slice(&self.buffer.data[c..c + k]);
+                    p += k as u64;
+                } else {
+                    let next = if p < cache_start {
+                        cache_start.min(e)
+                    } else {
+                        e
+                    };
+                    let k = (next - p) as usize;
+                    self.stats.seek();
+                    self.inner_pos_lost = true;
+                    (undo.read_at)(&mut self.inner, p, &mut bytes[o..o + k])?;
+                    self.stats.direct_read(k);
+                    p = next;
+                }
+            }
+            undo.append(s, &bytes)?;
+        }
+        self.recover_inner_position()
+    }
+
+    /// Stops saving overwritten bytes, flushing and dropping the backup writer
+    pub fn disable_undo_log(&mut self) -> std::io::Result<()> {
+        match self.undo.take() {
+            Some(mut undo) => undo.backup.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether an undo log is enabled
+    pub fn is_undo_log_enabled(&self) -> bool {
+        self.undo.is_some()
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Read + Write + Seek,
+{
+    /// Starts saving, to `backup`, the original bytes of each region of the stream
+    /// before it is first overwritten, so that the modifications can be reverted with [restore]
+    ///
+    /// The stream as it is when this is called (after writing the dirty buffered data)
+    /// is the one that [restore] brings back.
+    /// Each region is saved only once, bytes written past the original end are not saved.
+    ///
+    /// The `backup` is flushed after each record, before the region is overwritten.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bufrw::BufReaderWriter;
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// # let dir = std::env::temp_dir();
+    /// # let backup_path = dir.join(format!("bufrw-undo-doctest-{}", std::process::id()));
+    /// let mut rw = BufReaderWriter::from_vec(b"Hello World".to_vec());
+    /// rw.enable_undo_log(std::fs::File::create(&backup_path)?)?;
+    /// rw.seek(SeekFrom::Start(6))?;
+    /// rw.write_all(b"Rust!")?;
+    ///
+    /// let mut data = Cursor::new(rw.into_vec()?);
+    /// assert_eq!(data.get_ref(), b"Hello Rust!");
+    ///
+    /// bufrw::restore(&mut data, std::fs::File::open(&backup_path)?)?;
+    /// assert_eq!(data.get_ref(), b"Hello World");
+    /// # std::fs::remove_file(&backup_path)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn enable_undo_log(
+        &mut self,
+        mut backup: impl Write + Send + 'static,
+    ) -> std::io::Result<()> {
+        self.flush_writes()?;
+        let original_len = self.stream_len()?;
+
+        let mut header = [0u8; 16];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..].copy_from_slice(&original_len.to_le_bytes());
+        backup.write_all(&header)?;
+        backup.flush()?;
+
+        self.undo = Some(UndoLog {
+            backup: Box::new(backup),
+            read_at: read_at::<T>,
+            original_len,
+            saved: Vec::new(),
+        });
+        Ok(())
+    }
+}
+
+/// Reverts the modifications saved in an undo log, see [BufReaderWriter::enable_undo_log]
+///
+/// Returns the original length of the stream. The bytes written past it cannot be removed
+/// through [Write] and [Seek], truncate the stream to that length when possible
+/// (e.g. [std::fs::File::set_len]).
+///
+/// The undo log has the following format, all integers are little-endian:
+///
+/// * the 8 bytes `BRWUNDO\x01`, the last one being the version of the format
+/// * the original length of the stream, as a `u64`
+/// * any number of records, each being the offset as a `u64`, the length as a `u64`,
+///   then the original bytes
+///
+/// A truncated last record, left by a crash while it was appended,
+/// is ignored: the region it describes was not overwritten yet.
+///
+/// Returns an error of kind [std::io::ErrorKind::InvalidData] if `backup` is not an undo log.
+pub fn restore<F, B>(file: &mut F, mut backup: B) -> std::io::Result<u64>
+where
+    F: Write + Seek,
+    B: Read,
+{
+    let mut header = [0u8; 16];
+    backup.read_exact(&mut header).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated undo log header")
+        } else {
+            e
+        }
+    })?;
+    if &header[..8] != MAGIC {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not an undo log",
+        ));
+    }
+    let original_len = u64::from_le_bytes(header[8..].try_into().unwrap());
+
+    let mut bytes = Vec::new();
+    loop {
+        let mut record_header = [0u8; 16];
+        if !read_full(&mut backup, &mut record_header)? {
+            break;
+        }
+        let offset = u64::from_le_bytes(record_header[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(record_header[8..].try_into().unwrap());
+        if offset.checked_add(len).is_none_or(|end| end > original_len) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "undo record goes past the original length",
+            ));
+        }
+
+        bytes.clear();
+        bytes.resize(len as usize, 0);
+        if !read_full(&mut backup, &mut bytes)? {
+            break;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&bytes)?;
+    }
+    file.flush()?;
+    Ok(original_len)
+}
+
+/// Fills `buf`, returns false if the end of `src` is reached before
+fn read_full(mut src: impl Read, buf: &mut [u8]) -> std::io::Result<bool> {
+    match src.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BufReaderWriter;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// Backup writer whose content can be read back while the adapter is alive
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedLog {
+        fn bytes(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn pristine() -> Vec<u8> {
+        (0..1000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_undo_log_patch_and_restore() {
+        let log = SharedLog::default();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(pristine()), 64);
+        buf.enable_undo_log(log.clone()).unwrap();
+
+        // Buffered writes over read data, over unread data, and direct writes
+        let mut bytes = [0u8; 10];
+        buf.read_exact(&mut bytes).unwrap();
+        buf.write_all(b"patched").unwrap();
+        buf.seek(SeekFrom::Start(500)).unwrap();
+        buf.write_all(b"elsewhere").unwrap();
+        buf.seek(SeekFrom::Start(700)).unwrap();
+        buf.write_all(&[0xFF; 200]).unwrap();
+        // Rewrites of a region already saved, and past the end
+        buf.seek(SeekFrom::Start(5)).unwrap();
+        buf.write_all(b"again").unwrap();
+        buf.seek(SeekFrom::Start(990)).unwrap();
+        buf.write_all(&[0xEE; 20]).unwrap();
+        buf.swap_ranges(100, 300, 50).unwrap();
+        buf.apply_patches(&mut [(400, b"p".to_vec())]).unwrap();
+        buf.rewrite_with(8, |_, chunk| {
+            chunk.iter_mut().for_each(|b| *b = b.wrapping_add(1));
+            Ok(())
+        })
+        .unwrap();
+
+        let mut data = Cursor::new(buf.into_inner().unwrap().into_inner());
+        assert_ne!(&data.get_ref()[..1000], pristine().as_slice());
+        assert_eq!(data.get_ref().len(), 1010);
+
+        let original_len = super::restore(&mut data, log.bytes().as_slice()).unwrap();
+        assert_eq!(original_len, 1000);
+        let mut data = data.into_inner();
+        data.truncate(original_len as usize);
+        assert_eq!(data, pristine());
+    }
+
+    #[test]
+    fn test_undo_log_saves_each_region_once() {
+        let log = SharedLog::default();
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(pristine()), 64);
+        buf.enable_undo_log(log.clone()).unwrap();
+        assert!(buf.is_undo_log_enabled());
+
+        buf.write_all(b"0123").unwrap();
+        buf.seek(SeekFrom::Start(0)).unwrap();
+        buf.write_all(b"abcdef").unwrap();
+        // header, then [0, 4) and [4, 6)
+        assert_eq!(log.bytes().len(), 16 + (16 + 4) + (16 + 2));
+
+        buf.disable_undo_log().unwrap();
+        assert!(!buf.is_undo_log_enabled());
+        buf.seek(SeekFrom::Start(100)).unwrap();
+        buf.write_all(b"not saved").unwrap();
+        assert_eq!(log.bytes().len(), 16 + (16 + 4) + (16 + 2));
+    }
+
+    #[test]
+    fn test_restore_rejects_invalid_logs() {
+        let mut data = Cursor::new(pristine());
+        let err = super::restore(&mut data, &b"not an undo log at all"[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = super::restore(&mut data, &b"BRW"[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // A truncated last record is ignored
+        let mut log = super::MAGIC.to_vec();
+        log.extend_from_slice(&1000u64.to_le_bytes());
+        log.extend_from_slice(&0u64.to_le_bytes());
+        log.extend_from_slice(&4u64.to_le_bytes());
+        log.extend_from_slice(b"ab");
+        assert_eq!(super::restore(&mut data, log.as_slice()).unwrap(), 1000);
+        assert_eq!(data.get_ref(), &pristine());
+    }
+}
@@ -0,0 +1,239 @@
+//! Invalidation of the cached data when the file is modified by someone else
+use crate::BufReaderWriter;
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What to do on the next operation after the watched file changed,
+/// when no buffered write is pending, see [BufReaderWriter::watch_for_external_changes]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StalePolicy {
+    /// Drop the cached data and carry on, reading the file again
+    Invalidate,
+    /// Drop the cached data and fail the operation with a [StaleCache] error
+    Error,
+}
+
+/// The error returned when the watched file changed while data was cached,
+/// see [BufReaderWriter::watch_for_external_changes]
+///
+/// It is wrapped in an [std::io::Error] of kind [std::io::ErrorKind::Other],
+/// and can be retrieved with [std::io::Error::get_ref].
+#[derive(Debug)]
+pub struct StaleCache {
+    /// Whether buffered writes were pending, in which case they were kept
+    pub dirty: bool,
+}
+
+impl std::fmt::Display for StaleCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.dirty {
+            write!(
+                f,
+                "the file was modified externally while writes were buffered"
+            )
+        } else {
+            write!(
+                f,
+                "the file was modified externally, the cached data was dropped"
+            )
+        }
+    }
+}
+
+impl std::error::Error for StaleCache {}
+
+/// A filesystem watcher raising a flag, checked when an operation starts
+pub(crate) struct Watch {
+    stale: Arc<AtomicBool>,
+    policy: StalePolicy,
+    // None in tests, where changes are simulated with `mark_stale`
+    _watcher: Option<notify::RecommendedWatcher>,
+}
+
+impl Watch {
+    fn new(path: &Path, policy: StalePolicy) -> std::io::Result<Self> {
+        use notify::Watcher;
+
+        let stale = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stale);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                match event {
+                    Ok(event) if event.kind.is_access() => {}
+                    // On errors, the changes may have been missed
+                    _ => flag.store(true, Ordering::Release),
+                }
+            })
+            .map_err(std::io::Error::other)?;
+        watcher
+            .watch(path, notify::RecursiveMode::NonRecursive)
+            .map_err(std::io::Error::other)?;
+        Ok(Self {
+            stale,
+            policy,
+            _watcher: Some(watcher),
+        })
+    }
+
+    /// Returns whether the file changed since the last call
+    pub(crate) fn take_stale(&self) -> bool {
+        self.stale.swap(false, Ordering::Acquire)
+    }
+
+    #[cfg(test)]
+    fn mark_stale(&self) {
+        self.stale.store(true, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> BufReaderWriter<T, N>
+where
+    T: Write + Seek,
+{
+    /// Watches the file at `path`, which should be the inner stream's,
+    /// to stop serving cached data once it is modified by another handle or process
+    ///
+    /// The changes are noticed on the next read, write or seek after the change event,
+    /// which is delivered asynchronously. Then:
+    ///
+    /// * If no buffered write is pending, the cached data is dropped, and
+    ///   the operation carries on or fails depending on the `policy`.
+    /// * If buffered writes are pending, the operation fails with a [StaleCache]
+    ///   error whose `dirty` field is `true`, and the dirty data is kept: writing it
+    ///   (e.g. with [Self::flush_writes]) overwrites the external changes,
+    ///   and [Self::sync_cache] must be called before reading from the file again.
+    ///
+    /// The writes made through this adapter produce change events too,
+    /// so this is meant for files this adapter mostly reads.
+    ///
+    /// Calling this again replaces the previous watch.
+    pub fn watch_for_external_changes(
+        &mut self,
+        path: impl AsRef<Path>,
+        policy: StalePolicy,
+    ) -> std::io::Result<()> {
+        self.watch = Some(Watch::new(path.as_ref(), policy)?);
+        Ok(())
+    }
+
+    /// Stops watching the file, see [Self::watch_for_external_changes]
+    pub fn stop_watching(&mut self) {
+        self.watch = None;
+    }
+
+    /// Handles a change of the file reported by the watcher
+    pub(crate) fn on_external_change(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_dirty() {
+            return Err(std::io::Error::other(StaleCache { dirty: true }));
+        }
+        self.discard_buffer()?;
+        self.known_len = None;
+        match self.watch.as_ref().map(|watch| watch.policy) {
+            Some(StalePolicy::Error) => Err(std::io::Error::other(StaleCache { dirty: false })),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StaleCache, StalePolicy, Watch};
+    use crate::BufReaderWriter;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    fn simulated_watch(policy: StalePolicy) -> Watch {
+        Watch {
+            stale: Arc::new(AtomicBool::new(false)),
+            policy,
+            _watcher: None,
+        }
+    }
+
+    fn stale_cache(err: &std::io::Error) -> &StaleCache {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<StaleCache>())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_external_change_invalidates_the_clean_cache() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+        buf.watch = Some(simulated_watch(StalePolicy::Invalidate));
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+
+        buf.inner_mut().get_mut()[2..4].copy_from_slice(b"ab");
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"23");
+
+        buf.watch.as_ref().unwrap().mark_stale();
+        buf.seek(SeekFrom::Start(2)).unwrap();
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"ab");
+        assert_eq!(buf.position(), 4);
+    }
+
+    #[test]
+    fn test_external_change_error_policy() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+        buf.watch = Some(simulated_watch(StalePolicy::Error));
+        let mut bytes = [0u8; 2];
+        buf.read_exact(&mut bytes).unwrap();
+
+        buf.inner_mut().get_mut()[2..4].copy_from_slice(b"ab");
+        buf.watch.as_ref().unwrap().mark_stale();
+        let err = buf.read_exact(&mut bytes).unwrap_err();
+        assert!(!stale_cache(&err).dirty);
+
+        // The cache was dropped, so retrying reads the new data
+        buf.read_exact(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"ab");
+    }
+
+    #[test]
+    fn test_external_change_conflicts_with_dirty_data() {
+        let mut buf = BufReaderWriter::with_capacity(Cursor::new(b"0123456789".to_vec()), 8);
+        buf.watch = Some(simulated_watch(StalePolicy::Invalidate));
+        buf.write_all(b"xy").unwrap();
+
+        buf.watch.as_ref().unwrap().mark_stale();
+        let err = buf.write_all(b"z").unwrap_err();
+        assert!(stale_cache(&err).dirty);
+        assert!(buf.has_dirty_data());
+
+        // The caller chose to keep its writes
+        buf.write_all(b"z").unwrap();
+        buf.flush().unwrap();
+        assert_eq!(buf.inner().get_ref(), b"xyz3456789");
+    }
+
+    #[test]
+    fn test_watch_for_external_changes_registers_on_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched.bin");
+        std::fs::write(&path, b"data").unwrap();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        let mut buf = BufReaderWriter::new(file);
+        buf.watch_for_external_changes(&path, StalePolicy::Invalidate)
+            .unwrap();
+        let mut content = String::new();
+        buf.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "data");
+        buf.stop_watching();
+
+        let missing = dir.path().join("missing.bin");
+        assert!(
+            buf.watch_for_external_changes(missing, StalePolicy::Invalidate)
+                .is_err()
+        );
+    }
+}
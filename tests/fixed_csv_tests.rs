@@ -1,4 +1,4 @@
-use bufrw::BufReaderWriter;
+use bufrw::{BufCopyEditor, BufReaderWriter, InlineBufReaderWriter};
 use rand::Rng;
 use rand::seq::SliceRandom;
 use std::io::Cursor;
@@ -341,6 +341,119 @@ fn test_rewrite_in_swapped_order_using_seek_from_start_random_order() {
     }
 }
 
+#[test]
+fn test_rewrite_in_swapped_order_with_tiny_capacities() {
+    let tester = FixedCsvTest::new();
+
+    for capacity in [0, 1, 2, 7] {
+        let mut bufreadwrite = BufReaderWriter::with_capacity(Cursor::new(vec![]), capacity);
+
+        tester.write_base_data(&mut bufreadwrite);
+        assert_eq!(
+            bufreadwrite.inner().get_ref().len(),
+            tester.num_records * tester.record_size
+        );
+        tester.assert_records_are_in_order(bufreadwrite.inner().get_ref().as_slice());
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.assert_records_are_in_order(&mut bufreadwrite);
+
+        let mut all_even_indices = (0..tester.num_records)
+            .filter(|i| i % 2 == 0)
+            .collect::<Vec<_>>();
+        all_even_indices.shuffle(&mut rand::rng());
+        tester.rewrite_in_swapped_order_using_seek_from_start(&mut bufreadwrite, all_even_indices);
+        tester.assert_records_are_in_swapped_order(bufreadwrite.inner().get_ref().as_slice());
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.assert_records_are_in_swapped_order(&mut bufreadwrite);
+
+        // Small reads and writes, then seeks relative to the position
+        bufreadwrite.seek(SeekFrom::Start(1)).unwrap();
+        let mut byte = [0u8; 1];
+        bufreadwrite.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"n");
+        bufreadwrite.write_all(b"S").unwrap();
+        bufreadwrite.seek(SeekFrom::Current(-2)).unwrap();
+        bufreadwrite.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"n");
+        bufreadwrite.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"S");
+        assert_eq!(bufreadwrite.position(), 3);
+        assert_eq!(bufreadwrite.into_inner().unwrap().get_ref()[..4], *b"InSo");
+    }
+}
+
+#[test]
+fn test_rewrite_in_swapped_order_with_inline_buffers() {
+    fn rewrite<const N: usize>(tester: &FixedCsvTest) {
+        let mut bufreadwrite: InlineBufReaderWriter<_, N> =
+            BufReaderWriter::new_inline(Cursor::new(vec![]));
+        assert_eq!(bufreadwrite.capacity(), N);
+
+        tester.write_base_data(&mut bufreadwrite);
+        assert_eq!(
+            bufreadwrite.inner().get_ref().len(),
+            tester.num_records * tester.record_size
+        );
+        tester.assert_records_are_in_order(bufreadwrite.inner().get_ref().as_slice());
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.assert_records_are_in_order(&mut bufreadwrite);
+
+        let mut all_even_indices = (0..tester.num_records)
+            .filter(|i| i % 2 == 0)
+            .collect::<Vec<_>>();
+        all_even_indices.shuffle(&mut rand::rng());
+        tester.rewrite_in_swapped_order_using_seek_from_start(&mut bufreadwrite, all_even_indices);
+        tester.assert_records_are_in_swapped_order(bufreadwrite.inner().get_ref().as_slice());
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.assert_records_are_in_swapped_order(&mut bufreadwrite);
+    }
+
+    let tester = FixedCsvTest::new();
+    rewrite::<1>(&tester);
+    rewrite::<7>(&tester);
+    rewrite::<64>(&tester);
+    rewrite::<4096>(&tester);
+}
+
+#[test]
+fn test_rewrite_in_swapped_order_while_resizing() {
+    let tester = FixedCsvTest::new();
+
+    let mut all_even_indices = (0..tester.num_records)
+        .filter(|i| i % 2 == 0)
+        .collect::<Vec<_>>();
+    all_even_indices.shuffle(&mut rand::rng());
+
+    let mut fixed = BufReaderWriter::with_capacity(Cursor::new(vec![]), 1000);
+    tester.write_base_data(&mut fixed);
+    tester.rewrite_in_swapped_order_using_seek_from_start(&mut fixed, all_even_indices.clone());
+
+    let mut resized = BufReaderWriter::with_capacity(Cursor::new(vec![]), 1000);
+    tester.write_base_data(&mut resized);
+    let mut csv = FixedCSVFile::new(tester.field_sizes.to_vec(), &mut resized);
+    let capacities = [4096, 150, 1, 333, 0, 2000];
+    for (i, index) in all_even_indices.into_iter().enumerate() {
+        csv.seek(index).unwrap();
+        let even_record = csv.read().unwrap();
+        assert_eq!(even_record.as_slice(), tester.expected_records[0].as_slice());
+        csv.stream.set_capacity(capacities[i % capacities.len()]).unwrap();
+        let odd_record = csv.read().unwrap();
+        assert_eq!(odd_record.as_slice(), tester.expected_records[1].as_slice());
+
+        csv.seek_relative(-2).unwrap();
+        csv.write(&tester.records[1]).unwrap();
+        csv.stream.set_capacity(capacities[(i + 1) % capacities.len()]).unwrap();
+        csv.write(&tester.records[0]).unwrap();
+    }
+    csv.flush().unwrap();
+
+    tester.assert_records_are_in_swapped_order(resized.inner().get_ref().as_slice());
+    assert_eq!(
+        resized.into_inner().unwrap().into_inner(),
+        fixed.into_inner().unwrap().into_inner()
+    );
+}
+
 #[test]
 fn test_rewrite_in_swapped_order_using_seek_current_random_order() {
     let tester = FixedCsvTest::new();
@@ -562,3 +675,245 @@ fn test_rewrite_in_swapped_order_using_seek_current_backward() {
     bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
     tester.assert_records_are_in_swapped_order(&mut bufreadwrite);
 }
+
+#[test]
+fn test_rewrite_in_swapped_order_suspended_and_resumed_midway() {
+    let tester = FixedCsvTest::new();
+
+    let mut all_even_indices = (0..tester.num_records)
+        .filter(|i| i % 2 == 0)
+        .collect::<Vec<_>>();
+    let mut rng = rand::rng();
+    all_even_indices.shuffle(&mut rng);
+
+    // Uninterrupted run, used as reference
+    let mut bufreadwrite = BufReaderWriter::new(Cursor::new(vec![]));
+    tester.write_base_data(&mut bufreadwrite);
+    tester.rewrite_in_swapped_order_using_seek_from_start(
+        &mut bufreadwrite,
+        all_even_indices.clone(),
+    );
+    let expected = bufreadwrite.into_inner().unwrap().into_inner();
+
+    // Interrupted run
+    let mut bufreadwrite = BufReaderWriter::with_capacity(Cursor::new(vec![]), 1000);
+    tester.write_base_data(&mut bufreadwrite);
+    let second_half = all_even_indices.split_off(all_even_indices.len() / 2);
+    // Indices are popped from the back
+    tester.rewrite_in_swapped_order_using_seek_from_start(&mut bufreadwrite, second_half);
+    bufreadwrite.seek(SeekFrom::Start(137)).unwrap();
+    let state = bufreadwrite.suspend().unwrap();
+    assert_eq!(state.position(), 137);
+
+    // Simulate the process being killed: only what reached the inner stream survives
+    let on_disk = bufreadwrite.inner().get_ref().clone();
+    std::mem::forget(bufreadwrite);
+
+    let mut bufreadwrite = BufReaderWriter::resume(Cursor::new(on_disk), state).unwrap();
+    assert_eq!(bufreadwrite.position(), 137);
+    assert_eq!(bufreadwrite.capacity(), 1000);
+    tester.rewrite_in_swapped_order_using_seek_from_start(&mut bufreadwrite, all_even_indices);
+
+    let result = bufreadwrite.into_inner().unwrap().into_inner();
+    tester.assert_records_are_in_swapped_order(result.as_slice());
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_rewrite_in_swapped_order_using_swap_ranges() {
+    let tester = FixedCsvTest::new();
+
+    let mut bufreadwrite = BufReaderWriter::new(Cursor::new(vec![]));
+
+    let record_size = tester.record_size;
+    let num_records = tester.num_records;
+
+    // Write the base data to the file, using the bufr
+    tester.write_base_data(&mut bufreadwrite);
+    assert_eq!(
+        bufreadwrite.inner().get_ref().len(),
+        num_records * record_size
+    );
+
+    // Check the data is correct by reading directly the underlying file
+    tester.assert_records_are_in_order(bufreadwrite.inner().get_ref().as_slice());
+
+    // Then check the data is correct by reading via the bufrw
+    bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+    tester.assert_records_are_in_order(&mut bufreadwrite);
+
+    // Swap each pair, in random order
+    let mut all_even_indices = (0..tester.num_records)
+        .filter(|i| i % 2 == 0)
+        .collect::<Vec<_>>();
+    let mut rng = rand::rng();
+    all_even_indices.shuffle(&mut rng);
+    for index in all_even_indices {
+        let even = (index * record_size) as u64;
+        let odd = even + record_size as u64;
+        bufreadwrite
+            .swap_ranges(even, odd, record_size as u64)
+            .unwrap();
+    }
+    bufreadwrite.flush().unwrap();
+
+    // Test the underlying data is correct
+    tester.assert_records_are_in_swapped_order(bufreadwrite.inner().get_ref().as_slice());
+    // Test reading via the bufrw is correct
+    bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+    tester.assert_records_are_in_swapped_order(&mut bufreadwrite);
+}
+
+#[test]
+fn test_rewrite_in_swapped_order_while_sampling_stats() {
+    let tester = FixedCsvTest::new();
+
+    let mut bufreadwrite = BufReaderWriter::with_capacity(Cursor::new(vec![]), 1000);
+    let handle = bufreadwrite.stats_handle();
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let sampler = {
+        let done = done.clone();
+        std::thread::spawn(move || {
+            let mut previous = handle.snapshot();
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                // Counters only ever grow
+                let snapshot = handle.snapshot();
+                assert!(snapshot.bytes_written >= previous.bytes_written);
+                assert!(snapshot.seeks >= previous.seeks);
+                previous = snapshot;
+            }
+        })
+    };
+
+    tester.write_base_data(&mut bufreadwrite);
+    for _ in 0..tester.num_random_seek_tests {
+        let mut all_even_indices = (0..tester.num_records)
+            .filter(|i| i % 2 == 0)
+            .collect::<Vec<_>>();
+        let mut rng = rand::rng();
+        all_even_indices.shuffle(&mut rng);
+        tester.rewrite_in_swapped_order_using_seek_from_start(&mut bufreadwrite, all_even_indices);
+        // Back to the base data
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.assert_records_are_in_swapped_order(&mut bufreadwrite);
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.write_base_data(&mut bufreadwrite);
+    }
+
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    sampler.join().unwrap();
+    assert!(bufreadwrite.stats().dumps > 0);
+    tester.assert_records_are_in_order(bufreadwrite.inner().get_ref().as_slice());
+}
+
+#[test]
+fn test_repad_fields_using_copy_editor() {
+    let tester = FixedCsvTest::new();
+    let new_size = 60;
+
+    let mut source = Cursor::new(vec![]);
+    tester.write_base_data(&mut source);
+    source.set_position(0);
+
+    let mut editor = BufCopyEditor::with_capacity(source, Cursor::new(vec![]), 1000).unwrap();
+    let padding = vec![b' '; new_size - tester.field_sizes[0]];
+    let mut separator = [0u8];
+    for _ in 0..tester.num_records {
+        for size in tester.field_sizes {
+            assert_eq!(editor.copy(size as u64).unwrap(), size as u64);
+            editor.write_record(&padding).unwrap();
+            editor.read_record(&mut separator).unwrap();
+            editor.write_record(&separator).unwrap();
+        }
+    }
+    let progress = editor.progress();
+    assert_eq!(
+        progress.source_position,
+        (tester.num_records * tester.record_size) as u64
+    );
+    assert_eq!(
+        progress.dest_position,
+        (tester.num_records * (2 * new_size + 2)) as u64
+    );
+    let (_, dest) = editor.finish().unwrap();
+    assert_eq!(dest.get_ref().len() as u64, progress.dest_position);
+
+    let mut csv = FixedCSVFile::new(vec![new_size; 2], dest);
+    csv.stream.set_position(0);
+    for i in 0..tester.num_records {
+        let values = csv.read().unwrap();
+        let expected = tester.records[i % 2]
+            .iter()
+            .map(|value| format!("{value:<new_size$}"))
+            .collect::<Vec<_>>();
+        assert_eq!(values, expected);
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn test_rewrite_in_swapped_order_increasing_order_inner_calls() {
+    use bufrw::CountingStream;
+
+    let tester = FixedCsvTest::new();
+    let data_len = tester.num_records * tester.record_size;
+
+    let mut bufreadwrite = BufReaderWriter::new(CountingStream::new(Cursor::new(vec![])));
+    tester.write_base_data(&mut bufreadwrite);
+    bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+    bufreadwrite.inner_mut().reset();
+
+    // Visiting the records in increasing order, each buffer window is
+    // read once, and written once when leaving it
+    let num_windows = data_len.div_ceil(bufreadwrite.capacity());
+    let all_even_indices = (0..tester.num_records)
+        .filter(|i| i % 2 == 0)
+        .rev()
+        .collect::<Vec<_>>();
+    tester.rewrite_in_swapped_order_using_seek_from_start(&mut bufreadwrite, all_even_indices);
+    let inner = bufreadwrite.inner();
+    assert_eq!(inner.reads(), num_windows);
+    assert_eq!(inner.writes(), num_windows);
+    // Only the dirty data is written, the inner stream may then
+    // have to be put back at the end of the window
+    assert!(inner.seeks() <= 2 * num_windows);
+    assert!(inner.bytes_written() >= data_len as u64);
+
+    tester.assert_records_are_in_swapped_order(inner.get_ref().get_ref().as_slice());
+}
+
+#[test]
+#[cfg(feature = "test-util")]
+fn test_rewrite_in_swapped_order_replayed_from_a_recording() {
+    use bufrw::{RecordingStream, ReplayStream};
+
+    let tester = FixedCsvTest::new();
+    let mut all_even_indices = (0..tester.num_records)
+        .filter(|i| i % 2 == 0)
+        .collect::<Vec<_>>();
+    all_even_indices.shuffle(&mut rand::rng());
+
+    fn workload<T: Read + Write + Seek>(
+        tester: &FixedCsvTest,
+        bufreadwrite: &mut BufReaderWriter<T>,
+        all_even_indices: Vec<usize>,
+    ) {
+        tester.write_base_data(&mut *bufreadwrite);
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.rewrite_in_swapped_order_using_seek_from_start(&mut *bufreadwrite, all_even_indices);
+        bufreadwrite.seek(SeekFrom::Start(0)).unwrap();
+        tester.assert_records_are_in_swapped_order(&mut *bufreadwrite);
+    }
+
+    let mut bufreadwrite = BufReaderWriter::new(RecordingStream::new(Cursor::new(vec![])));
+    workload(&tester, &mut bufreadwrite, all_even_indices.clone());
+    let (data, log) = bufreadwrite.into_inner().unwrap().into_parts();
+    tester.assert_records_are_in_swapped_order(data.get_ref().as_slice());
+
+    // The replayed adapter reads the same records, making the same calls
+    let mut bufreadwrite = BufReaderWriter::new(ReplayStream::new(log));
+    workload(&tester, &mut bufreadwrite, all_even_indices);
+    let replay = bufreadwrite.into_inner().unwrap();
+    assert!(replay.is_finished());
+    assert!(replay.calls() > 0);
+}